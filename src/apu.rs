@@ -0,0 +1,1027 @@
+use crate::region::{Region, NTSC_CPU_CLOCK_RATE};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+/// The NTSC CPU clock, see [`Region`] for the others.
+pub const CPU_CLOCK_RATE: f64 = NTSC_CPU_CLOCK_RATE;
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+// one step of pulse volume through the linear approximation of the pulse
+// mixer, the unit expansion audio is given in
+const EXPANSION_AUDIO_STEP: f32 = 0.00752;
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+const TRIANGLE_TABLE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+const NTSC_NOISE_PERIOD_TABLE: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+const PAL_NOISE_PERIOD_TABLE: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+const NTSC_DMC_RATE_TABLE: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+const PAL_DMC_RATE_TABLE: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+// frame counter step positions in CPU cycles; the fourth ends the 4-step
+// sequence and the fifth the 5-step one
+const NTSC_FRAME_STEPS: [u32; 5] = [7457, 14913, 22371, 29829, 37281];
+const PAL_FRAME_STEPS: [u32; 5] = [8313, 16627, 24939, 33253, 41565];
+
+#[derive(Debug, Default)]
+struct Envelope {
+    start: bool,
+    looping: bool,
+    constant: bool,
+    period: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, val: u8) {
+        self.looping = val & 0x20 != 0;
+        self.constant = val & 0x10 != 0;
+        self.period = val & 0x0F;
+    }
+
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.period;
+            return;
+        }
+        if self.divider > 0 {
+            self.divider -= 1;
+            return;
+        }
+        self.divider = self.period;
+        if self.decay > 0 {
+            self.decay -= 1;
+        } else if self.looping {
+            self.decay = 15;
+        }
+    }
+
+    fn get_volume(&self) -> u8 {
+        if self.constant {
+            return self.period;
+        }
+        return self.decay;
+    }
+}
+
+/// A pulse channel, also used for the MMC5's two.
+#[derive(Debug, Default)]
+pub(crate) struct Pulse {
+    is_channel_1: bool,
+    // the MMC5's channels have no sweep unit and are never muted
+    without_sweep: bool,
+    enabled: bool,
+    duty: u8,
+    duty_step: u8,
+    length_counter: u8,
+    length_halt: bool,
+    envelope: Envelope,
+    timer_period: u16,
+    timer: u16,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+}
+
+impl Pulse {
+    pub(crate) fn init_without_sweep() -> Self {
+        return Pulse {
+            without_sweep: true,
+            ..Pulse::default()
+        };
+    }
+
+    pub(crate) fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length_counter = 0;
+        }
+    }
+
+    pub(crate) fn is_playing(&self) -> bool {
+        return self.length_counter > 0;
+    }
+
+    pub(crate) fn write_register(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.duty = val >> 6;
+                self.length_halt = val & 0x20 != 0;
+                self.envelope.write(val);
+            }
+            1 => {
+                self.sweep_enabled = val & 0x80 != 0;
+                self.sweep_period = (val >> 4) & 0b111;
+                self.sweep_negate = val & 0x08 != 0;
+                self.sweep_shift = val & 0b111;
+                self.sweep_reload = true;
+            }
+            2 => self.timer_period = (self.timer_period & 0x0700) | val as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | (((val & 0b111) as u16) << 8);
+                if self.enabled {
+                    self.length_counter = LENGTH_TABLE[(val >> 3) as usize];
+                }
+                self.duty_step = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    pub(crate) fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.duty_step = (self.duty_step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn get_sweep_target(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if !self.sweep_negate {
+            return self.timer_period + change;
+        }
+        // pulse 1 negates with ones' complement, pulse 2 with two's complement
+        if self.is_channel_1 {
+            return self.timer_period.saturating_sub(change + 1);
+        }
+        return self.timer_period.saturating_sub(change);
+    }
+
+    fn is_muted(&self) -> bool {
+        if self.without_sweep {
+            return false;
+        }
+        return self.timer_period < 8 || self.get_sweep_target() > 0x7FF;
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.without_sweep {
+            return;
+        }
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.is_muted()
+        {
+            self.timer_period = self.get_sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    pub(crate) fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub(crate) fn clock_length(&mut self) {
+        if !self.length_halt && self.length_counter > 0 {
+            self.length_counter -= 1;
+        }
+    }
+
+    pub(crate) fn output(&self) -> u8 {
+        if self.length_counter == 0
+            || self.is_muted()
+            || DUTY_TABLE[self.duty as usize][self.duty_step as usize] == 0
+        {
+            return 0;
+        }
+        return self.envelope.get_volume();
+    }
+}
+
+#[derive(Debug, Default)]
+struct Triangle {
+    enabled: bool,
+    length_counter: u8,
+    control: bool,
+    linear_counter: u8,
+    linear_period: u8,
+    linear_reload: bool,
+    timer_period: u16,
+    timer: u16,
+    step: u8,
+}
+
+impl Triangle {
+    fn write_register(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.control = val & 0x80 != 0;
+                self.linear_period = val & 0x7F;
+            }
+            2 => self.timer_period = (self.timer_period & 0x0700) | val as u16,
+            3 => {
+                self.timer_period = (self.timer_period & 0x00FF) | (((val & 0b111) as u16) << 8);
+                if self.enabled {
+                    self.length_counter = LENGTH_TABLE[(val >> 3) as usize];
+                }
+                self.linear_reload = true;
+            }
+            _ => {}
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.length_counter > 0 && self.linear_counter > 0 {
+                self.step = (self.step + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_linear(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_period;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if !self.control && self.length_counter > 0 {
+            self.length_counter -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        // ultrasonic periods are silenced instead of aliasing into a hiss
+        if self.timer_period < 2 {
+            return 7;
+        }
+        return TRIANGLE_TABLE[self.step as usize];
+    }
+}
+
+#[derive(Debug)]
+struct Noise {
+    enabled: bool,
+    length_counter: u8,
+    length_halt: bool,
+    envelope: Envelope,
+    mode: bool,
+    // kept so a region switch can look the period up again
+    period_index: u8,
+    timer_period: u16,
+    timer: u16,
+    shift_register: u16,
+    period_table: &'static [u16; 16],
+}
+
+impl Noise {
+    fn init() -> Self {
+        return Noise {
+            enabled: false,
+            length_counter: 0,
+            length_halt: false,
+            envelope: Envelope::default(),
+            mode: false,
+            period_index: 0,
+            timer_period: NTSC_NOISE_PERIOD_TABLE[0],
+            timer: 0,
+            shift_register: 1,
+            period_table: &NTSC_NOISE_PERIOD_TABLE,
+        };
+    }
+
+    fn write_register(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.length_halt = val & 0x20 != 0;
+                self.envelope.write(val);
+            }
+            2 => {
+                self.mode = val & 0x80 != 0;
+                self.period_index = val & 0x0F;
+                self.timer_period = self.period_table[self.period_index as usize];
+            }
+            3 => {
+                if self.enabled {
+                    self.length_counter = LENGTH_TABLE[(val >> 3) as usize];
+                }
+                self.envelope.start = true;
+            }
+            _ => {}
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_period;
+        let tap = if self.mode { 6 } else { 1 };
+        let feedback = (self.shift_register & 1) ^ ((self.shift_register >> tap) & 1);
+        self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+    }
+
+    fn clock_length(&mut self) {
+        if !self.length_halt && self.length_counter > 0 {
+            self.length_counter -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.length_counter == 0 || self.shift_register & 1 == 1 {
+            return 0;
+        }
+        return self.envelope.get_volume();
+    }
+}
+
+#[derive(Debug)]
+struct DMC {
+    irq_enabled: bool,
+    irq_pending: bool,
+    looping: bool,
+    timer_period: u16,
+    timer: u16,
+    output_level: u8,
+    sample_addr: u16,
+    sample_length: u16,
+    current_addr: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+    rate_index: u8,
+    rate_table: &'static [u16; 16],
+}
+
+impl DMC {
+    fn init() -> Self {
+        return DMC {
+            irq_enabled: false,
+            irq_pending: false,
+            looping: false,
+            timer_period: NTSC_DMC_RATE_TABLE[0],
+            timer: 0,
+            output_level: 0,
+            sample_addr: 0xC000,
+            sample_length: 1,
+            current_addr: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+            rate_index: 0,
+            rate_table: &NTSC_DMC_RATE_TABLE,
+        };
+    }
+
+    fn write_register(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.irq_enabled = val & 0x80 != 0;
+                if !self.irq_enabled {
+                    self.irq_pending = false;
+                }
+                self.looping = val & 0x40 != 0;
+                self.rate_index = val & 0x0F;
+                self.timer_period = self.rate_table[self.rate_index as usize];
+            }
+            1 => self.output_level = val & 0x7F,
+            2 => self.sample_addr = 0xC000 | ((val as u16) << 6),
+            _ => self.sample_length = ((val as u16) << 4) | 1,
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_addr = self.sample_addr;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    fn needs_sample(&self) -> bool {
+        return self.sample_buffer.is_none() && self.bytes_remaining > 0;
+    }
+
+    fn fill_sample_buffer(&mut self, val: u8) {
+        self.sample_buffer = Some(val);
+        self.current_addr = self.current_addr.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq_pending = true;
+            }
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_period - 1;
+        if !self.silence {
+            if self.shift_register & 1 == 1 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(sample) => {
+                    self.silence = false;
+                    self.shift_register = sample;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+}
+
+/// The 2A03 audio processing unit, clocked once per CPU cycle.
+#[derive(Debug)]
+pub struct APU {
+    pulse_1: Pulse,
+    pulse_2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: DMC,
+
+    frame_cycle: u32,
+    frame_steps: [u32; 5],
+    five_step_mode: bool,
+    frame_irq_inhibit: bool,
+    frame_irq_pending: bool,
+    cycle: u64,
+
+    cpu_clock_rate: f64,
+    sample_rate: u32,
+    sample_accumulator: f32,
+    sample_count: u32,
+    sample_phase: f64,
+    high_pass_prev_input: f32,
+    high_pass_prev_output: f32,
+    samples: Vec<f32>,
+    expansion_output: f32,
+}
+
+impl APU {
+    pub fn init() -> Self {
+        let mut apu = APU {
+            pulse_1: Pulse::default(),
+            pulse_2: Pulse::default(),
+            triangle: Triangle::default(),
+            noise: Noise::init(),
+            dmc: DMC::init(),
+            frame_cycle: 0,
+            frame_steps: NTSC_FRAME_STEPS,
+            five_step_mode: false,
+            frame_irq_inhibit: false,
+            frame_irq_pending: false,
+            cycle: 0,
+            cpu_clock_rate: NTSC_CPU_CLOCK_RATE,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            sample_accumulator: 0.0,
+            sample_count: 0,
+            sample_phase: 0.0,
+            high_pass_prev_input: 0.0,
+            high_pass_prev_output: 0.0,
+            samples: Vec::new(),
+            expansion_output: 0.0,
+        };
+        apu.pulse_1.is_channel_1 = true;
+        return apu;
+    }
+
+    /// Switches the frame counter, noise and DMC periods and the sample
+    /// timing to another region. The Dendy uses the NTSC tables.
+    pub fn set_region(&mut self, region: Region) {
+        let is_pal = region == Region::PAL;
+        self.noise.period_table = if is_pal {
+            &PAL_NOISE_PERIOD_TABLE
+        } else {
+            &NTSC_NOISE_PERIOD_TABLE
+        };
+        self.noise.timer_period = self.noise.period_table[self.noise.period_index as usize];
+        self.dmc.rate_table = if is_pal {
+            &PAL_DMC_RATE_TABLE
+        } else {
+            &NTSC_DMC_RATE_TABLE
+        };
+        self.dmc.timer_period = self.dmc.rate_table[self.dmc.rate_index as usize];
+        self.frame_steps = if is_pal {
+            PAL_FRAME_STEPS
+        } else {
+            NTSC_FRAME_STEPS
+        };
+        self.cpu_clock_rate = region.get_cpu_clock_rate();
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+    }
+
+    /// Drains the mono samples produced since the last call, in [-1.0, 1.0].
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        return std::mem::take(&mut self.samples);
+    }
+
+    /// The level of the cartridge's sound channels, in steps of pulse
+    /// channel volume, mixed in from the next cycle on.
+    pub fn set_expansion_output(&mut self, output: f32) {
+        self.expansion_output = output;
+    }
+
+    pub fn irq_pending(&self) -> bool {
+        return self.frame_irq_pending || self.dmc.irq_pending;
+    }
+
+    /// Address the DMC wants to fetch its next sample byte from, if any.
+    pub fn get_dmc_request(&self) -> Option<u16> {
+        if self.dmc.needs_sample() {
+            return Some(self.dmc.current_addr);
+        }
+        return None;
+    }
+
+    pub fn fill_dmc_sample(&mut self, val: u8) {
+        self.dmc.fill_sample_buffer(val);
+    }
+
+    pub fn read_status(&mut self) -> u8 {
+        let mut status = 0;
+        if self.pulse_1.length_counter > 0 {
+            status |= 0x01;
+        }
+        if self.pulse_2.length_counter > 0 {
+            status |= 0x02;
+        }
+        if self.triangle.length_counter > 0 {
+            status |= 0x04;
+        }
+        if self.noise.length_counter > 0 {
+            status |= 0x08;
+        }
+        if self.dmc.bytes_remaining > 0 {
+            status |= 0x10;
+        }
+        if self.frame_irq_pending {
+            status |= 0x40;
+        }
+        if self.dmc.irq_pending {
+            status |= 0x80;
+        }
+        self.frame_irq_pending = false;
+        return status;
+    }
+
+    pub fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x4000..=0x4003 => self.pulse_1.write_register(addr - 0x4000, val),
+            0x4004..=0x4007 => self.pulse_2.write_register(addr - 0x4004, val),
+            0x4008..=0x400B => self.triangle.write_register(addr - 0x4008, val),
+            0x400C..=0x400F => self.noise.write_register(addr - 0x400C, val),
+            0x4010..=0x4013 => self.dmc.write_register(addr - 0x4010, val),
+            0x4015 => {
+                self.pulse_1.set_enabled(val & 0x01 != 0);
+                self.pulse_2.set_enabled(val & 0x02 != 0);
+                self.triangle.enabled = val & 0x04 != 0;
+                self.noise.enabled = val & 0x08 != 0;
+                if !self.triangle.enabled {
+                    self.triangle.length_counter = 0;
+                }
+                if !self.noise.enabled {
+                    self.noise.length_counter = 0;
+                }
+                self.dmc.irq_pending = false;
+                if val & 0x10 == 0 {
+                    self.dmc.bytes_remaining = 0;
+                } else if self.dmc.bytes_remaining == 0 {
+                    self.dmc.restart();
+                }
+            }
+            0x4017 => {
+                self.five_step_mode = val & 0x80 != 0;
+                self.frame_irq_inhibit = val & 0x40 != 0;
+                if self.frame_irq_inhibit {
+                    self.frame_irq_pending = false;
+                }
+                self.frame_cycle = 0;
+                if self.five_step_mode {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        self.pulse_1.envelope.clock();
+        self.pulse_2.envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear();
+    }
+
+    fn clock_half_frame(&mut self) {
+        self.pulse_1.clock_length();
+        self.pulse_2.clock_length();
+        self.triangle.clock_length();
+        self.noise.clock_length();
+        self.pulse_1.clock_sweep();
+        self.pulse_2.clock_sweep();
+    }
+
+    fn clock_frame_counter(&mut self) {
+        self.frame_cycle += 1;
+        let [step_1, step_2, step_3, step_4, step_5] = self.frame_steps;
+        match self.frame_cycle {
+            cycle if cycle == step_1 || cycle == step_3 => self.clock_quarter_frame(),
+            cycle if cycle == step_2 => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            cycle if cycle == step_4 && !self.five_step_mode => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+                if !self.frame_irq_inhibit {
+                    self.frame_irq_pending = true;
+                }
+                self.frame_cycle = 0;
+            }
+            cycle if cycle == step_5 => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+                self.frame_cycle = 0;
+            }
+            _ => {}
+        }
+    }
+
+    fn mix(&self) -> f32 {
+        let pulse = (self.pulse_1.output() + self.pulse_2.output()) as f32;
+        let pulse_out = if pulse == 0.0 {
+            0.0
+        } else {
+            95.88 / (8128.0 / pulse + 100.0)
+        };
+        let tnd = self.triangle.output() as f32 / 8227.0
+            + self.noise.output() as f32 / 12241.0
+            + self.dmc.output_level as f32 / 22638.0;
+        let tnd_out = if tnd == 0.0 {
+            0.0
+        } else {
+            159.79 / (1.0 / tnd + 100.0)
+        };
+        return pulse_out + tnd_out + self.expansion_output * EXPANSION_AUDIO_STEP;
+    }
+
+    fn push_sample(&mut self, sample: f32) {
+        // a first order high-pass around 90Hz removes the DC offset of the mixer
+        let alpha = 0.987;
+        let output = alpha * (self.high_pass_prev_output + sample - self.high_pass_prev_input);
+        self.high_pass_prev_input = sample;
+        self.high_pass_prev_output = output;
+        self.samples.push(output.clamp(-1.0, 1.0));
+    }
+
+    /// Advances the APU by one CPU cycle.
+    pub fn tick(&mut self) {
+        self.cycle += 1;
+        self.triangle.clock_timer();
+        if self.cycle.is_multiple_of(2) {
+            self.pulse_1.clock_timer();
+            self.pulse_2.clock_timer();
+            self.noise.clock_timer();
+        }
+        self.dmc.clock_timer();
+        self.clock_frame_counter();
+
+        self.sample_accumulator += self.mix();
+        self.sample_count += 1;
+        self.sample_phase += self.sample_rate as f64;
+        if self.sample_phase >= self.cpu_clock_rate {
+            self.sample_phase -= self.cpu_clock_rate;
+            let sample = self.sample_accumulator / self.sample_count as f32;
+            self.sample_accumulator = 0.0;
+            self.sample_count = 0;
+            self.push_sample(sample);
+        }
+    }
+}
+
+impl SaveState for Envelope {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.start);
+        state.write(&self.looping);
+        state.write(&self.constant);
+        state.write(&self.period);
+        state.write(&self.divider);
+        state.write(&self.decay);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read(&mut self.start)?;
+        state.read(&mut self.looping)?;
+        state.read(&mut self.constant)?;
+        state.read(&mut self.period)?;
+        state.read(&mut self.divider)?;
+        state.read(&mut self.decay)?;
+        return Ok(());
+    }
+}
+
+impl SaveState for Pulse {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.enabled);
+        state.write(&self.duty);
+        state.write(&self.duty_step);
+        state.write(&self.length_counter);
+        state.write(&self.length_halt);
+        state.write(&self.envelope);
+        state.write(&self.timer_period);
+        state.write(&self.timer);
+        state.write(&self.sweep_enabled);
+        state.write(&self.sweep_period);
+        state.write(&self.sweep_negate);
+        state.write(&self.sweep_shift);
+        state.write(&self.sweep_divider);
+        state.write(&self.sweep_reload);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read(&mut self.enabled)?;
+        state.read(&mut self.duty)?;
+        state.read(&mut self.duty_step)?;
+        if self.duty as usize >= DUTY_TABLE.len() || self.duty_step >= 8 {
+            return Err(StateError::Invalid("pulse duty"));
+        }
+        state.read(&mut self.length_counter)?;
+        state.read(&mut self.length_halt)?;
+        state.read(&mut self.envelope)?;
+        state.read(&mut self.timer_period)?;
+        state.read(&mut self.timer)?;
+        state.read(&mut self.sweep_enabled)?;
+        state.read(&mut self.sweep_period)?;
+        state.read(&mut self.sweep_negate)?;
+        state.read(&mut self.sweep_shift)?;
+        if self.sweep_shift >= 16 {
+            return Err(StateError::Invalid("sweep shift"));
+        }
+        state.read(&mut self.sweep_divider)?;
+        state.read(&mut self.sweep_reload)?;
+        return Ok(());
+    }
+}
+
+impl SaveState for Triangle {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.enabled);
+        state.write(&self.length_counter);
+        state.write(&self.control);
+        state.write(&self.linear_counter);
+        state.write(&self.linear_period);
+        state.write(&self.linear_reload);
+        state.write(&self.timer_period);
+        state.write(&self.timer);
+        state.write(&self.step);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read(&mut self.enabled)?;
+        state.read(&mut self.length_counter)?;
+        state.read(&mut self.control)?;
+        state.read(&mut self.linear_counter)?;
+        state.read(&mut self.linear_period)?;
+        state.read(&mut self.linear_reload)?;
+        state.read(&mut self.timer_period)?;
+        state.read(&mut self.timer)?;
+        state.read(&mut self.step)?;
+        if self.step as usize >= TRIANGLE_TABLE.len() {
+            return Err(StateError::Invalid("triangle step"));
+        }
+        return Ok(());
+    }
+}
+
+impl SaveState for Noise {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.enabled);
+        state.write(&self.length_counter);
+        state.write(&self.length_halt);
+        state.write(&self.envelope);
+        state.write(&self.mode);
+        state.write(&self.period_index);
+        state.write(&self.timer);
+        state.write(&self.shift_register);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read(&mut self.enabled)?;
+        state.read(&mut self.length_counter)?;
+        state.read(&mut self.length_halt)?;
+        state.read(&mut self.envelope)?;
+        state.read(&mut self.mode)?;
+        state.read(&mut self.period_index)?;
+        if self.period_index >= 16 {
+            return Err(StateError::Invalid("noise period"));
+        }
+        self.timer_period = self.period_table[self.period_index as usize];
+        state.read(&mut self.timer)?;
+        state.read(&mut self.shift_register)?;
+        return Ok(());
+    }
+}
+
+impl SaveState for DMC {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.irq_enabled);
+        state.write(&self.irq_pending);
+        state.write(&self.looping);
+        state.write(&self.rate_index);
+        state.write(&self.timer);
+        state.write(&self.output_level);
+        state.write(&self.sample_addr);
+        state.write(&self.sample_length);
+        state.write(&self.current_addr);
+        state.write(&self.bytes_remaining);
+        state.write(&self.sample_buffer);
+        state.write(&self.shift_register);
+        state.write(&self.bits_remaining);
+        state.write(&self.silence);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read(&mut self.irq_enabled)?;
+        state.read(&mut self.irq_pending)?;
+        state.read(&mut self.looping)?;
+        state.read(&mut self.rate_index)?;
+        if self.rate_index >= 16 {
+            return Err(StateError::Invalid("DMC rate"));
+        }
+        self.timer_period = self.rate_table[self.rate_index as usize];
+        state.read(&mut self.timer)?;
+        state.read(&mut self.output_level)?;
+        state.read(&mut self.sample_addr)?;
+        state.read(&mut self.sample_length)?;
+        state.read(&mut self.current_addr)?;
+        state.read(&mut self.bytes_remaining)?;
+        state.read(&mut self.sample_buffer)?;
+        state.read(&mut self.shift_register)?;
+        state.read(&mut self.bits_remaining)?;
+        state.read(&mut self.silence)?;
+        return Ok(());
+    }
+}
+
+// resampling and filter state belong to the audio output, not the machine
+impl SaveState for APU {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.pulse_1);
+        state.write(&self.pulse_2);
+        state.write(&self.triangle);
+        state.write(&self.noise);
+        state.write(&self.dmc);
+        state.write(&self.frame_cycle);
+        state.write(&self.five_step_mode);
+        state.write(&self.frame_irq_inhibit);
+        state.write(&self.frame_irq_pending);
+        state.write(&self.cycle);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read(&mut self.pulse_1)?;
+        state.read(&mut self.pulse_2)?;
+        state.read(&mut self.triangle)?;
+        state.read(&mut self.noise)?;
+        state.read(&mut self.dmc)?;
+        state.read(&mut self.frame_cycle)?;
+        state.read(&mut self.five_step_mode)?;
+        state.read(&mut self.frame_irq_inhibit)?;
+        state.read(&mut self.frame_irq_pending)?;
+        state.read(&mut self.cycle)?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn reload<T: SaveState>(component: &mut T) -> Result<(), StateError> {
+        let mut state = StateWriter::init();
+        state.write(component);
+        let data = state.into_bytes();
+        return StateReader::init(&data).read(component);
+    }
+
+    #[test]
+    fn test_load_state_checks_table_indices() {
+        let mut pulse = Pulse::default();
+        assert_eq!(reload(&mut pulse), Ok(()));
+        pulse.duty = 4;
+        assert_eq!(reload(&mut pulse), Err(StateError::Invalid("pulse duty")));
+        pulse.duty = 0;
+        pulse.duty_step = 8;
+        assert_eq!(reload(&mut pulse), Err(StateError::Invalid("pulse duty")));
+        pulse.duty_step = 0;
+        pulse.sweep_shift = 16;
+        assert_eq!(reload(&mut pulse), Err(StateError::Invalid("sweep shift")));
+
+        let mut triangle = Triangle {
+            step: 32,
+            ..Triangle::default()
+        };
+        assert_eq!(
+            reload(&mut triangle),
+            Err(StateError::Invalid("triangle step"))
+        );
+    }
+
+    #[test]
+    fn test_region_switch_looks_periods_up_again() {
+        let mut apu = APU::init();
+        apu.write_register(0x400E, 0x05);
+        apu.write_register(0x4010, 0x03);
+        assert_eq!(apu.noise.timer_period, 96);
+        assert_eq!(apu.dmc.timer_period, 320);
+
+        apu.set_region(Region::PAL);
+        assert_eq!(apu.noise.timer_period, 88);
+        assert_eq!(apu.dmc.timer_period, 298);
+        assert_eq!(reload(&mut apu), Ok(()));
+        assert_eq!(apu.noise.timer_period, 88);
+        assert_eq!(apu.dmc.timer_period, 298);
+
+        apu.set_region(Region::NTSC);
+        assert_eq!(apu.noise.timer_period, 96);
+        assert_eq!(apu.dmc.timer_period, 320);
+    }
+
+    #[test]
+    fn test_pal_frame_counter_timing() {
+        let mut apu = APU::init();
+        apu.set_region(Region::PAL);
+        apu.write_register(0x4015, 0x01);
+        // length index 1, a count of 254
+        apu.write_register(0x4003, 0x08);
+        apu.write_register(0x4017, 0x00);
+
+        for _ in 0..16626 {
+            apu.tick();
+        }
+        assert_eq!(apu.pulse_1.length_counter, 254);
+        apu.tick();
+        assert_eq!(apu.pulse_1.length_counter, 253);
+
+        for _ in 16627..33252 {
+            apu.tick();
+        }
+        assert!(!apu.irq_pending());
+        apu.tick();
+        assert!(apu.irq_pending());
+        assert_eq!(apu.pulse_1.length_counter, 252);
+    }
+}
@@ -0,0 +1,538 @@
+//! A small two pass 6502 assembler, for tests and patching code from the
+//! debugger.
+//!
+//! Standard syntax, one statement per line:
+//!
+//! ```text
+//! PPUCTRL = $2000        ; constants
+//!         .org $8000
+//! reset:  LDX #0         ; labels end in a colon
+//! loop:   INX
+//!         STX $10
+//!         BNE loop
+//!         JMP (vector)
+//! vector: .word reset, $1234
+//! text:   .byte "HI", $0D, <reset, >reset
+//! ```
+//!
+//! Numbers are `$hex`, `%binary` or decimal, and expressions can add and
+//! subtract numbers, labels and `*` (the current address). `<` and `>` in
+//! front of an expression take its low and high byte. Operands that are known
+//! to fit in a byte when first seen use zero page addressing; labels defined
+//! further down always get absolute addressing.
+
+use crate::opcodes::{get_opcode, AddressingModes};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "line {}: {}", self.line, self.msg);
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// Assembled code, ready for [`CPU::load_to_memory`](crate::CPU::load_to_memory).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    /// Where `bytes` start.
+    pub origin: u16,
+    pub bytes: Vec<u8>,
+    labels: HashMap<String, u16>,
+}
+
+impl Program {
+    pub fn get_label(&self, name: &str) -> Option<u16> {
+        return self.labels.get(name).copied();
+    }
+}
+
+/// Operand syntax before the mnemonic decides between zero page, absolute and
+/// relative addressing.
+enum Operand<'a> {
+    None,
+    Accumulator,
+    Immediate(&'a str),
+    Address(&'a str),
+    AddressX(&'a str),
+    AddressY(&'a str),
+    Indirect(&'a str),
+    IndirectX(&'a str),
+    IndirectY(&'a str),
+}
+
+enum Statement<'a> {
+    Empty,
+    Constant(&'a str, &'a str),
+    Org(&'a str),
+    Bytes(&'a str),
+    Words(&'a str),
+    Instruction(String, Operand<'a>),
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    let first_ok =
+        matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '@');
+    return first_ok && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+}
+
+/// Drops a `;` comment, leaving `;` inside strings alone.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    return line;
+}
+
+/// Splits a directive's argument list on commas outside strings.
+fn split_list(text: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let mut in_string = false;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            ',' if !in_string => {
+                items.push(text[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(text[start..].trim());
+    return items;
+}
+
+fn strip_suffix_ignore_case<'a>(text: &'a str, suffix: &str) -> Option<&'a str> {
+    let split = text.len().checked_sub(suffix.len())?;
+    if !text.is_char_boundary(split) || !text[split..].eq_ignore_ascii_case(suffix) {
+        return None;
+    }
+    return Some(text[..split].trim_end());
+}
+
+fn parse_operand(text: &str) -> Operand<'_> {
+    if text.is_empty() {
+        return Operand::None;
+    }
+    if text.eq_ignore_ascii_case("a") {
+        return Operand::Accumulator;
+    }
+    if let Some(val) = text.strip_prefix('#') {
+        return Operand::Immediate(val.trim());
+    }
+    if let Some(inner) = text.strip_prefix('(') {
+        if let Some(pointer) = strip_suffix_ignore_case(inner, ",x)") {
+            return Operand::IndirectX(pointer.trim());
+        }
+        if let Some(pointer) = strip_suffix_ignore_case(inner, "),y") {
+            return Operand::IndirectY(pointer.trim());
+        }
+        if let Some(pointer) = inner.strip_suffix(')') {
+            return Operand::Indirect(pointer.trim());
+        }
+    }
+    if let Some(addr) = strip_suffix_ignore_case(text, ",x") {
+        return Operand::AddressX(addr);
+    }
+    if let Some(addr) = strip_suffix_ignore_case(text, ",y") {
+        return Operand::AddressY(addr);
+    }
+    return Operand::Address(text);
+}
+
+/// Parses a line into its label, if any, and statement.
+fn parse_line(line: &str) -> Result<(Option<&str>, Statement<'_>), String> {
+    let mut rest = strip_comment(line).trim();
+    let mut label = None;
+    if let Some((name, after)) = rest.split_once(':') {
+        if is_identifier(name.trim()) {
+            label = Some(name.trim());
+            rest = after.trim();
+        }
+    }
+    if rest.is_empty() {
+        return Ok((label, Statement::Empty));
+    }
+    if let Some((name, val)) = rest.split_once('=') {
+        let name = name.trim();
+        if label.is_none() && is_identifier(name) {
+            return Ok((None, Statement::Constant(name, val.trim())));
+        }
+    }
+    let (word, args) = rest
+        .split_once(|c: char| c.is_whitespace())
+        .unwrap_or((rest, ""));
+    let args = args.trim();
+    let statement = match word.to_ascii_lowercase().as_str() {
+        ".org" => Statement::Org(args),
+        ".byte" => Statement::Bytes(args),
+        ".word" => Statement::Words(args),
+        directive if directive.starts_with('.') => {
+            return Err(format!("unknown directive {}", word));
+        }
+        _ => Statement::Instruction(word.to_ascii_uppercase(), parse_operand(args)),
+    };
+    return Ok((label, statement));
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    if let Some(hex) = text.strip_prefix('$') {
+        return i64::from_str_radix(hex, 16).ok();
+    }
+    if let Some(binary) = text.strip_prefix('%') {
+        return i64::from_str_radix(binary, 2).ok();
+    }
+    return text.parse().ok();
+}
+
+struct Assembler {
+    labels: HashMap<String, u16>,
+    // the addressing mode picked for each instruction line in the first pass
+    modes: HashMap<usize, AddressingModes>,
+    final_pass: bool,
+    pc: u16,
+}
+
+impl Assembler {
+    /// Evaluates an expression. Labels that aren't defined yet are 0 in the
+    /// first pass, which reports them through the second return value.
+    fn eval(&self, expr: &str) -> Result<(i64, bool), String> {
+        let expr = expr.trim();
+        if let Some(inner) = expr.strip_prefix('<') {
+            let (val, known) = self.eval(inner)?;
+            return Ok((val & 0xFF, known));
+        }
+        if let Some(inner) = expr.strip_prefix('>') {
+            let (val, known) = self.eval(inner)?;
+            return Ok(((val >> 8) & 0xFF, known));
+        }
+        let mut total = 0;
+        let mut known = true;
+        let mut sign = 1;
+        let mut start = 0;
+        let bytes = expr.as_bytes();
+        for i in 0..=bytes.len() {
+            let at_operator =
+                i < bytes.len() && (bytes[i] == b'+' || bytes[i] == b'-') && i > start;
+            if i < bytes.len() && !at_operator {
+                continue;
+            }
+            let term = expr[start..i].trim();
+            let (val, term_known) = self.eval_term(term)?;
+            total += sign * val;
+            known &= term_known;
+            if i < bytes.len() {
+                sign = if bytes[i] == b'-' { -1 } else { 1 };
+            }
+            start = i + 1;
+        }
+        return Ok((total, known));
+    }
+
+    fn eval_term(&self, term: &str) -> Result<(i64, bool), String> {
+        if let Some(inner) = term.strip_prefix('-') {
+            let (val, known) = self.eval_term(inner.trim())?;
+            return Ok((-val, known));
+        }
+        if term == "*" {
+            return Ok((self.pc as i64, true));
+        }
+        if let Some(val) = parse_number(term) {
+            return Ok((val, true));
+        }
+        if !is_identifier(term) {
+            return Err(format!("can't parse '{}'", term));
+        }
+        match self.labels.get(term) {
+            Some(&val) => return Ok((val as i64, true)),
+            None if !self.final_pass => return Ok((0, false)),
+            None => return Err(format!("undefined label {}", term)),
+        }
+    }
+
+    fn eval_byte(&self, expr: &str) -> Result<u8, String> {
+        let (val, _) = self.eval(expr)?;
+        if !(-128..=0xFF).contains(&val) {
+            return Err(format!("{} doesn't fit in a byte", expr));
+        }
+        return Ok(val as u8);
+    }
+
+    fn eval_word(&self, expr: &str) -> Result<u16, String> {
+        let (val, _) = self.eval(expr)?;
+        if !(-0x8000..=0xFFFF).contains(&val) {
+            return Err(format!("{} doesn't fit in a word", expr));
+        }
+        return Ok(val as u16);
+    }
+
+    /// Zero page if the address is known to fit and the instruction has a
+    /// zero page form, absolute otherwise.
+    fn pick_mode(
+        &self,
+        mnemonic: &str,
+        addr: &str,
+        zero_page: AddressingModes,
+        absolute: AddressingModes,
+    ) -> Result<AddressingModes, String> {
+        let (val, known) = self.eval(addr)?;
+        if known && (0..=0xFF).contains(&val) && get_opcode(mnemonic, zero_page).is_some() {
+            return Ok(zero_page);
+        }
+        return Ok(absolute);
+    }
+
+    fn encode(
+        &mut self,
+        line: usize,
+        mnemonic: &str,
+        operand: &Operand,
+    ) -> Result<Vec<u8>, String> {
+        let (mode, expr) = match *operand {
+            Operand::None if get_opcode(mnemonic, AddressingModes::Implicit).is_some() => {
+                (AddressingModes::Implicit, None)
+            }
+            Operand::None | Operand::Accumulator => (AddressingModes::Accumulator, None),
+            Operand::Immediate(val) => (AddressingModes::Immediate, Some(val)),
+            Operand::Indirect(addr) => (AddressingModes::Indirect, Some(addr)),
+            Operand::IndirectX(addr) => (AddressingModes::IndirectX, Some(addr)),
+            Operand::IndirectY(addr) => (AddressingModes::IndirectY, Some(addr)),
+            Operand::Address(addr) if get_opcode(mnemonic, AddressingModes::Relative).is_some() => {
+                (AddressingModes::Relative, Some(addr))
+            }
+            Operand::Address(addr) | Operand::AddressX(addr) | Operand::AddressY(addr) => {
+                let mode = match self.modes.get(&line) {
+                    Some(&mode) => mode,
+                    None => {
+                        let (zero_page, absolute) = match operand {
+                            Operand::AddressX(_) => {
+                                (AddressingModes::ZeroPageX, AddressingModes::AbsoluteX)
+                            }
+                            Operand::AddressY(_) => {
+                                (AddressingModes::ZeroPageY, AddressingModes::AbsoluteY)
+                            }
+                            _ => (AddressingModes::ZeroPage, AddressingModes::Absolute),
+                        };
+                        self.pick_mode(mnemonic, addr, zero_page, absolute)?
+                    }
+                };
+                self.modes.insert(line, mode);
+                (mode, Some(addr))
+            }
+        };
+        let opcode = get_opcode(mnemonic, mode)
+            .ok_or_else(|| format!("{} doesn't support {:?} addressing", mnemonic, mode))?;
+        let mut bytes = vec![opcode];
+        let expr = match expr {
+            Some(expr) => expr,
+            None => return Ok(bytes),
+        };
+        match mode {
+            AddressingModes::Relative => {
+                let (target, _) = self.eval(expr)?;
+                let offset = target - (self.pc as i64 + 2);
+                if self.final_pass && !(-128..=127).contains(&offset) {
+                    return Err(format!("branch to {} is out of range", expr));
+                }
+                bytes.push(offset as u8);
+            }
+            _ if mode.get_length() == 2 => bytes.push(self.eval_byte(expr)?),
+            _ => bytes.extend(self.eval_word(expr)?.to_le_bytes()),
+        }
+        return Ok(bytes);
+    }
+
+    fn run_pass(&mut self, source: &str, origin: u16) -> Result<Program, AsmError> {
+        self.pc = origin;
+        let mut program = Program {
+            origin,
+            bytes: Vec::new(),
+            labels: HashMap::new(),
+        };
+        let mut org_seen = false;
+        for (index, line) in source.lines().enumerate() {
+            let error = |msg: String| AsmError {
+                line: index + 1,
+                msg,
+            };
+            let (label, statement) = parse_line(line).map_err(error)?;
+            if let Some(label) = label {
+                if !self.final_pass && self.labels.contains_key(label) {
+                    return Err(error(format!("{} is defined twice", label)));
+                }
+                self.labels.insert(label.to_string(), self.pc);
+            }
+            let bytes = match statement {
+                Statement::Empty => Vec::new(),
+                Statement::Constant(name, expr) => {
+                    let (val, known) = self.eval(expr).map_err(error)?;
+                    if known {
+                        self.labels.insert(name.to_string(), val as u16);
+                    }
+                    Vec::new()
+                }
+                Statement::Org(expr) => {
+                    let addr = self.eval_word(expr).map_err(error)?;
+                    if !org_seen && program.bytes.is_empty() {
+                        program.origin = addr;
+                    } else if addr < self.pc {
+                        return Err(error(format!(".org ${:04X} goes backwards", addr)));
+                    }
+                    org_seen = true;
+                    self.pc = addr;
+                    Vec::new()
+                }
+                Statement::Bytes(list) => {
+                    let mut bytes = Vec::new();
+                    for item in split_list(list) {
+                        match item.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+                            Some(text) => bytes.extend(text.bytes()),
+                            None => bytes.push(self.eval_byte(item).map_err(error)?),
+                        }
+                    }
+                    bytes
+                }
+                Statement::Words(list) => {
+                    let mut bytes = Vec::new();
+                    for item in split_list(list) {
+                        bytes.extend(self.eval_word(item).map_err(error)?.to_le_bytes());
+                    }
+                    bytes
+                }
+                Statement::Instruction(mnemonic, operand) => {
+                    self.encode(index, &mnemonic, &operand).map_err(error)?
+                }
+            };
+            let offset = match self.pc.checked_sub(program.origin) {
+                Some(offset) => offset as usize,
+                None => return Err(error(String::from("code runs past $FFFF"))),
+            };
+            if program.bytes.len() < offset {
+                program.bytes.resize(offset, 0);
+            }
+            program.bytes.extend(&bytes);
+            self.pc = self.pc.wrapping_add(bytes.len() as u16);
+        }
+        program.labels = self.labels.clone();
+        return Ok(program);
+    }
+}
+
+/// Assembles `source`, starting at `origin` unless the source begins with an
+/// `.org`.
+pub fn assemble(source: &str, origin: u16) -> Result<Program, AsmError> {
+    let mut assembler = Assembler {
+        labels: HashMap::new(),
+        modes: HashMap::new(),
+        final_pass: false,
+        pc: origin,
+    };
+    assembler.run_pass(source, origin)?;
+    assembler.final_pass = true;
+    return assembler.run_pass(source, origin);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bytes(source: &str) -> Vec<u8> {
+        return assemble(source, 0x8000).unwrap().bytes;
+    }
+
+    fn error(source: &str) -> String {
+        return assemble(source, 0x8000).unwrap_err().to_string();
+    }
+
+    #[test]
+    fn test_addressing_modes() {
+        assert_eq!(bytes("LDA #$C0"), vec![0xA9, 0xC0]);
+        assert_eq!(bytes("lda #%101"), vec![0xA9, 0x05]);
+        assert_eq!(bytes("LDA 16"), vec![0xA5, 0x10]);
+        assert_eq!(bytes("LDA $10,X"), vec![0xB5, 0x10]);
+        assert_eq!(bytes("LDX $10,y"), vec![0xB6, 0x10]);
+        assert_eq!(bytes("LDA $10,Y"), vec![0xB9, 0x10, 0x00]);
+        assert_eq!(bytes("STA $0200"), vec![0x8D, 0x00, 0x02]);
+        assert_eq!(bytes("STA $1234,X"), vec![0x9D, 0x34, 0x12]);
+        assert_eq!(bytes("LDA ($20,X)"), vec![0xA1, 0x20]);
+        assert_eq!(bytes("LDA ($20),Y"), vec![0xB1, 0x20]);
+        assert_eq!(bytes("JMP ($FFFC)"), vec![0x6C, 0xFC, 0xFF]);
+        assert_eq!(bytes("ASL"), vec![0x0A]);
+        assert_eq!(bytes("ror a"), vec![0x6A]);
+        assert_eq!(bytes("INX"), vec![0xE8]);
+    }
+
+    #[test]
+    fn test_labels_and_directives() {
+        let program = assemble(
+            "PTR = $20            ; zero page constant
+                     .org $C000
+             start:  LDX #0
+             loop:   INX
+                     STX PTR
+                     BNE loop
+                     JSR done
+                     JMP (vector)
+             done:   RTS
+             vector: .word start, * + 2
+             text:   .byte \"A;B\", <start, >start",
+            0,
+        )
+        .unwrap();
+        assert_eq!(program.origin, 0xC000);
+        assert_eq!(program.get_label("loop"), Some(0xC002));
+        assert_eq!(
+            program.bytes,
+            vec![
+                0xA2, 0x00, 0xE8, 0x86, 0x20, 0xD0, 0xFB, 0x20, 0x0D, 0xC0, 0x6C, 0x0E, 0xC0, 0x60,
+                0x00, 0xC0, 0x10, 0xC0, 0x41, 0x3B, 0x42, 0x00, 0xC0
+            ]
+        );
+    }
+
+    #[test]
+    fn test_forward_labels_use_absolute_addressing() {
+        assert_eq!(
+            bytes("LDA value\nvalue: .byte 7"),
+            vec![0xAD, 0x03, 0x80, 0x07]
+        );
+        assert_eq!(
+            bytes(".org $10\nBRK\n.org $12\nNOP"),
+            vec![0x00, 0x00, 0xEA]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            error("NOP\nLDA #$100"),
+            "line 2: $100 doesn't fit in a byte"
+        );
+        assert_eq!(error("JMP nowhere"), "line 1: undefined label nowhere");
+        assert_eq!(
+            error("STA #1"),
+            "line 1: STA doesn't support Immediate addressing"
+        );
+        assert_eq!(error("a: NOP\na: NOP"), "line 2: a is defined twice");
+        assert_eq!(
+            error(".org $10\n.org $8"),
+            "line 2: .org $0008 goes backwards"
+        );
+        assert!(error("far: .byte 0\n.org $8100\nBNE far").contains("out of range"));
+        assert!(error(".fill 4").contains("unknown directive"));
+    }
+}
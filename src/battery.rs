@@ -0,0 +1,217 @@
+//! Battery backed PRG RAM kept in a `.sav` file, and changes to FDS disks
+//! kept as an `.ips` patch so the disk image itself is never written.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const IPS_HEADER: &[u8] = b"PATCH";
+const IPS_FOOTER: &[u8] = b"EOF";
+const IPS_MAX_RECORD: usize = 0xFFFF;
+
+/// Frames between writes of a changed `.sav` while running, about 5 seconds.
+pub const DEFAULT_FLUSH_INTERVAL: u64 = 300;
+
+/// The `.sav` next to the ROM, e.g. `game.nes` -> `game.sav`.
+pub fn default_save_path<P: AsRef<Path>>(rom_path: P) -> PathBuf {
+    return rom_path.as_ref().with_extension("sav");
+}
+
+/// The `.ips` next to a disk image, e.g. `game.fds` -> `game.ips`.
+pub fn default_patch_path<P: AsRef<Path>>(rom_path: P) -> PathBuf {
+    return rom_path.as_ref().with_extension("ips");
+}
+
+/// An IPS patch turning `original` into `data`, which are the same size.
+fn encode_ips(original: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut patch = IPS_HEADER.to_vec();
+    let mut offset = 0;
+    while offset < data.len() {
+        if data[offset] == original[offset] {
+            offset += 1;
+            continue;
+        }
+        let mut end = offset;
+        while end < data.len() && data[end] != original[end] && end - offset < IPS_MAX_RECORD {
+            end += 1;
+        }
+        patch.extend_from_slice(&(offset as u32).to_be_bytes()[1..]);
+        patch.extend_from_slice(&((end - offset) as u16).to_be_bytes());
+        patch.extend_from_slice(&data[offset..end]);
+        offset = end;
+    }
+    patch.extend_from_slice(IPS_FOOTER);
+    return patch;
+}
+
+/// Applies an IPS patch to `data`, dropping anything past its end.
+fn apply_ips(patch: &[u8], data: &mut [u8]) -> io::Result<()> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not an IPS patch");
+    let mut rest = patch.strip_prefix(IPS_HEADER).ok_or_else(invalid)?;
+    while rest != IPS_FOOTER {
+        if rest.len() < 5 {
+            return Err(invalid());
+        }
+        let offset = u32::from_be_bytes([0, rest[0], rest[1], rest[2]]) as usize;
+        let size = u16::from_be_bytes([rest[3], rest[4]]) as usize;
+        rest = &rest[5..];
+        // a run of one value
+        let (size, bytes) = if size == 0 {
+            if rest.len() < 3 {
+                return Err(invalid());
+            }
+            let size = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+            let run = vec![rest[2]; size];
+            rest = &rest[3..];
+            (size, run)
+        } else {
+            if rest.len() < size {
+                return Err(invalid());
+            }
+            let bytes = rest[..size].to_vec();
+            rest = &rest[size..];
+            (size, bytes)
+        };
+        let end = (offset + size).min(data.len());
+        if offset < end {
+            data[offset..end].copy_from_slice(&bytes[..end - offset]);
+        }
+    }
+    return Ok(());
+}
+
+#[derive(Debug)]
+pub struct BatteryFile {
+    path: PathBuf,
+    // set for patch files, which hold the changes from this
+    original: Option<Vec<u8>>,
+    // what the file holds, so unchanged RAM isn't rewritten
+    saved: Vec<u8>,
+    flush_interval: u64,
+    frames_since_flush: u64,
+}
+
+impl BatteryFile {
+    pub fn init(path: PathBuf) -> Self {
+        return BatteryFile {
+            path,
+            original: None,
+            saved: Vec::new(),
+            flush_interval: DEFAULT_FLUSH_INTERVAL,
+            frames_since_flush: 0,
+        };
+    }
+
+    /// A file holding the changes to `original` as an IPS patch, for disks.
+    pub fn init_patch(path: PathBuf, original: Vec<u8>) -> Self {
+        let mut file = BatteryFile::init(path);
+        // nothing to write until the disk changes
+        file.saved = original.clone();
+        file.original = Some(original);
+        return file;
+    }
+
+    pub fn get_path(&self) -> &Path {
+        return &self.path;
+    }
+
+    pub fn set_flush_interval(&mut self, frames: u64) {
+        self.flush_interval = frames;
+    }
+
+    /// Copies the file into `ram`, or applies the patch to it. A missing file
+    /// leaves `ram` untouched and a file of the wrong size fills as much as it
+    /// can.
+    pub fn load(&mut self, ram: &mut [u8]) -> io::Result<()> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        if self.original.is_some() {
+            apply_ips(&data, ram)?;
+        } else {
+            let len = data.len().min(ram.len());
+            ram[..len].copy_from_slice(&data[..len]);
+        }
+        self.saved = ram.to_vec();
+        return Ok(());
+    }
+
+    /// Writes `ram` out if it changed since the last write. The data goes to
+    /// a temporary file first so a crash mid-write can't corrupt the save.
+    pub fn save(&mut self, ram: &[u8]) -> io::Result<()> {
+        self.frames_since_flush = 0;
+        if self.saved == ram {
+            return Ok(());
+        }
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        match &self.original {
+            Some(original) => fs::write(&tmp_path, encode_ips(original, ram))?,
+            None => fs::write(&tmp_path, ram)?,
+        }
+        fs::rename(&tmp_path, &self.path)?;
+        self.saved = ram.to_vec();
+        return Ok(());
+    }
+
+    /// Counts a frame and returns true when a periodic flush is due.
+    pub fn tick_frame(&mut self) -> bool {
+        self.frames_since_flush += 1;
+        return self.frames_since_flush >= self.flush_interval;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_save_and_load() {
+        let path = env::temp_dir().join(format!("rustes-battery-{}.sav", process::id()));
+        let mut file = BatteryFile::init(path.clone());
+        let mut ram = vec![0; 4];
+        file.load(&mut ram).unwrap();
+        assert_eq!(ram, vec![0; 4]);
+
+        file.save(&[1, 2, 3, 4]).unwrap();
+        let mut ram = vec![0; 8];
+        BatteryFile::init(path.clone()).load(&mut ram).unwrap();
+        assert_eq!(ram, vec![1, 2, 3, 4, 0, 0, 0, 0]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_patch_round_trip() {
+        let original = vec![0; 0x20000];
+        let mut data = original.clone();
+        data[3] = 1;
+        data[4] = 2;
+        data[0x1FFFF] = 3;
+        let patch = encode_ips(&original, &data);
+        assert_eq!(patch.len(), 5 + 5 + 2 + 5 + 1 + 3);
+        let mut patched = original.clone();
+        apply_ips(&patch, &mut patched).unwrap();
+        assert_eq!(patched, data);
+
+        // a run of 3 copies of 7 at offset 1
+        let rle = b"PATCH\x00\x00\x01\x00\x00\x00\x03\x07EOF";
+        apply_ips(rle, &mut patched).unwrap();
+        assert_eq!(patched[..6], [0, 7, 7, 7, 2, 0]);
+        assert!(apply_ips(b"PATCH\x00\x00", &mut patched).is_err());
+    }
+
+    #[test]
+    fn test_flush_interval() {
+        let mut file = BatteryFile::init(PathBuf::from("unused.sav"));
+        file.set_flush_interval(2);
+        assert!(!file.tick_frame());
+        assert!(file.tick_frame());
+        // unchanged RAM is not written, so this doesn't touch the disk
+        file.save(&[]).unwrap();
+        assert!(!file.tick_frame());
+    }
+}
@@ -0,0 +1,178 @@
+//! blargg's test ROM status protocol.
+//!
+//! The ROMs write a signature to $6001-$6003 once they start, keep $6000 at
+//! $80 while running and finally store their result there, with a NUL
+//! terminated message from $6004. A status of $81 asks for a reset.
+
+use crate::bus::MemoryBus;
+use crate::console::Console;
+
+const STATUS_ADDR: u16 = 0x6000;
+const SIGNATURE_ADDR: u16 = 0x6001;
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const MESSAGE_ADDR: u16 = 0x6004;
+const END_MESSAGE: u16 = 0x7FFF;
+
+pub const STATUS_PASSED: u8 = 0x00;
+pub const STATUS_RUNNING: u8 = 0x80;
+pub const STATUS_NEEDS_RESET: u8 = 0x81;
+// the ROMs want the reset to come at least 100ms after they ask for it
+const RESET_DELAY_FRAMES: u64 = 7;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TestResult {
+    /// The ROM stored a final status; 0 is a pass.
+    Finished { status: u8, message: String },
+    /// The ROM was still running when the frame limit was hit.
+    TimedOut,
+    /// The ROM never wrote the signature.
+    NotDetected,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        return matches!(
+            self,
+            TestResult::Finished {
+                status: STATUS_PASSED,
+                ..
+            }
+        );
+    }
+}
+
+/// Follows a test ROM from frame to frame, resetting the console when the
+/// ROM asks for it.
+#[derive(Debug, Default)]
+pub struct TestMonitor {
+    frame: u64,
+    reset_at: Option<u64>,
+}
+
+impl TestMonitor {
+    pub fn init() -> Self {
+        return TestMonitor::default();
+    }
+
+    /// Call once after every frame. Returns the result once the ROM has
+    /// stored a final status.
+    pub fn update(&mut self, console: &mut Console) -> Option<TestResult> {
+        let frame = self.frame;
+        self.frame += 1;
+        match console.get_test_status() {
+            None | Some(STATUS_RUNNING) => return None,
+            Some(STATUS_NEEDS_RESET) => {
+                let reset_at = *self.reset_at.get_or_insert(frame + RESET_DELAY_FRAMES);
+                if frame >= reset_at {
+                    console.reset();
+                    self.reset_at = None;
+                }
+                return None;
+            }
+            Some(status) => {
+                return Some(TestResult::Finished {
+                    status,
+                    message: console.get_test_message(),
+                });
+            }
+        }
+    }
+}
+
+impl Console {
+    /// The status byte at $6000, or None if the ROM hasn't written the
+    /// protocol signature.
+    pub fn get_test_status(&mut self) -> Option<u8> {
+        let bus = self.get_cpu_mut().get_bus_mut();
+        let signature = [
+            bus.peek_memory_byte(SIGNATURE_ADDR),
+            bus.peek_memory_byte(SIGNATURE_ADDR + 1),
+            bus.peek_memory_byte(SIGNATURE_ADDR + 2),
+        ];
+        if signature != SIGNATURE {
+            return None;
+        }
+        return Some(bus.peek_memory_byte(STATUS_ADDR));
+    }
+
+    /// The text the ROM has written from $6004, trimmed.
+    pub fn get_test_message(&mut self) -> String {
+        let bus = self.get_cpu_mut().get_bus_mut();
+        let mut message = Vec::new();
+        for addr in MESSAGE_ADDR..=END_MESSAGE {
+            let byte = bus.peek_memory_byte(addr);
+            if byte == 0 {
+                break;
+            }
+            message.push(byte);
+        }
+        return String::from_utf8_lossy(&message).trim().to_string();
+    }
+
+    /// Runs a test ROM until it reports a result or `max_frames` have passed.
+    pub fn run_test(&mut self, max_frames: u64) -> TestResult {
+        let mut monitor = TestMonitor::init();
+        for _ in 0..max_frames {
+            self.run_frame();
+            if let Some(result) = monitor.update(self) {
+                return result;
+            }
+        }
+        match self.get_test_status() {
+            Some(_) => return TestResult::TimedOut,
+            None => return TestResult::NotDetected,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::nrom_with_program;
+    use crate::cartridge::Cartridge;
+
+    fn console_with_program(source: &str) -> Console {
+        let cartridge = Cartridge::from_ines(&nrom_with_program(source)).unwrap();
+        return Console::init(&cartridge).unwrap();
+    }
+
+    #[test]
+    fn test_run_test_reads_status_and_message() {
+        let mut console = console_with_program(
+            "       LDA #$80        ; running
+                    STA $6000
+                    LDA #$DE        ; signature
+                    STA $6001
+                    LDA #$B0
+                    STA $6002
+                    LDA #$61
+                    STA $6003
+                    LDA #$6F        ; message: ok, newline
+                    STA $6004
+                    LDA #$6B
+                    STA $6005
+                    LDA #$0A
+                    STA $6006
+                    LDA #0
+                    STA $6007
+                    STA $6000       ; passed
+            loop:   JMP loop",
+        );
+
+        let result = console.run_test(10);
+        assert!(result.passed());
+        assert_eq!(
+            result,
+            TestResult::Finished {
+                status: 0,
+                message: String::from("ok")
+            }
+        );
+    }
+
+    #[test]
+    fn test_run_test_without_signature() {
+        let mut console = console_with_program("loop: JMP loop");
+        assert_eq!(console.run_test(2), TestResult::NotDetected);
+    }
+}
@@ -1,20 +1,529 @@
-use crate::ram::RAM;
+use crate::apu::APU;
+use crate::cdl::{self, CHRLogger, CDL};
+use crate::debugger::{WatchHit, Watchpoint};
+use crate::input::Controller;
+use crate::mapper::Mapper;
+use crate::ppu::PPU;
+use crate::ram::{
+    END_AUDIO_CONTROLLERS_REGISTERS, END_CARTRIDGE_RAM, END_CARTRIDGE_ROM, END_EXPANSION_MODULES,
+    END_PPU_REGISTERS, END_PPU_REGISTERS_MIRRORS, END_SYS_RAM, END_SYS_RAM_MIRRORS, RAM,
+    START_AUDIO_CONTROLLERS_REGISTERS, START_CARTRIDGE_RAM, START_CARTRIDGE_ROM,
+    START_EXPANSION_MODULES, START_PPU_REGISTERS, START_SYS_RAM,
+};
+use crate::region::Region;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 
+const OAM_DMA: u16 = 0x4014;
+const APU_STATUS: u16 = 0x4015;
+const CONTROLLER_1: u16 = 0x4016;
+const CONTROLLER_2: u16 = 0x4017;
+
+// $4015 drives every bit but bit 5, the controllers only bits 0-4
+const APU_STATUS_OPEN_BUS: u8 = 0b00100000;
+const CONTROLLER_OPEN_BUS: u8 = 0b11100000;
+
+const OAM_DMA_CYCLES: u16 = 513;
+const DMC_DMA_CYCLES: u16 = 4;
+
+/// What a CPU read was for, as far as a code/data logger cares.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadKind {
+    /// Opcodes and operand bytes.
+    Code,
+    /// The target of an indirect jump, before its opcode is fetched.
+    IndirectCode,
+    Data,
+    /// Data read through a zero page pointer.
+    IndirectData,
+}
+
+/// Memory as seen by the CPU. Implement this to run the 6502 core against
+/// something other than the NES memory map.
+///
+/// Only the two memory accesses are required; the rest let a bus with
+/// clocked hardware on it stay in step with the CPU and raise interrupts.
+pub trait MemoryBus {
+    fn read_memory_byte(&mut self, addr: u16) -> u8;
+    fn write_memory_byte(&mut self, addr: u16, val: u8);
+
+    /// Reads without side effects, for debugging tools. Buses where reads
+    /// have side effects should override this.
+    fn peek_memory_byte(&mut self, addr: u16) -> u8 {
+        return self.read_memory_byte(addr);
+    }
+
+    /// Writes for debugging tools, changing what later reads of `addr` see
+    /// even where that is ROM. Buses with ROM should override this.
+    fn poke_memory_byte(&mut self, addr: u16, val: u8) {
+        self.write_memory_byte(addr, val);
+    }
+
+    /// Called after reads that fetch code or data, for code/data logging.
+    /// Dummy reads, stack accesses and vector fetches aren't reported.
+    fn log_read(&mut self, addr: u16, kind: ReadKind) {
+        let _ = (addr, kind);
+    }
+
+    /// Called once per CPU cycle, before the memory access of that cycle.
+    fn tick(&mut self) {}
+
+    /// Returns true once for every NMI edge.
+    fn poll_nmi(&mut self) -> bool {
+        return false;
+    }
+
+    /// Level of the IRQ line.
+    fn poll_irq(&mut self) -> bool {
+        return false;
+    }
+
+    /// Cycles the CPU has to sit out, e.g. for DMA, since the last call.
+    fn take_stall_cycles(&mut self) -> u16 {
+        return 0;
+    }
+}
+
+/// The NES CPU bus. Without a mapper the whole 64KB address space is plain
+/// RAM, which is what the CPU tests run against.
 #[derive(Debug)]
 pub struct BUS {
     ram: RAM,
+    ppu: PPU,
+    apu: APU,
+    mapper: Option<Box<dyn Mapper>>,
+    controllers: [Controller; 2],
+    cycles: u64,
+    stall_cycles: u16,
+    // the last value on the CPU data bus, which is what reads of addresses
+    // nothing answers to return
+    open_bus: u8,
+    region: Region,
+    // PAL runs 16 PPU dots every 5 CPU cycles, this carries the leftover
+    ppu_dot_phase: u8,
+    watchpoints: Vec<Watchpoint>,
+    watch_hit: Option<WatchHit>,
+    cdl: Option<CDL>,
 }
 
 impl BUS {
     pub fn init() -> Self {
-        return BUS { ram: RAM::init() };
+        return BUS {
+            ram: RAM::init(),
+            ppu: PPU::init(),
+            apu: APU::init(),
+            mapper: None,
+            controllers: [Controller::init(), Controller::init()],
+            cycles: 0,
+            stall_cycles: 0,
+            open_bus: 0,
+            region: Region::NTSC,
+            ppu_dot_phase: 0,
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            cdl: None,
+        };
+    }
+
+    pub fn with_mapper(mapper: Box<dyn Mapper>) -> Self {
+        let mut bus = BUS::init();
+        bus.mapper = Some(mapper);
+        return bus;
+    }
+
+    pub fn get_mapper(&self) -> Option<&dyn Mapper> {
+        return self.mapper.as_deref();
+    }
+
+    pub fn get_mapper_mut(&mut self) -> Option<&mut (dyn Mapper + 'static)> {
+        return self.mapper.as_deref_mut();
+    }
+
+    pub fn get_region(&self) -> Region {
+        return self.region;
+    }
+
+    /// Switches the CPU/PPU clock ratio, frame layout and APU timing.
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.ppu_dot_phase = 0;
+        self.ppu.set_region(region);
+        self.apu.set_region(region);
+    }
+
+    /// PPU dots to run for the current CPU cycle.
+    fn take_ppu_dots(&mut self) -> u8 {
+        let (dots, cycles) = self.region.get_ppu_dots_per_cpu_cycle();
+        self.ppu_dot_phase += dots;
+        let due = self.ppu_dot_phase / cycles;
+        self.ppu_dot_phase %= cycles;
+        return due;
+    }
+
+    pub fn get_ppu(&self) -> &PPU {
+        return &self.ppu;
+    }
+
+    pub fn get_ppu_mut(&mut self) -> &mut PPU {
+        return &mut self.ppu;
+    }
+
+    pub fn get_apu(&self) -> &APU {
+        return &self.apu;
+    }
+
+    pub fn get_apu_mut(&mut self) -> &mut APU {
+        return &mut self.apu;
+    }
+
+    pub fn get_controller(&self, port: usize) -> &Controller {
+        return &self.controllers[port];
+    }
+
+    pub fn get_controller_mut(&mut self, port: usize) -> &mut Controller {
+        return &mut self.controllers[port];
+    }
+
+    pub fn get_watchpoints(&self) -> &[Watchpoint] {
+        return &self.watchpoints;
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    pub fn remove_watchpoint(&mut self, index: usize) -> Option<Watchpoint> {
+        if index >= self.watchpoints.len() {
+            return None;
+        }
+        return Some(self.watchpoints.remove(index));
+    }
+
+    /// The first watched access since the last call.
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        return self.watch_hit.take();
+    }
+
+    /// Logs code and data accesses from now on, replacing any running log.
+    pub fn start_cdl(&mut self, cdl: CDL) {
+        self.cdl = Some(cdl);
+    }
+
+    pub fn stop_cdl(&mut self) -> Option<CDL> {
+        return self.cdl.take();
+    }
+
+    pub fn get_cdl(&self) -> Option<&CDL> {
+        return self.cdl.as_ref();
+    }
+
+    fn check_watchpoints(&mut self, addr: u16, val: u8, is_write: bool) {
+        if self.watch_hit.is_some() {
+            return;
+        }
+        if self.watchpoints.iter().any(|w| w.matches(addr, is_write)) {
+            self.watch_hit = Some(WatchHit {
+                addr,
+                val,
+                is_write,
+            });
+        }
+    }
+
+    fn read_byte(&mut self, addr: u16) -> u8 {
+        let mapper = match &mut self.mapper {
+            Some(mapper) => mapper,
+            None => return self.ram.read_u8(addr),
+        };
+        match addr {
+            START_SYS_RAM..=END_SYS_RAM_MIRRORS => return self.ram.read_u8(addr & END_SYS_RAM),
+            START_PPU_REGISTERS..=END_PPU_REGISTERS_MIRRORS => {
+                let addr = addr & END_PPU_REGISTERS;
+                match &mut self.cdl {
+                    Some(cdl) => {
+                        let mut logger = CHRLogger {
+                            mapper: mapper.as_mut(),
+                            cdl,
+                            flags: cdl::CHR_READ,
+                        };
+                        return self.ppu.read_register(addr, &mut logger);
+                    }
+                    None => return self.ppu.read_register(addr, mapper.as_mut()),
+                }
+            }
+            APU_STATUS => {
+                let status = self.apu.read_status() & !APU_STATUS_OPEN_BUS;
+                return status | (self.open_bus & APU_STATUS_OPEN_BUS);
+            }
+            CONTROLLER_1 | CONTROLLER_2 => {
+                let val = self.controllers[(addr - CONTROLLER_1) as usize].read();
+                return val | (self.open_bus & CONTROLLER_OPEN_BUS);
+            }
+            START_EXPANSION_MODULES..=END_EXPANSION_MODULES => {
+                return mapper.read_expansion(addr).unwrap_or(self.open_bus);
+            }
+            START_CARTRIDGE_RAM..=END_CARTRIDGE_RAM | START_CARTRIDGE_ROM..=END_CARTRIDGE_ROM => {
+                return mapper.read_prg(addr);
+            }
+            // write-only APU registers and the APU test registers
+            _ => return self.open_bus,
+        }
+    }
+
+    fn run_oam_dma(&mut self, page: u8) {
+        let start = (page as u16) << 8;
+        for offset in 0..256 {
+            let val = self.read_memory_byte(start + offset);
+            self.ppu.write_oam_byte(val);
+        }
+        // one extra alignment cycle when the DMA starts on an odd cycle
+        self.stall_cycles += OAM_DMA_CYCLES + (self.cycles % 2) as u16;
+    }
+}
+
+impl MemoryBus for BUS {
+    fn read_memory_byte(&mut self, addr: u16) -> u8 {
+        let val = self.read_byte(addr);
+        self.open_bus = val;
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(addr, val, false);
+        }
+        return val;
+    }
+
+    fn peek_memory_byte(&mut self, addr: u16) -> u8 {
+        let mapper = match &self.mapper {
+            Some(mapper) => mapper,
+            None => return self.ram.read_u8(addr),
+        };
+        match addr {
+            // registers that change state when read
+            START_PPU_REGISTERS..=END_AUDIO_CONTROLLERS_REGISTERS => return 0,
+            START_EXPANSION_MODULES..=END_EXPANSION_MODULES => {
+                return mapper.peek_expansion(addr).unwrap_or(self.open_bus);
+            }
+            _ => return self.read_byte(addr),
+        }
+    }
+
+    fn poke_memory_byte(&mut self, addr: u16, val: u8) {
+        // writes to ROM go to mapper registers, patch the mapped byte instead
+        if let Some(mapper) = &mut self.mapper {
+            if addr >= START_CARTRIDGE_ROM && mapper.poke_prg(addr, val) {
+                return;
+            }
+        }
+        self.write_memory_byte(addr, val);
+    }
+
+    fn log_read(&mut self, addr: u16, kind: ReadKind) {
+        let (cdl, mapper) = match (&mut self.cdl, &self.mapper) {
+            (Some(cdl), Some(mapper)) => (cdl, mapper),
+            _ => return,
+        };
+        if let Some(offset) = mapper.get_prg_rom_offset(addr) {
+            let flags = match kind {
+                ReadKind::Code => cdl::PRG_CODE,
+                ReadKind::IndirectCode => cdl::PRG_INDIRECT_CODE,
+                ReadKind::Data => cdl::PRG_DATA,
+                ReadKind::IndirectData => cdl::PRG_INDIRECT_DATA,
+            };
+            cdl.mark_prg(offset, addr, flags);
+        }
+    }
+
+    fn write_memory_byte(&mut self, addr: u16, val: u8) {
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(addr, val, true);
+        }
+        self.open_bus = val;
+        let mapper = match &mut self.mapper {
+            Some(mapper) => mapper,
+            None => return self.ram.write_u8(addr, val),
+        };
+        match addr {
+            START_SYS_RAM..=END_SYS_RAM_MIRRORS => self.ram.write_u8(addr & END_SYS_RAM, val),
+            START_PPU_REGISTERS..=END_PPU_REGISTERS_MIRRORS => {
+                self.ppu.write_register(addr & END_PPU_REGISTERS, val);
+                mapper.notify_ppu_write(addr & END_PPU_REGISTERS, val);
+            }
+            OAM_DMA => self.run_oam_dma(val),
+            CONTROLLER_1 => {
+                self.controllers[0].write(val);
+                self.controllers[1].write(val);
+            }
+            START_AUDIO_CONTROLLERS_REGISTERS..=END_AUDIO_CONTROLLERS_REGISTERS => {
+                self.apu.write_register(addr, val);
+            }
+            START_EXPANSION_MODULES..=END_EXPANSION_MODULES => mapper.write_expansion(addr, val),
+            START_CARTRIDGE_RAM..=END_CARTRIDGE_RAM | START_CARTRIDGE_ROM..=END_CARTRIDGE_ROM => {
+                mapper.write_prg(addr, val);
+            }
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self) {
+        if self.mapper.is_none() {
+            return;
+        }
+        self.cycles += 1;
+        let ppu_dots = self.take_ppu_dots();
+        let mapper = match &mut self.mapper {
+            Some(mapper) => mapper,
+            None => return,
+        };
+        match &mut self.cdl {
+            Some(cdl) => {
+                let mut logger = CHRLogger {
+                    mapper: mapper.as_mut(),
+                    cdl,
+                    flags: cdl::CHR_RENDERED,
+                };
+                for _ in 0..ppu_dots {
+                    self.ppu.tick(&mut logger);
+                }
+            }
+            None => {
+                for _ in 0..ppu_dots {
+                    self.ppu.tick(mapper.as_mut());
+                }
+            }
+        }
+        mapper.tick();
+        self.apu.set_expansion_output(mapper.get_audio_output());
+        self.apu.tick();
+        if let Some(addr) = self.apu.get_dmc_request() {
+            if let (Some(cdl), Some(offset)) = (&mut self.cdl, mapper.get_prg_rom_offset(addr)) {
+                cdl.mark_prg(offset, addr, cdl::PRG_PCM_AUDIO);
+            }
+            let val = mapper.read_prg(addr);
+            self.open_bus = val;
+            self.apu.fill_dmc_sample(val);
+            self.stall_cycles += DMC_DMA_CYCLES;
+        }
     }
 
-    pub fn read_memory_byte(&self, addr: u16) -> u8 {
-        return self.ram.read_u8(addr);
+    fn poll_nmi(&mut self) -> bool {
+        return self.ppu.poll_nmi();
+    }
+
+    fn poll_irq(&mut self) -> bool {
+        if let Some(mapper) = &self.mapper {
+            if mapper.irq_pending() {
+                return true;
+            }
+        }
+        return self.apu.irq_pending();
+    }
+
+    fn take_stall_cycles(&mut self) -> u16 {
+        let stall_cycles = self.stall_cycles;
+        self.stall_cycles = 0;
+        return stall_cycles;
+    }
+}
+
+impl SaveState for BUS {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.ram);
+        state.write(&self.ppu);
+        state.write(&self.apu);
+        if let Some(mapper) = &self.mapper {
+            state.write(mapper.as_ref());
+        }
+        state.write(&self.controllers[0]);
+        state.write(&self.controllers[1]);
+        state.write(&self.cycles);
+        state.write(&self.stall_cycles);
+        state.write(&self.open_bus);
+        state.write(&self.ppu_dot_phase);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read(&mut self.ram)?;
+        state.read(&mut self.ppu)?;
+        state.read(&mut self.apu)?;
+        if let Some(mapper) = &mut self.mapper {
+            state.read(mapper.as_mut())?;
+        }
+        state.read(&mut self.controllers[0])?;
+        state.read(&mut self.controllers[1])?;
+        state.read(&mut self.cycles)?;
+        state.read(&mut self.stall_cycles)?;
+        state.read(&mut self.open_bus)?;
+        state.read(&mut self.ppu_dot_phase)?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Mirroring;
+
+    // answers at $5000-$50FF only, like a chip with 256 bytes of RAM there
+    #[derive(Debug)]
+    struct ExpansionRAM {
+        ram: [u8; 0x100],
+    }
+
+    impl Mapper for ExpansionRAM {
+        fn read_prg(&mut self, _addr: u16) -> u8 {
+            return 0;
+        }
+
+        fn write_prg(&mut self, _addr: u16, _val: u8) {}
+
+        fn read_chr(&mut self, _addr: u16) -> u8 {
+            return 0;
+        }
+
+        fn write_chr(&mut self, _addr: u16, _val: u8) {}
+
+        fn get_mirroring(&self) -> Mirroring {
+            return Mirroring::Vertical;
+        }
+
+        fn read_expansion(&mut self, addr: u16) -> Option<u8> {
+            if addr & 0xFF00 != 0x5000 {
+                return None;
+            }
+            return Some(self.ram[(addr & 0xFF) as usize]);
+        }
+
+        fn peek_expansion(&self, addr: u16) -> Option<u8> {
+            if addr & 0xFF00 != 0x5000 {
+                return None;
+            }
+            return Some(self.ram[(addr & 0xFF) as usize]);
+        }
+
+        fn write_expansion(&mut self, addr: u16, val: u8) {
+            if addr & 0xFF00 == 0x5000 {
+                self.ram[(addr & 0xFF) as usize] = val;
+            }
+        }
+    }
+
+    impl SaveState for ExpansionRAM {
+        fn save_state(&self, state: &mut StateWriter) {
+            state.write(&self.ram);
+        }
+
+        fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+            return state.read(&mut self.ram);
+        }
     }
 
-    pub fn write_memory_byte(&mut self, addr: u16, val: u8) {
-        return self.ram.write_u8(addr, val);
+    #[test]
+    fn test_expansion_area_goes_to_the_mapper() {
+        let mut bus = BUS::with_mapper(Box::new(ExpansionRAM { ram: [0; 0x100] }));
+        bus.write_memory_byte(0x5010, 0x42);
+        assert_eq!(bus.read_memory_byte(0x5010), 0x42);
+        // what the mapper doesn't answer reads back the bus
+        bus.write_memory_byte(0x4020, 0x99);
+        assert_eq!(bus.read_memory_byte(0x4020), 0x99);
+        assert_eq!(bus.read_memory_byte(0x5FFF), 0x99);
+        assert_eq!(bus.peek_memory_byte(0x5010), 0x42);
     }
 }
@@ -0,0 +1,450 @@
+use crate::region::Region;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const INES_MAGIC: [u8; 4] = [b'N', b'E', b'S', 0x1A];
+pub(crate) const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+const PRG_ROM_BANK_SIZE: usize = 0x4000;
+const CHR_ROM_BANK_SIZE: usize = 0x2000;
+const PRG_RAM_BANK_SIZE: usize = 0x2000;
+
+const FDS_MAGIC: [u8; 4] = [b'F', b'D', b'S', 0x1A];
+// every disk side starts with its disk info block
+const FDS_DISK_INFO: &[u8] = b"\x01*NINTENDO-HVC*";
+pub(crate) const FDS_SIDE_SIZE: usize = 65500;
+const FDS_BIOS_SIZE: usize = 0x2000;
+const FDS_PRG_RAM_SIZE: usize = 0x8000;
+/// The mapper number iNES set aside for the Famicom Disk System, which disk
+/// images are loaded as.
+pub const FDS_MAPPER_ID: u16 = 20;
+
+const FNV_OFFSET_BASIS: u64 = 0xCBF29CE484222325;
+const FNV_PRIME: u64 = 0x100000001B3;
+
+/// How the four nametables at $2000-$2FFF map onto VRAM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mirroring {
+    /// $2000 and $2400 share the first 1KB, $2800 and $2C00 the second.
+    Horizontal,
+    /// $2000 and $2800 share the first 1KB, $2400 and $2C00 the second.
+    Vertical,
+    /// All four show the first 1KB.
+    SingleScreenA,
+    /// All four show the second 1KB.
+    SingleScreenB,
+    /// Each has its own 1KB, using 2KB of extra VRAM on the cartridge.
+    FourScreen,
+}
+
+impl Mirroring {
+    /// Where the nametable address `addr` in $2000-$3EFF lands in VRAM.
+    pub fn get_vram_offset(self, addr: u16) -> usize {
+        let addr = addr & 0x0FFF;
+        let table = addr / 0x400;
+        let offset = (addr & 0x3FF) as usize;
+        let bank = match self {
+            Mirroring::Horizontal => table / 2,
+            Mirroring::Vertical => table % 2,
+            Mirroring::SingleScreenA => 0,
+            Mirroring::SingleScreenB => 1,
+            Mirroring::FourScreen => table,
+        };
+        return bank as usize * 0x400 + offset;
+    }
+}
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(io::Error),
+    InvalidHeader,
+    Truncated,
+    UnsupportedMapper(u16),
+    InvalidDisk,
+    MissingBIOS,
+    InvalidBIOS,
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::Io(err) => return write!(f, "could not read ROM: {}", err),
+            CartridgeError::InvalidHeader => return write!(f, "not an iNES ROM"),
+            CartridgeError::Truncated => return write!(f, "ROM is smaller than its header says"),
+            CartridgeError::UnsupportedMapper(id) => {
+                return write!(f, "mapper {} is not supported", id)
+            }
+            CartridgeError::InvalidDisk => return write!(f, "not an FDS disk image"),
+            CartridgeError::MissingBIOS => return write!(f, "disk images need the FDS BIOS"),
+            CartridgeError::InvalidBIOS => return write!(f, "the FDS BIOS should be 8KB"),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+impl From<io::Error> for CartridgeError {
+    fn from(err: io::Error) -> Self {
+        return CartridgeError::Io(err);
+    }
+}
+
+/// A parsed iNES / NES 2.0 ROM image, or an FDS disk image with the BIOS
+/// as its PRG ROM.
+#[derive(Debug, Clone)]
+pub struct Cartridge {
+    prg_rom: Vec<u8>,
+    chr_rom: Vec<u8>,
+    mapper_id: u16,
+    mirroring: Mirroring,
+    has_battery: bool,
+    prg_ram_size: usize,
+    chr_ram_size: usize,
+    region: Region,
+    disk_sides: Vec<Vec<u8>>,
+}
+
+impl Cartridge {
+    pub fn from_ines(data: &[u8]) -> Result<Self, CartridgeError> {
+        if data.len() < HEADER_SIZE || data[0..4] != INES_MAGIC {
+            return Err(CartridgeError::InvalidHeader);
+        }
+        let flags_6 = data[6];
+        let flags_7 = data[7];
+        let is_nes_2 = flags_7 & 0b00001100 == 0b00001000;
+
+        let mut mapper_id = ((flags_7 & 0xF0) | (flags_6 >> 4)) as u16;
+        let mut prg_rom_size = data[4] as usize * PRG_ROM_BANK_SIZE;
+        let mut chr_rom_size = data[5] as usize * CHR_ROM_BANK_SIZE;
+        let mut prg_ram_size = (data[8].max(1)) as usize * PRG_RAM_BANK_SIZE;
+        let mut chr_ram_size = 0;
+        let mut region = Region::NTSC;
+        if is_nes_2 {
+            mapper_id |= ((data[8] & 0x0F) as u16) << 8;
+            prg_rom_size += ((data[9] & 0x0F) as usize) << 8 << 14;
+            chr_rom_size += ((data[9] >> 4) as usize) << 8 << 13;
+            prg_ram_size = nes_2_ram_size(data[10] & 0x0F) + nes_2_ram_size(data[10] >> 4);
+            chr_ram_size = nes_2_ram_size(data[11] & 0x0F) + nes_2_ram_size(data[11] >> 4);
+            // multi-region games run as NTSC
+            region = match data[12] & 0b11 {
+                1 => Region::PAL,
+                3 => Region::Dendy,
+                _ => Region::NTSC,
+            };
+        }
+        // every board needs code to run
+        if prg_rom_size == 0 {
+            return Err(CartridgeError::InvalidHeader);
+        }
+        if chr_rom_size == 0 && chr_ram_size == 0 {
+            chr_ram_size = CHR_ROM_BANK_SIZE;
+        }
+
+        let mirroring = if flags_6 & 0b1000 != 0 {
+            Mirroring::FourScreen
+        } else if flags_6 & 0b1 != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+
+        let mut offset = HEADER_SIZE;
+        if flags_6 & 0b100 != 0 {
+            offset += TRAINER_SIZE;
+        }
+        let prg_end = offset + prg_rom_size;
+        let chr_end = prg_end + chr_rom_size;
+        if data.len() < chr_end {
+            return Err(CartridgeError::Truncated);
+        }
+
+        return Ok(Cartridge {
+            prg_rom: data[offset..prg_end].to_vec(),
+            chr_rom: data[prg_end..chr_end].to_vec(),
+            mapper_id,
+            mirroring,
+            has_battery: flags_6 & 0b10 != 0,
+            prg_ram_size,
+            chr_ram_size,
+            region,
+            disk_sides: Vec::new(),
+        });
+    }
+
+    /// Reads a `.fds` image, with or without the fwNES header, to run on the
+    /// RAM adapter with the 8KB `bios`.
+    pub fn from_fds(data: &[u8], bios: &[u8]) -> Result<Self, CartridgeError> {
+        let data = match data.strip_prefix(&FDS_MAGIC) {
+            Some(_) if data.len() >= HEADER_SIZE => &data[HEADER_SIZE..],
+            Some(_) => return Err(CartridgeError::InvalidDisk),
+            None => data,
+        };
+        if !data.starts_with(FDS_DISK_INFO) || data.len() < FDS_SIDE_SIZE {
+            return Err(CartridgeError::InvalidDisk);
+        }
+        if bios.len() != FDS_BIOS_SIZE {
+            return Err(CartridgeError::InvalidBIOS);
+        }
+        return Ok(Cartridge {
+            prg_rom: bios.to_vec(),
+            chr_rom: Vec::new(),
+            mapper_id: FDS_MAPPER_ID,
+            mirroring: Mirroring::Horizontal,
+            has_battery: false,
+            prg_ram_size: FDS_PRG_RAM_SIZE,
+            chr_ram_size: CHR_ROM_BANK_SIZE,
+            region: Region::NTSC,
+            disk_sides: data
+                .chunks_exact(FDS_SIDE_SIZE)
+                .map(|side| side.to_vec())
+                .collect(),
+        });
+    }
+
+    /// Loads an iNES ROM. Disk images fail with
+    /// [`CartridgeError::MissingBIOS`] and need [`Cartridge::load_fds`].
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, CartridgeError> {
+        let data = fs::read(path)?;
+        if data.starts_with(&FDS_MAGIC) || data.starts_with(FDS_DISK_INFO) {
+            return Err(CartridgeError::MissingBIOS);
+        }
+        return Cartridge::from_ines(&data);
+    }
+
+    pub fn load_fds<P: AsRef<Path>, Q: AsRef<Path>>(
+        path: P,
+        bios_path: Q,
+    ) -> Result<Self, CartridgeError> {
+        let data = fs::read(path)?;
+        let bios = match fs::read(bios_path) {
+            Ok(bios) => bios,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(CartridgeError::MissingBIOS)
+            }
+            Err(err) => return Err(CartridgeError::Io(err)),
+        };
+        return Cartridge::from_fds(&data, &bios);
+    }
+
+    pub fn get_prg_rom(&self) -> &[u8] {
+        return &self.prg_rom;
+    }
+
+    /// CHR ROM contents, empty when the board uses CHR RAM instead.
+    pub fn get_chr_rom(&self) -> &[u8] {
+        return &self.chr_rom;
+    }
+
+    pub fn get_mapper_id(&self) -> u16 {
+        return self.mapper_id;
+    }
+
+    pub fn get_mirroring(&self) -> Mirroring {
+        return self.mirroring;
+    }
+
+    pub fn has_battery(&self) -> bool {
+        return self.has_battery;
+    }
+
+    pub fn get_prg_ram_size(&self) -> usize {
+        return self.prg_ram_size;
+    }
+
+    pub fn get_chr_ram_size(&self) -> usize {
+        return self.chr_ram_size;
+    }
+
+    /// The timing the ROM was made for, from the NES 2.0 header. Plain iNES
+    /// ROMs are taken as NTSC.
+    pub fn get_region(&self) -> Region {
+        return self.region;
+    }
+
+    /// The 65500 byte sides of a disk image, empty for cartridges.
+    pub fn get_disk_sides(&self) -> &[Vec<u8>] {
+        return &self.disk_sides;
+    }
+
+    /// An FNV-1a hash of the mapper and the ROM contents, which tells games
+    /// apart in save states.
+    pub fn get_hash(&self) -> u64 {
+        let mut hash = FNV_OFFSET_BASIS;
+        let sides = self.disk_sides.iter().map(|side| side.as_slice());
+        let parts = [
+            &self.mapper_id.to_le_bytes()[..],
+            &self.prg_rom,
+            &self.chr_rom,
+        ];
+        for byte in parts.into_iter().chain(sides).flatten() {
+            hash = (hash ^ *byte as u64).wrapping_mul(FNV_PRIME);
+        }
+        return hash;
+    }
+}
+
+fn nes_2_ram_size(shift: u8) -> usize {
+    if shift == 0 {
+        return 0;
+    }
+    return 64 << shift;
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::asm::assemble;
+
+    pub(crate) fn ines_rom(mapper_id: u8, prg_banks: u8, chr_banks: u8, flags_6: u8) -> Vec<u8> {
+        let mut data = vec![
+            b'N',
+            b'E',
+            b'S',
+            0x1A,
+            prg_banks,
+            chr_banks,
+            (mapper_id << 4) | flags_6,
+            mapper_id & 0xF0,
+        ];
+        data.resize(HEADER_SIZE, 0);
+        data.resize(
+            HEADER_SIZE
+                + prg_banks as usize * PRG_ROM_BANK_SIZE
+                + chr_banks as usize * CHR_ROM_BANK_SIZE,
+            0,
+        );
+        return data;
+    }
+
+    /// Counts up in X and stores each count to $10.
+    pub(crate) const COUNTER: &str = "
+        LDX #0
+loop:   INX
+        STX $10
+        JMP loop
+";
+
+    /// An NROM image with `source` assembled at $8000, where it starts from
+    /// reset.
+    pub(crate) fn nrom_with_program(source: &str) -> Vec<u8> {
+        let program = assemble(source, 0x8000).unwrap();
+        let mut data = ines_rom(0, 1, 1, 0);
+        data[HEADER_SIZE..HEADER_SIZE + program.bytes.len()].copy_from_slice(&program.bytes);
+        // the reset vector at $FFFC is read from the end of the mirrored bank
+        data[HEADER_SIZE + 0x3FFD] = 0x80;
+        return data;
+    }
+
+    #[test]
+    fn test_parse_ines_header() {
+        let cartridge = Cartridge::from_ines(&ines_rom(0, 2, 1, 0b11)).unwrap();
+        assert_eq!(cartridge.get_prg_rom().len(), 0x8000);
+        assert_eq!(cartridge.get_chr_rom().len(), 0x2000);
+        assert_eq!(cartridge.get_mapper_id(), 0);
+        assert_eq!(cartridge.get_mirroring(), Mirroring::Vertical);
+        assert!(cartridge.has_battery());
+        assert_eq!(cartridge.get_chr_ram_size(), 0);
+    }
+
+    #[test]
+    fn test_nes_2_region() {
+        let mut data = ines_rom(0, 1, 1, 0);
+        assert_eq!(
+            Cartridge::from_ines(&data).unwrap().get_region(),
+            Region::NTSC
+        );
+        data[7] |= 0b00001000;
+        data[12] = 1;
+        assert_eq!(
+            Cartridge::from_ines(&data).unwrap().get_region(),
+            Region::PAL
+        );
+        data[12] = 3;
+        assert_eq!(
+            Cartridge::from_ines(&data).unwrap().get_region(),
+            Region::Dendy
+        );
+        data[12] = 2;
+        assert_eq!(
+            Cartridge::from_ines(&data).unwrap().get_region(),
+            Region::NTSC
+        );
+    }
+
+    #[test]
+    fn test_chr_ram_when_no_chr_rom() {
+        let cartridge = Cartridge::from_ines(&ines_rom(2, 8, 0, 0)).unwrap();
+        assert_eq!(cartridge.get_mapper_id(), 2);
+        assert_eq!(cartridge.get_mirroring(), Mirroring::Horizontal);
+        assert_eq!(cartridge.get_chr_ram_size(), 0x2000);
+    }
+
+    #[test]
+    fn test_nametable_mirroring() {
+        let banks = |mirroring: Mirroring| {
+            return [0x2000, 0x2400, 0x2800, 0x2C00]
+                .map(|addr| mirroring.get_vram_offset(addr + 5) / 0x400);
+        };
+        assert_eq!(banks(Mirroring::Horizontal), [0, 0, 1, 1]);
+        assert_eq!(banks(Mirroring::Vertical), [0, 1, 0, 1]);
+        assert_eq!(banks(Mirroring::SingleScreenA), [0, 0, 0, 0]);
+        assert_eq!(banks(Mirroring::SingleScreenB), [1, 1, 1, 1]);
+        assert_eq!(banks(Mirroring::FourScreen), [0, 1, 2, 3]);
+        // $3000-$3EFF mirrors $2000-$2EFF
+        assert_eq!(Mirroring::FourScreen.get_vram_offset(0x3C05), 0xC05);
+    }
+
+    pub(crate) fn fds_image(sides: usize) -> Vec<u8> {
+        let mut data = FDS_MAGIC.to_vec();
+        data.push(sides as u8);
+        data.resize(HEADER_SIZE, 0);
+        for _ in 0..sides {
+            let start = data.len();
+            data.extend_from_slice(FDS_DISK_INFO);
+            data.resize(start + FDS_SIDE_SIZE, 0);
+        }
+        return data;
+    }
+
+    #[test]
+    fn test_parse_fds_image() {
+        let bios = vec![0; FDS_BIOS_SIZE];
+        let cartridge = Cartridge::from_fds(&fds_image(2), &bios).unwrap();
+        assert_eq!(cartridge.get_mapper_id(), FDS_MAPPER_ID);
+        assert_eq!(cartridge.get_disk_sides().len(), 2);
+        assert_eq!(cartridge.get_disk_sides()[1][..4], FDS_DISK_INFO[..4]);
+        assert_eq!(cartridge.get_prg_rom().len(), FDS_BIOS_SIZE);
+        // headerless images too
+        let headerless = &fds_image(1)[HEADER_SIZE..];
+        assert!(Cartridge::from_fds(headerless, &bios).is_ok());
+
+        assert!(matches!(
+            Cartridge::from_fds(&fds_image(1), &bios[..0x1000]),
+            Err(CartridgeError::InvalidBIOS)
+        ));
+        assert!(matches!(
+            Cartridge::from_fds(&ines_rom(0, 4, 0, 0), &bios),
+            Err(CartridgeError::InvalidDisk)
+        ));
+    }
+
+    #[test]
+    fn test_rejects_bad_roms() {
+        assert!(matches!(
+            Cartridge::from_ines(b"not a rom at all"),
+            Err(CartridgeError::InvalidHeader)
+        ));
+        assert!(matches!(
+            Cartridge::from_ines(&ines_rom(0, 0, 1, 0)),
+            Err(CartridgeError::InvalidHeader)
+        ));
+        let mut data = ines_rom(0, 2, 1, 0);
+        data.truncate(0x1000);
+        assert!(matches!(
+            Cartridge::from_ines(&data),
+            Err(CartridgeError::Truncated)
+        ));
+    }
+}
@@ -0,0 +1,269 @@
+//! Code/data logging in FCEUX's `.cdl` format: one flag byte per PRG ROM
+//! byte followed by one per CHR ROM byte.
+
+use crate::cartridge::{Cartridge, Mirroring};
+use crate::mapper::{Mapper, PPUEvent};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub const PRG_CODE: u8 = 0x01;
+pub const PRG_DATA: u8 = 0x02;
+// bits 2-3 hold the 8KB CPU window ($8000, $A000, $C000, $E000) of the access
+const PRG_WINDOW_SHIFT: u16 = 11;
+const PRG_WINDOW_MASK: u16 = 0x6000;
+/// Code jumped to through a pointer, i.e. `JMP ($xxxx)`.
+pub const PRG_INDIRECT_CODE: u8 = 0x10;
+/// Data read through a pointer, i.e. `($xx),Y` and `($xx,X)`.
+pub const PRG_INDIRECT_DATA: u8 = 0x20;
+/// Samples fetched by the DMC.
+pub const PRG_PCM_AUDIO: u8 = 0x40;
+
+/// Tiles fetched by the PPU while drawing.
+pub const CHR_RENDERED: u8 = 0x01;
+/// Bytes read by the CPU through $2007.
+pub const CHR_READ: u8 = 0x02;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CDL {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+}
+
+impl CDL {
+    pub fn init(prg_size: usize, chr_size: usize) -> Self {
+        return CDL {
+            prg: vec![0; prg_size],
+            chr: vec![0; chr_size],
+        };
+    }
+
+    /// An empty log sized for `cartridge`. Carts with CHR RAM get no CHR
+    /// part, like in FCEUX.
+    pub fn for_cartridge(cartridge: &Cartridge) -> Self {
+        return CDL::init(cartridge.get_prg_rom().len(), cartridge.get_chr_rom().len());
+    }
+
+    /// Reads a log written earlier so a new session adds to it. A missing
+    /// file gives an empty log.
+    pub fn load<P: AsRef<Path>>(path: P, prg_size: usize, chr_size: usize) -> io::Result<Self> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(CDL::init(prg_size, chr_size))
+            }
+            Err(err) => return Err(err),
+        };
+        if data.len() != prg_size + chr_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "log is {} bytes but the ROM needs {}",
+                    data.len(),
+                    prg_size + chr_size
+                ),
+            ));
+        }
+        let (prg, chr) = data.split_at(prg_size);
+        return Ok(CDL {
+            prg: prg.to_vec(),
+            chr: chr.to_vec(),
+        });
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        return fs::write(path, self.to_bytes());
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        return [self.prg.as_slice(), self.chr.as_slice()].concat();
+    }
+
+    pub fn get_prg(&self) -> &[u8] {
+        return &self.prg;
+    }
+
+    pub fn get_chr(&self) -> &[u8] {
+        return &self.chr;
+    }
+
+    /// Flags the PRG ROM byte at `offset`, read by the CPU at `addr`.
+    pub fn mark_prg(&mut self, offset: usize, addr: u16, flags: u8) {
+        let window = ((addr & PRG_WINDOW_MASK) >> PRG_WINDOW_SHIFT) as u8;
+        if let Some(byte) = self.prg.get_mut(offset) {
+            *byte |= flags | window;
+        }
+    }
+
+    pub fn mark_chr(&mut self, offset: usize, flags: u8) {
+        if let Some(byte) = self.chr.get_mut(offset) {
+            *byte |= flags;
+        }
+    }
+
+    /// How many PRG bytes have been seen as code or data.
+    pub fn count_prg_logged(&self) -> usize {
+        return self.prg.iter().filter(|byte| **byte != 0).count();
+    }
+}
+
+/// Stands in for the mapper while the PPU runs so CHR ROM reads end up in
+/// the log.
+#[derive(Debug)]
+pub(crate) struct CHRLogger<'a> {
+    pub mapper: &'a mut dyn Mapper,
+    pub cdl: &'a mut CDL,
+    pub flags: u8,
+}
+
+impl Mapper for CHRLogger<'_> {
+    fn read_prg(&mut self, addr: u16) -> u8 {
+        return self.mapper.read_prg(addr);
+    }
+
+    fn write_prg(&mut self, addr: u16, val: u8) {
+        self.mapper.write_prg(addr, val);
+    }
+
+    fn read_chr(&mut self, addr: u16) -> u8 {
+        if let Some(offset) = self.mapper.get_chr_rom_offset(addr) {
+            self.cdl.mark_chr(offset, self.flags);
+        }
+        return self.mapper.read_chr(addr);
+    }
+
+    fn write_chr(&mut self, addr: u16, val: u8) {
+        self.mapper.write_chr(addr, val);
+    }
+
+    fn get_mirroring(&self) -> Mirroring {
+        return self.mapper.get_mirroring();
+    }
+
+    fn read_nametable(&mut self, addr: u16, vram: &[u8]) -> u8 {
+        return self.mapper.read_nametable(addr, vram);
+    }
+
+    fn write_nametable(&mut self, addr: u16, val: u8, vram: &mut [u8]) {
+        self.mapper.write_nametable(addr, val, vram);
+    }
+
+    fn notify_ppu(&mut self, event: PPUEvent) {
+        self.mapper.notify_ppu(event);
+    }
+
+    fn notify_ppu_write(&mut self, addr: u16, val: u8) {
+        self.mapper.notify_ppu_write(addr, val);
+    }
+
+    fn irq_pending(&self) -> bool {
+        return self.mapper.irq_pending();
+    }
+
+    fn tick(&mut self) {
+        self.mapper.tick();
+    }
+
+    fn get_audio_output(&self) -> f32 {
+        return self.mapper.get_audio_output();
+    }
+
+    fn read_expansion(&mut self, addr: u16) -> Option<u8> {
+        return self.mapper.read_expansion(addr);
+    }
+
+    fn peek_expansion(&self, addr: u16) -> Option<u8> {
+        return self.mapper.peek_expansion(addr);
+    }
+
+    fn write_expansion(&mut self, addr: u16, val: u8) {
+        self.mapper.write_expansion(addr, val);
+    }
+
+    fn get_prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        return self.mapper.get_prg_rom_offset(addr);
+    }
+
+    fn get_chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        return self.mapper.get_chr_rom_offset(addr);
+    }
+}
+
+impl SaveState for CHRLogger<'_> {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.mapper.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        return self.mapper.load_state(state);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::nrom_with_program;
+    use crate::Console;
+
+    fn console_with_program(source: &str) -> Console {
+        let cartridge = Cartridge::from_ines(&nrom_with_program(source)).unwrap();
+        let mut console = Console::init(&cartridge).unwrap();
+        console.start_cdl(CDL::for_cartridge(&cartridge));
+        return console;
+    }
+
+    #[test]
+    fn test_marks_code_and_data() {
+        let mut console = console_with_program(
+            "       LDA #<table
+                    STA $00
+                    LDA #>table
+                    STA $01
+                    LDY #1
+                    LDA ($00),Y
+                    LDA table
+                    JMP (vector)
+            vector: .word target
+            table:  .byte 1, 2
+            target: JMP target",
+        );
+        for _ in 0..9 {
+            console.step();
+        }
+        let cdl = console.stop_cdl().unwrap();
+        let prg = cdl.get_prg();
+        assert_eq!(prg[0], PRG_CODE);
+        assert_eq!(prg[1], PRG_CODE);
+        // vector at $8012, table at $8014, target at $8016
+        assert_eq!(prg[0x12], PRG_DATA);
+        assert_eq!(prg[0x14], PRG_DATA);
+        assert_eq!(prg[0x15], PRG_INDIRECT_DATA);
+        assert_eq!(prg[0x16], PRG_CODE | PRG_INDIRECT_CODE);
+        assert_eq!(prg[0x17], PRG_CODE);
+        assert_eq!(cdl.count_prg_logged(), 0x19);
+        // interrupt vectors aren't logged
+        assert_eq!(prg[0x3FFD], 0);
+        assert_eq!(cdl.to_bytes().len(), 0x4000 + 0x2000);
+    }
+
+    #[test]
+    fn test_marks_rendered_chr_and_load() {
+        let mut console = console_with_program(
+            "       LDA #$08
+                    STA $2001
+            loop:   JMP loop",
+        );
+        console.run_frame();
+        console.run_frame();
+        let cdl = console.stop_cdl().unwrap();
+        // background tile 0 from the left pattern table
+        assert_eq!(cdl.get_chr()[0], CHR_RENDERED);
+
+        let path = std::env::temp_dir().join(format!("rustes-{}.cdl", std::process::id()));
+        cdl.save(&path).unwrap();
+        assert_eq!(CDL::load(&path, 0x4000, 0x2000).unwrap(), cdl);
+        assert!(CDL::load(&path, 0x8000, 0x2000).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
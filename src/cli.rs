@@ -0,0 +1,395 @@
+use rustes::region::Region;
+use rustes::rewind::DEFAULT_REWIND_MB;
+use rustes::trace::{TraceFilter, TraceFormat, TraceTrigger};
+use rustes::video::VideoFilter;
+
+pub const USAGE: &str = "usage:
+    rustes <rom.nes>
+    rustes run [--headless] <rom.nes> [options]
+    rustes debug <rom.nes> [--gdb PORT]
+
+The ROM can also be an .fds Famicom Disk System image.
+
+options:
+    --fds-bios FILE the FDS BIOS to run disk images with (default:
+                    disksys.rom next to the image)
+    --region ntsc|pal|dendy
+                    timing to emulate (default: from the NES 2.0 header,
+                    or PAL for movies recorded on PAL, otherwise NTSC)
+    --video palette|ntsc
+                    look colours up in the palette (default) or run the
+                    picture through a simulated NTSC composite signal
+    --palette FILE  use the colours from a 64 or 512 entry .pal file
+    --save FILE     keep battery RAM in FILE, or what was written to a disk
+                    as an IPS patch (default: the ROM's name with .sav, or
+                    .ips for disks, when running in a window, none when
+                    headless)
+    --rewind MB     memory to keep for rewinding in a window, 0 turns
+                    rewinding off (default 64)
+    --movie FILE    play back an FCEUX .fm2 movie
+    --record FILE   record the controllers to an .fm2 movie from power-on
+                    (window only)
+    --trace FILE    log every instruction to FILE
+    --trace-format nestest|mesen|fceux
+                    layout of the trace lines (default nestest)
+    --trace-range START-END
+                    only log instructions in this hex address range, can be
+                    given more than once
+    --trace-bank N  only log instructions running from 8KB PRG ROM bank N
+    --trace-from ADDR
+                    start logging when PC first reaches hex ADDR
+    --trace-from-frame N
+                    start logging at frame N
+    --symbols FILE  show labels from a ca65 .dbg, FCEUX .nl or Mesen .mlb
+                    file in the trace, can be given more than once
+    --cdl FILE      log which PRG and CHR bytes are used as code or data to
+                    an FCEUX .cdl file, adding to it if it exists
+
+debug options:
+    --gdb PORT      serve the GDB remote protocol on localhost:PORT instead
+                    of starting the command line monitor
+    --symbols FILE  load labels into the monitor, can be given more than once
+    --fds-bios FILE as for run
+
+headless options:
+    --frames N      stop after N frames (default: the movie's length, or 600)
+    --input FILE    feed controller input from a script
+    --png FILE      write the final frame as a PNG
+    --ram FILE      write the 2KB of CPU RAM as hex
+
+exit codes in headless mode:
+    0               finished, or the ROM reported a passing test
+    1-123           the status code a blargg test ROM reported at $6000
+    124             the test ROM was still running after the last frame
+    125             an error, e.g. the ROM or an output file couldn't be
+                    read or written";
+
+pub const DEFAULT_HEADLESS_FRAMES: u64 = 600;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct RunOptions {
+    pub rom: String,
+    pub fds_bios: Option<String>,
+    pub headless: bool,
+    pub region: Option<Region>,
+    pub video_filter: VideoFilter,
+    pub palette: Option<String>,
+    pub frames: Option<u64>,
+    pub input: Option<String>,
+    pub png: Option<String>,
+    pub ram: Option<String>,
+    pub save: Option<String>,
+    pub rewind_mb: usize,
+    pub movie: Option<String>,
+    pub record: Option<String>,
+    pub trace: Option<String>,
+    pub trace_format: TraceFormat,
+    pub trace_filter: TraceFilter,
+    pub symbols: Vec<String>,
+    pub cdl: Option<String>,
+}
+
+impl RunOptions {
+    fn init(rom: String) -> Self {
+        return RunOptions {
+            rom,
+            fds_bios: None,
+            headless: false,
+            region: None,
+            video_filter: VideoFilter::Palette,
+            palette: None,
+            frames: None,
+            input: None,
+            png: None,
+            ram: None,
+            save: None,
+            rewind_mb: DEFAULT_REWIND_MB,
+            movie: None,
+            record: None,
+            trace: None,
+            trace_format: TraceFormat::Nestest,
+            trace_filter: TraceFilter::default(),
+            symbols: Vec::new(),
+            cdl: None,
+        };
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct DebugOptions {
+    pub rom: String,
+    pub fds_bios: Option<String>,
+    pub gdb_port: Option<u16>,
+    pub symbols: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Run(Box<RunOptions>),
+    Debug(DebugOptions),
+}
+
+fn take_value<'a, I: Iterator<Item = &'a String>>(
+    flag: &str,
+    args: &mut I,
+) -> Result<String, String> {
+    match args.next() {
+        Some(val) => return Ok(val.clone()),
+        None => return Err(format!("{} needs a value", flag)),
+    }
+}
+
+fn parse_hex_addr(flag: &str, val: &str) -> Result<u16, String> {
+    let digits = val.strip_prefix('$').unwrap_or(val);
+    return u16::from_str_radix(digits, 16)
+        .map_err(|_| format!("{}: '{}' is not a hex address", flag, val));
+}
+
+fn parse_run(args: &[String]) -> Result<Command, String> {
+    let mut rom = None;
+    let mut options = RunOptions::init(String::new());
+    let mut headless_only = Vec::new();
+    let mut trace_only = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--headless" => options.headless = true,
+            "--frames" => {
+                let val = take_value(arg, &mut args)?;
+                let frames = val
+                    .parse()
+                    .map_err(|_| format!("--frames: '{}' is not a number", val))?;
+                options.frames = Some(frames);
+                headless_only.push(arg);
+            }
+            "--input" => {
+                options.input = Some(take_value(arg, &mut args)?);
+                headless_only.push(arg);
+            }
+            "--png" => {
+                options.png = Some(take_value(arg, &mut args)?);
+                headless_only.push(arg);
+            }
+            "--ram" => {
+                options.ram = Some(take_value(arg, &mut args)?);
+                headless_only.push(arg);
+            }
+            "--region" => {
+                let val = take_value(arg, &mut args)?;
+                let region = Region::from_name(&val)
+                    .ok_or_else(|| format!("--region: unknown region '{}'", val))?;
+                options.region = Some(region);
+            }
+            "--video" => {
+                let val = take_value(arg, &mut args)?;
+                options.video_filter = VideoFilter::from_name(&val)
+                    .ok_or_else(|| format!("--video: unknown filter '{}'", val))?;
+            }
+            "--fds-bios" => options.fds_bios = Some(take_value(arg, &mut args)?),
+            "--palette" => options.palette = Some(take_value(arg, &mut args)?),
+            "--save" => options.save = Some(take_value(arg, &mut args)?),
+            "--movie" => options.movie = Some(take_value(arg, &mut args)?),
+            "--record" => options.record = Some(take_value(arg, &mut args)?),
+            "--trace" => options.trace = Some(take_value(arg, &mut args)?),
+            "--cdl" => options.cdl = Some(take_value(arg, &mut args)?),
+            "--trace-format" => {
+                let val = take_value(arg, &mut args)?;
+                options.trace_format = TraceFormat::from_name(&val)
+                    .ok_or_else(|| format!("--trace-format: unknown format '{}'", val))?;
+                trace_only.push(arg);
+            }
+            "--trace-range" => {
+                let val = take_value(arg, &mut args)?;
+                let (start, end) = val
+                    .split_once('-')
+                    .ok_or_else(|| format!("--trace-range: expected START-END, got '{}'", val))?;
+                let range = (parse_hex_addr(arg, start)?, parse_hex_addr(arg, end)?);
+                options.trace_filter.ranges.push(range);
+                trace_only.push(arg);
+            }
+            "--trace-bank" => {
+                let val = take_value(arg, &mut args)?;
+                let bank = val
+                    .parse()
+                    .map_err(|_| format!("--trace-bank: '{}' is not a number", val))?;
+                options.trace_filter.prg_bank = Some(bank);
+                trace_only.push(arg);
+            }
+            "--trace-from" => {
+                let addr = parse_hex_addr(arg, &take_value(arg, &mut args)?)?;
+                options.trace_filter.trigger = Some(TraceTrigger::Pc(addr));
+                trace_only.push(arg);
+            }
+            "--trace-from-frame" => {
+                let val = take_value(arg, &mut args)?;
+                let frame = val
+                    .parse()
+                    .map_err(|_| format!("--trace-from-frame: '{}' is not a number", val))?;
+                options.trace_filter.trigger = Some(TraceTrigger::Frame(frame));
+                trace_only.push(arg);
+            }
+            "--symbols" => {
+                options.symbols.push(take_value(arg, &mut args)?);
+                trace_only.push(arg);
+            }
+            "--rewind" => {
+                let val = take_value(arg, &mut args)?;
+                options.rewind_mb = val
+                    .parse()
+                    .map_err(|_| format!("--rewind: '{}' is not a number", val))?;
+            }
+            flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+            _ if rom.is_none() => rom = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+    if let Some(flag) = headless_only.first().filter(|_| !options.headless) {
+        return Err(format!("{} only works with --headless", flag));
+    }
+    if let Some(flag) = trace_only.first().filter(|_| options.trace.is_none()) {
+        return Err(format!("{} only works with --trace", flag));
+    }
+    if options.headless && options.record.is_some() {
+        return Err(String::from("--record doesn't work with --headless"));
+    }
+    if options.movie.is_some() && (options.record.is_some() || options.input.is_some()) {
+        return Err(String::from(
+            "--movie can't be combined with --record or --input",
+        ));
+    }
+    match rom {
+        Some(rom) => options.rom = rom,
+        None => return Err(String::from("no ROM given")),
+    }
+    return Ok(Command::Run(Box::new(options)));
+}
+
+fn parse_debug(args: &[String]) -> Result<Command, String> {
+    let mut rom = None;
+    let mut fds_bios = None;
+    let mut gdb_port = None;
+    let mut symbols = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--gdb" => {
+                let val = take_value(arg, &mut args)?;
+                let port = val
+                    .parse()
+                    .map_err(|_| format!("--gdb: '{}' is not a port", val))?;
+                gdb_port = Some(port);
+            }
+            "--symbols" => symbols.push(take_value(arg, &mut args)?),
+            "--fds-bios" => fds_bios = Some(take_value(arg, &mut args)?),
+            flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+            _ if rom.is_none() => rom = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+    match rom {
+        Some(rom) => {
+            return Ok(Command::Debug(DebugOptions {
+                rom,
+                fds_bios,
+                gdb_port,
+                symbols,
+            }));
+        }
+        None => return Err(String::from("no ROM given")),
+    }
+}
+
+/// Parses the arguments after the program name.
+pub fn parse_args(args: &[String]) -> Result<Command, String> {
+    match args.first().map(|arg| arg.as_str()) {
+        Some("run") => return parse_run(&args[1..]),
+        Some("debug") => return parse_debug(&args[1..]),
+        Some(rom) if args.len() == 1 && !rom.starts_with('-') => {
+            return Ok(Command::Run(Box::new(RunOptions::init(rom.to_string()))));
+        }
+        _ => return Err(String::from("expected a ROM or a command")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        return line.split_whitespace().map(String::from).collect();
+    }
+
+    fn parse_run_options(line: &str) -> RunOptions {
+        match parse_args(&args(line)).unwrap() {
+            Command::Run(options) => return *options,
+            command => panic!("expected run, got {:?}", command),
+        }
+    }
+
+    #[test]
+    fn test_parse_bare_rom() {
+        let options = parse_run_options("game.nes");
+        assert_eq!(options, RunOptions::init(String::from("game.nes")));
+        assert_eq!(
+            parse_args(&args("debug game.nes --gdb 2345 --symbols game.dbg")),
+            Ok(Command::Debug(DebugOptions {
+                rom: String::from("game.nes"),
+                fds_bios: None,
+                gdb_port: Some(2345),
+                symbols: vec![String::from("game.dbg")],
+            }))
+        );
+    }
+
+    #[test]
+    fn test_parse_headless_run() {
+        let options =
+            parse_run_options("run --headless rom.nes --frames 120 --png out.png --ram ram.hex");
+        assert!(options.headless);
+        assert_eq!(options.rom, "rom.nes");
+        assert_eq!(options.frames, Some(120));
+        assert_eq!(options.png.as_deref(), Some("out.png"));
+        assert_eq!(options.ram.as_deref(), Some("ram.hex"));
+        assert_eq!(options.input, None);
+        assert_eq!(options.save, None);
+
+        let options = parse_run_options("run game.nes --save slot.sav");
+        assert!(!options.headless);
+        assert_eq!(options.save.as_deref(), Some("slot.sav"));
+        assert_eq!(options.rewind_mb, DEFAULT_REWIND_MB);
+        let options = parse_run_options("run game.nes --rewind 0");
+        assert_eq!(options.rewind_mb, 0);
+        let options = parse_run_options("run game.nes --region dendy");
+        assert_eq!(options.region, Some(Region::Dendy));
+        let options = parse_run_options("run game.nes --video ntsc --palette smooth.pal");
+        assert_eq!(options.video_filter, VideoFilter::NTSC);
+        assert_eq!(options.palette.as_deref(), Some("smooth.pal"));
+        let options = parse_run_options("run game.fds --fds-bios bios.rom");
+        assert_eq!(options.fds_bios.as_deref(), Some("bios.rom"));
+
+        let options = parse_run_options(
+            "run game.nes --trace t.log --trace-format fceux --trace-range 8000-80ff --trace-from $c000",
+        );
+        assert_eq!(options.trace.as_deref(), Some("t.log"));
+        assert_eq!(options.trace_format, TraceFormat::FCEUX);
+        assert_eq!(options.trace_filter.ranges, vec![(0x8000, 0x80FF)]);
+        assert_eq!(options.trace_filter.trigger, Some(TraceTrigger::Pc(0xC000)));
+        let options = parse_run_options("run --headless game.nes --cdl game.cdl");
+        assert_eq!(options.cdl.as_deref(), Some("game.cdl"));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse_args(&args("run rom.nes --frames 10")).is_err());
+        assert!(parse_args(&args("run --headless rom.nes --frames ten")).is_err());
+        assert!(parse_args(&args("run --headless")).is_err());
+        assert!(parse_args(&args("run --headless a.nes b.nes")).is_err());
+        assert!(parse_args(&args("run --headless a.nes --record a.fm2")).is_err());
+        assert!(parse_args(&args("run a.nes --movie a.fm2 --record b.fm2")).is_err());
+        assert!(parse_args(&args("debug")).is_err());
+        assert!(parse_args(&args("run a.nes --region secam")).is_err());
+        assert!(parse_args(&args("run a.nes --trace-bank 1")).is_err());
+        assert!(parse_args(&args("run a.nes --trace t.log --trace-range 8000")).is_err());
+        assert!(parse_args(&[]).is_err());
+    }
+}
@@ -0,0 +1,626 @@
+use crate::battery::BatteryFile;
+use crate::bus::BUS;
+use crate::cartridge::{Cartridge, CartridgeError};
+use crate::cdl::CDL;
+use crate::cpu::CPU;
+use crate::input::Controller;
+use crate::mapper;
+use crate::palette::Palette;
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::region::Region;
+use crate::rewind::RewindBuffer;
+use crate::savestate::{self, StateError};
+use crate::trace::TraceLogger;
+use crate::video::{NTSCFilter, VideoFilter};
+use std::io;
+use std::path::PathBuf;
+
+/// A complete NES: the CPU and everything hanging off its bus.
+#[derive(Debug)]
+pub struct Console {
+    cpu: CPU<BUS>,
+    // tags save states so they only load into the same game
+    rom_hash: u64,
+    battery_file: Option<BatteryFile>,
+    rewind: Option<RewindBuffer>,
+    trace: Option<TraceLogger>,
+    palette: Palette,
+    // set when the NTSC filter is selected
+    ntsc_filter: Option<NTSCFilter>,
+}
+
+impl Console {
+    pub fn init(cartridge: &Cartridge) -> Result<Self, CartridgeError> {
+        let mapper = mapper::from_cartridge(cartridge)?;
+        let mut console = Console {
+            cpu: CPU::with_bus(BUS::with_mapper(mapper)),
+            rom_hash: cartridge.get_hash(),
+            battery_file: None,
+            rewind: None,
+            trace: None,
+            palette: Palette::init(),
+            ntsc_filter: None,
+        };
+        console.set_region(cartridge.get_region());
+        console.reset();
+        return Ok(console);
+    }
+
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+    }
+
+    pub fn get_region(&self) -> Region {
+        return self.cpu.get_bus().get_region();
+    }
+
+    /// Overrides the region picked from the ROM header.
+    pub fn set_region(&mut self, region: Region) {
+        self.cpu.get_bus_mut().set_region(region);
+    }
+
+    /// Frames per second at the current region's timing.
+    pub fn get_frame_rate(&self) -> f64 {
+        return self.get_region().get_frame_rate();
+    }
+
+    /// Runs a single CPU instruction and returns the number of cycles it took.
+    pub fn step(&mut self) -> u64 {
+        if let Some(trace) = &mut self.trace {
+            trace.log(&mut self.cpu);
+        }
+        return self.cpu.step();
+    }
+
+    fn emulate_frame(&mut self) {
+        loop {
+            self.step();
+            if self.cpu.get_bus_mut().get_ppu_mut().take_frame_complete() {
+                return;
+            }
+        }
+    }
+
+    /// Runs until the PPU reaches vblank, i.e. one full frame has been drawn.
+    pub fn run_frame(&mut self) {
+        self.emulate_frame();
+        let capture_due = match &mut self.rewind {
+            Some(rewind) => rewind.tick_frame(),
+            None => false,
+        };
+        if capture_due {
+            let state = self.save_state();
+            if let Some(rewind) = &mut self.rewind {
+                rewind.push(state);
+            }
+        }
+    }
+
+    /// Logs every instruction from now on, replacing any running trace.
+    pub fn start_trace(&mut self, trace: TraceLogger) {
+        self.trace = Some(trace);
+    }
+
+    /// Stops tracing and hands back the logger.
+    pub fn stop_trace(&mut self) -> Option<TraceLogger> {
+        return self.trace.take();
+    }
+
+    /// Stops tracing and flushes the log, if there is one.
+    pub fn finish_trace(&mut self) -> io::Result<()> {
+        match self.trace.take() {
+            Some(trace) => return trace.finish(),
+            None => return Ok(()),
+        }
+    }
+
+    /// Marks PRG and CHR bytes as code or data from now on, replacing any
+    /// running log.
+    pub fn start_cdl(&mut self, cdl: CDL) {
+        self.cpu.get_bus_mut().start_cdl(cdl);
+    }
+
+    pub fn stop_cdl(&mut self) -> Option<CDL> {
+        return self.cpu.get_bus_mut().stop_cdl();
+    }
+
+    /// Starts capturing a state every `interval` frames, keeping up to
+    /// `max_mb` megabytes of history.
+    pub fn enable_rewind(&mut self, max_mb: usize, interval: u64) {
+        self.rewind = Some(RewindBuffer::init(max_mb, interval));
+    }
+
+    pub fn disable_rewind(&mut self) {
+        self.rewind = None;
+    }
+
+    pub fn get_rewind(&self) -> Option<&RewindBuffer> {
+        return self.rewind.as_ref();
+    }
+
+    /// Steps back to the newest captured state and redraws the frame after
+    /// it, without capturing. Returns false, with the console and the history
+    /// untouched, once the history is used up or a state fails to load.
+    pub fn rewind_frame(&mut self) -> bool {
+        let frame = self.cpu.get_bus().get_ppu().get_frame();
+        let backup = self.save_state();
+        let mut popped = Vec::new();
+        let mut stepped_back = false;
+        // the newest capture can be of the frame on screen, which would
+        // step forwards; skip it
+        while let Some(state) = self.rewind.as_mut().and_then(|rewind| rewind.pop()) {
+            let loaded = self.load_state(&state).is_ok();
+            popped.push(state);
+            if !loaded {
+                break;
+            }
+            if self.cpu.get_bus().get_ppu().get_frame() != frame {
+                stepped_back = true;
+                break;
+            }
+        }
+        if !stepped_back {
+            self.load_state(&backup)
+                .expect("restoring the previous state");
+            if let Some(rewind) = self.rewind.as_mut() {
+                for state in popped.into_iter().rev() {
+                    rewind.push(state);
+                }
+            }
+            return false;
+        }
+        self.emulate_frame();
+        // the audio would play forwards, drop it
+        self.take_audio_samples();
+        return true;
+    }
+
+    /// The last frame as 256x240 colour indices with emphasis bits.
+    pub fn get_framebuffer(&self) -> &[u16] {
+        return self.cpu.get_bus().get_ppu().get_framebuffer();
+    }
+
+    /// The colours used without the NTSC filter.
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
+
+    pub fn get_video_filter(&self) -> VideoFilter {
+        match self.ntsc_filter {
+            Some(_) => return VideoFilter::NTSC,
+            None => return VideoFilter::Palette,
+        }
+    }
+
+    /// Picks how [`Console::get_frame_rgb`] turns pixels into colours.
+    pub fn set_video_filter(&mut self, filter: VideoFilter) {
+        if filter == self.get_video_filter() {
+            return;
+        }
+        self.ntsc_filter = match filter {
+            VideoFilter::Palette => None,
+            VideoFilter::NTSC => Some(NTSCFilter::init()),
+        };
+    }
+
+    /// The last frame as 256x240 packed RGB24, through the video filter.
+    pub fn get_frame_rgb(&self) -> Vec<u8> {
+        let mut rgb = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * 3];
+        match &self.ntsc_filter {
+            Some(filter) => {
+                let frame = self.cpu.get_bus().get_ppu().get_frame();
+                filter.filter_frame(self.get_framebuffer(), frame, &mut rgb);
+            }
+            None => self.palette.pixels_to_rgb(self.get_framebuffer(), &mut rgb),
+        }
+        return rgb;
+    }
+
+    pub fn set_audio_sample_rate(&mut self, sample_rate: u32) {
+        self.cpu
+            .get_bus_mut()
+            .get_apu_mut()
+            .set_sample_rate(sample_rate);
+    }
+
+    /// Drains the audio produced since the last call as mono f32 samples.
+    pub fn take_audio_samples(&mut self) -> Vec<f32> {
+        return self.cpu.get_bus_mut().get_apu_mut().take_samples();
+    }
+
+    pub fn get_cpu(&self) -> &CPU<BUS> {
+        return &self.cpu;
+    }
+
+    pub fn get_cpu_mut(&mut self) -> &mut CPU<BUS> {
+        return &mut self.cpu;
+    }
+
+    pub fn get_controller_mut(&mut self, port: usize) -> &mut Controller {
+        return self.cpu.get_bus_mut().get_controller_mut(port);
+    }
+
+    /// The cartridge's battery backed PRG RAM, if it has any.
+    pub fn get_battery_ram(&self) -> Option<&[u8]> {
+        return self.cpu.get_bus().get_mapper()?.get_battery_ram();
+    }
+
+    /// Keeps battery RAM in the file at `path`, loading it now if the file
+    /// exists. Disks keep what was written to them there as an IPS patch
+    /// instead. Does nothing for cartridges without a battery.
+    pub fn open_battery_file<P: Into<PathBuf>>(&mut self, path: P) -> io::Result<()> {
+        let mapper = match self.cpu.get_bus_mut().get_mapper_mut() {
+            Some(mapper) => mapper,
+            None => return Ok(()),
+        };
+        if let Some(mut image) = mapper.get_disk_image() {
+            let mut file = BatteryFile::init_patch(path.into(), image.clone());
+            file.load(&mut image)?;
+            mapper.set_disk_image(&image);
+            self.battery_file = Some(file);
+            return Ok(());
+        }
+        let ram = match mapper.get_battery_ram_mut() {
+            Some(ram) => ram,
+            None => return Ok(()),
+        };
+        let mut file = BatteryFile::init(path.into());
+        file.load(ram)?;
+        self.battery_file = Some(file);
+        return Ok(());
+    }
+
+    pub fn get_battery_file(&mut self) -> Option<&mut BatteryFile> {
+        return self.battery_file.as_mut();
+    }
+
+    /// Writes battery RAM to its file now, if it changed since the last write.
+    pub fn flush_battery_file(&mut self) -> io::Result<()> {
+        let (file, mapper) = match (&mut self.battery_file, self.cpu.get_bus().get_mapper()) {
+            (Some(file), Some(mapper)) => (file, mapper),
+            _ => return Ok(()),
+        };
+        if let Some(image) = mapper.get_disk_image() {
+            return file.save(&image);
+        }
+        if let Some(ram) = mapper.get_battery_ram() {
+            file.save(ram)?;
+        }
+        return Ok(());
+    }
+
+    /// Call once per frame; flushes battery RAM every flush interval so a
+    /// crash loses at most a few seconds of progress.
+    pub fn update_battery_file(&mut self) -> io::Result<()> {
+        let flush_due = match &mut self.battery_file {
+            Some(file) => file.tick_frame(),
+            None => false,
+        };
+        if flush_due {
+            return self.flush_battery_file();
+        }
+        return Ok(());
+    }
+
+    /// Sides of the inserted Famicom Disk System disk, 0 for cartridges.
+    pub fn get_disk_side_count(&self) -> usize {
+        match self.cpu.get_bus().get_mapper() {
+            Some(mapper) => return mapper.get_disk_side_count(),
+            None => return 0,
+        }
+    }
+
+    pub fn get_disk_side(&self) -> Option<usize> {
+        return self.cpu.get_bus().get_mapper()?.get_disk_side();
+    }
+
+    /// Ejects the disk and, given a side, puts it in the drive a moment later
+    /// so the BIOS sees the change.
+    pub fn set_disk_side(&mut self, side: Option<usize>) {
+        if let Some(mapper) = self.cpu.get_bus_mut().get_mapper_mut() {
+            mapper.set_disk_side(side);
+        }
+    }
+
+    /// Snapshots the whole machine. Only valid for the same ROM.
+    pub fn save_state(&self) -> Vec<u8> {
+        return savestate::encode(&self.cpu, self.rom_hash);
+    }
+
+    /// Restores a snapshot from [`Console::save_state`]. The console is left
+    /// untouched if the state can't be loaded.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let backup = self.save_state();
+        if let Err(err) = savestate::decode(&mut self.cpu, self.rom_hash, data) {
+            savestate::decode(&mut self.cpu, self.rom_hash, &backup)
+                .expect("restoring the previous state");
+            return Err(err);
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::MemoryBus;
+    use crate::cartridge::test::{ines_rom, nrom_with_program};
+    use crate::input::Button;
+
+    fn console_with_program(source: &str) -> Console {
+        let cartridge = Cartridge::from_ines(&nrom_with_program(source)).unwrap();
+        return Console::init(&cartridge).unwrap();
+    }
+
+    #[test]
+    fn test_reset_jumps_to_reset_vector() {
+        let console = console_with_program("");
+        assert_eq!(console.get_cpu().get_pc(), 0x8000);
+        assert_eq!(console.get_cpu().get_cycles(), 7);
+    }
+
+    #[test]
+    fn test_step_runs_from_prg_rom() {
+        let mut console = console_with_program(
+            "LDA #$42
+             STA $0200
+             LDA $0A00  ; mirror of $0200",
+        );
+        assert_eq!(console.step(), 2);
+        assert_eq!(console.step(), 4);
+        console.get_cpu_mut().set_a(0);
+        assert_eq!(console.step(), 4);
+        assert_eq!(console.get_cpu().get_a(), 0x42);
+    }
+
+    #[test]
+    fn test_run_frame_stops_at_vblank() {
+        let mut console = console_with_program("JMP $8000");
+        console.run_frame();
+        assert_eq!(console.get_cpu().get_bus().get_ppu().get_scanline(), 241);
+        let first_frame_cycles = console.get_cpu().get_cycles();
+        console.run_frame();
+        // 262 scanlines of 341 dots at 3 dots per CPU cycle, give or take an instruction
+        let cycles = console.get_cpu().get_cycles() - first_frame_cycles;
+        assert!((29778..29784).contains(&cycles), "{}", cycles);
+    }
+
+    #[test]
+    fn test_pal_and_dendy_frame_timing() {
+        // 312 scanlines at 3.2 dots per CPU cycle on PAL and 3 on Dendy,
+        // with vblank starting 50 lines later on the Dendy
+        for (region, vblank_scanline, expected) in [
+            (Region::PAL, 241, 33247..33253),
+            (Region::Dendy, 291, 35461..35467),
+        ] {
+            let mut console = console_with_program("JMP $8000");
+            console.set_region(region);
+            console.run_frame();
+            let ppu = console.get_cpu().get_bus().get_ppu();
+            assert_eq!(ppu.get_scanline(), vblank_scanline);
+            let first_frame_cycles = console.get_cpu().get_cycles();
+            console.run_frame();
+            let cycles = console.get_cpu().get_cycles() - first_frame_cycles;
+            assert!(expected.contains(&cycles), "{:?}: {}", region, cycles);
+        }
+    }
+
+    #[test]
+    fn test_greyscale_and_emphasis_reach_the_framebuffer() {
+        let program = "
+                    LDA #$3F
+                    STA $2006
+                    LDA #$00
+                    STA $2006
+                    LDA #$16        ; red backdrop
+                    STA $2007
+                    LDA #%00100001  ; red emphasis, greyscale
+                    STA $2001
+            loop:   JMP loop";
+        let mut console = console_with_program(program);
+        console.run_frame();
+        console.run_frame();
+        assert_eq!(console.get_framebuffer()[0], 0x10 | 0b001 << 6);
+        let rgb = console.get_frame_rgb();
+        assert!(rgb[0] > rgb[1] && rgb[1] == rgb[2]);
+
+        // the PAL PPU swaps red and green emphasis
+        let mut console = console_with_program(program);
+        console.set_region(Region::PAL);
+        console.run_frame();
+        console.run_frame();
+        assert_eq!(console.get_framebuffer()[0], 0x10 | 0b010 << 6);
+    }
+
+    #[test]
+    fn test_ppu_open_bus_and_palette_read_buffer() {
+        let mut console = console_with_program(
+            "       LDA #$2F
+                    STA $2006
+                    LDA #$00
+                    STA $2006
+                    LDA #$55        ; the nametable byte under $3F00
+                    STA $2007
+                    LDA #$3F
+                    STA $2006
+                    LDA #$00
+                    STA $2006
+                    LDA #$21
+                    STA $2007
+                    LDA #$3F        ; back to $3F00
+                    STA $2006
+                    LDA #$00
+                    STA $2006
+                    LDA #$C0        ; leaves $C0 on the I/O latch
+                    STA $2003
+                    LDA $2007       ; palette, with bits 6-7 from the latch
+                    STA $00
+                    LDA #$20
+                    STA $2006
+                    LDA #$00
+                    STA $2006
+                    LDA $2007       ; the buffer was filled from $2F00
+                    STA $01
+                    LDA #$C0
+                    STA $2003
+            loop:   LDA $2000       ; write-only, reads the latch
+                    STA $02
+                    JMP loop",
+        );
+        console.run_frame();
+        let bus = console.get_cpu_mut().get_bus_mut();
+        assert_eq!(bus.peek_memory_byte(0x00), 0xE1);
+        assert_eq!(bus.peek_memory_byte(0x01), 0x55);
+        assert_eq!(bus.peek_memory_byte(0x02), 0xC0);
+        // and the latch fades after a while
+        for _ in 0..40 {
+            console.run_frame();
+        }
+        let bus = console.get_cpu_mut().get_bus_mut();
+        assert_eq!(bus.peek_memory_byte(0x02), 0);
+    }
+
+    #[test]
+    fn test_unmapped_reads_return_open_bus() {
+        let mut console = console_with_program(
+            "       LDA $5000       ; the last byte fetched was $50
+                    STA $00
+                    LDA $4000       ; write-only
+                    STA $01
+                    LDA $4016       ; bits 5-7 aren't driven
+                    STA $02
+            loop:   JMP loop",
+        );
+        for _ in 0..6 {
+            console.step();
+        }
+        let bus = console.get_cpu_mut().get_bus_mut();
+        assert_eq!(bus.peek_memory_byte(0x00), 0x50);
+        assert_eq!(bus.peek_memory_byte(0x01), 0x40);
+        assert_eq!(bus.peek_memory_byte(0x02), 0x40);
+    }
+
+    #[test]
+    fn test_controller_is_read_through_4016() {
+        let mut console = console_with_program("");
+        console
+            .get_controller_mut(0)
+            .set_button(Button::Start, true);
+        let bus = console.get_cpu_mut().get_bus_mut();
+        bus.write_memory_byte(0x4016, 1);
+        bus.write_memory_byte(0x4016, 0);
+        let bits: Vec<u8> = (0..8).map(|_| bus.read_memory_byte(0x4016)).collect();
+        assert_eq!(bits, vec![0, 0, 0, 1, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_battery_ram_persists() {
+        let path = std::env::temp_dir().join(format!("rustes-console-{}.sav", std::process::id()));
+        let cartridge = Cartridge::from_ines(&ines_rom(0, 1, 1, 0b10)).unwrap();
+        let mut console = Console::init(&cartridge).unwrap();
+        console.open_battery_file(&path).unwrap();
+        console
+            .get_cpu_mut()
+            .get_bus_mut()
+            .write_memory_byte(0x6123, 0x42);
+        console.flush_battery_file().unwrap();
+
+        let mut console = Console::init(&cartridge).unwrap();
+        console.open_battery_file(&path).unwrap();
+        assert_eq!(console.get_battery_ram().unwrap()[0x123], 0x42);
+        std::fs::remove_file(&path).unwrap();
+
+        // without the battery flag there's nothing to keep
+        let mut console =
+            Console::init(&Cartridge::from_ines(&ines_rom(0, 1, 1, 0)).unwrap()).unwrap();
+        console.open_battery_file(&path).unwrap();
+        assert!(console.get_battery_ram().is_none());
+        console.flush_battery_file().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_load_state_restores_the_machine() {
+        let mut console = console_with_program("INC $10\nJMP $8000");
+        console.run_frame();
+        let state = console.save_state();
+        let cycles = console.get_cpu().get_cycles();
+        let counter = console.get_cpu_mut().get_bus_mut().read_memory_byte(0x10);
+
+        console.run_frame();
+        console.run_frame();
+        console.load_state(&state).unwrap();
+        assert_eq!(console.get_cpu().get_cycles(), cycles);
+        assert_eq!(
+            console.get_cpu_mut().get_bus_mut().read_memory_byte(0x10),
+            counter
+        );
+        assert_eq!(console.save_state(), state);
+
+        // running on from the restored state matches running on from the original
+        console.run_frame();
+        let mut expected = console_with_program("INC $10\nJMP $8000");
+        expected.run_frame();
+        expected.run_frame();
+        assert_eq!(console.save_state(), expected.save_state());
+    }
+
+    #[test]
+    fn test_rewind_frame_steps_back() {
+        let mut console = console_with_program("INC $10\nJMP $8000");
+        console.enable_rewind(1, 2);
+        for _ in 0..8 {
+            console.run_frame();
+        }
+        let frame = console.get_cpu().get_bus().get_ppu().get_frame();
+        assert_eq!(console.get_rewind().unwrap().len(), 4);
+
+        // states were captured after every second frame, the last one of the
+        // frame on screen; each step back lands on the frame after an older one
+        for frames_back in [1, 3, 5] {
+            assert!(console.rewind_frame());
+            let ppu_frame = console.get_cpu().get_bus().get_ppu().get_frame();
+            assert_eq!(ppu_frame, frame - frames_back);
+        }
+        assert!(!console.rewind_frame());
+    }
+
+    #[test]
+    fn test_rewind_frame_keeps_everything_when_a_state_fails() {
+        let mut console = console_with_program("INC $10\nJMP $8000");
+        console.enable_rewind(1, 1);
+        for _ in 0..3 {
+            console.run_frame();
+        }
+        // a capture of the frame on screen, skipped, then one that won't load
+        let mut damaged = console.save_state();
+        damaged[0] ^= 0xff;
+        let rewind = console.rewind.as_mut().unwrap();
+        let newest = rewind.pop().unwrap();
+        rewind.push(damaged);
+        rewind.push(newest);
+        let before = console.save_state();
+
+        assert!(!console.rewind_frame());
+        assert_eq!(console.save_state(), before);
+        let rewind = console.rewind.as_mut().unwrap();
+        assert_eq!(rewind.len(), 4);
+        assert_eq!(rewind.pop().unwrap(), before);
+    }
+
+    #[test]
+    fn test_load_state_rejects_other_games() {
+        let mut console = console_with_program("");
+        let before = console.save_state();
+        let other = Console::init(&Cartridge::from_ines(&ines_rom(0, 1, 0, 0)).unwrap()).unwrap();
+        assert!(console.load_state(&other.save_state()).is_err());
+        // same memory sizes, different code
+        let other = console_with_program("NOP");
+        assert_eq!(
+            console.load_state(&other.save_state()),
+            Err(StateError::WrongGame)
+        );
+        assert!(console.load_state(&before[..10]).is_err());
+        assert_eq!(console.save_state(), before);
+    }
+}
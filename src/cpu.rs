@@ -1,36 +1,28 @@
-use crate::bus::BUS;
+use crate::bus::{MemoryBus, ReadKind, BUS};
+use crate::opcodes::{get_mode_from_opcode, AddressingModes};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 
 #[derive(Debug)]
-pub struct CPU {
+pub struct CPU<B: MemoryBus = BUS> {
     pc: u16,
     sp: u8,
     a: u8,
     x: u8,
     y: u8,
     ps: u8,
-    bus: BUS,
-}
-
-#[derive(Debug, PartialEq, Eq)]
-enum AddressingModes {
-    Implicit,
-    Accumulator,
-    Immediate,
-    ZeroPage,
-    ZeroPageX,
-    ZeroPageY,
-    Relative,
-    Absolute,
-    AbsoluteX,
-    AbsoluteY,
-    Indirect,
-    IndirectX,
-    IndirectY,
-    Error,
+    cycles: u64,
+    // an NMI edge seen by the bus, and whether it was seen in time to be
+    // taken after the current instruction
+    nmi_detected: bool,
+    nmi_ready: bool,
+    bus: B,
 }
 
 const STACK_START: u16 = 0x0100;
-const STACK_END: u16 = 0x01FF;
+
+const NMI_VECTOR: u16 = 0xFFFA;
+const RESET_VECTOR: u16 = 0xFFFC;
+const IRQ_VECTOR: u16 = 0xFFFE;
 
 fn get_nth_bit_u8(byte: u8, n: u8) -> u8 {
     return (byte >> n) & 1;
@@ -40,194 +32,229 @@ fn assemble_2_bytes_le_u16(ms_byte: u8, ls_byte: u8) -> u16 {
     return ((ms_byte as u16) << 8) | (ls_byte as u16);
 }
 
-fn get_mode_from_opcode(opcode: u8) -> AddressingModes {
-    match opcode {
-        0x00 | 0x08 | 0x18 | 0x28 | 0x38 | 0x40 | 0x48 | 0x58 | 0x60 | 0x68 | 0x78 | 0x88
-        | 0x8A | 0x98 | 0x9A | 0xA8 | 0xAA | 0xBA | 0xB8 | 0xC8 | 0xCA | 0xD8 | 0xE8 | 0xEA
-        | 0xF8 => {
-            return AddressingModes::Implicit;
-        }
-        0x01 | 0x21 | 0x41 | 0x61 | 0x81 | 0xA1 | 0xC1 | 0xE1 => {
-            return AddressingModes::IndirectX;
-        }
-        0x05 | 0x06 | 0x65 | 0x24 | 0x25 | 0x26 | 0x45 | 0x46 | 0x66 | 0x85 | 0x86 | 0xA4
-        | 0xA5 | 0xA6 | 0xC4 | 0xC5 | 0xC6 | 0xE4 | 0xE5 | 0xE6 => {
-            return AddressingModes::ZeroPage;
-        }
-        0x09 | 0x29 | 0x49 | 0x69 | 0xA0 | 0xA2 | 0xA9 | 0xC0 | 0xC9 | 0xE0 | 0xE9 => {
-            return AddressingModes::Immediate;
-        }
-        0x0A | 0x2A | 0x4A | 0x6A => {
-            return AddressingModes::Accumulator;
-        }
-        0x0D | 0x0E | 0x6D | 0x20 | 0x2C | 0x2D | 0x2E | 0x4C | 0x4D | 0x4E | 0x6E | 0x8C
-        | 0x8D | 0x8E | 0xAC | 0xAD | 0xAE | 0xCC | 0xCD | 0xCE | 0xEC | 0xED | 0xEE => {
-            return AddressingModes::Absolute;
-        }
-        0x10 | 0x30 | 0x50 | 0x70 | 0x90 | 0xB0 | 0xD0 | 0xF0 => {
-            return AddressingModes::Relative;
-        }
-        0x11 | 0x31 | 0x51 | 0x71 | 0x91 | 0xB1 | 0xD1 | 0xF1 => {
-            return AddressingModes::IndirectY;
-        }
-        0x15 | 0x16 | 0x35 | 0x36 | 0x55 | 0x56 | 0x75 | 0x76 | 0x94 | 0x95 | 0xB4 | 0xB5
-        | 0xD5 | 0xD6 | 0xF5 | 0xF6 => {
-            return AddressingModes::ZeroPageX;
-        }
-        0x1D | 0x1E | 0x7D | 0x3D | 0x3E | 0x5E | 0x5D | 0x7E | 0x9D | 0xBC | 0xBD | 0xDD
-        | 0xDE | 0xFD | 0xFE => {
-            return AddressingModes::AbsoluteX;
-        }
-        0x6C => {
-            return AddressingModes::Indirect;
-        }
-        0x79 | 0x39 | 0x59 | 0x99 | 0xB9 | 0xD9 | 0xF9 => {
-            return AddressingModes::AbsoluteY;
-        }
-        0xB6 | 0x96 => {
-            return AddressingModes::ZeroPageY;
-        }
-        0x19 | 0xBE => {
-            return AddressingModes::AbsoluteY;
-        }
-        _ => {
-            println!("no mode for {}", opcode);
-            return AddressingModes::Error;
-        }
-    }
+fn page_crossed(addr_1: u16, addr_2: u16) -> bool {
+    return (addr_1 & 0xFF00) != (addr_2 & 0xFF00);
 }
 
-impl CPU {
+impl CPU<BUS> {
     pub fn init() -> Self {
+        return CPU::with_bus(BUS::init());
+    }
+}
+
+impl<B: MemoryBus> CPU<B> {
+    pub fn with_bus(bus: B) -> Self {
         return CPU {
             pc: 0,
-            sp: 0,
+            sp: 0xFD,
             a: 0,
             x: 0,
             y: 0,
-            ps: 0,
-            bus: BUS::init(),
+            ps: 0b00100100,
+            cycles: 0,
+            nmi_detected: false,
+            nmi_ready: false,
+            bus,
         };
     }
 
+    pub fn get_bus(&self) -> &B {
+        return &self.bus;
+    }
+
+    pub fn get_bus_mut(&mut self) -> &mut B {
+        return &mut self.bus;
+    }
+
+    pub fn get_cycles(&self) -> u64 {
+        return self.cycles;
+    }
+
+    fn tick(&mut self) {
+        self.cycles += 1;
+        // the NMI line is sampled each cycle, and an edge has to arrive
+        // before the last cycle of an instruction to be taken after it
+        self.nmi_ready = self.nmi_detected;
+        if self.bus.poll_nmi() {
+            self.nmi_detected = true;
+        }
+        self.bus.tick();
+    }
+
     fn read_byte_from_memory(&mut self, addr: u16) -> u8 {
-        self.update_program_counter_n(1);
+        self.tick();
         return self.bus.read_memory_byte(addr);
     }
 
     fn write_byte_to_memory(&mut self, addr: u16, val: u8) {
-        self.update_program_counter_n(1);
+        self.tick();
         self.bus.write_memory_byte(addr, val);
+        for _ in 0..self.bus.take_stall_cycles() {
+            self.tick();
+        }
+    }
+
+    fn dummy_read(&mut self, addr: u16) {
+        self.read_byte_from_memory(addr);
     }
 
     fn read_2_bytes_from_memory(&mut self, addr: u16) -> u16 {
         let ls_byte = self.read_byte_from_memory(addr);
-        let ms_byte = self.read_byte_from_memory(addr + 1);
+        let ms_byte = self.read_byte_from_memory(addr.wrapping_add(1));
 
         return assemble_2_bytes_le_u16(ms_byte, ls_byte);
     }
 
+    #[cfg(test)]
     fn write_2_bytes_to_memory(&mut self, addr: u16, val: u16) {
         let ls_byte = (val >> 8) as u8;
         let ms_byte = (val & 0xFF) as u8;
 
         self.write_byte_to_memory(addr, ms_byte);
-        self.write_byte_to_memory(addr + 1, ls_byte);
+        self.write_byte_to_memory(addr.wrapping_add(1), ls_byte);
     }
 
-    fn pop_byte_from_stack(&mut self) -> u8 {
-        let sp = self.get_sp();
-        let val = self.read_byte_from_memory(STACK_START + (sp as u16));
-        self.set_sp(sp + 1);
+    fn fetch_byte(&mut self) -> u8 {
+        let pc = self.get_pc();
+        let val = self.read_byte_from_memory(pc);
+        self.bus.log_read(pc, ReadKind::Code);
+        self.set_pc(pc.wrapping_add(1));
         return val;
     }
 
+    fn fetch_2_bytes(&mut self) -> u16 {
+        let ls_byte = self.fetch_byte();
+        let ms_byte = self.fetch_byte();
+        return assemble_2_bytes_le_u16(ms_byte, ls_byte);
+    }
+
+    fn pop_byte_from_stack(&mut self) -> u8 {
+        let sp = self.get_sp().wrapping_add(1);
+        self.set_sp(sp);
+        return self.read_byte_from_memory(STACK_START + (sp as u16));
+    }
+
     fn push_byte_to_stack(&mut self, val: u8) {
         let sp = self.get_sp();
         self.write_byte_to_memory(STACK_START + (sp as u16), val);
-        self.set_sp(sp - 1);
+        self.set_sp(sp.wrapping_sub(1));
     }
 
     fn pop_2_bytes_from_stack(&mut self) -> u16 {
-        let sp = self.get_sp();
-        let val = self.read_2_bytes_from_memory(STACK_START + (sp as u16));
-        self.set_sp(sp + 2);
-        return val;
+        let ls_byte = self.pop_byte_from_stack();
+        let ms_byte = self.pop_byte_from_stack();
+        return assemble_2_bytes_le_u16(ms_byte, ls_byte);
     }
 
     fn push_2_bytes_to_stack(&mut self, val: u16) {
+        self.push_byte_to_stack((val >> 8) as u8);
+        self.push_byte_to_stack((val & 0xFF) as u8);
+    }
+
+    fn dummy_read_stack(&mut self) {
         let sp = self.get_sp();
-        self.write_2_bytes_to_memory(STACK_START + (sp as u16), val);
-        self.set_sp(sp - 2);
+        self.dummy_read(STACK_START + (sp as u16));
     }
 
-    // prob needs some work
-    fn handle_addressing_mode(&mut self, mode: &AddressingModes) -> u16 {
+    // Resolves the effective address of the operand, consuming the operand bytes
+    // and spending the same dummy cycles the 6502 does. Indexed modes only pay
+    // the page crossing cycle on reads, stores and read-modify-writes always do.
+    fn handle_addressing_mode(&mut self, mode: &AddressingModes, is_write: bool) -> u16 {
         match mode {
-            AddressingModes::Implicit => return 0,
-            AddressingModes::Accumulator => return self.get_a() as u16,
-            AddressingModes::Immediate => return self.get_pc(),
-            AddressingModes::ZeroPage => {
+            AddressingModes::Implicit | AddressingModes::Accumulator => return 0,
+            AddressingModes::Immediate => {
                 let pc = self.get_pc();
-                return self.read_byte_from_memory(pc) as u16;
+                self.set_pc(pc.wrapping_add(1));
+                return pc;
+            }
+            AddressingModes::ZeroPage => {
+                return self.fetch_byte() as u16;
             }
             AddressingModes::ZeroPageX => {
-                let x = self.get_x() as u16;
-                let pc = self.get_pc();
-                return self.read_byte_from_memory(pc.wrapping_add(x)) as u16;
+                let base = self.fetch_byte();
+                self.dummy_read(base as u16);
+                return base.wrapping_add(self.get_x()) as u16;
             }
             AddressingModes::ZeroPageY => {
-                let y = self.get_y() as u16;
-                let pc = self.get_pc();
-                return self.read_byte_from_memory(pc.wrapping_add(y)) as u16;
+                let base = self.fetch_byte();
+                self.dummy_read(base as u16);
+                return base.wrapping_add(self.get_y()) as u16;
             }
             AddressingModes::Relative => {
-                let pc = self.get_pc();
-                let pc_2 = self.get_pc();
-                return pc.wrapping_add_signed(self.read_byte_from_memory(pc_2) as i16);
+                let offset = self.fetch_byte() as i8;
+                return self.get_pc().wrapping_add_signed(offset as i16);
             }
             AddressingModes::Absolute => {
-                let pc = self.get_pc();
-                return self.read_2_bytes_from_memory(pc);
+                return self.fetch_2_bytes();
             }
             AddressingModes::AbsoluteX => {
-                let pc = self.get_pc();
-                let x = self.get_x() as u16;
-                return self.read_2_bytes_from_memory(pc.wrapping_add(x));
+                let base = self.fetch_2_bytes();
+                let addr = base.wrapping_add(self.get_x() as u16);
+                if is_write || page_crossed(base, addr) {
+                    self.dummy_read((base & 0xFF00) | (addr & 0x00FF));
+                }
+                return addr;
             }
             AddressingModes::AbsoluteY => {
-                let pc = self.get_pc();
-                let y = self.get_y() as u16;
-                return self.read_2_bytes_from_memory(pc.wrapping_add(y));
+                let base = self.fetch_2_bytes();
+                let addr = base.wrapping_add(self.get_y() as u16);
+                if is_write || page_crossed(base, addr) {
+                    self.dummy_read((base & 0xFF00) | (addr & 0x00FF));
+                }
+                return addr;
             }
             AddressingModes::Indirect => {
-                let pc = self.get_pc();
-                let ls_byte_location = self.read_byte_from_memory(pc) as u16;
-                return self.read_2_bytes_from_memory(ls_byte_location);
+                // the high byte is fetched without carrying into the page,
+                // so JMP ($xxFF) wraps around like it does on hardware
+                let ptr = self.fetch_2_bytes();
+                let ms_ptr = (ptr & 0xFF00) | (ptr.wrapping_add(1) & 0x00FF);
+                let ls_byte = self.read_byte_from_memory(ptr);
+                let ms_byte = self.read_byte_from_memory(ms_ptr);
+                self.bus.log_read(ptr, ReadKind::Data);
+                self.bus.log_read(ms_ptr, ReadKind::Data);
+                return assemble_2_bytes_le_u16(ms_byte, ls_byte);
             }
             AddressingModes::IndirectX => {
-                let pc = self.get_pc();
-                let x = self.get_x() as u16;
-                let ls_byte_location = self.read_byte_from_memory(pc.wrapping_add(x)) as u16;
-                return self.read_2_bytes_from_memory(ls_byte_location);
+                let base = self.fetch_byte();
+                self.dummy_read(base as u16);
+                let ptr = base.wrapping_add(self.get_x());
+                let ls_byte = self.read_byte_from_memory(ptr as u16);
+                let ms_byte = self.read_byte_from_memory(ptr.wrapping_add(1) as u16);
+                return assemble_2_bytes_le_u16(ms_byte, ls_byte);
             }
             AddressingModes::IndirectY => {
-                let pc = self.get_pc();
-                let ls_byte_location = self.read_byte_from_memory(pc) as u16;
-                let y = self.get_y();
-                return self
-                    .read_2_bytes_from_memory(ls_byte_location)
-                    .wrapping_add(y as u16);
-            }
-            _ => {
-                println!("Unknown mode");
-                return 0;
+                let ptr = self.fetch_byte();
+                let ls_byte = self.read_byte_from_memory(ptr as u16);
+                let ms_byte = self.read_byte_from_memory(ptr.wrapping_add(1) as u16);
+                let base = assemble_2_bytes_le_u16(ms_byte, ls_byte);
+                let addr = base.wrapping_add(self.get_y() as u16);
+                if is_write || page_crossed(base, addr) {
+                    self.dummy_read((base & 0xFF00) | (addr & 0x00FF));
+                }
+                return addr;
             }
         }
     }
 
+    fn read_operand(&mut self, mode: &AddressingModes) -> u8 {
+        let addr = self.handle_addressing_mode(mode, false);
+        let val = self.read_byte_from_memory(addr);
+        let kind = match mode {
+            AddressingModes::Immediate => ReadKind::Code,
+            AddressingModes::IndirectX | AddressingModes::IndirectY => ReadKind::IndirectData,
+            _ => ReadKind::Data,
+        };
+        self.bus.log_read(addr, kind);
+        return val;
+    }
+
     fn run_instruction_function_from_opcode(&mut self, opcode: u8) {
         let mode = get_mode_from_opcode(opcode);
+        if mode == AddressingModes::Implicit || mode == AddressingModes::Accumulator {
+            // single byte instructions still read the byte after the opcode
+            let pc = self.get_pc();
+            self.dummy_read(pc);
+        }
         match opcode {
             0x00 => {
                 self.brk();
@@ -248,7 +275,7 @@ impl CPU {
                 self.clc();
             }
             0x20 => {
-                self.jsr(mode);
+                self.jsr();
             }
             0x21 | 0x25 | 0x29 | 0x2D | 0x31 | 0x35 | 0x39 | 0x3D => {
                 self.and(mode);
@@ -398,84 +425,80 @@ impl CPU {
                 self.sed();
             }
             _ => {
-                println!("instr not found for {}", opcode);
+                self.nop();
             }
         }
     }
 
-    fn get_pc(&mut self) -> u16 {
+    pub fn get_pc(&self) -> u16 {
         return self.pc;
     }
 
-    fn get_ps(&mut self) -> u8 {
+    pub fn get_ps(&self) -> u8 {
         return self.ps;
     }
 
-    fn get_sp(&mut self) -> u8 {
+    pub fn get_sp(&self) -> u8 {
         return self.sp;
     }
 
-    fn get_a(&mut self) -> u8 {
+    pub fn get_a(&self) -> u8 {
         return self.a;
     }
 
-    fn get_x(&mut self) -> u8 {
+    pub fn get_x(&self) -> u8 {
         return self.x;
     }
 
-    fn get_y(&mut self) -> u8 {
+    pub fn get_y(&self) -> u8 {
         return self.y;
     }
 
-    fn get_carry_flag(&mut self) -> u8 {
-        return get_nth_bit_u8(self.get_ps() & 0b00000001, 0);
-    }
-
-    fn get_zero_flag(&mut self) -> u8 {
-        return get_nth_bit_u8(self.get_ps() & 0b00000010, 1);
+    pub fn get_carry_flag(&self) -> u8 {
+        return get_nth_bit_u8(self.get_ps(), 0);
     }
 
-    fn get_interrupt_disable(&mut self) -> u8 {
-        return get_nth_bit_u8(self.get_ps() & 0b00000100, 2);
+    pub fn get_zero_flag(&self) -> u8 {
+        return get_nth_bit_u8(self.get_ps(), 1);
     }
 
-    fn get_decimal_mode(&mut self) -> u8 {
-        return get_nth_bit_u8(self.get_ps() & 0b00001000, 3);
+    pub fn get_interrupt_disable(&self) -> u8 {
+        return get_nth_bit_u8(self.get_ps(), 2);
     }
 
-    fn get_break_command(&mut self) -> u8 {
-        return get_nth_bit_u8(self.get_ps() & 0b00110000, 5);
+    pub fn get_decimal_mode(&self) -> u8 {
+        return get_nth_bit_u8(self.get_ps(), 3);
     }
 
-    fn get_overflow_flag(&mut self) -> u8 {
-        return get_nth_bit_u8(self.get_ps() & 0b01000000, 6);
+    pub fn get_overflow_flag(&self) -> u8 {
+        return get_nth_bit_u8(self.get_ps(), 6);
     }
 
-    fn get_negative_flag(&mut self) -> u8 {
-        return get_nth_bit_u8(self.get_ps() & 0b10000000, 7);
+    pub fn get_negative_flag(&self) -> u8 {
+        return get_nth_bit_u8(self.get_ps(), 7);
     }
 
-    fn set_pc(&mut self, val: u16) {
+    pub fn set_pc(&mut self, val: u16) {
         self.pc = val;
     }
 
-    fn set_ps(&mut self, val: u8) {
+    pub fn set_ps(&mut self, val: u8) {
         self.ps = val;
     }
 
-    fn set_sp(&mut self, val: u8) {
+    pub fn set_sp(&mut self, val: u8) {
         self.sp = val;
     }
 
-    fn set_a(&mut self, val: u8) {
+    pub fn set_a(&mut self, val: u8) {
         self.a = val;
     }
 
-    fn set_x(&mut self, val: u8) {
+    pub fn set_x(&mut self, val: u8) {
         self.x = val;
     }
 
-    fn set_y(&mut self, val: u8) {
+    pub fn set_y(&mut self, val: u8) {
         self.y = val;
     }
 
@@ -499,11 +522,6 @@ impl CPU {
         self.set_ps(ps | 0b00001000);
     }
 
-    fn set_break_command(&mut self) {
-        let ps = self.get_ps();
-        self.set_ps(ps | 0b00110000);
-    }
-
     fn set_overflow_flag(&mut self) {
         let ps = self.get_ps();
         self.set_ps(ps | 0b01000000);
@@ -534,11 +552,6 @@ impl CPU {
         self.set_ps(ps & 0b11110111);
     }
 
-    fn unset_break_command(&mut self) {
-        let ps = self.get_ps();
-        self.set_ps(ps & 0b11001111);
-    }
-
     fn unset_overflow_flag(&mut self) {
         let ps = self.get_ps();
         self.set_ps(ps & 0b10111111);
@@ -549,13 +562,19 @@ impl CPU {
         self.set_ps(ps & 0b01111111);
     }
 
-    fn update_program_counter_n(&mut self, val: i8) {
-        //TODO: prob needs work
-        let pc = self.get_pc();
-        if val >= 0 {
-            self.set_pc(pc.wrapping_add(val as u16));
+    fn update_carry_flag(&mut self, carry: bool) {
+        if carry {
+            self.set_carry_flag();
+        } else {
+            self.unset_carry_flag();
+        }
+    }
+
+    fn update_overflow_flag(&mut self, overflow: bool) {
+        if overflow {
+            self.set_overflow_flag();
         } else {
-            self.set_pc(pc.wrapping_sub(val as u16));
+            self.unset_overflow_flag();
         }
     }
 
@@ -572,199 +591,151 @@ impl CPU {
         }
     }
 
-    fn adc(&mut self, mode: AddressingModes) {
-        let addr = self.handle_addressing_mode(&mode);
-        let val = self.read_byte_from_memory(addr);
+    fn add_with_carry(&mut self, val: u8) {
         let a = self.get_a();
-        let carry_flag = self.get_carry_flag();
-        let result = a + val + carry_flag;
+        let sum = (a as u16) + (val as u16) + (self.get_carry_flag() as u16);
+        let result = sum as u8;
 
+        self.update_carry_flag(sum > 0xFF);
+        self.update_overflow_flag((a ^ result) & (val ^ result) & 0x80 != 0);
         self.set_a(result);
-        //TODO: fix logic for overflow
-        // let overflow =
-        // if overflow == 1 {
-        //     unset_carry_flag();
-        //     set_overflow_flag();
-        // } else {
-        //     set_carry_flag();
-        //     unset_overflow_flag();
-        // }
         self.update_zero_and_negative_flags_u8(result);
     }
 
-    fn and(&mut self, mode: AddressingModes) {
-        let addr = self.handle_addressing_mode(&mode);
-        let val = self.read_byte_from_memory(addr);
-        let a = self.get_a();
-        let result = a & val;
-
-        self.set_a(result);
-        self.update_zero_and_negative_flags_u8(result);
+    fn compare(&mut self, register: u8, val: u8) {
+        self.update_carry_flag(register >= val);
+        self.update_zero_and_negative_flags_u8(register.wrapping_sub(val));
     }
 
-    fn asl(&mut self, mode: AddressingModes) {
-        let val;
-        match mode {
-            AddressingModes::Accumulator => val = self.get_a(),
-            _ => {
-                let addr = self.handle_addressing_mode(&mode);
-                val = self.read_byte_from_memory(addr);
+    fn branch(&mut self, mode: AddressingModes, condition: bool) {
+        let target = self.handle_addressing_mode(&mode, false);
+        if condition {
+            let pc = self.get_pc();
+            self.dummy_read(pc);
+            if page_crossed(pc, target) {
+                self.dummy_read((pc & 0xFF00) | (target & 0x00FF));
             }
+            self.set_pc(target);
         }
-        let bit_7 = get_nth_bit_u8(val, 7);
-        let result = val << 1;
+    }
 
-        match mode {
-            AddressingModes::Accumulator => self.set_a(result),
-            _ => {
-                let addr = self.handle_addressing_mode(&mode);
-                self.write_byte_to_memory(addr, result);
-            }
+    // Shared by the shift and rotate instructions, which either work on the
+    // accumulator or do a read-modify-write on memory.
+    fn read_modify_write<F: Fn(&mut Self, u8) -> u8>(&mut self, mode: AddressingModes, op: F) {
+        if mode == AddressingModes::Accumulator {
+            let a = self.get_a();
+            let result = op(self, a);
+            self.set_a(result);
+            return;
         }
+        let addr = self.handle_addressing_mode(&mode, true);
+        let val = self.read_byte_from_memory(addr);
+        self.bus.log_read(addr, ReadKind::Data);
+        self.write_byte_to_memory(addr, val);
+        let result = op(self, val);
+        self.write_byte_to_memory(addr, result);
+    }
 
-        let a = self.get_a();
-        if bit_7 == 1 {
-            self.set_carry_flag();
-        } else {
-            self.unset_carry_flag();
-        }
-        // TODO: check if always checking a? or check result
-        if a == 0 {
-            self.set_zero_flag();
-        } else {
-            self.unset_zero_flag();
-        }
-        if get_nth_bit_u8(result, 7) == 1 {
-            self.set_negative_flag();
-        } else {
-            self.unset_negative_flag();
-        }
+    fn interrupt(&mut self, vector: u16) {
+        let pc = self.get_pc();
+        self.dummy_read(pc);
+        self.dummy_read(pc);
+        self.push_2_bytes_to_stack(pc);
+        let ps = self.get_ps();
+        self.push_byte_to_stack((ps & 0b11101111) | 0b00100000);
+        self.set_interrupt_disable();
+        let addr = self.read_2_bytes_from_memory(vector);
+        self.set_pc(addr);
+    }
+
+    fn adc(&mut self, mode: AddressingModes) {
+        let val = self.read_operand(&mode);
+        self.add_with_carry(val);
+    }
+
+    fn and(&mut self, mode: AddressingModes) {
+        let val = self.read_operand(&mode);
+        let result = self.get_a() & val;
+
+        self.set_a(result);
+        self.update_zero_and_negative_flags_u8(result);
+    }
+
+    fn asl(&mut self, mode: AddressingModes) {
+        self.read_modify_write(mode, |cpu, val| {
+            let result = val << 1;
+            cpu.update_carry_flag(get_nth_bit_u8(val, 7) == 1);
+            cpu.update_zero_and_negative_flags_u8(result);
+            return result;
+        });
     }
 
     fn bcc(&mut self, mode: AddressingModes) {
         let carry_flag = self.get_carry_flag();
-        if carry_flag == 0 {
-            let addr = self.handle_addressing_mode(&mode);
-            let val = self.read_byte_from_memory(addr) as i8;
-            let pc = self.get_pc();
-            //TODO: check logic
-            self.set_pc(pc.wrapping_add(val as u16));
-        }
+        self.branch(mode, carry_flag == 0);
     }
 
     fn bcs(&mut self, mode: AddressingModes) {
         let carry_flag = self.get_carry_flag();
-        if carry_flag == 1 {
-            let addr = self.handle_addressing_mode(&mode);
-            let val = self.read_byte_from_memory(addr) as i8;
-            let pc = self.get_pc();
-            //TODO: check logic
-            self.set_pc(pc.wrapping_add(val as u16));
-        }
+        self.branch(mode, carry_flag == 1);
     }
 
     fn beq(&mut self, mode: AddressingModes) {
         let zero_flag = self.get_zero_flag();
-        if zero_flag == 1 {
-            let addr = self.handle_addressing_mode(&mode);
-            let val = self.read_byte_from_memory(addr) as i8;
-            let pc = self.get_pc();
-            //TODO: check logic
-            self.set_pc(pc.wrapping_add(val as u16));
-        }
+        self.branch(mode, zero_flag == 1);
     }
 
     fn bit(&mut self, mode: AddressingModes) {
-        let addr = self.handle_addressing_mode(&mode);
-        let val = self.read_byte_from_memory(addr);
-        let a = self.get_a();
-
-        let result = a & val;
-        let bit_7 = get_nth_bit_u8(val, 7);
-        let bit_6 = get_nth_bit_u8(val, 6);
+        let val = self.read_operand(&mode);
+        let result = self.get_a() & val;
 
         if result == 0 {
             self.set_zero_flag();
         } else {
             self.unset_zero_flag();
         }
-        if bit_7 == 1 {
+        if get_nth_bit_u8(val, 7) == 1 {
             self.set_negative_flag();
         } else {
             self.unset_negative_flag();
         }
-        if bit_6 == 1 {
-            self.set_overflow_flag();
-        } else {
-            self.unset_overflow_flag();
-        }
+        self.update_overflow_flag(get_nth_bit_u8(val, 6) == 1);
     }
 
     fn bmi(&mut self, mode: AddressingModes) {
         let negative_flag = self.get_negative_flag();
-        if negative_flag == 1 {
-            let addr = self.handle_addressing_mode(&mode);
-            let val = self.read_byte_from_memory(addr) as i8;
-            let pc = self.get_pc();
-            //TODO: check logic
-            self.set_pc(pc.wrapping_add(val as u16));
-        }
+        self.branch(mode, negative_flag == 1);
     }
 
     fn bne(&mut self, mode: AddressingModes) {
         let zero_flag = self.get_zero_flag();
-        if zero_flag == 0 {
-            let addr = self.handle_addressing_mode(&mode);
-            let val = self.read_byte_from_memory(addr) as i8;
-            let pc = self.get_pc();
-            //TODO: check logic
-            self.set_pc(pc.wrapping_add(val as u16));
-        }
+        self.branch(mode, zero_flag == 0);
     }
 
     fn bpl(&mut self, mode: AddressingModes) {
         let negative_flag = self.get_negative_flag();
-        if negative_flag == 0 {
-            let addr = self.handle_addressing_mode(&mode);
-            let val = self.read_byte_from_memory(addr) as i8;
-            let pc = self.get_pc();
-            //TODO: check logic
-            self.set_pc(pc.wrapping_add(val as u16));
-        }
+        self.branch(mode, negative_flag == 0);
     }
 
     fn brk(&mut self) {
-        //TODO: handle brk
-        let pc = self.get_pc();
+        // the byte after BRK is a padding byte that gets skipped on return
+        let pc = self.get_pc().wrapping_add(1);
+        self.push_2_bytes_to_stack(pc);
         let ps = self.get_ps();
-        // let irq = self.read_2_bytes_from_memory(0xFFFE);
-
-        // self.push_2_bytes_to_stack(pc);
-        // self.push_byte_to_stack(ps);
-        //self.set_pc(irq);
-        self.set_break_command();
+        self.push_byte_to_stack(ps | 0b00110000);
+        self.set_interrupt_disable();
+        let addr = self.read_2_bytes_from_memory(IRQ_VECTOR);
+        self.set_pc(addr);
     }
 
     fn bvc(&mut self, mode: AddressingModes) {
         let overflow_flag = self.get_overflow_flag();
-        if overflow_flag == 0 {
-            let addr = self.handle_addressing_mode(&mode);
-            let val = self.read_byte_from_memory(addr) as i8;
-            let pc = self.get_pc();
-            //TODO: check logic
-            self.set_pc(pc.wrapping_add(val as u16));
-        }
+        self.branch(mode, overflow_flag == 0);
     }
 
     fn bvs(&mut self, mode: AddressingModes) {
         let overflow_flag = self.get_overflow_flag();
-        if overflow_flag == 1 {
-            let addr = self.handle_addressing_mode(&mode);
-            let val = self.read_byte_from_memory(addr) as i8;
-            let pc = self.get_pc();
-            //TODO: check logic
-            self.set_pc(pc.wrapping_add(val as u16));
-        }
+        self.branch(mode, overflow_flag == 1);
     }
 
     fn clc(&mut self) {
@@ -784,86 +755,29 @@ impl CPU {
     }
 
     fn cmp(&mut self, mode: AddressingModes) {
-        let addr = self.handle_addressing_mode(&mode);
-        let val = self.read_byte_from_memory(addr);
+        let val = self.read_operand(&mode);
         let a = self.get_a();
-        let result = a - val;
-
-        if a >= val {
-            self.set_carry_flag();
-        } else {
-            self.unset_carry_flag();
-        }
-
-        if a == val {
-            self.set_zero_flag()
-        } else {
-            self.unset_zero_flag()
-        }
-
-        if get_nth_bit_u8(result, 7) == 1 {
-            self.set_negative_flag();
-        } else {
-            self.unset_negative_flag();
-        }
+        self.compare(a, val);
     }
 
     fn cpx(&mut self, mode: AddressingModes) {
-        let addr = self.handle_addressing_mode(&mode);
-        let val = self.read_byte_from_memory(addr);
+        let val = self.read_operand(&mode);
         let x = self.get_x();
-        let result = x.wrapping_sub(val);
-
-        if x >= val {
-            self.set_carry_flag();
-        } else {
-            self.unset_carry_flag();
-        }
-
-        if x == val {
-            self.set_zero_flag()
-        } else {
-            self.unset_zero_flag()
-        }
-
-        if get_nth_bit_u8(result, 7) == 1 {
-            self.set_negative_flag();
-        } else {
-            self.unset_negative_flag();
-        }
+        self.compare(x, val);
     }
 
     fn cpy(&mut self, mode: AddressingModes) {
-        let addr = self.handle_addressing_mode(&mode);
-        let val = self.read_byte_from_memory(addr);
+        let val = self.read_operand(&mode);
         let y = self.get_y();
-        let result = y.wrapping_sub(val);
-
-        if y >= val {
-            self.set_carry_flag();
-        } else {
-            self.unset_carry_flag();
-        }
-
-        if y == val {
-            self.set_zero_flag()
-        } else {
-            self.unset_zero_flag()
-        }
-
-        if get_nth_bit_u8(result, 7) == 1 {
-            self.set_negative_flag();
-        } else {
-            self.unset_negative_flag();
-        }
+        self.compare(y, val);
     }
 
     fn dec(&mut self, mode: AddressingModes) {
-        let addr = self.handle_addressing_mode(&mode);
-        let val = self.read_byte_from_memory(addr);
-        let result = val.wrapping_sub(val);
-        self.write_byte_to_memory(addr, result);
-        self.update_zero_and_negative_flags_u8(result);
+        self.read_modify_write(mode, |cpu, val| {
+            let result = val.wrapping_sub(1);
+            cpu.update_zero_and_negative_flags_u8(result);
+            return result;
+        });
     }
 
     fn dex(&mut self) {
@@ -881,20 +795,18 @@ impl CPU {
     }
 
     fn eor(&mut self, mode: AddressingModes) {
-        let addr = self.handle_addressing_mode(&mode);
-        let val = self.read_byte_from_memory(addr);
-        let a = self.get_a();
-        let result = a ^ val;
+        let val = self.read_operand(&mode);
+        let result = self.get_a() ^ val;
         self.set_a(result);
         self.update_zero_and_negative_flags_u8(result);
     }
 
     fn inc(&mut self, mode: AddressingModes) {
-        let addr = self.handle_addressing_mode(&mode);
-        let val = self.read_byte_from_memory(addr);
-        let result = val.wrapping_add(1);
-        self.write_byte_to_memory(addr, result);
-        self.update_zero_and_negative_flags_u8(result);
+        self.read_modify_write(mode, |cpu, val| {
+            let result = val.wrapping_add(1);
+            cpu.update_zero_and_negative_flags_u8(result);
+            return result;
+        });
     }
 
     fn inx(&mut self) {
@@ -912,74 +824,55 @@ impl CPU {
     }
 
     fn jmp(&mut self, mode: AddressingModes) {
-        let addr = self.handle_addressing_mode(&mode);
+        let addr = self.handle_addressing_mode(&mode, false);
+        if mode == AddressingModes::Indirect {
+            self.bus.log_read(addr, ReadKind::IndirectCode);
+        }
         self.set_pc(addr);
     }
 
-    fn jsr(&mut self, mode: AddressingModes) {
-        let addr = self.handle_addressing_mode(&mode);
-        //TODO: check this
-        self.push_2_bytes_to_stack(addr.wrapping_sub(1));
-        self.set_pc(addr);
+    fn jsr(&mut self) {
+        // the return address pushed is the last byte of the JSR instruction
+        let ls_byte = self.fetch_byte();
+        self.dummy_read_stack();
+        let pc = self.get_pc();
+        self.push_2_bytes_to_stack(pc);
+        let ms_byte = self.fetch_byte();
+        self.set_pc(assemble_2_bytes_le_u16(ms_byte, ls_byte));
     }
+
     fn lda(&mut self, mode: AddressingModes) {
-        let addr = self.handle_addressing_mode(&mode);
-        let val = self.read_byte_from_memory(addr);
+        let val = self.read_operand(&mode);
         self.set_a(val);
         self.update_zero_and_negative_flags_u8(val);
     }
 
     fn ldx(&mut self, mode: AddressingModes) {
-        let addr = self.handle_addressing_mode(&mode);
-        let val = self.read_byte_from_memory(addr);
+        let val = self.read_operand(&mode);
         self.set_x(val);
         self.update_zero_and_negative_flags_u8(val);
     }
 
     fn ldy(&mut self, mode: AddressingModes) {
-        let addr = self.handle_addressing_mode(&mode);
-        let val = self.read_byte_from_memory(addr);
-        self.y = val;
-        self.update_zero_and_negative_flags_u8(self.y);
+        let val = self.read_operand(&mode);
+        self.set_y(val);
+        self.update_zero_and_negative_flags_u8(val);
     }
 
     fn lsr(&mut self, mode: AddressingModes) {
-        let val;
-        match mode {
-            AddressingModes::Accumulator => val = self.get_a(),
-            _ => {
-                let addr = self.handle_addressing_mode(&mode);
-                val = self.read_byte_from_memory(addr);
-            }
-        }
-        let bit_0 = val & 1;
-        let newval = val >> 1;
-
-        if mode == AddressingModes::Accumulator {
-            self.set_a(newval);
-        } else {
-            let addr = self.handle_addressing_mode(&mode);
-            self.write_byte_to_memory(addr, newval);
-        }
-
-        if bit_0 == 1 {
-            self.set_carry_flag();
-        } else {
-            self.unset_carry_flag();
-        }
-
-        self.update_zero_and_negative_flags_u8(newval);
+        self.read_modify_write(mode, |cpu, val| {
+            let result = val >> 1;
+            cpu.update_carry_flag(val & 1 == 1);
+            cpu.update_zero_and_negative_flags_u8(result);
+            return result;
+        });
     }
 
-    fn nop(&mut self) {
-        self.update_program_counter_n(1);
-    }
+    fn nop(&mut self) {}
 
     fn ora(&mut self, mode: AddressingModes) {
-        let addr = self.handle_addressing_mode(&mode);
-        let val = self.read_byte_from_memory(addr);
-        let a = self.get_a();
-        let result = a | val;
+        let val = self.read_operand(&mode);
+        let result = self.get_a() | val;
         self.set_a(result);
         self.update_zero_and_negative_flags_u8(result);
     }
@@ -991,131 +884,60 @@ impl CPU {
 
     fn php(&mut self) {
         let ps = self.get_ps();
-        self.push_byte_to_stack(ps);
+        self.push_byte_to_stack(ps | 0b00110000);
     }
 
     fn pla(&mut self) {
+        self.dummy_read_stack();
         let val_stack = self.pop_byte_from_stack();
         self.set_a(val_stack);
         self.update_zero_and_negative_flags_u8(val_stack);
     }
 
     fn plp(&mut self) {
+        self.dummy_read_stack();
         let val_stack = self.pop_byte_from_stack();
-        self.set_ps(val_stack);
+        self.set_ps((val_stack & 0b11001111) | 0b00100000);
     }
 
     fn rol(&mut self, mode: AddressingModes) {
-        let val;
-        match mode {
-            AddressingModes::Accumulator => val = self.get_a(),
-            _ => {
-                let addr = self.handle_addressing_mode(&mode);
-                val = self.read_byte_from_memory(addr);
-            }
-        }
-        let bit_7 = get_nth_bit_u8(val, 7);
-        let carry_flag = self.get_carry_flag();
-        let result = (val << 1).wrapping_add(carry_flag);
-
-        match mode {
-            AddressingModes::Accumulator => self.set_a(result),
-            _ => {
-                let addr = self.handle_addressing_mode(&mode);
-                self.write_byte_to_memory(addr, result);
-            }
-        }
-
-        let a = self.get_a();
-        if bit_7 == 1 {
-            self.set_carry_flag();
-        } else {
-            self.unset_carry_flag();
-        }
-        // TODO: check if always checking a? or check result
-        if a == 0 {
-            self.set_zero_flag();
-        } else {
-            self.unset_zero_flag();
-        }
-        if get_nth_bit_u8(result, 7) == 1 {
-            self.set_negative_flag();
-        } else {
-            self.unset_negative_flag();
-        }
+        self.read_modify_write(mode, |cpu, val| {
+            let result = (val << 1) | cpu.get_carry_flag();
+            cpu.update_carry_flag(get_nth_bit_u8(val, 7) == 1);
+            cpu.update_zero_and_negative_flags_u8(result);
+            return result;
+        });
     }
 
     fn ror(&mut self, mode: AddressingModes) {
-        let val;
-        match mode {
-            AddressingModes::Accumulator => val = self.get_a(),
-            _ => {
-                let addr = self.handle_addressing_mode(&mode);
-                val = self.read_byte_from_memory(addr);
-            }
-        }
-        let bit_0 = get_nth_bit_u8(val, 0);
-        let carry_flag = self.get_carry_flag();
-        let result = (val >> 1) | (carry_flag << 7);
-
-        match mode {
-            AddressingModes::Accumulator => self.set_a(result),
-            _ => {
-                let addr = self.handle_addressing_mode(&mode);
-                self.write_byte_to_memory(addr, result);
-            }
-        }
-
-        let a = self.get_a();
-        if bit_0 == 1 {
-            self.set_carry_flag();
-        } else {
-            self.unset_carry_flag();
-        }
-        // TODO: check if always checking a? or check result
-        if a == 0 {
-            self.set_zero_flag();
-        } else {
-            self.unset_zero_flag();
-        }
-        if get_nth_bit_u8(result, 7) == 1 {
-            self.set_negative_flag();
-        } else {
-            self.unset_negative_flag();
-        }
+        self.read_modify_write(mode, |cpu, val| {
+            let result = (val >> 1) | (cpu.get_carry_flag() << 7);
+            cpu.update_carry_flag(get_nth_bit_u8(val, 0) == 1);
+            cpu.update_zero_and_negative_flags_u8(result);
+            return result;
+        });
     }
 
     fn rti(&mut self) {
+        self.dummy_read_stack();
         let ps_stack = self.pop_byte_from_stack();
         let pc_stack = self.pop_2_bytes_from_stack();
 
-        self.set_ps(ps_stack);
+        self.set_ps((ps_stack & 0b11001111) | 0b00100000);
         self.set_pc(pc_stack);
     }
 
     fn rts(&mut self) {
+        self.dummy_read_stack();
         let pc_stack = self.pop_2_bytes_from_stack();
+        self.dummy_read(pc_stack);
 
-        self.set_pc(pc_stack.wrapping_sub(1));
+        self.set_pc(pc_stack.wrapping_add(1));
     }
 
     fn sbc(&mut self, mode: AddressingModes) {
-        let addr = self.handle_addressing_mode(&mode);
-        let val = self.read_byte_from_memory(addr);
-        let a = self.get_a();
-        let carry_flag = self.get_carry_flag();
-        // TODO: fix logic
-        let result = a - val - (1 - carry_flag);
-        self.set_a(result);
-        // let overflow =
-        // if overflow == 1 {
-        //     unset_carry_flag();
-        //     set_overflow_flag();
-        // } else {
-        //     set_carry_flag();
-        //     unset_overflow_flag();
-        // }
-        self.update_zero_and_negative_flags_u8(result);
+        let val = self.read_operand(&mode);
+        self.add_with_carry(!val);
     }
 
     fn sec(&mut self) {
@@ -1131,19 +953,19 @@ impl CPU {
     }
 
     fn sta(&mut self, mode: AddressingModes) {
-        let addr = self.handle_addressing_mode(&mode);
+        let addr = self.handle_addressing_mode(&mode, true);
         let a = self.get_a();
         self.write_byte_to_memory(addr, a);
     }
 
     fn stx(&mut self, mode: AddressingModes) {
-        let addr = self.handle_addressing_mode(&mode);
+        let addr = self.handle_addressing_mode(&mode, true);
         let x = self.get_x();
         self.write_byte_to_memory(addr, x);
     }
 
     fn sty(&mut self, mode: AddressingModes) {
-        let addr = self.handle_addressing_mode(&mode);
+        let addr = self.handle_addressing_mode(&mode, true);
         let y = self.get_y();
         self.write_byte_to_memory(addr, y);
     }
@@ -1184,31 +1006,232 @@ impl CPU {
     }
 
     pub fn load_to_memory(&mut self, start_addr: u16, data_vec: Vec<u8>) {
-        let data_vec_iterator = data_vec.iter();
         let mut cur_addr = start_addr;
-        for data in data_vec_iterator {
-            self.write_byte_to_memory(cur_addr, *data);
+        for data in data_vec.iter() {
+            self.bus.write_memory_byte(cur_addr, *data);
             cur_addr = cur_addr.wrapping_add(1);
-            println!("adding data");
         }
     }
 
+    pub fn reset(&mut self) {
+        let sp = self.get_sp();
+        self.set_sp(sp.wrapping_sub(3));
+        self.set_interrupt_disable();
+        let addr = self.read_2_bytes_from_memory(RESET_VECTOR);
+        self.set_pc(addr);
+        // the remaining cycles of the reset sequence are spent on suppressed pushes
+        for _ in 0..5 {
+            self.tick();
+        }
+    }
+
+    pub fn nmi(&mut self) {
+        self.interrupt(NMI_VECTOR);
+    }
+
+    pub fn irq(&mut self) {
+        if self.get_interrupt_disable() == 0 {
+            self.interrupt(IRQ_VECTOR);
+        }
+    }
+
+    fn execute_next_instruction(&mut self) -> u8 {
+        let opcode = self.fetch_byte();
+        self.run_instruction_function_from_opcode(opcode);
+        return opcode;
+    }
+
+    fn poll_interrupts(&mut self) {
+        if self.nmi_ready {
+            self.nmi_detected = false;
+            self.nmi_ready = false;
+            self.nmi();
+        } else if self.bus.poll_irq() {
+            self.irq();
+        }
+    }
+
+    /// Runs a single instruction, then services any pending interrupt, and
+    /// returns the number of cycles it took.
+    pub fn step(&mut self) -> u64 {
+        let start_cycles = self.get_cycles();
+        self.execute_next_instruction();
+        for _ in 0..self.bus.take_stall_cycles() {
+            self.tick();
+        }
+        self.poll_interrupts();
+        return self.get_cycles() - start_cycles;
+    }
+
+    /// Runs from `start_addr` until a BRK instruction is executed.
     pub fn start(&mut self, start_addr: u16) {
         self.pc = start_addr;
         loop {
-            if self.get_break_command() == 1 {
+            if self.execute_next_instruction() == 0x00 {
                 return;
             }
-            let pc = self.pc;
-            let opcode = self.read_byte_from_memory(pc);
-            self.run_instruction_function_from_opcode(opcode);
         }
     }
 }
 
+impl<B: MemoryBus + SaveState> SaveState for CPU<B> {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.pc);
+        state.write(&self.sp);
+        state.write(&self.a);
+        state.write(&self.x);
+        state.write(&self.y);
+        state.write(&self.ps);
+        state.write(&self.cycles);
+        state.write(&self.nmi_detected);
+        state.write(&self.nmi_ready);
+        state.write(&self.bus);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read(&mut self.pc)?;
+        state.read(&mut self.sp)?;
+        state.read(&mut self.a)?;
+        state.read(&mut self.x)?;
+        state.read(&mut self.y)?;
+        state.read(&mut self.ps)?;
+        state.read(&mut self.cycles)?;
+        state.read(&mut self.nmi_detected)?;
+        state.read(&mut self.nmi_ready)?;
+        state.read(&mut self.bus)?;
+        return Ok(());
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::asm::assemble;
+
+    /// Assembles `source` at $8000 and runs it until BRK.
+    fn run_program(source: &str) -> CPU {
+        let program = assemble(source, 0x8000).unwrap();
+        let mut cpu = CPU::init();
+        cpu.load_to_memory(program.origin, program.bytes);
+        cpu.start(program.origin);
+        return cpu;
+    }
+
+    /// Loads `bytes` at `addr` and points PC at them without running.
+    fn cpu_at(addr: u16, bytes: &[u8]) -> CPU {
+        let mut cpu = CPU::init();
+        cpu.load_to_memory(addr, bytes.to_vec());
+        cpu.set_pc(addr);
+        return cpu;
+    }
+
+    fn flags(cpu: &CPU) -> (u8, u8, u8, u8) {
+        return (
+            cpu.get_carry_flag(),
+            cpu.get_zero_flag(),
+            cpu.get_overflow_flag(),
+            cpu.get_negative_flag(),
+        );
+    }
+
+    #[test]
+    fn test_adc_and_sbc_carry_and_overflow() {
+        let program = assemble(
+            "CLC
+             LDA #$50
+             ADC #$50
+             ADC #$60
+             SEC
+             LDA #$50
+             SBC #$B0
+             SEC
+             LDA #$05
+             SBC #$03
+             SBC #$03",
+            0x8000,
+        )
+        .unwrap();
+        let mut cpu = cpu_at(0x8000, &program.bytes);
+        cpu.step();
+        cpu.step();
+        // two positives making a negative overflow
+        cpu.step();
+        assert_eq!((cpu.get_a(), flags(&cpu)), (0xA0, (0, 0, 1, 1)));
+        // a negative and a positive don't, but carry out
+        cpu.step();
+        assert_eq!((cpu.get_a(), flags(&cpu)), (0x00, (1, 1, 0, 0)));
+
+        // positive minus negative overflows, and borrows
+        cpu.step();
+        cpu.step();
+        cpu.step();
+        assert_eq!((cpu.get_a(), flags(&cpu)), (0xA0, (0, 0, 1, 1)));
+        // carry set means no borrow
+        cpu.step();
+        cpu.step();
+        cpu.step();
+        assert_eq!((cpu.get_a(), flags(&cpu)), (0x02, (1, 0, 0, 0)));
+        cpu.step();
+        assert_eq!((cpu.get_a(), flags(&cpu)), (0xFF, (0, 0, 0, 1)));
+    }
+
+    #[test]
+    fn test_brk_and_rti_flags() {
+        // SEC; BRK; padding byte; NOP
+        let mut cpu = cpu_at(0x8000, &[0x38, 0x00, 0xEA, 0xEA]);
+        cpu.load_to_memory(IRQ_VECTOR, vec![0x00, 0x90]);
+        // RTI
+        cpu.load_to_memory(0x9000, vec![0x40]);
+        cpu.step();
+        assert_eq!(cpu.step(), 7);
+        assert_eq!(cpu.get_pc(), 0x9000);
+        assert_eq!(cpu.get_interrupt_disable(), 1);
+        let sp = cpu.get_sp() as u16;
+        // the pushed flags have B and the unused bit set
+        assert_eq!(cpu.read_byte_from_memory(0x101 + sp), 0b00110101);
+        // the return address skips the padding byte
+        assert_eq!(cpu.read_2_bytes_from_memory(0x102 + sp), 0x8003);
+
+        assert_eq!(cpu.step(), 6);
+        assert_eq!(cpu.get_pc(), 0x8003);
+        // B doesn't exist in the register, only on the stack
+        assert_eq!(cpu.get_ps(), 0b00100101);
+    }
+
+    #[test]
+    fn test_jsr_and_rts_return_address() {
+        // JSR $8004; BRK; RTS
+        let mut cpu = cpu_at(0x8000, &[0x20, 0x04, 0x80, 0x00, 0x60]);
+        assert_eq!(cpu.step(), 6);
+        assert_eq!(cpu.get_pc(), 0x8004);
+        // JSR pushes the address of its own last byte
+        let sp = cpu.get_sp() as u16;
+        assert_eq!(cpu.read_2_bytes_from_memory(0x101 + sp), 0x8002);
+        assert_eq!(cpu.step(), 6);
+        assert_eq!(cpu.get_pc(), 0x8003);
+        assert_eq!(cpu.get_sp(), 0xFD);
+    }
+
+    #[test]
+    fn test_page_cross_cycles() {
+        // LDX #$01; LDA $8000,X; LDA $80FF,X; STA $0200,X
+        let mut cpu = cpu_at(
+            0x8000,
+            &[
+                0xA2, 0x01, 0xBD, 0x00, 0x80, 0xBD, 0xFF, 0x80, 0x9D, 0x00, 0x02,
+            ],
+        );
+        let cycles: Vec<u64> = (0..4).map(|_| cpu.step()).collect();
+        // stores always take the extra cycle
+        assert_eq!(cycles, [2, 4, 5, 5]);
+
+        // BNE to the next byte, BNE +2 into the next page, BEQ not taken
+        let mut cpu = cpu_at(0x80FB, &[0xD0, 0x00, 0xD0, 0x02]);
+        cpu.load_to_memory(0x8101, vec![0xF0, 0x00]);
+        let cycles: Vec<u64> = (0..3).map(|_| cpu.step()).collect();
+        assert_eq!(cycles, [3, 4, 2]);
+        assert_eq!(cpu.get_pc(), 0x8103);
+    }
 
     #[test]
     fn test_0xa9_lda_immediate_load_data() {
@@ -1240,10 +1263,12 @@ mod test {
 
     #[test]
     fn test_5_ops_working_together() {
-        let mut cpu = CPU::init();
-        cpu.load_to_memory(0x8000, vec![0xa9, 0xc0, 0xaa, 0xe8, 0x00]);
-        cpu.start(0x8000);
-
+        let cpu = run_program(
+            "LDA #$C0
+             TAX
+             INX
+             BRK",
+        );
         assert_eq!(cpu.x, 0xc1)
     }
 
@@ -1275,21 +1300,35 @@ mod test {
 
     #[test]
     fn test_lda_and_sta() {
-        let mut cpu = CPU::init();
-        let addr = 0x8000;
-        cpu.load_to_memory(addr, vec![0xa9, 0xc0, 0x85, 0xe8]);
-        cpu.start(addr);
-
+        let mut cpu = run_program("LDA #$C0\nSTA $E8\nBRK");
         assert_eq!(cpu.read_byte_from_memory(0xe8), 0xc0);
     }
 
     #[test]
     fn test_ldx_and_stx() {
-        let mut cpu = CPU::init();
-        let addr = 0x8000;
-        cpu.load_to_memory(addr, vec![0xa2, 0xc0, 0x86, 0xe8]);
-        cpu.start(addr);
-
+        let mut cpu = run_program("LDX #$C0\nSTX $E8\nBRK");
         assert_eq!(cpu.read_byte_from_memory(0xe8), 0xc0);
     }
+
+    #[test]
+    fn test_loop_and_subroutine() {
+        // adds 1 to 10 with a subroutine call per step
+        let mut cpu = run_program(
+            "SUM = $10
+                     LDA #0
+                     STA SUM
+                     LDX #10
+             loop:   JSR add_x
+                     DEX
+                     BNE loop
+                     BRK
+             add_x:  TXA
+                     CLC
+                     ADC SUM
+                     STA SUM
+                     RTS",
+        );
+        assert_eq!(cpu.read_byte_from_memory(0x10), 55);
+        assert_eq!(cpu.x, 0);
+    }
 }
@@ -0,0 +1,211 @@
+//! Breakpoints, watchpoints and a run loop that stops on them, shared by the
+//! command line monitor and the GDB stub.
+
+use crate::console::Console;
+use std::collections::BTreeSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+/// Stops execution when the CPU touches `start..=end`. Checked on the bus,
+/// so DMA reads count too and mirrors have to be watched separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    pub fn matches(&self, addr: u16, is_write: bool) -> bool {
+        if addr < self.start || addr > self.end {
+            return false;
+        }
+        match self.kind {
+            WatchKind::Read => return !is_write,
+            WatchKind::Write => return is_write,
+            WatchKind::Access => return true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub addr: u16,
+    pub val: u8,
+    pub is_write: bool,
+}
+
+/// When [`Debugger::run`] should give control back. Unset limits don't
+/// apply; with none set it runs until a breakpoint or watchpoint.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RunLimit {
+    pub instructions: Option<u64>,
+    pub frames: Option<u64>,
+    pub until_pc: Option<u16>,
+    /// Stop on a breakpoint at the starting PC instead of stepping over it,
+    /// for carrying on a run that was split up.
+    pub break_at_start: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Breakpoint(u16),
+    Watchpoint(WatchHit),
+    ReachedPc(u16),
+    InstructionLimit,
+    FrameLimit,
+}
+
+#[derive(Debug, Default)]
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+}
+
+impl Debugger {
+    pub fn init() -> Self {
+        return Debugger::default();
+    }
+
+    /// Returns false if there already was a breakpoint at `addr`.
+    pub fn add_breakpoint(&mut self, addr: u16) -> bool {
+        return self.breakpoints.insert(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        return self.breakpoints.remove(&addr);
+    }
+
+    pub fn get_breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        return self.breakpoints.iter().copied();
+    }
+
+    /// Runs instruction by instruction until `limit` or a breakpoint or
+    /// watchpoint is hit. A breakpoint at the starting PC is stepped over so
+    /// that running again resumes from it, unless `limit.break_at_start` is set.
+    pub fn run(&self, console: &mut Console, limit: RunLimit) -> StopReason {
+        let mut instructions = 0;
+        let mut frames = 0;
+        console.get_cpu_mut().get_bus_mut().take_watch_hit();
+        loop {
+            let pc = console.get_cpu().get_pc();
+            if instructions > 0 && limit.until_pc == Some(pc) {
+                return StopReason::ReachedPc(pc);
+            }
+            if (instructions > 0 || limit.break_at_start) && self.breakpoints.contains(&pc) {
+                return StopReason::Breakpoint(pc);
+            }
+            if limit.instructions == Some(instructions) {
+                return StopReason::InstructionLimit;
+            }
+
+            console.step();
+            instructions += 1;
+            let bus = console.get_cpu_mut().get_bus_mut();
+            if let Some(hit) = bus.take_watch_hit() {
+                return StopReason::Watchpoint(hit);
+            }
+            if bus.get_ppu_mut().take_frame_complete() {
+                frames += 1;
+                if limit.frames == Some(frames) {
+                    return StopReason::FrameLimit;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::{nrom_with_program, COUNTER};
+    use crate::cartridge::Cartridge;
+
+    fn console_with_program(source: &str) -> Console {
+        let cartridge = Cartridge::from_ines(&nrom_with_program(source)).unwrap();
+        return Console::init(&cartridge).unwrap();
+    }
+
+    #[test]
+    fn test_breakpoint_stops_and_resumes() {
+        let mut console = console_with_program(COUNTER);
+        let mut debugger = Debugger::init();
+        debugger.add_breakpoint(0x8005);
+        assert_eq!(
+            debugger.run(&mut console, RunLimit::default()),
+            StopReason::Breakpoint(0x8005)
+        );
+        assert_eq!(console.get_cpu().get_x(), 1);
+        assert_eq!(
+            debugger.run(&mut console, RunLimit::default()),
+            StopReason::Breakpoint(0x8005)
+        );
+        assert_eq!(console.get_cpu().get_x(), 2);
+
+        let limit = RunLimit {
+            instructions: Some(1),
+            break_at_start: true,
+            ..RunLimit::default()
+        };
+        assert_eq!(
+            debugger.run(&mut console, limit),
+            StopReason::Breakpoint(0x8005)
+        );
+        assert_eq!(console.get_cpu().get_x(), 2);
+    }
+
+    #[test]
+    fn test_watchpoint_reports_write() {
+        let mut console = console_with_program(COUNTER);
+        console
+            .get_cpu_mut()
+            .get_bus_mut()
+            .add_watchpoint(Watchpoint {
+                start: 0x10,
+                end: 0x10,
+                kind: WatchKind::Write,
+            });
+        let stop = Debugger::init().run(&mut console, RunLimit::default());
+        assert_eq!(
+            stop,
+            StopReason::Watchpoint(WatchHit {
+                addr: 0x10,
+                val: 1,
+                is_write: true
+            })
+        );
+        assert_eq!(console.get_cpu().get_pc(), 0x8005);
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut console = console_with_program(COUNTER);
+        let debugger = Debugger::init();
+        let limit = RunLimit {
+            instructions: Some(3),
+            ..RunLimit::default()
+        };
+        assert_eq!(
+            debugger.run(&mut console, limit),
+            StopReason::InstructionLimit
+        );
+        assert_eq!(console.get_cpu().get_pc(), 0x8005);
+        let limit = RunLimit {
+            until_pc: Some(0x8003),
+            ..RunLimit::default()
+        };
+        assert_eq!(
+            debugger.run(&mut console, limit),
+            StopReason::ReachedPc(0x8003)
+        );
+        let limit = RunLimit {
+            frames: Some(1),
+            ..RunLimit::default()
+        };
+        assert_eq!(debugger.run(&mut console, limit), StopReason::FrameLimit);
+    }
+}
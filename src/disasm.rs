@@ -0,0 +1,210 @@
+//! 6502 disassembler, decoding with the CPU's own opcode tables.
+
+use crate::bus::MemoryBus;
+use crate::opcodes::{get_mnemonic, get_mode_from_opcode, AddressingModes};
+use std::fmt;
+
+/// A decoded instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub addr: u16,
+    pub opcode: u8,
+    pub mode: AddressingModes,
+    /// The operand bytes as a little endian value, 0 when there are none.
+    pub operand: u16,
+}
+
+impl Instruction {
+    pub fn get_length(&self) -> u16 {
+        return self.mode.get_length();
+    }
+
+    /// The mnemonic, or None for unofficial opcodes.
+    pub fn get_mnemonic(&self) -> Option<&'static str> {
+        return get_mnemonic(self.opcode);
+    }
+
+    /// The raw bytes of the instruction.
+    pub fn get_bytes(&self) -> Vec<u8> {
+        let bytes = [self.opcode, self.operand as u8, (self.operand >> 8) as u8];
+        return bytes[..self.get_length() as usize].to_vec();
+    }
+
+    /// Where a branch or jump goes, if that is known without running it.
+    pub fn get_target(&self) -> Option<u16> {
+        match self.mode {
+            AddressingModes::Relative => {
+                let next = self.addr.wrapping_add(2);
+                return Some(next.wrapping_add(self.operand as u8 as i8 as u16));
+            }
+            AddressingModes::Absolute if matches!(self.opcode, 0x20 | 0x4C) => {
+                return Some(self.operand);
+            }
+            _ => return None,
+        }
+    }
+
+    /// The operand in standard syntax, e.g. `$12,X` or `($FFFC)`.
+    pub fn get_operand_text(&self) -> String {
+        return self.format_operand(None);
+    }
+
+    /// The instruction with addresses that `label` knows replaced by their
+    /// labels, e.g. `JSR update_sprites` or `LDA buffer,X`.
+    pub fn format_with_labels<'a>(&self, label: impl Fn(u16) -> Option<&'a str>) -> String {
+        let mnemonic = match self.get_mnemonic() {
+            Some(mnemonic) => mnemonic,
+            None => return self.to_string(),
+        };
+        let addr = match self.mode {
+            AddressingModes::Relative => self.get_target(),
+            AddressingModes::Implicit
+            | AddressingModes::Accumulator
+            | AddressingModes::Immediate => None,
+            _ => Some(self.operand),
+        };
+        let operand = self.format_operand(addr.and_then(label));
+        if operand.is_empty() {
+            return String::from(mnemonic);
+        }
+        return format!("{} {}", mnemonic, operand);
+    }
+
+    fn format_operand(&self, label: Option<&str>) -> String {
+        let operand = self.operand;
+        let zero_page = label.map_or_else(|| format!("${:02X}", operand), String::from);
+        let absolute = label.map_or_else(|| format!("${:04X}", operand), String::from);
+        match self.mode {
+            AddressingModes::Implicit => return String::new(),
+            AddressingModes::Accumulator => return String::from("A"),
+            AddressingModes::Immediate => return format!("#${:02X}", operand),
+            AddressingModes::ZeroPage => return zero_page,
+            AddressingModes::ZeroPageX => return format!("{},X", zero_page),
+            AddressingModes::ZeroPageY => return format!("{},Y", zero_page),
+            AddressingModes::Relative => {
+                return label.map_or_else(
+                    || format!("${:04X}", self.get_target().unwrap()),
+                    String::from,
+                );
+            }
+            AddressingModes::Absolute => return absolute,
+            AddressingModes::AbsoluteX => return format!("{},X", absolute),
+            AddressingModes::AbsoluteY => return format!("{},Y", absolute),
+            AddressingModes::Indirect => return format!("({})", absolute),
+            AddressingModes::IndirectX => return format!("({},X)", zero_page),
+            AddressingModes::IndirectY => return format!("({}),Y", zero_page),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    /// `LDA $12,X`, or `.byte $xx` for opcodes the CPU doesn't implement.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mnemonic = match self.get_mnemonic() {
+            Some(mnemonic) => mnemonic,
+            None => return write!(f, ".byte ${:02X}", self.opcode),
+        };
+        let operand = self.get_operand_text();
+        if operand.is_empty() {
+            return write!(f, "{}", mnemonic);
+        }
+        return write!(f, "{} {}", mnemonic, operand);
+    }
+}
+
+/// Decodes the instruction at the start of `bytes`, which sit at `addr`.
+/// Bytes past the end of the slice read as 0.
+pub fn disassemble(bytes: &[u8], addr: u16) -> Instruction {
+    let byte = |i: usize| *bytes.get(i).unwrap_or(&0);
+    let opcode = byte(0);
+    let mode = get_mode_from_opcode(opcode);
+    let operand = match mode.get_length() {
+        3 => ((byte(2) as u16) << 8) | byte(1) as u16,
+        2 => byte(1) as u16,
+        _ => 0,
+    };
+    return Instruction {
+        addr,
+        opcode,
+        mode,
+        operand,
+    };
+}
+
+/// Decodes the instruction at `addr` without disturbing any hardware.
+pub fn disassemble_at<B: MemoryBus>(bus: &mut B, addr: u16) -> Instruction {
+    let bytes: Vec<u8> = (0..3)
+        .map(|i| bus.peek_memory_byte(addr.wrapping_add(i)))
+        .collect();
+    return disassemble(&bytes, addr);
+}
+
+/// Decodes `count` instructions one after another from `addr`.
+pub fn disassemble_range<B: MemoryBus>(bus: &mut B, addr: u16, count: usize) -> Vec<Instruction> {
+    let mut instructions = Vec::with_capacity(count);
+    let mut addr = addr;
+    for _ in 0..count {
+        let instruction = disassemble_at(bus, addr);
+        addr = addr.wrapping_add(instruction.get_length());
+        instructions.push(instruction);
+    }
+    return instructions;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::BUS;
+
+    fn text(bytes: &[u8], addr: u16) -> String {
+        return disassemble(bytes, addr).to_string();
+    }
+
+    #[test]
+    fn test_operand_syntax() {
+        assert_eq!(text(&[0xB5, 0x12], 0), "LDA $12,X");
+        assert_eq!(text(&[0x6C, 0xFC, 0xFF], 0), "JMP ($FFFC)");
+        assert_eq!(text(&[0xD0, 0x0E], 0x8000), "BNE $8010");
+        assert_eq!(text(&[0xD0, 0xFE], 0x8000), "BNE $8000");
+        assert_eq!(text(&[0xA9, 0x01], 0), "LDA #$01");
+        assert_eq!(text(&[0x0A], 0), "ASL A");
+        assert_eq!(text(&[0x81, 0x20], 0), "STA ($20,X)");
+        assert_eq!(text(&[0xB1, 0x20], 0), "LDA ($20),Y");
+        assert_eq!(text(&[0xBE, 0x34, 0x12], 0), "LDX $1234,Y");
+        assert_eq!(text(&[0xE8], 0), "INX");
+        assert_eq!(text(&[0x02], 0), ".byte $02");
+    }
+
+    #[test]
+    fn test_format_with_labels() {
+        let label = |addr: u16| match addr {
+            0x10 => Some("ptr"),
+            0x8000 => Some("loop"),
+            _ => None,
+        };
+        let format = |bytes: &[u8]| disassemble(bytes, 0x8002).format_with_labels(label);
+        assert_eq!(format(&[0xB1, 0x10]), "LDA (ptr),Y");
+        assert_eq!(format(&[0xD0, 0xFC]), "BNE loop");
+        assert_eq!(format(&[0xBD, 0x00, 0x80]), "LDA loop,X");
+        assert_eq!(format(&[0xA9, 0x10]), "LDA #$10");
+        assert_eq!(format(&[0x8D, 0x01, 0x80]), "STA $8001");
+    }
+
+    #[test]
+    fn test_disassemble_range_from_bus() {
+        let mut bus = BUS::init();
+        // LDX #$00; INX; BNE $8002; JSR $C000
+        for (i, byte) in [0xA2, 0x00, 0xE8, 0xD0, 0xFD, 0x20, 0x00, 0xC0]
+            .iter()
+            .enumerate()
+        {
+            bus.write_memory_byte(0x8000 + i as u16, *byte);
+        }
+        let instructions = disassemble_range(&mut bus, 0x8000, 4);
+        let lines: Vec<String> = instructions.iter().map(|i| i.to_string()).collect();
+        assert_eq!(lines, vec!["LDX #$00", "INX", "BNE $8002", "JSR $C000"]);
+        assert_eq!(instructions[3].addr, 0x8005);
+        assert_eq!(instructions[3].get_bytes(), vec![0x20, 0x00, 0xC0]);
+        assert_eq!(instructions[3].get_target(), Some(0xC000));
+    }
+}
@@ -0,0 +1,373 @@
+use crate::cli::RunOptions;
+use rustes::movie::Movie;
+use rustes::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use rustes::video::VideoFilter;
+use rustes::{Button, Console, Region};
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::controller::{Axis, Button as PadButton, GameController};
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::rect::Rect;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const WINDOW_SCALE: u32 = 3;
+// NTSC pixels are slightly wider than they are tall
+const PIXEL_ASPECT_RATIO: f64 = 8.0 / 7.0;
+const SAMPLE_RATE: i32 = 48_000;
+// drop audio rather than let latency build up past this many frames
+const MAX_QUEUED_AUDIO_FRAMES: u32 = 4;
+const AXIS_DEADZONE: i16 = 16_000;
+
+fn map_key(keycode: Keycode) -> Option<Button> {
+    match keycode {
+        Keycode::X => return Some(Button::A),
+        Keycode::Z => return Some(Button::B),
+        Keycode::RSHIFT | Keycode::BACKSPACE => return Some(Button::Select),
+        Keycode::RETURN => return Some(Button::Start),
+        Keycode::UP => return Some(Button::Up),
+        Keycode::DOWN => return Some(Button::Down),
+        Keycode::LEFT => return Some(Button::Left),
+        Keycode::RIGHT => return Some(Button::Right),
+        _ => return None,
+    }
+}
+
+fn map_pad_button(button: PadButton) -> Option<Button> {
+    match button {
+        PadButton::A => return Some(Button::A),
+        PadButton::B | PadButton::X => return Some(Button::B),
+        PadButton::Back => return Some(Button::Select),
+        PadButton::Start => return Some(Button::Start),
+        PadButton::DPadUp => return Some(Button::Up),
+        PadButton::DPadDown => return Some(Button::Down),
+        PadButton::DPadLeft => return Some(Button::Left),
+        PadButton::DPadRight => return Some(Button::Right),
+        _ => return None,
+    }
+}
+
+/// Largest whole multiple of the NES resolution that fits the window, with
+/// the pixel aspect ratio applied horizontally, centred in the window.
+fn get_output_rect(window_width: u32, window_height: u32, correct_aspect: bool) -> Rect {
+    let pixel_width = if correct_aspect {
+        PIXEL_ASPECT_RATIO
+    } else {
+        1.0
+    };
+    let max_scale_x = window_width as f64 / (SCREEN_WIDTH as f64 * pixel_width);
+    let max_scale_y = window_height as f64 / SCREEN_HEIGHT as f64;
+    let mut scale = max_scale_x.min(max_scale_y).floor();
+    if scale < 1.0 {
+        // smaller than 1x, fall back to fitting the window
+        scale = max_scale_x.min(max_scale_y);
+    }
+    let width = (SCREEN_WIDTH as f64 * pixel_width * scale).round() as u32;
+    let height = (SCREEN_HEIGHT as f64 * scale).round() as u32;
+    let x = (window_width.saturating_sub(width) / 2) as i32;
+    let y = (window_height.saturating_sub(height) / 2) as i32;
+    return Rect::new(x, y, width.max(1), height.max(1));
+}
+
+fn set_axis(console: &mut Console, port: usize, axis: Axis, value: i16) {
+    let (negative, positive) = match axis {
+        Axis::LeftX => (Button::Left, Button::Right),
+        Axis::LeftY => (Button::Up, Button::Down),
+        _ => return,
+    };
+    let controller = console.get_controller_mut(port);
+    controller.set_button(negative, value < -AXIS_DEADZONE);
+    controller.set_button(positive, value > AXIS_DEADZONE);
+}
+
+fn save_state(console: &Console, path: &Path) {
+    match fs::write(path, console.save_state()) {
+        Ok(()) => println!("saved state to {}", path.display()),
+        Err(err) => eprintln!("{}: {}", path.display(), err),
+    }
+}
+
+fn load_state(console: &mut Console, path: &Path) {
+    let result = fs::read(path)
+        .map_err(|err| err.to_string())
+        .and_then(|data| console.load_state(&data).map_err(|err| err.to_string()));
+    match result {
+        Ok(()) => println!("loaded state from {}", path.display()),
+        Err(err) => eprintln!("{}: {}", path.display(), err),
+    }
+}
+
+/// Puts the next side of an FDS disk in the drive, or takes it out.
+fn change_disk_side(console: &mut Console, eject: bool) {
+    let count = console.get_disk_side_count();
+    if count == 0 {
+        return;
+    }
+    let side = match console.get_disk_side() {
+        _ if eject => None,
+        Some(side) => Some((side + 1) % count),
+        None => Some(0),
+    };
+    console.set_disk_side(side);
+    match side {
+        Some(side) => println!(
+            "inserted disk {} side {}",
+            side / 2 + 1,
+            ['A', 'B'][side % 2]
+        ),
+        None => println!("ejected the disk"),
+    }
+}
+
+/// Opens a window and runs `console` until the window is closed or Escape is
+/// pressed. The keyboard drives controller 1; game controllers are assigned
+/// to ports in the order they are connected. F5/F7 save and load a state
+/// file next to the ROM, F8/F9 flip and eject FDS disks and holding R
+/// rewinds. Loading states and changing disks don't work while a movie is
+/// playing or recording, since the movie would lose sync.
+pub fn run(mut console: Console, options: &RunOptions, movie: Option<Movie>) -> Result<(), String> {
+    let rom_path = options.rom.as_str();
+    let sdl = sdl2::init()?;
+    let video = sdl.video()?;
+    let audio = sdl.audio()?;
+    let game_controller = sdl.game_controller()?;
+
+    let window_width = (SCREEN_WIDTH as f64 * PIXEL_ASPECT_RATIO) as u32 * WINDOW_SCALE;
+    let window = video
+        .window(rom_path, window_width, SCREEN_HEIGHT as u32 * WINDOW_SCALE)
+        .position_centered()
+        .resizable()
+        .build()
+        .map_err(|err| err.to_string())?;
+    let mut canvas = window
+        .into_canvas()
+        .build()
+        .map_err(|err| err.to_string())?;
+    let texture_creator = canvas.texture_creator();
+    let mut texture = texture_creator
+        .create_texture_streaming(
+            PixelFormatEnum::RGB24,
+            SCREEN_WIDTH as u32,
+            SCREEN_HEIGHT as u32,
+        )
+        .map_err(|err| err.to_string())?;
+
+    let audio_queue: AudioQueue<f32> = audio.open_queue(
+        None,
+        &AudioSpecDesired {
+            freq: Some(SAMPLE_RATE),
+            channels: Some(1),
+            samples: Some(1024),
+        },
+    )?;
+    let sample_rate = audio_queue.spec().freq as u32;
+    let max_queued_bytes =
+        (sample_rate as f64 / console.get_frame_rate()) as u32 * MAX_QUEUED_AUDIO_FRAMES * 4;
+    console.set_audio_sample_rate(sample_rate);
+    audio_queue.resume();
+
+    let state_path = Path::new(rom_path).with_extension("state");
+    let mut pads: HashMap<u32, (GameController, usize)> = HashMap::new();
+    let mut correct_aspect = true;
+    let mut rewinding = false;
+    let mut reset_pending = false;
+    let mut playback = movie;
+    let mut movie_frame = 0;
+    let mut recording = options.record.as_ref().map(|_| {
+        let mut movie = Movie::init();
+        let rom_name = Path::new(rom_path).file_stem().unwrap_or_default();
+        movie.set_header("romFilename", &rom_name.to_string_lossy());
+        if console.get_region() == Region::PAL {
+            movie.set_header("palFlag", "1");
+        }
+        movie
+    });
+    let mut event_pump = sdl.event_pump()?;
+    let frame_duration = Duration::from_secs_f64(1.0 / console.get_frame_rate());
+    let mut next_frame = Instant::now();
+
+    'running: loop {
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::ESCAPE),
+                    ..
+                } => break 'running,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F2),
+                    repeat: false,
+                    ..
+                } => reset_pending = true,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F3),
+                    repeat: false,
+                    ..
+                } => correct_aspect = !correct_aspect,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F4),
+                    repeat: false,
+                    ..
+                } => {
+                    let filter = match console.get_video_filter() {
+                        VideoFilter::Palette => VideoFilter::NTSC,
+                        VideoFilter::NTSC => VideoFilter::Palette,
+                    };
+                    console.set_video_filter(filter);
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F5),
+                    repeat: false,
+                    ..
+                } => save_state(&console, &state_path),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F7),
+                    repeat: false,
+                    ..
+                } => {
+                    if playback.is_some() || recording.is_some() {
+                        eprintln!("can't load a state during a movie");
+                    } else {
+                        load_state(&mut console, &state_path);
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(keycode @ (Keycode::F8 | Keycode::F9)),
+                    repeat: false,
+                    ..
+                } => {
+                    if playback.is_some() || recording.is_some() {
+                        eprintln!("can't change disks during a movie");
+                    } else {
+                        change_disk_side(&mut console, keycode == Keycode::F9);
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::R),
+                    ..
+                } => rewinding = true,
+                Event::KeyUp {
+                    keycode: Some(Keycode::R),
+                    ..
+                } => rewinding = false,
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    ..
+                } => {
+                    if let Some(button) = map_key(keycode) {
+                        console.get_controller_mut(0).set_button(button, true);
+                    }
+                }
+                Event::KeyUp {
+                    keycode: Some(keycode),
+                    ..
+                } => {
+                    if let Some(button) = map_key(keycode) {
+                        console.get_controller_mut(0).set_button(button, false);
+                    }
+                }
+                Event::ControllerDeviceAdded { which, .. } if pads.len() < 2 => {
+                    let pad = game_controller.open(which).map_err(|err| err.to_string())?;
+                    let port = (0..2).find(|port| pads.values().all(|(_, used)| used != port));
+                    pads.insert(pad.instance_id(), (pad, port.unwrap_or(0)));
+                }
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    pads.remove(&which);
+                }
+                Event::ControllerButtonDown { which, button, .. }
+                | Event::ControllerButtonUp { which, button, .. } => {
+                    let pressed = matches!(event, Event::ControllerButtonDown { .. });
+                    if let (Some((_, port)), Some(button)) =
+                        (pads.get(&which), map_pad_button(button))
+                    {
+                        console
+                            .get_controller_mut(*port)
+                            .set_button(button, pressed);
+                    }
+                }
+                Event::ControllerAxisMotion {
+                    which, axis, value, ..
+                } => {
+                    if let Some((_, port)) = pads.get(&which) {
+                        set_axis(&mut console, *port, axis, value);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if rewinding && playback.is_none() && recording.is_none() {
+            console.rewind_frame();
+        } else {
+            if let Some(movie) = &playback {
+                if !movie.play_frame(movie_frame, &mut console) {
+                    println!("movie finished after {} frames", movie_frame);
+                    playback = None;
+                }
+                movie_frame += 1;
+            }
+            match &mut recording {
+                Some(movie) if reset_pending => {
+                    movie.record_reset(&console);
+                    console.reset();
+                }
+                Some(movie) => movie.record_frame(&console),
+                None if reset_pending => console.reset(),
+                None => {}
+            }
+            reset_pending = false;
+            console.run_frame();
+        }
+        if let Err(err) = console.update_battery_file() {
+            eprintln!("could not write battery save: {}", err);
+        }
+
+        let samples = console.take_audio_samples();
+        if audio_queue.size() < max_queued_bytes {
+            audio_queue.queue_audio(&samples)?;
+        }
+
+        texture
+            .update(None, &console.get_frame_rgb(), SCREEN_WIDTH * 3)
+            .map_err(|err| err.to_string())?;
+        let (output_width, output_height) = canvas.output_size()?;
+        canvas.clear();
+        canvas.copy(
+            &texture,
+            None,
+            Some(get_output_rect(output_width, output_height, correct_aspect)),
+        )?;
+        canvas.present();
+
+        next_frame += frame_duration;
+        let now = Instant::now();
+        if next_frame > now {
+            thread::sleep(next_frame - now);
+        } else if now - next_frame > frame_duration * 4 {
+            // fell too far behind (debugger, window drag), don't try to catch up
+            next_frame = now;
+        }
+    }
+    if let Err(err) = console.flush_battery_file() {
+        eprintln!("could not write battery save: {}", err);
+    }
+    if let Err(err) = console.finish_trace() {
+        eprintln!("could not write trace log: {}", err);
+    }
+    if let (Some(path), Some(cdl)) = (&options.cdl, console.stop_cdl()) {
+        if let Err(err) = cdl.save(path) {
+            eprintln!("{}: {}", path, err);
+        }
+    }
+    if let (Some(movie), Some(path)) = (&recording, &options.record) {
+        match movie.save(path) {
+            Ok(()) => println!("recorded {} frames to {}", movie.len(), path),
+            Err(err) => eprintln!("{}: {}", path, err),
+        }
+    }
+    return Ok(());
+}
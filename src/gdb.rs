@@ -0,0 +1,537 @@
+//! A GDB remote serial protocol server, so debugger front-ends can attach
+//! over TCP.
+//!
+//! GDB has no 6502 target of its own, so the stub describes its registers in
+//! a `target.xml`: `a`, `x`, `y`, `p` and `sp` are 8 bits wide and `pc` is 16,
+//! in that order. Memory is the CPU address space; reads have no side effects
+//! and writes go through the bus like CPU writes, except that writes to PRG
+//! ROM patch the ROM instead of reaching mapper registers. Breakpoints
+//! (`Z0`/`Z1`) stop on PC and watchpoints (`Z2`-`Z4`) on bus accesses.
+
+use crate::console::Console;
+use crate::debugger::{Debugger, RunLimit, StopReason, WatchKind, Watchpoint};
+use crate::MemoryBus;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+const INTERRUPT: u8 = 0x03;
+const MAX_PACKET_SIZE: usize = 0x1000;
+// instructions run between checks for an interrupt from the client
+const CONTINUE_CHUNK: u64 = 10_000;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.rustes.6502">
+    <reg name="a" bitsize="8" type="uint8"/>
+    <reg name="x" bitsize="8" type="uint8"/>
+    <reg name="y" bitsize="8" type="uint8"/>
+    <reg name="p" bitsize="8" type="uint8"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+const REGISTER_PC: usize = 5;
+// five 8 bit registers and the 16 bit PC
+const REGISTER_BYTES: usize = 7;
+
+fn checksum(data: &[u8]) -> u8 {
+    return data.iter().fold(0, |sum: u8, byte| sum.wrapping_add(*byte));
+}
+
+fn encode_hex(data: &[u8]) -> String {
+    return data.iter().map(|byte| format!("{:02x}", byte)).collect();
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    return (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect();
+}
+
+fn parse_hex(text: &str) -> Option<u16> {
+    return u16::from_str_radix(text, 16).ok();
+}
+
+/// Parses `addr,len`, the argument of most memory commands.
+fn parse_range(text: &str) -> Option<(u16, usize)> {
+    let (addr, len) = text.split_once(',')?;
+    return Some((parse_hex(addr)?, usize::from_str_radix(len, 16).ok()?));
+}
+
+enum Packet {
+    Command(String),
+    Interrupt,
+}
+
+/// Framing of packets, `$<data>#<checksum>`, each acknowledged with `+`.
+struct Connection {
+    stream: TcpStream,
+}
+
+impl Connection {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => return Ok(None),
+            _ => return Ok(Some(byte[0])),
+        }
+    }
+
+    /// The next packet, or None once the client hung up.
+    fn read_packet(&mut self) -> io::Result<Option<Packet>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(INTERRUPT) => return Ok(Some(Packet::Interrupt)),
+                Some(b'$') => {}
+                // acks and line noise between packets
+                Some(_) => continue,
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) if data.len() < MAX_PACKET_SIZE => data.push(byte),
+                    Some(_) => {}
+                }
+            }
+            let mut sum = [0; 2];
+            self.stream.read_exact(&mut sum)?;
+            let expected = std::str::from_utf8(&sum)
+                .ok()
+                .and_then(|sum| u8::from_str_radix(sum, 16).ok());
+            if expected != Some(checksum(&data)) {
+                self.stream.write_all(b"-")?;
+                continue;
+            }
+            self.stream.write_all(b"+")?;
+            return Ok(Some(Packet::Command(
+                String::from_utf8_lossy(&data).into_owned(),
+            )));
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())?;
+        return self.stream.flush();
+    }
+
+    /// Checks for a Ctrl-C from the client without waiting for one.
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0];
+        let result = self.stream.peek(&mut byte);
+        self.stream.set_nonblocking(false)?;
+        match result {
+            Ok(1) if byte[0] == INTERRUPT => {
+                self.stream.read_exact(&mut byte)?;
+                return Ok(true);
+            }
+            Ok(_) => return Ok(false),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+pub struct GdbStub {
+    console: Console,
+    debugger: Debugger,
+}
+
+impl GdbStub {
+    pub fn init(console: Console) -> Self {
+        return GdbStub {
+            console,
+            debugger: Debugger::init(),
+        };
+    }
+
+    pub fn get_console(&self) -> &Console {
+        return &self.console;
+    }
+
+    pub fn get_console_mut(&mut self) -> &mut Console {
+        return &mut self.console;
+    }
+
+    /// Handles one client until it detaches, kills the session or hangs up.
+    pub fn serve(&mut self, stream: TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        let mut connection = Connection { stream };
+        while let Some(packet) = connection.read_packet()? {
+            let command = match packet {
+                Packet::Command(command) => command,
+                // already stopped
+                Packet::Interrupt => {
+                    connection.write_packet(&format!("S{:02x}", SIGINT))?;
+                    continue;
+                }
+            };
+            match command.as_bytes().first() {
+                Some(b'c') => {
+                    let reply = self.resume(&command[1..], &mut connection)?;
+                    connection.write_packet(&reply)?;
+                }
+                Some(b'D') => {
+                    connection.write_packet("OK")?;
+                    return Ok(());
+                }
+                Some(b'k') => return Ok(()),
+                _ => {
+                    let reply = self.execute(&command);
+                    connection.write_packet(&reply)?;
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    /// Answers every command that doesn't run for long. Unsupported commands
+    /// get the empty reply.
+    fn execute(&mut self, command: &str) -> String {
+        let kind = match command.chars().next() {
+            Some(kind) => kind,
+            None => return String::new(),
+        };
+        let args = &command[kind.len_utf8()..];
+        let reply = match kind {
+            '?' => Some(format!("S{:02x}", SIGTRAP)),
+            'g' => Some(encode_hex(&self.get_registers())),
+            'G' => decode_hex(args).and_then(|regs| self.set_registers(&regs)),
+            'p' => self.read_register(args),
+            'P' => self.write_register(args),
+            'm' => self.read_memory(args),
+            'M' => self.write_memory(args),
+            's' => {
+                self.set_resume_address(args);
+                let limit = RunLimit {
+                    instructions: Some(1),
+                    ..RunLimit::default()
+                };
+                let stop = self.debugger.run(&mut self.console, limit);
+                Some(Self::stop_reply(stop))
+            }
+            'Z' => self.set_breakpoint(args, true),
+            'z' => self.set_breakpoint(args, false),
+            'H' => Some(String::from("OK")),
+            'q' => return self.query(args),
+            _ => return String::new(),
+        };
+        return reply.unwrap_or_else(|| String::from("E01"));
+    }
+
+    fn query(&self, query: &str) -> String {
+        if query.starts_with("Supported") {
+            return format!("PacketSize={:x};qXfer:features:read+", MAX_PACKET_SIZE);
+        }
+        if let Some(range) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            let (offset, len) = match parse_range(range) {
+                Some((offset, len)) => (offset as usize, len),
+                None => return String::from("E01"),
+            };
+            let start = offset.min(TARGET_XML.len());
+            let end = (start + len).min(TARGET_XML.len());
+            let more = if end < TARGET_XML.len() { "m" } else { "l" };
+            return format!("{}{}", more, &TARGET_XML[start..end]);
+        }
+        match query {
+            "Attached" => return String::from("1"),
+            "C" => return String::from("QC1"),
+            "fThreadInfo" => return String::from("m1"),
+            "sThreadInfo" => return String::from("l"),
+            _ => return String::new(),
+        }
+    }
+
+    /// Runs in chunks so a Ctrl-C from the client can stop it. Only the
+    /// first chunk steps over a breakpoint where it starts.
+    fn resume(&mut self, args: &str, connection: &mut Connection) -> io::Result<String> {
+        self.set_resume_address(args);
+        let mut limit = RunLimit {
+            instructions: Some(CONTINUE_CHUNK),
+            ..RunLimit::default()
+        };
+        loop {
+            match self.debugger.run(&mut self.console, limit) {
+                StopReason::InstructionLimit => {}
+                stop => return Ok(Self::stop_reply(stop)),
+            }
+            if connection.poll_interrupt()? {
+                return Ok(format!("S{:02x}", SIGINT));
+            }
+            limit.break_at_start = true;
+        }
+    }
+
+    fn set_resume_address(&mut self, args: &str) {
+        if let Some(addr) = parse_hex(args) {
+            self.console.get_cpu_mut().set_pc(addr);
+        }
+    }
+
+    fn stop_reply(stop: StopReason) -> String {
+        match stop {
+            StopReason::Watchpoint(hit) => {
+                let kind = match hit.is_write {
+                    true => "watch",
+                    false => "rwatch",
+                };
+                return format!("T{:02x}{}:{:x};", SIGTRAP, kind, hit.addr);
+            }
+            _ => return format!("S{:02x}", SIGTRAP),
+        }
+    }
+
+    fn get_registers(&self) -> Vec<u8> {
+        let cpu = self.console.get_cpu();
+        let pc = cpu.get_pc();
+        return vec![
+            cpu.get_a(),
+            cpu.get_x(),
+            cpu.get_y(),
+            cpu.get_ps(),
+            cpu.get_sp(),
+            pc as u8,
+            (pc >> 8) as u8,
+        ];
+    }
+
+    fn set_registers(&mut self, regs: &[u8]) -> Option<String> {
+        if regs.len() != REGISTER_BYTES {
+            return None;
+        }
+        let cpu = self.console.get_cpu_mut();
+        cpu.set_a(regs[0]);
+        cpu.set_x(regs[1]);
+        cpu.set_y(regs[2]);
+        cpu.set_ps(regs[3]);
+        cpu.set_sp(regs[4]);
+        cpu.set_pc(u16::from_le_bytes([regs[5], regs[6]]));
+        return Some(String::from("OK"));
+    }
+
+    fn read_register(&self, args: &str) -> Option<String> {
+        let index = parse_hex(args)? as usize;
+        let regs = self.get_registers();
+        match index {
+            REGISTER_PC => return Some(encode_hex(&regs[REGISTER_PC..])),
+            _ if index < REGISTER_PC => return Some(encode_hex(&regs[index..index + 1])),
+            _ => return None,
+        }
+    }
+
+    fn write_register(&mut self, args: &str) -> Option<String> {
+        let (index, val) = args.split_once('=')?;
+        let index = parse_hex(index)? as usize;
+        let val = decode_hex(val)?;
+        let mut regs = self.get_registers();
+        let width = if index == REGISTER_PC { 2 } else { 1 };
+        if index > REGISTER_PC || val.len() != width {
+            return None;
+        }
+        regs[index..index + width].copy_from_slice(&val);
+        return self.set_registers(&regs);
+    }
+
+    fn read_memory(&mut self, args: &str) -> Option<String> {
+        let (addr, len) = parse_range(args)?;
+        let bus = self.console.get_cpu_mut().get_bus_mut();
+        let data: Vec<u8> = (0..len.min(MAX_PACKET_SIZE / 2))
+            .map(|i| bus.peek_memory_byte(addr.wrapping_add(i as u16)))
+            .collect();
+        return Some(encode_hex(&data));
+    }
+
+    fn write_memory(&mut self, args: &str) -> Option<String> {
+        let (range, data) = args.split_once(':')?;
+        let (addr, len) = parse_range(range)?;
+        let data = decode_hex(data)?;
+        if data.len() != len {
+            return None;
+        }
+        let bus = self.console.get_cpu_mut().get_bus_mut();
+        for (i, byte) in data.iter().enumerate() {
+            bus.poke_memory_byte(addr.wrapping_add(i as u16), *byte);
+        }
+        bus.take_watch_hit();
+        return Some(String::from("OK"));
+    }
+
+    /// `Z<type>,<addr>,<kind>` inserts and `z` removes.
+    fn set_breakpoint(&mut self, args: &str, insert: bool) -> Option<String> {
+        let mut fields = args.split(',');
+        let kind = fields.next()?;
+        let addr = parse_hex(fields.next()?)?;
+        let len = fields
+            .next()
+            .and_then(|len| u16::from_str_radix(len, 16).ok())
+            .unwrap_or(1)
+            .max(1);
+        let watch_kind = match kind {
+            "0" | "1" => {
+                if insert {
+                    self.debugger.add_breakpoint(addr);
+                } else {
+                    self.debugger.remove_breakpoint(addr);
+                }
+                return Some(String::from("OK"));
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return Some(String::new()),
+        };
+        let watchpoint = Watchpoint {
+            start: addr,
+            end: addr.saturating_add(len - 1),
+            kind: watch_kind,
+        };
+        let bus = self.console.get_cpu_mut().get_bus_mut();
+        if insert {
+            bus.add_watchpoint(watchpoint);
+        } else if let Some(index) = bus.get_watchpoints().iter().position(|w| *w == watchpoint) {
+            bus.remove_watchpoint(index);
+        }
+        return Some(String::from("OK"));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::{nrom_with_program, COUNTER};
+    use crate::cartridge::Cartridge;
+    use std::net::TcpListener;
+    use std::thread;
+
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn send(&mut self, data: &str) -> String {
+            let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+            self.stream.write_all(packet.as_bytes()).unwrap();
+            let mut ack = [0];
+            self.stream.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+');
+            return self.read_reply();
+        }
+
+        fn read_reply(&mut self) -> String {
+            let mut reply = Vec::new();
+            let mut byte = [0];
+            self.stream.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], b'$');
+            loop {
+                self.stream.read_exact(&mut byte).unwrap();
+                if byte[0] == b'#' {
+                    break;
+                }
+                reply.push(byte[0]);
+            }
+            let mut sum = [0; 2];
+            self.stream.read_exact(&mut sum).unwrap();
+            assert_eq!(sum, format!("{:02x}", checksum(&reply)).as_bytes());
+            return String::from_utf8(reply).unwrap();
+        }
+    }
+
+    fn start_stub() -> (Client, thread::JoinHandle<()>) {
+        return start_stub_with_program(COUNTER);
+    }
+
+    fn start_stub_with_program(source: &'static str) -> (Client, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let cartridge = Cartridge::from_ines(&nrom_with_program(source)).unwrap();
+            let console = Console::init(&cartridge).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            GdbStub::init(console).serve(stream).unwrap();
+        });
+        let stream = TcpStream::connect(addr).unwrap();
+        return (Client { stream }, server);
+    }
+
+    #[test]
+    fn test_registers_memory_and_stepping() {
+        let (mut client, server) = start_stub();
+        assert!(client
+            .send("qSupported:xmlRegisters=i386")
+            .contains("qXfer"));
+        assert!(client
+            .send("qXfer:features:read:target.xml:0,1000")
+            .starts_with("l<?xml"));
+        assert_eq!(client.send("?"), "S05");
+        assert_eq!(client.send("p5"), "0080");
+        assert_eq!(client.send("s"), "S05");
+        assert_eq!(client.send("s"), "S05");
+        assert_eq!(client.send("p1"), "01");
+        assert_eq!(client.send("P0=42"), "OK");
+        assert_eq!(&client.send("g")[..2], "42");
+        assert_eq!(client.send("M300,2:beef"), "OK");
+        assert_eq!(client.send("m2ff,3"), "00beef");
+        assert_eq!(client.send("m8000,2"), "a200");
+        // the LDX operand in PRG ROM changes and reads back changed
+        assert_eq!(client.send("M8001,1:05"), "OK");
+        assert_eq!(client.send("m8000,2"), "a205");
+        assert_eq!(client.send("vMustReplyEmpty"), "");
+        assert_eq!(client.send("D"), "OK");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_breakpoints_watchpoints_and_interrupt() {
+        let (mut client, server) = start_stub();
+        assert_eq!(client.send("Z0,8005,1"), "OK");
+        assert_eq!(client.send("c"), "S05");
+        assert_eq!(client.send("p5"), "0580");
+        assert_eq!(client.send("z0,8005,1"), "OK");
+        assert_eq!(client.send("Z2,10,1"), "OK");
+        assert_eq!(client.send("c"), "T05watch:10;");
+        assert_eq!(client.send("z2,10,1"), "OK");
+
+        // nothing left to stop on, so only Ctrl-C ends this
+        client.stream.write_all(b"$c#63").unwrap();
+        let mut ack = [0];
+        client.stream.read_exact(&mut ack).unwrap();
+        client.stream.write_all(&[INTERRUPT]).unwrap();
+        assert_eq!(client.read_reply(), "S02");
+        client.stream.write_all(b"$k#6b").unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_breakpoint_on_a_chunk_boundary() {
+        // `done` is reached after exactly one chunk
+        let (mut client, server) = start_stub_with_program(
+            "       LDY #99
+            outer:  LDX #49
+            inner:  DEX
+                    BNE inner
+                    DEY
+                    BNE outer
+            done:   JMP done",
+        );
+        assert_eq!(1 + 99 * (1 + 49 * 2 + 2), CONTINUE_CHUNK);
+        assert_eq!(client.send("Z0,800a,1"), "OK");
+        assert_eq!(client.send("c"), "S05");
+        assert_eq!(client.send("p5"), "0a80");
+        client.stream.write_all(b"$k#6b").unwrap();
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,237 @@
+use crate::cli::{RunOptions, DEFAULT_HEADLESS_FRAMES};
+use crate::png;
+use rustes::blargg::{TestMonitor, TestResult};
+use rustes::movie::Movie;
+use rustes::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use rustes::{Button, Console, MemoryBus};
+use std::fs;
+use std::io;
+
+pub const EXIT_PASSED: i32 = 0;
+pub const EXIT_TIMEOUT: i32 = 124;
+/// Outside the range of test results, so CI can tell the two apart.
+pub const EXIT_ERROR: i32 = 125;
+
+const RAM_SIZE: u16 = 0x800;
+
+#[derive(Debug, PartialEq, Eq)]
+struct InputEvent {
+    frame: u64,
+    port: usize,
+    buttons: u8,
+}
+
+/// Controller input for a headless run.
+///
+/// Each line is `<frame> [p1|p2] <buttons>`, where buttons are joined with
+/// `+` (`a`, `b`, `select`, `start`, `up`, `down`, `left`, `right`) or are
+/// `none`. The buttons are held from that frame until the next line for the
+/// same port. `#` starts a comment.
+#[derive(Debug, Default)]
+pub struct InputScript {
+    events: Vec<InputEvent>,
+}
+
+fn parse_button(name: &str) -> Option<Button> {
+    match name.to_ascii_lowercase().as_str() {
+        "a" => return Some(Button::A),
+        "b" => return Some(Button::B),
+        "select" => return Some(Button::Select),
+        "start" => return Some(Button::Start),
+        "up" => return Some(Button::Up),
+        "down" => return Some(Button::Down),
+        "left" => return Some(Button::Left),
+        "right" => return Some(Button::Right),
+        _ => return None,
+    }
+}
+
+impl InputScript {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut events = Vec::new();
+        for (line_number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |msg: &str| format!("line {}: {}", line_number + 1, msg);
+            let mut fields = line.split_whitespace();
+            let frame = fields
+                .next()
+                .and_then(|frame| frame.parse().ok())
+                .ok_or_else(|| error("expected a frame number"))?;
+            let mut field = fields.next().ok_or_else(|| error("expected buttons"))?;
+            let mut port = 0;
+            if field == "p1" || field == "p2" {
+                port = if field == "p1" { 0 } else { 1 };
+                field = fields.next().ok_or_else(|| error("expected buttons"))?;
+            }
+            if fields.next().is_some() {
+                return Err(error("unexpected text after the buttons"));
+            }
+            let mut buttons = 0;
+            if field != "none" {
+                for name in field.split('+') {
+                    let button = parse_button(name)
+                        .ok_or_else(|| error(&format!("unknown button {}", name)))?;
+                    buttons |= button.get_mask();
+                }
+            }
+            events.push(InputEvent {
+                frame,
+                port,
+                buttons,
+            });
+        }
+        // keep lines for the same frame in file order
+        events.sort_by_key(|event| event.frame);
+        return Ok(InputScript { events });
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+        return InputScript::parse(&text).map_err(|err| format!("{}: {}", path, err));
+    }
+
+    fn apply(&self, frame: u64, console: &mut Console) {
+        for event in self.events.iter().filter(|event| event.frame == frame) {
+            console
+                .get_controller_mut(event.port)
+                .set_buttons(event.buttons);
+        }
+    }
+}
+
+fn write_ram_hex(console: &mut Console, path: &str) -> io::Result<()> {
+    let bus = console.get_cpu_mut().get_bus_mut();
+    let mut out = String::new();
+    for row in (0..RAM_SIZE).step_by(16) {
+        out += &format!("{:04X}:", row);
+        for addr in row..row + 16 {
+            out += &format!(" {:02X}", bus.peek_memory_byte(addr));
+        }
+        out.push('\n');
+    }
+    return fs::write(path, out);
+}
+
+/// Runs `console` without a window and returns the process exit code.
+/// `movie`, if any, has already been started on the console.
+pub fn run(mut console: Console, options: &RunOptions, movie: Option<&Movie>) -> i32 {
+    let script = match &options.input {
+        Some(path) => match InputScript::load(path) {
+            Ok(script) => script,
+            Err(err) => {
+                eprintln!("{}", err);
+                return EXIT_ERROR;
+            }
+        },
+        None => InputScript::default(),
+    };
+
+    let frames = match (options.frames, movie) {
+        (Some(frames), _) => frames,
+        (None, Some(movie)) => movie.len() as u64,
+        (None, None) => DEFAULT_HEADLESS_FRAMES,
+    };
+    let mut monitor = TestMonitor::init();
+    let mut result = None;
+    for frame in 0..frames {
+        match movie {
+            Some(movie) => {
+                movie.play_frame(frame as usize, &mut console);
+            }
+            None => script.apply(frame, &mut console),
+        }
+        console.run_frame();
+        result = monitor.update(&mut console);
+        if result.is_some() {
+            break;
+        }
+    }
+
+    if let Err(err) = console.flush_battery_file() {
+        eprintln!("{}: {}", options.save.as_deref().unwrap_or_default(), err);
+        return EXIT_ERROR;
+    }
+    if let Err(err) = console.finish_trace() {
+        eprintln!("{}: {}", options.trace.as_deref().unwrap_or_default(), err);
+        return EXIT_ERROR;
+    }
+    if let (Some(path), Some(cdl)) = (&options.cdl, console.stop_cdl()) {
+        if let Err(err) = cdl.save(path) {
+            eprintln!("{}: {}", path, err);
+            return EXIT_ERROR;
+        }
+    }
+    if let Some(path) = &options.png {
+        let rgb = console.get_frame_rgb();
+        if let Err(err) = png::write_rgb(path, SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32, &rgb) {
+            eprintln!("{}: {}", path, err);
+            return EXIT_ERROR;
+        }
+    }
+    if let Some(path) = &options.ram {
+        if let Err(err) = write_ram_hex(&mut console, path) {
+            eprintln!("{}: {}", path, err);
+            return EXIT_ERROR;
+        }
+    }
+
+    match result {
+        Some(TestResult::Finished { status, message }) => {
+            println!("{}", message);
+            return status.min(EXIT_TIMEOUT as u8 - 1) as i32;
+        }
+        _ if console.get_test_status().is_some() => {
+            eprintln!("test did not finish within {} frames", frames);
+            return EXIT_TIMEOUT;
+        }
+        _ => return EXIT_PASSED,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_input_script() {
+        let script = InputScript::parse(
+            "# title screen\n\
+             60 start\n\
+             62 p2 a+right   # jump\n\
+             61 none\n",
+        )
+        .unwrap();
+        assert_eq!(
+            script.events,
+            vec![
+                InputEvent {
+                    frame: 60,
+                    port: 0,
+                    buttons: Button::Start.get_mask()
+                },
+                InputEvent {
+                    frame: 61,
+                    port: 0,
+                    buttons: 0
+                },
+                InputEvent {
+                    frame: 62,
+                    port: 1,
+                    buttons: Button::A.get_mask() | Button::Right.get_mask()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_input_script_errors() {
+        assert!(InputScript::parse("start").is_err());
+        assert!(InputScript::parse("10").is_err());
+        assert!(InputScript::parse("10 jump").is_err());
+        assert!(InputScript::parse("10 p3 a").is_err());
+        assert!(InputScript::parse("10 a b").is_err());
+    }
+}
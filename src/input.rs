@@ -0,0 +1,102 @@
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+/// Buttons on a standard NES controller, in the order they are shifted out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    A,
+    B,
+    Select,
+    Start,
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl Button {
+    pub const ALL: [Button; 8] = [
+        Button::A,
+        Button::B,
+        Button::Select,
+        Button::Start,
+        Button::Up,
+        Button::Down,
+        Button::Left,
+        Button::Right,
+    ];
+
+    pub fn get_mask(self) -> u8 {
+        return 1 << (self as u8);
+    }
+}
+
+/// A standard controller plugged into $4016/$4017.
+#[derive(Debug, Clone, Default)]
+pub struct Controller {
+    buttons: u8,
+    strobe: bool,
+    shift: u8,
+}
+
+impl Controller {
+    pub fn init() -> Self {
+        return Controller::default();
+    }
+
+    /// Button state as a bitmask, bit 0 being A and bit 7 being Right.
+    pub fn get_buttons(&self) -> u8 {
+        return self.buttons;
+    }
+
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.buttons = buttons;
+    }
+
+    pub fn is_pressed(&self, button: Button) -> bool {
+        return self.buttons & button.get_mask() != 0;
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        if pressed {
+            self.buttons |= button.get_mask();
+        } else {
+            self.buttons &= !button.get_mask();
+        }
+    }
+
+    pub fn write(&mut self, val: u8) {
+        self.strobe = val & 1 == 1;
+        if self.strobe {
+            self.shift = 0;
+        }
+    }
+
+    pub fn read(&mut self) -> u8 {
+        if self.strobe {
+            return self.buttons & 1;
+        }
+        // after all 8 buttons have been read the shift register returns 1s
+        let val = if self.shift < 8 {
+            (self.buttons >> self.shift) & 1
+        } else {
+            1
+        };
+        self.shift = self.shift.saturating_add(1);
+        return val;
+    }
+}
+
+impl SaveState for Controller {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.buttons);
+        state.write(&self.strobe);
+        state.write(&self.shift);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read(&mut self.buttons)?;
+        state.read(&mut self.strobe)?;
+        state.read(&mut self.shift)?;
+        return Ok(());
+    }
+}
@@ -0,0 +1,38 @@
+//! rustES, a NES emulator core.
+//!
+//! [`Console`] ties everything together; [`CPU`] can also be driven on its own
+//! against any [`MemoryBus`].
+#![allow(clippy::needless_return, clippy::upper_case_acronyms)]
+
+pub mod apu;
+pub mod asm;
+pub mod battery;
+pub mod blargg;
+pub mod bus;
+pub mod cartridge;
+pub mod cdl;
+pub mod console;
+pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod gdb;
+pub mod input;
+pub mod mapper;
+pub mod movie;
+pub mod opcodes;
+pub mod palette;
+pub mod ppu;
+mod ram;
+pub mod region;
+pub mod rewind;
+pub mod savestate;
+pub mod symbols;
+pub mod trace;
+pub mod video;
+
+pub use bus::{MemoryBus, BUS};
+pub use cartridge::{Cartridge, CartridgeError, Mirroring};
+pub use console::Console;
+pub use cpu::CPU;
+pub use input::{Button, Controller};
+pub use region::{Region, NTSC_FRAME_RATE};
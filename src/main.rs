@@ -1,6 +1,216 @@
-mod bus;
-mod cpu;
-mod ram;
+#![allow(clippy::needless_return)]
+
+mod cli;
+#[cfg(feature = "frontend")]
+mod frontend;
+mod headless;
+mod monitor;
+mod png;
+
+use cli::{Command, RunOptions};
+use headless::EXIT_ERROR;
+use rustes::battery;
+use rustes::cdl::CDL;
+use rustes::gdb::GdbStub;
+use rustes::movie::Movie;
+use rustes::palette::Palette;
+use rustes::rewind::DEFAULT_REWIND_INTERVAL;
+use rustes::symbols::SymbolTable;
+use rustes::trace::TraceLogger;
+use rustes::{Cartridge, CartridgeError, Console, Region};
+use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Loads a ROM, or a disk image with `fds_bios` or the `disksys.rom` next
+/// to it.
+fn load_cartridge(path: &str, fds_bios: Option<&str>) -> Cartridge {
+    let result = match Cartridge::load(path) {
+        Err(CartridgeError::MissingBIOS) => {
+            let bios = match fds_bios {
+                Some(bios) => PathBuf::from(bios),
+                None => Path::new(path).with_file_name("disksys.rom"),
+            };
+            Cartridge::load_fds(path, &bios).map_err(|err| match err {
+                CartridgeError::MissingBIOS | CartridgeError::InvalidBIOS => {
+                    format!("{}: {}", bios.display(), err)
+                }
+                err => format!("{}: {}", path, err),
+            })
+        }
+        result => result.map_err(|err| format!("{}: {}", path, err)),
+    };
+    match result {
+        Ok(cartridge) => return cartridge,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(EXIT_ERROR);
+        }
+    }
+}
+
+fn load_console(path: &str, cartridge: &Cartridge) -> Console {
+    match Console::init(cartridge) {
+        Ok(console) => return console,
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            process::exit(EXIT_ERROR);
+        }
+    }
+}
+
+fn load_movie(path: &str) -> Movie {
+    match Movie::load(path) {
+        Ok(movie) => return movie,
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            process::exit(EXIT_ERROR);
+        }
+    }
+}
+
+fn load_symbols(paths: &[String]) -> SymbolTable {
+    let mut symbols = SymbolTable::init();
+    for path in paths {
+        if let Err(err) = symbols.load(path) {
+            eprintln!("{}: {}", path, err);
+            process::exit(EXIT_ERROR);
+        }
+    }
+    return symbols;
+}
+
 fn main() {
-    let mut cpu6502 = cpu::CPU::init();
+    let args: Vec<String> = env::args().collect();
+    let command = match cli::parse_args(&args[1..]) {
+        Ok(command) => command,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, cli::USAGE);
+            process::exit(2);
+        }
+    };
+
+    match command {
+        Command::Run(options) => {
+            let cartridge = load_cartridge(&options.rom, options.fds_bios.as_deref());
+            let mut console = load_console(&options.rom, &cartridge);
+            let movie = options.movie.as_deref().map(load_movie);
+            let movie_region = movie
+                .as_ref()
+                .filter(|movie| movie.get_header("palFlag") == Some("1"))
+                .map(|_| Region::PAL);
+            if let Some(region) = options.region.or(movie_region) {
+                console.set_region(region);
+            }
+            console.set_video_filter(options.video_filter);
+            if let Some(path) = &options.palette {
+                match Palette::load(path) {
+                    Ok(palette) => console.set_palette(palette),
+                    Err(err) => {
+                        eprintln!("{}: {}", path, err);
+                        process::exit(EXIT_ERROR);
+                    }
+                }
+            }
+            if let (Some(movie), Some(path)) = (&movie, &options.movie) {
+                if let Err(err) = movie.start(&mut console) {
+                    eprintln!("{}: {}", path, err);
+                    process::exit(EXIT_ERROR);
+                }
+            }
+            let save_path = match &options.save {
+                Some(path) => Some(path.into()),
+                // movies expect the cartridge RAM they were recorded with
+                None if options.movie.is_some() || options.record.is_some() => None,
+                None if options.headless => None,
+                None if !cartridge.get_disk_sides().is_empty() => {
+                    Some(battery::default_patch_path(&options.rom))
+                }
+                None => Some(battery::default_save_path(&options.rom)),
+            };
+            if let Some(path) = save_path {
+                if let Err(err) = console.open_battery_file(&path) {
+                    eprintln!("{}: {}", path.display(), err);
+                    process::exit(EXIT_ERROR);
+                }
+            }
+            if let Some(path) = &options.trace {
+                let file = match File::create(path) {
+                    Ok(file) => file,
+                    Err(err) => {
+                        eprintln!("{}: {}", path, err);
+                        process::exit(EXIT_ERROR);
+                    }
+                };
+                let mut trace = TraceLogger::init(
+                    Box::new(BufWriter::new(file)),
+                    options.trace_format,
+                    options.trace_filter.clone(),
+                );
+                if !options.symbols.is_empty() {
+                    trace.set_symbols(load_symbols(&options.symbols));
+                }
+                console.start_trace(trace);
+            }
+            if let Some(path) = &options.cdl {
+                let prg_size = cartridge.get_prg_rom().len();
+                let chr_size = cartridge.get_chr_rom().len();
+                match CDL::load(path, prg_size, chr_size) {
+                    Ok(cdl) => console.start_cdl(cdl),
+                    Err(err) => {
+                        eprintln!("{}: {}", path, err);
+                        process::exit(EXIT_ERROR);
+                    }
+                }
+            }
+            if options.headless {
+                process::exit(headless::run(console, &options, movie.as_ref()));
+            }
+            if options.rewind_mb > 0 {
+                console.enable_rewind(options.rewind_mb, DEFAULT_REWIND_INTERVAL);
+            }
+            run_frontend(console, &options, movie);
+        }
+        Command::Debug(options) => {
+            let cartridge = load_cartridge(&options.rom, options.fds_bios.as_deref());
+            let console = load_console(&options.rom, &cartridge);
+            let result = match options.gdb_port {
+                Some(port) => serve_gdb(console, port),
+                None => {
+                    let mut monitor = monitor::Monitor::init(console);
+                    *monitor.get_symbols_mut() = load_symbols(&options.symbols);
+                    monitor.run(io::stdin().lock(), io::stdout())
+                }
+            };
+            if let Err(err) = result {
+                eprintln!("{}", err);
+                process::exit(EXIT_ERROR);
+            }
+        }
+    }
+}
+
+fn serve_gdb(console: Console, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    eprintln!("waiting for a GDB connection on {}", listener.local_addr()?);
+    let (stream, addr) = listener.accept()?;
+    eprintln!("connected to {}", addr);
+    return GdbStub::init(console).serve(stream);
+}
+
+#[cfg(feature = "frontend")]
+fn run_frontend(console: Console, options: &RunOptions, movie: Option<Movie>) {
+    if let Err(err) = frontend::run(console, options, movie) {
+        eprintln!("frontend error: {}", err);
+        process::exit(EXIT_ERROR);
+    }
+}
+
+#[cfg(not(feature = "frontend"))]
+fn run_frontend(_console: Console, _options: &RunOptions, _movie: Option<Movie>) {
+    eprintln!("rustes was built without a frontend, rebuild with --features frontend");
+    process::exit(EXIT_ERROR);
 }
@@ -0,0 +1,194 @@
+mod axrom;
+mod fds;
+mod fds_audio;
+mod fme7;
+mod mmc1;
+mod mmc5;
+mod namco163;
+mod nrom;
+mod opll;
+mod vrc6;
+mod vrc7;
+mod vrc_irq;
+
+pub use axrom::AxROM;
+pub use fds::FDS;
+pub use fme7::FME7;
+pub use mmc1::MMC1;
+pub use mmc5::MMC5;
+pub use namco163::Namco163;
+pub use nrom::NROM;
+pub use vrc6::VRC6;
+pub use vrc7::VRC7;
+
+use crate::cartridge::{Cartridge, CartridgeError, Mirroring, FDS_MAPPER_ID};
+use crate::savestate::SaveState;
+
+/// Points in the PPU's rendering that mappers can follow, since the
+/// console doesn't model every fetch the way the real PPU bus shows them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PPUEvent {
+    /// Background fetches for the scanline (0-239) are starting, at dot 321
+    /// of the line before.
+    Scanline(u16),
+    /// Sprite pattern fetches for the next scanline are starting, at dot 257.
+    SpriteFetches,
+    /// Rendering is done for the frame or was switched off.
+    RenderingStopped,
+}
+
+/// Cartridge hardware sitting between the console and the ROM chips.
+///
+/// `read_prg`/`write_prg` receive CPU addresses in $6000-$FFFF and
+/// `read_chr`/`write_chr` receive PPU addresses in $0000-$1FFF. Save states
+/// cover the mapper's RAM and banking registers, not its ROM.
+pub trait Mapper: std::fmt::Debug + SaveState {
+    fn read_prg(&mut self, addr: u16) -> u8;
+    fn write_prg(&mut self, addr: u16, val: u8);
+    fn read_chr(&mut self, addr: u16) -> u8;
+    fn write_chr(&mut self, addr: u16, val: u8);
+    /// Used by the default nametable accesses, so mappers can switch it at
+    /// any time.
+    fn get_mirroring(&self) -> Mirroring;
+
+    /// A PPU read from the nametables at $2000-$3EFF. `vram` is the
+    /// console's 2KB (4KB with four-screen VRAM), for mappers that arrange it
+    /// in ways `get_mirroring` can't express or supply nametables themselves.
+    fn read_nametable(&mut self, addr: u16, vram: &[u8]) -> u8 {
+        return vram[self.get_mirroring().get_vram_offset(addr)];
+    }
+
+    fn write_nametable(&mut self, addr: u16, val: u8, vram: &mut [u8]) {
+        vram[self.get_mirroring().get_vram_offset(addr)] = val;
+    }
+
+    fn notify_ppu(&mut self, event: PPUEvent) {
+        let _ = event;
+    }
+
+    /// CPU writes to the PPU registers at $2000-$2007, which some boards
+    /// watch on the bus.
+    fn notify_ppu_write(&mut self, addr: u16, val: u8) {
+        let _ = (addr, val);
+    }
+
+    fn irq_pending(&self) -> bool {
+        return false;
+    }
+
+    /// Advances the mapper by one CPU cycle, for cycle counting IRQs and
+    /// sound channels.
+    fn tick(&mut self) {}
+
+    /// The cartridge's own sound channels, in steps of APU pulse volume (a
+    /// pulse channel at full volume is 15.0).
+    fn get_audio_output(&self) -> f32 {
+        return 0.0;
+    }
+
+    /// Where the CPU address `addr` in $8000-$FFFF currently reads from in
+    /// PRG ROM, as an offset from the start of the ROM.
+    fn get_prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        let _ = addr;
+        return None;
+    }
+
+    /// The whole PRG ROM, for debuggers patching code. None for mappers that
+    /// don't report PRG ROM offsets.
+    fn get_prg_rom_mut(&mut self) -> Option<&mut [u8]> {
+        return None;
+    }
+
+    /// Changes the PRG ROM byte the CPU address `addr` currently reads from,
+    /// without the register write a bus write there would be. Returns false
+    /// when `addr` isn't mapped to PRG ROM.
+    fn poke_prg(&mut self, addr: u16, val: u8) -> bool {
+        let offset = match self.get_prg_rom_offset(addr) {
+            Some(offset) => offset,
+            None => return false,
+        };
+        match self.get_prg_rom_mut() {
+            Some(prg_rom) => {
+                prg_rom[offset] = val;
+                return true;
+            }
+            None => return false,
+        }
+    }
+
+    /// Where the PPU address `addr` in $0000-$1FFF currently reads from in
+    /// CHR ROM. None for CHR RAM.
+    fn get_chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        let _ = addr;
+        return None;
+    }
+
+    /// Registers and RAM in the expansion area at $4020-$5FFF, such as
+    /// MMC5's ExRAM or the FDS drive. None leaves the read to open bus.
+    fn read_expansion(&mut self, addr: u16) -> Option<u8> {
+        let _ = addr;
+        return None;
+    }
+
+    /// What [`Mapper::read_expansion`] would return, without acknowledging
+    /// IRQs or the like, for debuggers.
+    fn peek_expansion(&self, addr: u16) -> Option<u8> {
+        let _ = addr;
+        return None;
+    }
+
+    fn write_expansion(&mut self, addr: u16, val: u8) {
+        let _ = (addr, val);
+    }
+
+    /// PRG RAM kept alive by the cartridge battery, if it has one.
+    fn get_battery_ram(&self) -> Option<&[u8]> {
+        return None;
+    }
+
+    fn get_battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        return None;
+    }
+
+    /// Sides of the Famicom Disk System disk, 0 for cartridges.
+    fn get_disk_side_count(&self) -> usize {
+        return 0;
+    }
+
+    /// The side in the drive or on its way in, None while it's empty.
+    fn get_disk_side(&self) -> Option<usize> {
+        return None;
+    }
+
+    /// Ejects the disk, then inserts `side` once the BIOS has had time to
+    /// notice the drive was empty.
+    fn set_disk_side(&mut self, side: Option<usize>) {
+        let _ = side;
+    }
+
+    /// The disk laid out as a headerless `.fds` image, with everything
+    /// written to it.
+    fn get_disk_image(&self) -> Option<Vec<u8>> {
+        return None;
+    }
+
+    fn set_disk_image(&mut self, image: &[u8]) {
+        let _ = image;
+    }
+}
+
+pub fn from_cartridge(cartridge: &Cartridge) -> Result<Box<dyn Mapper>, CartridgeError> {
+    match cartridge.get_mapper_id() {
+        0 => return Ok(Box::new(NROM::init(cartridge))),
+        1 => return Ok(Box::new(MMC1::init(cartridge))),
+        5 => return Ok(Box::new(MMC5::init(cartridge))),
+        7 => return Ok(Box::new(AxROM::init(cartridge))),
+        19 => return Ok(Box::new(Namco163::init(cartridge))),
+        FDS_MAPPER_ID => return Ok(Box::new(FDS::init(cartridge))),
+        24 => return Ok(Box::new(VRC6::init(cartridge, false))),
+        26 => return Ok(Box::new(VRC6::init(cartridge, true))),
+        69 => return Ok(Box::new(FME7::init(cartridge))),
+        85 => return Ok(Box::new(VRC7::init(cartridge))),
+        id => return Err(CartridgeError::UnsupportedMapper(id)),
+    }
+}
@@ -0,0 +1,114 @@
+use crate::cartridge::{Cartridge, Mirroring};
+use crate::mapper::Mapper;
+use crate::ram::START_CARTRIDGE_ROM;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x8000;
+
+/// Mapper 7: 32KB PRG banks, 8KB of CHR RAM and a register bit picking
+/// which nametable fills the screen.
+#[derive(Debug)]
+pub struct AxROM {
+    prg_rom: Vec<u8>,
+    chr_ram: Vec<u8>,
+    // bits 0-2 pick the PRG bank, bit 4 the nametable
+    bank_select: u8,
+}
+
+impl AxROM {
+    pub fn init(cartridge: &Cartridge) -> Self {
+        return AxROM {
+            prg_rom: cartridge.get_prg_rom().to_vec(),
+            chr_ram: vec![0; cartridge.get_chr_ram_size().max(0x2000)],
+            bank_select: 0,
+        };
+    }
+}
+
+impl Mapper for AxROM {
+    fn read_prg(&mut self, addr: u16) -> u8 {
+        match self.get_prg_rom_offset(addr) {
+            Some(offset) => return self.prg_rom[offset],
+            // no PRG RAM
+            None => return 0,
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, val: u8) {
+        if addr >= START_CARTRIDGE_ROM {
+            self.bank_select = val;
+        }
+    }
+
+    fn read_chr(&mut self, addr: u16) -> u8 {
+        return self.chr_ram[addr as usize % self.chr_ram.len()];
+    }
+
+    fn write_chr(&mut self, addr: u16, val: u8) {
+        let len = self.chr_ram.len();
+        self.chr_ram[addr as usize % len] = val;
+    }
+
+    fn get_mirroring(&self) -> Mirroring {
+        if self.bank_select & 0b10000 != 0 {
+            return Mirroring::SingleScreenB;
+        }
+        return Mirroring::SingleScreenA;
+    }
+
+    fn get_prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr < START_CARTRIDGE_ROM {
+            return None;
+        }
+        let bank = (self.bank_select & 0b111) as usize;
+        let offset = bank * PRG_BANK_SIZE + (addr - START_CARTRIDGE_ROM) as usize;
+        return Some(offset % self.prg_rom.len());
+    }
+
+    fn get_prg_rom_mut(&mut self) -> Option<&mut [u8]> {
+        return Some(&mut self.prg_rom);
+    }
+}
+
+impl SaveState for AxROM {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.chr_ram);
+        state.write(&self.bank_select);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read(&mut self.chr_ram)?;
+        state.read(&mut self.bank_select)?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::ines_rom;
+
+    #[test]
+    fn test_banking_and_mirroring() {
+        // 4 PRG banks that each start with their own number
+        let mut data = ines_rom(7, 8, 0, 0);
+        for bank in 0..4 {
+            data[16 + bank * PRG_BANK_SIZE] = bank as u8;
+        }
+        let mut axrom = AxROM::init(&Cartridge::from_ines(&data).unwrap());
+        assert_eq!(axrom.read_prg(0x8000), 0);
+        assert_eq!(axrom.get_mirroring(), Mirroring::SingleScreenA);
+
+        axrom.write_prg(0x8000, 0b10010);
+        assert_eq!(axrom.read_prg(0x8000), 2);
+        assert_eq!(
+            axrom.get_prg_rom_offset(0xFFFF),
+            Some(3 * PRG_BANK_SIZE - 1)
+        );
+        assert_eq!(axrom.get_mirroring(), Mirroring::SingleScreenB);
+        // banks past the end of the ROM wrap around
+        axrom.write_prg(0xFFFF, 0b00111);
+        assert_eq!(axrom.read_prg(0x8000), 3);
+        assert_eq!(axrom.get_mirroring(), Mirroring::SingleScreenA);
+    }
+}
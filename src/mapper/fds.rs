@@ -0,0 +1,679 @@
+use crate::cartridge::{Cartridge, Mirroring, FDS_SIDE_SIZE};
+use crate::mapper::fds_audio::FDSAudio;
+use crate::mapper::Mapper;
+use crate::ram::START_CARTRIDGE_RAM;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+const START_BIOS: u16 = 0xE000;
+
+const TIMER_RELOAD_LOW: u16 = 0x4020;
+const TIMER_RELOAD_HIGH: u16 = 0x4021;
+const TIMER_CONTROL: u16 = 0x4022;
+const IO_ENABLE: u16 = 0x4023;
+const WRITE_DATA: u16 = 0x4024;
+const CONTROL: u16 = 0x4025;
+const EXTERNAL_WRITE: u16 = 0x4026;
+const DISK_STATUS: u16 = 0x4030;
+const READ_DATA: u16 = 0x4031;
+const DRIVE_STATUS: u16 = 0x4032;
+const EXTERNAL_READ: u16 = 0x4033;
+const START_AUDIO: u16 = 0x4040;
+const END_AUDIO: u16 = 0x4097;
+
+const TIMER_REPEAT: u8 = 0x01;
+const TIMER_ENABLE: u8 = 0x02;
+const IO_ENABLE_DISK: u8 = 0x01;
+const IO_ENABLE_SOUND: u8 = 0x02;
+
+const CONTROL_MOTOR: u8 = 0x01;
+const CONTROL_TRANSFER_RESET: u8 = 0x02;
+const CONTROL_READ: u8 = 0x04;
+const CONTROL_HORIZONTAL: u8 = 0x08;
+const CONTROL_CRC: u8 = 0x10;
+const CONTROL_TRANSFER: u8 = 0x40;
+const CONTROL_DISK_IRQ: u8 = 0x80;
+
+const STATUS_TIMER_IRQ: u8 = 0x01;
+const STATUS_TRANSFERRED: u8 = 0x02;
+const STATUS_END_OF_HEAD: u8 = 0x40;
+const DRIVE_NO_DISK: u8 = 0x01;
+const DRIVE_NOT_READY: u8 = 0x02;
+const DRIVE_WRITE_PROTECTED: u8 = 0x04;
+// the battery in the RAM adapter is good
+const EXTERNAL_BATTERY_GOOD: u8 = 0x80;
+
+// what the drive sees before the first block and between blocks
+const LEAD_IN_GAP: usize = 28300 / 8;
+const BLOCK_GAP: usize = 976 / 8;
+const BLOCK_START: u8 = 0x80;
+// the CRC isn't checked, so any two bytes will do
+const BLOCK_CRC: [u8; 2] = [0x4D, 0x62];
+// a side with its gaps, with room for the blocks a game adds
+const RAW_SIDE_SIZE: usize = 0x14000;
+
+const DISK_INFO_BLOCK: u8 = 1;
+const FILE_COUNT_BLOCK: u8 = 2;
+const FILE_HEADER_BLOCK: u8 = 3;
+const FILE_DATA_BLOCK: u8 = 4;
+const FILE_SIZE: usize = 13;
+
+// about 96.4kbit/s
+const BYTE_CYCLES: u32 = 150;
+const HEAD_RETURN_CYCLES: u32 = 50000;
+// the BIOS only looks for a new disk after seeing the drive empty, about
+// half a second
+const INSERT_CYCLES: u32 = 900_000;
+
+// at full volume the FDS is about 2.4 times as loud as a full-volume pulse
+const WAVETABLE_LEVEL: f32 = 2.4 * 15.0 / 63.0;
+
+fn get_block_size(kind: u8, file_size: usize) -> Option<usize> {
+    match kind {
+        DISK_INFO_BLOCK => return Some(56),
+        FILE_COUNT_BLOCK => return Some(2),
+        FILE_HEADER_BLOCK => return Some(16),
+        FILE_DATA_BLOCK => return Some(1 + file_size),
+        _ => return None,
+    }
+}
+
+/// Lays a side out as the drive sees it, with gaps, start marks and CRCs
+/// around the blocks.
+fn add_gaps(side: &[u8]) -> Vec<u8> {
+    let mut raw = vec![0; LEAD_IN_GAP];
+    let mut offset = 0;
+    let mut file_size = 0;
+    while offset < side.len() {
+        let size = match get_block_size(side[offset], file_size) {
+            Some(size) => size,
+            None => break,
+        };
+        let block = &side[offset..(offset + size).min(side.len())];
+        if block[0] == FILE_HEADER_BLOCK && block.len() == size {
+            file_size = u16::from_le_bytes([block[FILE_SIZE], block[FILE_SIZE + 1]]) as usize;
+        }
+        raw.push(BLOCK_START);
+        raw.extend_from_slice(block);
+        raw.extend_from_slice(&BLOCK_CRC);
+        raw.resize(raw.len() + BLOCK_GAP, 0);
+        offset += block.len();
+    }
+    raw.resize(RAW_SIDE_SIZE, 0);
+    return raw;
+}
+
+/// The blocks of a side as the drive sees it, back in `.fds` layout.
+fn strip_gaps(raw: &[u8]) -> Vec<u8> {
+    let mut side = Vec::with_capacity(FDS_SIDE_SIZE);
+    let mut pos = 0;
+    let mut file_size = 0;
+    loop {
+        while pos < raw.len() && raw[pos] == 0 {
+            pos += 1;
+        }
+        if pos + 1 >= raw.len() || raw[pos] != BLOCK_START {
+            break;
+        }
+        pos += 1;
+        let size = match get_block_size(raw[pos], file_size) {
+            Some(size) => size,
+            None => break,
+        };
+        let block = &raw[pos..(pos + size).min(raw.len())];
+        if block[0] == FILE_HEADER_BLOCK && block.len() == size {
+            file_size = u16::from_le_bytes([block[FILE_SIZE], block[FILE_SIZE + 1]]) as usize;
+        }
+        side.extend_from_slice(block);
+        pos += block.len() + BLOCK_CRC.len();
+    }
+    side.resize(FDS_SIDE_SIZE, 0);
+    return side;
+}
+
+/// The Famicom Disk System: the RAM adapter with 32KB of PRG RAM, 8KB of
+/// CHR RAM, the BIOS, a timer IRQ and wavetable sound, and the drive
+/// streaming a disk side past the head a byte at a time.
+#[derive(Debug)]
+pub struct FDS {
+    bios: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr_ram: Vec<u8>,
+    disk_sides: Vec<Vec<u8>>,
+    side: Option<usize>,
+    insert_delay: u32,
+
+    io_enable: u8,
+    timer_reload: u16,
+    timer_counter: u16,
+    timer_repeat: bool,
+    timer_enabled: bool,
+    timer_irq: bool,
+
+    control: u8,
+    external: u8,
+    write_data: u8,
+    read_data: u8,
+    transferred: bool,
+    disk_irq: bool,
+    motor_on: bool,
+    scanning: bool,
+    end_of_head: bool,
+    gap_ended: bool,
+    position: usize,
+    delay: u32,
+
+    audio: FDSAudio,
+}
+
+impl FDS {
+    pub fn init(cartridge: &Cartridge) -> Self {
+        let disk_sides = cartridge
+            .get_disk_sides()
+            .iter()
+            .map(|side| add_gaps(side))
+            .collect();
+        return FDS {
+            bios: cartridge.get_prg_rom().to_vec(),
+            prg_ram: vec![0; cartridge.get_prg_ram_size()],
+            chr_ram: vec![0; cartridge.get_chr_ram_size()],
+            disk_sides,
+            side: Some(0),
+            insert_delay: 0,
+            io_enable: 0,
+            timer_reload: 0,
+            timer_counter: 0,
+            timer_repeat: false,
+            timer_enabled: false,
+            timer_irq: false,
+            control: 0,
+            external: 0,
+            write_data: 0,
+            read_data: 0,
+            transferred: false,
+            disk_irq: false,
+            motor_on: false,
+            scanning: false,
+            end_of_head: true,
+            gap_ended: false,
+            position: 0,
+            delay: 0,
+            audio: FDSAudio::init(),
+        };
+    }
+
+    fn is_disk_inserted(&self) -> bool {
+        return self.side.is_some() && self.insert_delay == 0;
+    }
+
+    fn tick_timer(&mut self) {
+        if !self.timer_enabled {
+            return;
+        }
+        if self.timer_counter > 0 {
+            self.timer_counter -= 1;
+            return;
+        }
+        self.timer_irq = true;
+        self.timer_counter = self.timer_reload;
+        self.timer_enabled = self.timer_repeat;
+    }
+
+    // moves the disk a byte past the head every BYTE_CYCLES once the motor
+    // is running and the head is back at the start
+    fn tick_drive(&mut self) {
+        if self.insert_delay > 0 {
+            self.insert_delay -= 1;
+        }
+        let side = match self.side {
+            Some(side) if self.is_disk_inserted() && self.motor_on => side,
+            _ => {
+                self.end_of_head = true;
+                self.scanning = false;
+                return;
+            }
+        };
+        if self.control & CONTROL_TRANSFER_RESET != 0 && !self.scanning {
+            return;
+        }
+        if self.end_of_head {
+            self.end_of_head = false;
+            self.delay = HEAD_RETURN_CYCLES;
+            self.position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+        self.scanning = true;
+
+        let raw = &mut self.disk_sides[side];
+        let mut irq = self.control & CONTROL_DISK_IRQ != 0;
+        let transfer = self.control & CONTROL_TRANSFER != 0;
+        if self.control & CONTROL_READ != 0 {
+            let data = raw[self.position];
+            if !transfer {
+                self.gap_ended = false;
+            } else if data != 0 && !self.gap_ended {
+                // the start mark ends the gap without interrupting
+                self.gap_ended = true;
+                irq = false;
+            }
+            if self.gap_ended {
+                self.read_data = data;
+                self.transferred = true;
+                self.disk_irq |= irq;
+            }
+        } else {
+            // the CRC the drive appends is left as it was
+            if self.control & CONTROL_CRC == 0 {
+                raw[self.position] = if transfer { self.write_data } else { 0 };
+                self.transferred = true;
+                self.disk_irq |= irq;
+            }
+            self.gap_ended = false;
+        }
+
+        self.position += 1;
+        if self.position >= raw.len() {
+            self.motor_on = false;
+        } else {
+            self.delay = BYTE_CYCLES;
+        }
+    }
+}
+
+impl Mapper for FDS {
+    fn read_prg(&mut self, addr: u16) -> u8 {
+        if let Some(offset) = self.get_prg_rom_offset(addr) {
+            return self.bios[offset];
+        }
+        return self.prg_ram[(addr - START_CARTRIDGE_RAM) as usize];
+    }
+
+    fn write_prg(&mut self, addr: u16, val: u8) {
+        if addr < START_BIOS {
+            self.prg_ram[(addr - START_CARTRIDGE_RAM) as usize] = val;
+        }
+    }
+
+    fn read_chr(&mut self, addr: u16) -> u8 {
+        return self.chr_ram[addr as usize];
+    }
+
+    fn write_chr(&mut self, addr: u16, val: u8) {
+        self.chr_ram[addr as usize] = val;
+    }
+
+    fn get_mirroring(&self) -> Mirroring {
+        if self.control & CONTROL_HORIZONTAL != 0 {
+            return Mirroring::Horizontal;
+        }
+        return Mirroring::Vertical;
+    }
+
+    fn irq_pending(&self) -> bool {
+        return self.timer_irq || self.disk_irq;
+    }
+
+    fn tick(&mut self) {
+        self.tick_timer();
+        self.tick_drive();
+        self.audio.tick();
+    }
+
+    fn get_audio_output(&self) -> f32 {
+        return self.audio.output() * WAVETABLE_LEVEL;
+    }
+
+    fn get_prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr < START_BIOS {
+            return None;
+        }
+        return Some((addr - START_BIOS) as usize);
+    }
+
+    fn get_prg_rom_mut(&mut self) -> Option<&mut [u8]> {
+        return Some(&mut self.bios);
+    }
+
+    fn read_expansion(&mut self, addr: u16) -> Option<u8> {
+        let val = self.peek_expansion(addr);
+        if val.is_some() && matches!(addr, DISK_STATUS | READ_DATA) {
+            if addr == DISK_STATUS {
+                self.timer_irq = false;
+            }
+            self.disk_irq = false;
+            self.transferred = false;
+        }
+        return val;
+    }
+
+    fn peek_expansion(&self, addr: u16) -> Option<u8> {
+        let disk_enabled = self.io_enable & IO_ENABLE_DISK != 0;
+        match addr {
+            DISK_STATUS if disk_enabled => {
+                let mut status = 0;
+                if self.timer_irq {
+                    status |= STATUS_TIMER_IRQ;
+                }
+                if self.transferred {
+                    status |= STATUS_TRANSFERRED;
+                }
+                if self.end_of_head {
+                    status |= STATUS_END_OF_HEAD;
+                }
+                return Some(status);
+            }
+            READ_DATA if disk_enabled => return Some(self.read_data),
+            DRIVE_STATUS if disk_enabled => {
+                let mut status = 0;
+                if !self.is_disk_inserted() {
+                    status |= DRIVE_NO_DISK | DRIVE_WRITE_PROTECTED;
+                }
+                if !self.is_disk_inserted() || !self.scanning {
+                    status |= DRIVE_NOT_READY;
+                }
+                return Some(status);
+            }
+            EXTERNAL_READ if disk_enabled => return Some(EXTERNAL_BATTERY_GOOD),
+            START_AUDIO..=END_AUDIO if self.io_enable & IO_ENABLE_SOUND != 0 => {
+                return self.audio.read_register(addr);
+            }
+            _ => return None,
+        }
+    }
+
+    fn write_expansion(&mut self, addr: u16, val: u8) {
+        let disk_enabled = self.io_enable & IO_ENABLE_DISK != 0;
+        match addr {
+            TIMER_RELOAD_LOW => self.timer_reload = (self.timer_reload & 0xFF00) | val as u16,
+            TIMER_RELOAD_HIGH => {
+                self.timer_reload = (self.timer_reload & 0x00FF) | (val as u16) << 8;
+            }
+            TIMER_CONTROL if disk_enabled => {
+                self.timer_repeat = val & TIMER_REPEAT != 0;
+                self.timer_enabled = val & TIMER_ENABLE != 0;
+                if self.timer_enabled {
+                    self.timer_counter = self.timer_reload;
+                } else {
+                    self.timer_irq = false;
+                }
+            }
+            IO_ENABLE => {
+                self.io_enable = val;
+                if val & IO_ENABLE_DISK == 0 {
+                    self.timer_enabled = false;
+                    self.timer_irq = false;
+                    self.disk_irq = false;
+                }
+            }
+            WRITE_DATA if disk_enabled => {
+                self.write_data = val;
+                self.transferred = false;
+                self.disk_irq = false;
+            }
+            CONTROL if disk_enabled => {
+                self.control = val;
+                self.motor_on = val & CONTROL_MOTOR != 0;
+                self.disk_irq = false;
+            }
+            EXTERNAL_WRITE if disk_enabled => self.external = val,
+            START_AUDIO..=END_AUDIO if self.io_enable & IO_ENABLE_SOUND != 0 => {
+                self.audio.write_register(addr, val);
+            }
+            _ => {}
+        }
+    }
+
+    fn get_disk_side_count(&self) -> usize {
+        return self.disk_sides.len();
+    }
+
+    fn get_disk_side(&self) -> Option<usize> {
+        return self.side;
+    }
+
+    fn set_disk_side(&mut self, side: Option<usize>) {
+        self.side = side.filter(|&side| side < self.disk_sides.len());
+        self.insert_delay = if self.side.is_some() {
+            INSERT_CYCLES
+        } else {
+            0
+        };
+    }
+
+    fn get_disk_image(&self) -> Option<Vec<u8>> {
+        return Some(
+            self.disk_sides
+                .iter()
+                .flat_map(|raw| strip_gaps(raw))
+                .collect(),
+        );
+    }
+
+    fn set_disk_image(&mut self, image: &[u8]) {
+        for (raw, side) in self.disk_sides.iter_mut().zip(image.chunks(FDS_SIDE_SIZE)) {
+            *raw = add_gaps(side);
+        }
+    }
+}
+
+impl SaveState for FDS {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.prg_ram);
+        state.write(&self.chr_ram);
+        for raw in &self.disk_sides {
+            state.write(raw);
+        }
+        state.write(&self.side.map_or(u8::MAX, |side| side as u8));
+        state.write(&self.insert_delay);
+        state.write(&self.io_enable);
+        state.write(&self.timer_reload);
+        state.write(&self.timer_counter);
+        state.write(&self.timer_repeat);
+        state.write(&self.timer_enabled);
+        state.write(&self.timer_irq);
+        state.write(&self.control);
+        state.write(&self.external);
+        state.write(&self.write_data);
+        state.write(&self.read_data);
+        state.write(&self.transferred);
+        state.write(&self.disk_irq);
+        state.write(&self.motor_on);
+        state.write(&self.scanning);
+        state.write(&self.end_of_head);
+        state.write(&self.gap_ended);
+        state.write(&self.position);
+        state.write(&self.delay);
+        state.write(&self.audio);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read(&mut self.prg_ram)?;
+        state.read(&mut self.chr_ram)?;
+        for raw in &mut self.disk_sides {
+            state.read(raw)?;
+        }
+        let mut side = 0u8;
+        state.read(&mut side)?;
+        self.side = match side {
+            u8::MAX => None,
+            side if (side as usize) < self.disk_sides.len() => Some(side as usize),
+            _ => return Err(StateError::Invalid("disk side")),
+        };
+        state.read(&mut self.insert_delay)?;
+        state.read(&mut self.io_enable)?;
+        state.read(&mut self.timer_reload)?;
+        state.read(&mut self.timer_counter)?;
+        state.read(&mut self.timer_repeat)?;
+        state.read(&mut self.timer_enabled)?;
+        state.read(&mut self.timer_irq)?;
+        state.read(&mut self.control)?;
+        state.read(&mut self.external)?;
+        state.read(&mut self.write_data)?;
+        state.read(&mut self.read_data)?;
+        state.read(&mut self.transferred)?;
+        state.read(&mut self.disk_irq)?;
+        state.read(&mut self.motor_on)?;
+        state.read(&mut self.scanning)?;
+        state.read(&mut self.end_of_head)?;
+        state.read(&mut self.gap_ended)?;
+        state.read(&mut self.position)?;
+        if self.position >= RAW_SIDE_SIZE {
+            return Err(StateError::Invalid("disk position"));
+        }
+        state.read(&mut self.delay)?;
+        state.read(&mut self.audio)?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::fds_image;
+    use crate::cartridge::HEADER_SIZE;
+
+    fn disk_with_file(data: &[u8]) -> Vec<u8> {
+        let mut side = fds_image(1)[HEADER_SIZE..].to_vec();
+        let mut offset = 56;
+        side[offset..offset + 2].copy_from_slice(&[FILE_COUNT_BLOCK, 1]);
+        offset += 2;
+        side[offset] = FILE_HEADER_BLOCK;
+        side[offset + FILE_SIZE] = data.len() as u8;
+        offset += 16;
+        side[offset] = FILE_DATA_BLOCK;
+        side[offset + 1..offset + 1 + data.len()].copy_from_slice(data);
+        return side;
+    }
+
+    fn fds_with_disk(side: &[u8]) -> FDS {
+        let cartridge = Cartridge::from_fds(side, &[0; 0x2000]).unwrap();
+        let mut fds = FDS::init(&cartridge);
+        fds.write_expansion(IO_ENABLE, IO_ENABLE_DISK | IO_ENABLE_SOUND);
+        return fds;
+    }
+
+    #[test]
+    fn test_gaps_round_trip() {
+        let side = disk_with_file(&[1, 2, 3]);
+        let raw = add_gaps(&side);
+        assert_eq!(raw.len(), RAW_SIDE_SIZE);
+        assert_eq!(raw[LEAD_IN_GAP], BLOCK_START);
+        assert_eq!(raw[LEAD_IN_GAP + 1], DISK_INFO_BLOCK);
+        assert_eq!(strip_gaps(&raw), side);
+    }
+
+    #[test]
+    fn test_reads_blocks_with_irqs() {
+        let mut fds = fds_with_disk(&disk_with_file(&[0xAA]));
+        fds.write_expansion(
+            CONTROL,
+            CONTROL_MOTOR | CONTROL_READ | CONTROL_TRANSFER | CONTROL_DISK_IRQ,
+        );
+        // the head returns, then the gap goes by until the start mark
+        let mut cycles = 0;
+        while !fds.irq_pending() {
+            fds.tick();
+            cycles += 1;
+        }
+        let gap_cycles = (LEAD_IN_GAP + 1) as u32 * (BYTE_CYCLES + 1);
+        assert!(cycles > HEAD_RETURN_CYCLES + gap_cycles);
+        assert_eq!(fds.read_expansion(DISK_STATUS), Some(STATUS_TRANSFERRED));
+        assert_eq!(fds.read_expansion(READ_DATA), Some(DISK_INFO_BLOCK));
+        assert!(!fds.irq_pending());
+        assert_eq!(fds.read_expansion(DRIVE_STATUS), Some(0));
+
+        // ejecting empties the drive, reinserting takes a moment
+        fds.set_disk_side(None);
+        fds.tick();
+        assert_eq!(fds.read_expansion(DRIVE_STATUS), Some(0b111));
+        fds.set_disk_side(Some(0));
+        for _ in 0..INSERT_CYCLES {
+            fds.tick();
+        }
+        assert_eq!(fds.read_expansion(DRIVE_STATUS).unwrap() & DRIVE_NO_DISK, 0);
+    }
+
+    #[test]
+    fn test_writes_reach_the_image() {
+        let side = disk_with_file(&[0xAA]);
+        let mut fds = fds_with_disk(&side);
+        // write the gap up to the start mark, then rewrite the start of the
+        // disk info block
+        fds.write_expansion(CONTROL, CONTROL_MOTOR);
+        while fds.position < LEAD_IN_GAP {
+            fds.tick();
+        }
+        fds.write_expansion(CONTROL, CONTROL_MOTOR | CONTROL_TRANSFER);
+        for val in [BLOCK_START, DISK_INFO_BLOCK, 0x55] {
+            fds.write_expansion(WRITE_DATA, val);
+            let position = fds.position;
+            while fds.position == position {
+                fds.tick();
+            }
+        }
+        let image = fds.get_disk_image().unwrap();
+        assert_eq!(image.len(), FDS_SIDE_SIZE);
+        assert_eq!(image[1], 0x55);
+        assert_eq!(image[2..], side[2..]);
+    }
+
+    #[test]
+    fn test_load_state_checks_indices() {
+        let mut fds = fds_with_disk(&disk_with_file(&[]));
+        fds.side = Some(1);
+        let mut state = StateWriter::init();
+        state.write(&fds);
+        let data = state.into_bytes();
+        assert_eq!(
+            StateReader::init(&data).read(&mut fds),
+            Err(StateError::Invalid("disk side"))
+        );
+
+        fds.side = Some(0);
+        fds.position = RAW_SIDE_SIZE;
+        let mut state = StateWriter::init();
+        state.write(&fds);
+        let data = state.into_bytes();
+        assert_eq!(
+            StateReader::init(&data).read(&mut fds),
+            Err(StateError::Invalid("disk position"))
+        );
+    }
+
+    #[test]
+    fn test_timer_irq() {
+        let mut fds = fds_with_disk(&disk_with_file(&[]));
+        fds.write_expansion(TIMER_RELOAD_LOW, 2);
+        fds.write_expansion(TIMER_CONTROL, TIMER_ENABLE | TIMER_REPEAT);
+        for _ in 0..3 {
+            assert!(!fds.irq_pending());
+            fds.tick();
+        }
+        assert!(fds.irq_pending());
+        // debuggers can look without acknowledging it
+        assert_eq!(
+            fds.peek_expansion(DISK_STATUS).unwrap() & STATUS_TIMER_IRQ,
+            1
+        );
+        assert!(fds.irq_pending());
+        assert_eq!(
+            fds.read_expansion(DISK_STATUS).unwrap() & STATUS_TIMER_IRQ,
+            1
+        );
+        assert!(!fds.irq_pending());
+
+        // turning disk I/O off stops the timer and drops its IRQ
+        fds.write_expansion(TIMER_CONTROL, TIMER_ENABLE);
+        for _ in 0..3 {
+            fds.tick();
+        }
+        assert!(fds.irq_pending());
+        fds.write_expansion(IO_ENABLE, 0);
+        assert!(!fds.irq_pending());
+        assert!(!fds.timer_enabled);
+    }
+}
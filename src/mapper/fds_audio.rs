@@ -0,0 +1,406 @@
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+const WAVE_RAM: u16 = 0x4040;
+const END_WAVE_RAM: u16 = 0x407F;
+const VOLUME_ENVELOPE: u16 = 0x4080;
+const PITCH_LOW: u16 = 0x4082;
+const PITCH_HIGH: u16 = 0x4083;
+const MOD_ENVELOPE: u16 = 0x4084;
+const MOD_COUNTER: u16 = 0x4085;
+const MOD_PITCH_LOW: u16 = 0x4086;
+const MOD_PITCH_HIGH: u16 = 0x4087;
+const MOD_TABLE: u16 = 0x4088;
+const WAVE_CONTROL: u16 = 0x4089;
+const ENVELOPE_SPEED: u16 = 0x408A;
+const VOLUME_GAIN: u16 = 0x4090;
+const MOD_GAIN: u16 = 0x4092;
+
+const ENVELOPE_INCREASE: u8 = 0x40;
+const ENVELOPE_DISABLE: u8 = 0x80;
+const PITCH_ENVELOPES_HALT: u8 = 0x40;
+const PITCH_WAVE_HALT: u8 = 0x80;
+const MOD_HALT: u8 = 0x80;
+const WAVE_WRITE: u8 = 0x80;
+
+const WAVE_STEPS: usize = 64;
+const MAX_GAIN: u8 = 32;
+const DEFAULT_ENVELOPE_SPEED: u8 = 0xE8;
+// steps of the 64-entry modulation table, with 4 resetting the counter
+const MOD_STEPS: [i8; 8] = [0, 1, 2, 4, 0, -4, -2, -1];
+// the master volume's 2/2, 2/3, 2/4 and 2/5, over 1152 so a full wave at
+// full gain comes out at 63
+const MASTER_VOLUMES: [u32; 4] = [36, 24, 17, 14];
+
+#[derive(Debug)]
+struct Envelope {
+    speed: u8,
+    gain: u8,
+    increase: bool,
+    disabled: bool,
+    timer: u32,
+}
+
+impl Envelope {
+    fn init() -> Self {
+        return Envelope {
+            speed: 0,
+            gain: 0,
+            increase: false,
+            disabled: true,
+            timer: 0,
+        };
+    }
+
+    // with the envelope off, the speed bits set the gain directly
+    fn write(&mut self, val: u8, master_speed: u8) {
+        self.speed = val & 0x3F;
+        self.increase = val & ENVELOPE_INCREASE != 0;
+        self.disabled = val & ENVELOPE_DISABLE != 0;
+        if self.disabled {
+            self.gain = self.speed;
+        }
+        self.reset_timer(master_speed);
+    }
+
+    fn reset_timer(&mut self, master_speed: u8) {
+        self.timer = 8 * (self.speed as u32 + 1) * master_speed as u32;
+    }
+
+    fn tick(&mut self, master_speed: u8) {
+        if self.disabled || master_speed == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.reset_timer(master_speed);
+        if self.increase && self.gain < MAX_GAIN {
+            self.gain += 1;
+        } else if !self.increase && self.gain > 0 {
+            self.gain -= 1;
+        }
+    }
+}
+
+/// The disk system's sound: a 64-step, 6-bit wavetable with a volume
+/// envelope, and a modulator bending its pitch through a table of steps.
+#[derive(Debug)]
+pub(crate) struct FDSAudio {
+    wave: [u8; WAVE_STEPS],
+    wave_write: bool,
+    master_volume: u8,
+    envelope_speed: u8,
+    envelopes_halted: bool,
+    wave_halted: bool,
+    volume: Envelope,
+    pitch: u16,
+    wave_accumulator: u32,
+    wave_position: u8,
+    output: u8,
+
+    modulator: Envelope,
+    mod_pitch: u16,
+    mod_halted: bool,
+    mod_table: [u8; WAVE_STEPS],
+    mod_position: u8,
+    mod_accumulator: u32,
+    // 7-bit signed
+    mod_counter: i8,
+}
+
+impl FDSAudio {
+    pub(crate) fn init() -> Self {
+        return FDSAudio {
+            wave: [0; WAVE_STEPS],
+            wave_write: false,
+            master_volume: 0,
+            envelope_speed: DEFAULT_ENVELOPE_SPEED,
+            envelopes_halted: false,
+            wave_halted: true,
+            volume: Envelope::init(),
+            pitch: 0,
+            wave_accumulator: 0,
+            wave_position: 0,
+            output: 0,
+            modulator: Envelope::init(),
+            mod_pitch: 0,
+            mod_halted: true,
+            mod_table: [0; WAVE_STEPS],
+            mod_position: 0,
+            mod_accumulator: 0,
+            mod_counter: 0,
+        };
+    }
+
+    /// Registers at $4040-$408A.
+    pub(crate) fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            WAVE_RAM..=END_WAVE_RAM if self.wave_write => {
+                self.wave[(addr - WAVE_RAM) as usize] = val & 0x3F;
+            }
+            VOLUME_ENVELOPE => self.volume.write(val, self.envelope_speed),
+            PITCH_LOW => self.pitch = (self.pitch & 0x0F00) | val as u16,
+            PITCH_HIGH => {
+                self.pitch = (self.pitch & 0x00FF) | ((val & 0x0F) as u16) << 8;
+                self.envelopes_halted = val & PITCH_ENVELOPES_HALT != 0;
+                self.wave_halted = val & PITCH_WAVE_HALT != 0;
+                if self.wave_halted {
+                    self.wave_position = 0;
+                    self.wave_accumulator = 0;
+                }
+                if self.envelopes_halted {
+                    self.volume.reset_timer(self.envelope_speed);
+                    self.modulator.reset_timer(self.envelope_speed);
+                }
+            }
+            MOD_ENVELOPE => self.modulator.write(val, self.envelope_speed),
+            MOD_COUNTER => self.mod_counter = ((val << 1) as i8) >> 1,
+            MOD_PITCH_LOW => self.mod_pitch = (self.mod_pitch & 0x0F00) | val as u16,
+            MOD_PITCH_HIGH => {
+                self.mod_pitch = (self.mod_pitch & 0x00FF) | ((val & 0x0F) as u16) << 8;
+                self.mod_halted = val & MOD_HALT != 0;
+                if self.mod_halted {
+                    self.mod_accumulator = 0;
+                }
+            }
+            // each write fills two steps, and only while the modulator is
+            // halted, which can leave it on an odd step
+            MOD_TABLE if self.mod_halted => {
+                let position = (self.mod_position & !1) as usize;
+                self.mod_table[position] = val & 0b111;
+                self.mod_table[position + 1] = val & 0b111;
+                self.mod_position = ((position + 2) % WAVE_STEPS) as u8;
+            }
+            WAVE_CONTROL => {
+                self.wave_write = val & WAVE_WRITE != 0;
+                self.master_volume = val & 0b11;
+            }
+            ENVELOPE_SPEED => self.envelope_speed = val,
+            _ => {}
+        }
+    }
+
+    pub(crate) fn read_register(&self, addr: u16) -> Option<u8> {
+        match addr {
+            WAVE_RAM..=END_WAVE_RAM => return Some(self.wave[(addr - WAVE_RAM) as usize]),
+            VOLUME_GAIN => return Some(self.volume.gain),
+            MOD_GAIN => return Some(self.modulator.gain),
+            _ => return None,
+        }
+    }
+
+    // the modulator's counter times its gain, scaled to bend the pitch
+    fn get_modulated_pitch(&self) -> u32 {
+        let counter = self.mod_counter as i32;
+        let mut temp = counter * self.modulator.gain as i32;
+        let remainder = temp & 0x0F;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            temp += if counter < 0 { -1 } else { 2 };
+        }
+        if temp >= 192 {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+        let pitch = self.pitch as i32;
+        temp *= pitch;
+        let remainder = temp & 0x3F;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+        return (pitch + temp).max(0) as u32;
+    }
+
+    pub(crate) fn tick(&mut self) {
+        if !self.wave_halted && !self.envelopes_halted {
+            self.volume.tick(self.envelope_speed);
+            self.modulator.tick(self.envelope_speed);
+        }
+
+        if !self.mod_halted && self.mod_pitch > 0 {
+            self.mod_accumulator += self.mod_pitch as u32;
+            if self.mod_accumulator >= 0x10000 {
+                self.mod_accumulator -= 0x10000;
+                let step = self.mod_table[self.mod_position as usize];
+                self.mod_counter = if step == 4 {
+                    0
+                } else {
+                    let counter = self.mod_counter + MOD_STEPS[step as usize];
+                    (counter << 1) >> 1
+                };
+                self.mod_position = (self.mod_position + 1) % WAVE_STEPS as u8;
+            }
+        }
+
+        // the output holds while the CPU can write the wave
+        if self.wave_write {
+            return;
+        }
+        if !self.wave_halted {
+            self.wave_accumulator += self.get_modulated_pitch();
+            if self.wave_accumulator >= 0x10000 {
+                self.wave_accumulator -= 0x10000;
+                self.wave_position = (self.wave_position + 1) % WAVE_STEPS as u8;
+            }
+        }
+        let level =
+            self.volume.gain.min(MAX_GAIN) as u32 * MASTER_VOLUMES[self.master_volume as usize];
+        self.output = (self.wave[self.wave_position as usize] as u32 * level / 1152) as u8;
+    }
+
+    /// From 0 to 63.
+    pub(crate) fn output(&self) -> f32 {
+        return self.output as f32;
+    }
+}
+
+impl SaveState for Envelope {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.speed);
+        state.write(&self.gain);
+        state.write(&self.increase);
+        state.write(&self.disabled);
+        state.write(&self.timer);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read(&mut self.speed)?;
+        state.read(&mut self.gain)?;
+        state.read(&mut self.increase)?;
+        state.read(&mut self.disabled)?;
+        state.read(&mut self.timer)?;
+        return Ok(());
+    }
+}
+
+impl SaveState for FDSAudio {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.wave);
+        state.write(&self.wave_write);
+        state.write(&self.master_volume);
+        state.write(&self.envelope_speed);
+        state.write(&self.envelopes_halted);
+        state.write(&self.wave_halted);
+        state.write(&self.volume);
+        state.write(&self.pitch);
+        state.write(&self.wave_accumulator);
+        state.write(&self.wave_position);
+        state.write(&self.output);
+        state.write(&self.modulator);
+        state.write(&self.mod_pitch);
+        state.write(&self.mod_halted);
+        state.write(&self.mod_table);
+        state.write(&self.mod_position);
+        state.write(&self.mod_accumulator);
+        state.write(&(self.mod_counter as u8));
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read(&mut self.wave)?;
+        state.read(&mut self.wave_write)?;
+        state.read(&mut self.master_volume)?;
+        state.read(&mut self.envelope_speed)?;
+        state.read(&mut self.envelopes_halted)?;
+        state.read(&mut self.wave_halted)?;
+        state.read(&mut self.volume)?;
+        state.read(&mut self.pitch)?;
+        state.read(&mut self.wave_accumulator)?;
+        state.read(&mut self.wave_position)?;
+        if self.wave_position as usize >= WAVE_STEPS {
+            return Err(StateError::Invalid("wave position"));
+        }
+        state.read(&mut self.output)?;
+        state.read(&mut self.modulator)?;
+        state.read(&mut self.mod_pitch)?;
+        state.read(&mut self.mod_halted)?;
+        state.read(&mut self.mod_table)?;
+        state.read(&mut self.mod_position)?;
+        if self.mod_position as usize >= WAVE_STEPS {
+            return Err(StateError::Invalid("modulation position"));
+        }
+        state.read(&mut self.mod_accumulator)?;
+        let mut mod_counter = 0u8;
+        state.read(&mut mod_counter)?;
+        self.mod_counter = mod_counter as i8;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wave_steps_at_pitch() {
+        let mut audio = FDSAudio::init();
+        audio.write_register(WAVE_CONTROL, WAVE_WRITE);
+        for step in 0..WAVE_STEPS as u16 {
+            audio.write_register(WAVE_RAM + step, step as u8);
+        }
+        audio.write_register(WAVE_CONTROL, 0);
+        // full gain, with the envelope off
+        audio.write_register(VOLUME_ENVELOPE, ENVELOPE_DISABLE | MAX_GAIN);
+        assert_eq!(audio.read_register(VOLUME_GAIN), Some(MAX_GAIN));
+        // a step every 32 cycles
+        audio.write_register(PITCH_LOW, 0x00);
+        audio.write_register(PITCH_HIGH, 0x08);
+        let mut outputs = Vec::new();
+        for cycle in 1..=128 {
+            audio.tick();
+            if cycle % 32 == 0 {
+                outputs.push(audio.output());
+            }
+        }
+        assert_eq!(outputs, [1.0, 2.0, 3.0, 4.0]);
+
+        // a modulator stuck at +1 raises the pitch
+        audio.write_register(MOD_PITCH_HIGH, MOD_HALT);
+        for _ in 0..32 {
+            audio.write_register(MOD_TABLE, 1);
+        }
+        audio.write_register(MOD_ENVELOPE, ENVELOPE_DISABLE | 0x3F);
+        audio.write_register(MOD_COUNTER, 0x10);
+        assert!(audio.get_modulated_pitch() > 0x800);
+        audio.write_register(MOD_COUNTER, 0x70);
+        assert!(audio.get_modulated_pitch() < 0x800);
+    }
+
+    #[test]
+    fn test_mod_table_writes_after_odd_steps() {
+        let mut audio = FDSAudio::init();
+        audio.write_register(MOD_PITCH_LOW, 0xFF);
+        audio.write_register(MOD_PITCH_HIGH, 0x0F);
+        while audio.mod_position != WAVE_STEPS as u8 - 1 {
+            audio.tick();
+        }
+        audio.write_register(MOD_PITCH_HIGH, MOD_HALT);
+        audio.write_register(MOD_TABLE, 3);
+        assert_eq!(audio.mod_table[WAVE_STEPS - 2..], [3, 3]);
+        assert_eq!(audio.mod_position, 0);
+    }
+
+    #[test]
+    fn test_load_state_checks_positions() {
+        let reload = |audio: &mut FDSAudio| {
+            let mut state = StateWriter::init();
+            state.write(audio);
+            let data = state.into_bytes();
+            return StateReader::init(&data).read(audio);
+        };
+        let mut audio = FDSAudio::init();
+        audio.wave_position = WAVE_STEPS as u8;
+        assert_eq!(
+            reload(&mut audio),
+            Err(StateError::Invalid("wave position"))
+        );
+        audio.wave_position = 0;
+        audio.mod_position = WAVE_STEPS as u8;
+        assert_eq!(
+            reload(&mut audio),
+            Err(StateError::Invalid("modulation position"))
+        );
+    }
+}
@@ -0,0 +1,543 @@
+use crate::cartridge::{Cartridge, Mirroring};
+use crate::mapper::Mapper;
+use crate::ram::{START_CARTRIDGE_RAM, START_CARTRIDGE_ROM};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x400;
+
+const COMMAND: u16 = 0x8000;
+const PARAMETER: u16 = 0xA000;
+const AUDIO_ADDRESS: u16 = 0xC000;
+
+const COMMAND_PRG_RAM_BANK: u8 = 0x8;
+const COMMAND_MIRRORING: u8 = 0xC;
+const COMMAND_IRQ_CONTROL: u8 = 0xD;
+const COMMAND_IRQ_LOW: u8 = 0xE;
+
+const RAM_SELECT: u8 = 0x40;
+const RAM_ENABLE: u8 = 0x80;
+const IRQ_ENABLE: u8 = 0x01;
+const IRQ_COUNTER_ENABLE: u8 = 0x80;
+
+const TONE_PERIOD: usize = 0x0;
+const NOISE_PERIOD: usize = 0x6;
+const MIXER: usize = 0x7;
+const VOLUME: usize = 0x8;
+const ENVELOPE_PERIOD: usize = 0xB;
+const ENVELOPE_SHAPE: usize = 0xD;
+
+const VOLUME_ENVELOPE: u8 = 0x10;
+const SHAPE_HOLD: u8 = 0x1;
+const SHAPE_ALTERNATE: u8 = 0x2;
+const SHAPE_ATTACK: u8 = 0x4;
+const SHAPE_CONTINUE: u8 = 0x8;
+
+// the tone and noise counters run at 1/16th of the CPU clock, the envelope
+// at twice that for its 32 steps
+const TONE_DIVIDER: u8 = 16;
+const ENVELOPE_DIVIDER: u8 = 8;
+const ENVELOPE_STEPS: u8 = 32;
+const DB_PER_STEP: f32 = 1.5;
+// a channel at full volume, in pulse volume steps
+const CHANNEL_LEVEL: f32 = 15.0;
+
+/// The AY-3-8910 style sound of the Sunsoft 5B: three square tones that can
+/// each mix in the shared noise, with a fixed or enveloped logarithmic
+/// volume.
+#[derive(Debug)]
+struct Sunsoft5B {
+    address: u8,
+    registers: [u8; 16],
+    divider: u8,
+    tone_counters: [u16; 3],
+    tone_outputs: [bool; 3],
+    noise_counter: u8,
+    // 17-bit LFSR
+    noise_shift: u32,
+    envelope_counter: u16,
+    envelope_step: u8,
+    envelope_attack: bool,
+    envelope_holding: bool,
+    // amplitude of each 1.5dB level, 0 being silent
+    levels: [f32; ENVELOPE_STEPS as usize],
+}
+
+impl Sunsoft5B {
+    fn init() -> Self {
+        let mut levels = [0.0; ENVELOPE_STEPS as usize];
+        for (level, amplitude) in levels.iter_mut().enumerate().skip(1) {
+            let db = (ENVELOPE_STEPS as usize - 1 - level) as f32 * DB_PER_STEP;
+            *amplitude = 10.0f32.powf(-db / 20.0);
+        }
+        return Sunsoft5B {
+            address: 0,
+            registers: [0; 16],
+            divider: 0,
+            tone_counters: [0; 3],
+            tone_outputs: [false; 3],
+            noise_counter: 0,
+            noise_shift: 1,
+            envelope_counter: 0,
+            envelope_step: 0,
+            envelope_attack: false,
+            envelope_holding: false,
+            levels,
+        };
+    }
+
+    fn write_address(&mut self, val: u8) {
+        self.address = val & 0x0F;
+    }
+
+    fn write_data(&mut self, val: u8) {
+        self.registers[self.address as usize] = val;
+        if self.address as usize == ENVELOPE_SHAPE {
+            self.envelope_step = 0;
+            self.envelope_attack = val & SHAPE_ATTACK != 0;
+            self.envelope_holding = false;
+        }
+    }
+
+    fn get_tone_period(&self, channel: usize) -> u16 {
+        let low = self.registers[TONE_PERIOD + channel * 2] as u16;
+        let high = (self.registers[TONE_PERIOD + channel * 2 + 1] & 0x0F) as u16;
+        return (high << 8 | low).max(1);
+    }
+
+    fn tick(&mut self) {
+        self.divider += 1;
+        if self.divider == ENVELOPE_DIVIDER || self.divider == TONE_DIVIDER {
+            self.clock_envelope();
+        }
+        if self.divider < TONE_DIVIDER {
+            return;
+        }
+        self.divider = 0;
+
+        for channel in 0..3 {
+            self.tone_counters[channel] += 1;
+            if self.tone_counters[channel] >= self.get_tone_period(channel) {
+                self.tone_counters[channel] = 0;
+                self.tone_outputs[channel] = !self.tone_outputs[channel];
+            }
+        }
+        // the noise steps at half the rate of a tone with the same period
+        self.noise_counter += 1;
+        if self.noise_counter >= (self.registers[NOISE_PERIOD] & 0x1F).max(1) * 2 {
+            self.noise_counter = 0;
+            let feedback = (self.noise_shift ^ (self.noise_shift >> 3)) & 1;
+            self.noise_shift = (self.noise_shift >> 1) | (feedback << 16);
+        }
+    }
+
+    fn clock_envelope(&mut self) {
+        let period = u16::from_le_bytes([
+            self.registers[ENVELOPE_PERIOD],
+            self.registers[ENVELOPE_PERIOD + 1],
+        ]);
+        self.envelope_counter += 1;
+        if self.envelope_counter < period.max(1) {
+            return;
+        }
+        self.envelope_counter = 0;
+        if self.envelope_holding {
+            return;
+        }
+        self.envelope_step += 1;
+        if self.envelope_step < ENVELOPE_STEPS {
+            return;
+        }
+        let shape = self.registers[ENVELOPE_SHAPE];
+        if shape & SHAPE_CONTINUE == 0 {
+            // silent after one cycle
+            self.envelope_attack = false;
+            self.envelope_holding = true;
+        } else if shape & SHAPE_HOLD != 0 {
+            self.envelope_attack ^= shape & SHAPE_ALTERNATE != 0;
+            self.envelope_holding = true;
+        } else {
+            self.envelope_attack ^= shape & SHAPE_ALTERNATE != 0;
+            self.envelope_step = 0;
+            return;
+        }
+        self.envelope_step = ENVELOPE_STEPS - 1;
+    }
+
+    fn get_envelope_level(&self) -> u8 {
+        if self.envelope_attack {
+            return self.envelope_step;
+        }
+        return ENVELOPE_STEPS - 1 - self.envelope_step;
+    }
+
+    fn output(&self) -> f32 {
+        let mixer = self.registers[MIXER];
+        let noise = self.noise_shift & 1 != 0;
+        let mut output = 0.0;
+        for channel in 0..3 {
+            let tone_off = mixer & (1 << channel) != 0;
+            let noise_off = mixer & (8 << channel) != 0;
+            if !((tone_off || self.tone_outputs[channel]) && (noise_off || noise)) {
+                continue;
+            }
+            let volume = self.registers[VOLUME + channel];
+            // fixed volumes land on every other envelope level
+            let level = if volume & VOLUME_ENVELOPE != 0 {
+                self.get_envelope_level()
+            } else if volume & 0x0F == 0 {
+                0
+            } else {
+                (volume & 0x0F) * 2 + 1
+            };
+            output += self.levels[level as usize];
+        }
+        return output * CHANNEL_LEVEL;
+    }
+}
+
+/// Mapper 69, Sunsoft's FME-7: a command/parameter pair of registers for
+/// four 8KB PRG banks (one at $6000, which can also be RAM), eight 1KB CHR
+/// banks, mirroring and a cycle counting IRQ, with the 5B's sound on the
+/// boards that have it.
+#[derive(Debug)]
+pub struct FME7 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    has_battery: bool,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+
+    command: u8,
+    prg_banks: [u8; 4],
+    chr_banks: [u8; 8],
+    mirroring: u8,
+    irq_control: u8,
+    irq_counter: u16,
+    irq_pending: bool,
+    audio: Sunsoft5B,
+}
+
+impl FME7 {
+    pub fn init(cartridge: &Cartridge) -> Self {
+        let chr_is_ram = cartridge.get_chr_rom().is_empty();
+        let chr = if chr_is_ram {
+            vec![0; cartridge.get_chr_ram_size()]
+        } else {
+            cartridge.get_chr_rom().to_vec()
+        };
+        return FME7 {
+            prg_rom: cartridge.get_prg_rom().to_vec(),
+            prg_ram: vec![0; cartridge.get_prg_ram_size()],
+            has_battery: cartridge.has_battery(),
+            chr,
+            chr_is_ram,
+            command: 0,
+            prg_banks: [0; 4],
+            chr_banks: [0; 8],
+            mirroring: 0,
+            irq_control: 0,
+            irq_counter: 0,
+            irq_pending: false,
+            audio: Sunsoft5B::init(),
+        };
+    }
+
+    fn write_parameter(&mut self, val: u8) {
+        match self.command {
+            0..=7 => self.chr_banks[self.command as usize] = val,
+            COMMAND_PRG_RAM_BANK..COMMAND_MIRRORING => {
+                self.prg_banks[(self.command - COMMAND_PRG_RAM_BANK) as usize] = val;
+            }
+            COMMAND_MIRRORING => self.mirroring = val & 0b11,
+            COMMAND_IRQ_CONTROL => {
+                self.irq_control = val;
+                self.irq_pending = false;
+            }
+            COMMAND_IRQ_LOW => self.irq_counter = (self.irq_counter & 0xFF00) | val as u16,
+            _ => self.irq_counter = (self.irq_counter & 0x00FF) | (val as u16) << 8,
+        }
+    }
+
+    fn get_chr_offset(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[addr as usize / CHR_BANK_SIZE] as usize;
+        return (bank * CHR_BANK_SIZE + addr as usize % CHR_BANK_SIZE) % self.chr.len();
+    }
+
+    fn get_prg_offset(&self, bank: u8, addr: u16) -> usize {
+        let offset = bank as usize * PRG_BANK_SIZE + addr as usize % PRG_BANK_SIZE;
+        return offset % self.prg_rom.len();
+    }
+
+    fn is_ram_selected(&self) -> bool {
+        return self.prg_banks[0] & RAM_SELECT != 0;
+    }
+}
+
+impl Mapper for FME7 {
+    fn read_prg(&mut self, addr: u16) -> u8 {
+        if let Some(offset) = self.get_prg_rom_offset(addr) {
+            return self.prg_rom[offset];
+        }
+        if !self.is_ram_selected() {
+            return self.prg_rom[self.get_prg_offset(self.prg_banks[0] & 0x3F, addr)];
+        }
+        if self.prg_ram.is_empty() || self.prg_banks[0] & RAM_ENABLE == 0 {
+            return 0;
+        }
+        return self.prg_ram[(addr - START_CARTRIDGE_RAM) as usize % self.prg_ram.len()];
+    }
+
+    fn write_prg(&mut self, addr: u16, val: u8) {
+        match addr & 0xE000 {
+            COMMAND => self.command = val & 0x0F,
+            PARAMETER => self.write_parameter(val),
+            AUDIO_ADDRESS => self.audio.write_address(val),
+            0xE000 => self.audio.write_data(val),
+            _ => {
+                let ram_writable =
+                    self.prg_banks[0] & (RAM_SELECT | RAM_ENABLE) == RAM_SELECT | RAM_ENABLE;
+                if ram_writable && !self.prg_ram.is_empty() {
+                    let len = self.prg_ram.len();
+                    self.prg_ram[(addr - START_CARTRIDGE_RAM) as usize % len] = val;
+                }
+            }
+        }
+    }
+
+    fn read_chr(&mut self, addr: u16) -> u8 {
+        return self.chr[self.get_chr_offset(addr)];
+    }
+
+    fn write_chr(&mut self, addr: u16, val: u8) {
+        if self.chr_is_ram {
+            let offset = self.get_chr_offset(addr);
+            self.chr[offset] = val;
+        }
+    }
+
+    fn get_mirroring(&self) -> Mirroring {
+        match self.mirroring {
+            0 => return Mirroring::Vertical,
+            1 => return Mirroring::Horizontal,
+            2 => return Mirroring::SingleScreenA,
+            _ => return Mirroring::SingleScreenB,
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        return self.irq_pending;
+    }
+
+    fn tick(&mut self) {
+        if self.irq_control & IRQ_COUNTER_ENABLE != 0 {
+            self.irq_counter = self.irq_counter.wrapping_sub(1);
+            if self.irq_counter == 0xFFFF && self.irq_control & IRQ_ENABLE != 0 {
+                self.irq_pending = true;
+            }
+        }
+        self.audio.tick();
+    }
+
+    fn get_audio_output(&self) -> f32 {
+        return self.audio.output();
+    }
+
+    fn get_prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr < START_CARTRIDGE_ROM {
+            return None;
+        }
+        let slot = (addr - START_CARTRIDGE_ROM) as usize / PRG_BANK_SIZE;
+        let bank = match self.prg_banks.get(slot + 1) {
+            Some(&bank) => bank & 0x3F,
+            None => (self.prg_rom.len() / PRG_BANK_SIZE - 1) as u8,
+        };
+        return Some(self.get_prg_offset(bank, addr));
+    }
+
+    fn get_prg_rom_mut(&mut self) -> Option<&mut [u8]> {
+        return Some(&mut self.prg_rom);
+    }
+
+    fn get_chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        if self.chr_is_ram {
+            return None;
+        }
+        return Some(self.get_chr_offset(addr));
+    }
+
+    fn get_battery_ram(&self) -> Option<&[u8]> {
+        if self.has_battery && !self.prg_ram.is_empty() {
+            return Some(&self.prg_ram);
+        }
+        return None;
+    }
+
+    fn get_battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        if self.has_battery && !self.prg_ram.is_empty() {
+            return Some(&mut self.prg_ram);
+        }
+        return None;
+    }
+}
+
+impl SaveState for Sunsoft5B {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.address);
+        state.write(&self.registers);
+        state.write(&self.divider);
+        for counter in &self.tone_counters {
+            state.write(counter);
+        }
+        for output in &self.tone_outputs {
+            state.write(output);
+        }
+        state.write(&self.noise_counter);
+        state.write(&self.noise_shift);
+        state.write(&self.envelope_counter);
+        state.write(&self.envelope_step);
+        state.write(&self.envelope_attack);
+        state.write(&self.envelope_holding);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read(&mut self.address)?;
+        if self.address as usize >= self.registers.len() {
+            return Err(StateError::Invalid("audio register address"));
+        }
+        state.read(&mut self.registers)?;
+        state.read(&mut self.divider)?;
+        for counter in &mut self.tone_counters {
+            state.read(counter)?;
+        }
+        for output in &mut self.tone_outputs {
+            state.read(output)?;
+        }
+        state.read(&mut self.noise_counter)?;
+        state.read(&mut self.noise_shift)?;
+        state.read(&mut self.envelope_counter)?;
+        state.read(&mut self.envelope_step)?;
+        state.read(&mut self.envelope_attack)?;
+        state.read(&mut self.envelope_holding)?;
+        return Ok(());
+    }
+}
+
+impl SaveState for FME7 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.prg_ram);
+        if self.chr_is_ram {
+            state.write(&self.chr);
+        }
+        state.write(&self.command);
+        state.write(&self.prg_banks);
+        state.write(&self.chr_banks);
+        state.write(&self.mirroring);
+        state.write(&self.irq_control);
+        state.write(&self.irq_counter);
+        state.write(&self.irq_pending);
+        state.write(&self.audio);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read(&mut self.prg_ram)?;
+        if self.chr_is_ram {
+            state.read(&mut self.chr)?;
+        }
+        state.read(&mut self.command)?;
+        state.read(&mut self.prg_banks)?;
+        state.read(&mut self.chr_banks)?;
+        state.read(&mut self.mirroring)?;
+        state.read(&mut self.irq_control)?;
+        state.read(&mut self.irq_counter)?;
+        state.read(&mut self.irq_pending)?;
+        state.read(&mut self.audio)?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::ines_rom;
+
+    fn write_command(fme7: &mut FME7, command: u8, val: u8) {
+        fme7.write_prg(COMMAND, command);
+        fme7.write_prg(PARAMETER, val);
+    }
+
+    #[test]
+    fn test_banking_and_irq() {
+        let mut data = ines_rom(69, 8, 2, 0);
+        for bank in 0..16 {
+            data[16 + bank * PRG_BANK_SIZE] = bank as u8;
+        }
+        let mut fme7 = FME7::init(&Cartridge::from_ines(&data).unwrap());
+        assert_eq!(fme7.read_prg(0xE000), 15);
+        // the $A000 bank
+        write_command(&mut fme7, COMMAND_PRG_RAM_BANK + 2, 4);
+        assert_eq!(fme7.read_prg(0xA000), 4);
+        // ROM at $6000 until RAM is selected and enabled
+        write_command(&mut fme7, COMMAND_PRG_RAM_BANK, 3);
+        assert_eq!(fme7.read_prg(0x6000), 3);
+        write_command(&mut fme7, COMMAND_PRG_RAM_BANK, RAM_SELECT | RAM_ENABLE);
+        fme7.write_prg(0x6000, 0x55);
+        assert_eq!(fme7.read_prg(0x6000), 0x55);
+
+        write_command(&mut fme7, COMMAND_IRQ_LOW, 1);
+        write_command(&mut fme7, COMMAND_IRQ_LOW + 1, 0);
+        write_command(
+            &mut fme7,
+            COMMAND_IRQ_CONTROL,
+            IRQ_ENABLE | IRQ_COUNTER_ENABLE,
+        );
+        fme7.tick();
+        assert!(!fme7.irq_pending());
+        fme7.tick();
+        assert!(fme7.irq_pending());
+        write_command(&mut fme7, COMMAND_IRQ_CONTROL, 0);
+        assert!(!fme7.irq_pending());
+    }
+
+    #[test]
+    fn test_tone_volume_and_envelope() {
+        let mut audio = Sunsoft5B::init();
+        let mut write = |reg: usize, val: u8| {
+            audio.write_address(reg as u8);
+            audio.write_data(val);
+        };
+        // tone A alone at full fixed volume
+        write(MIXER, 0b111110);
+        write(VOLUME, 0x0F);
+        audio.tone_outputs[0] = true;
+        assert_eq!(audio.output(), CHANNEL_LEVEL);
+        // each volume step down is 3dB
+        audio.registers[VOLUME] = 0x0E;
+        let ratio = audio.output() / CHANNEL_LEVEL;
+        assert!((20.0 * ratio.log10() + 3.0).abs() < 0.01);
+
+        // a single decay ends silent
+        audio.registers[VOLUME] = VOLUME_ENVELOPE;
+        audio.write_address(ENVELOPE_SHAPE as u8);
+        audio.write_data(0);
+        assert_eq!(audio.output(), CHANNEL_LEVEL);
+        for _ in 0..ENVELOPE_STEPS as u32 * ENVELOPE_DIVIDER as u32 {
+            audio.tick();
+        }
+        assert_eq!(audio.get_envelope_level(), 0);
+        assert_eq!(audio.output(), 0.0);
+    }
+
+    #[test]
+    fn test_load_state_checks_the_audio_address() {
+        let mut audio = Sunsoft5B::init();
+        audio.address = 16;
+        let mut state = StateWriter::init();
+        state.write(&audio);
+        let data = state.into_bytes();
+        assert_eq!(
+            StateReader::init(&data).read(&mut audio),
+            Err(StateError::Invalid("audio register address"))
+        );
+    }
+}
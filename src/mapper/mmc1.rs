@@ -0,0 +1,262 @@
+use crate::cartridge::{Cartridge, Mirroring};
+use crate::mapper::Mapper;
+use crate::ram::{START_CARTRIDGE_RAM, START_CARTRIDGE_ROM};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_BANK_SIZE: usize = 0x1000;
+// boards with 512KB of PRG ROM (SUROM) pick the 256KB half with a CHR bit
+const PRG_OUTER_BANK_SIZE: usize = 0x40000;
+
+const SHIFT_RESET: u8 = 0b10000;
+
+const CONTROL_MIRRORING: u8 = 0b00011;
+const CONTROL_PRG_MODE: u8 = 0b01100;
+const CONTROL_CHR_4KB: u8 = 0b10000;
+const PRG_RAM_DISABLE: u8 = 0b10000;
+
+/// Mapper 1: registers loaded one bit at a time through a shift register,
+/// switching 16KB or 32KB of PRG, 4KB or 8KB of CHR and the mirroring.
+#[derive(Debug)]
+pub struct MMC1 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    has_battery: bool,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+
+    // the 1 marks how many bits have been shifted in
+    shift: u8,
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+}
+
+impl MMC1 {
+    pub fn init(cartridge: &Cartridge) -> Self {
+        let chr_is_ram = cartridge.get_chr_rom().is_empty();
+        let chr = if chr_is_ram {
+            vec![0; cartridge.get_chr_ram_size()]
+        } else {
+            cartridge.get_chr_rom().to_vec()
+        };
+        return MMC1 {
+            prg_rom: cartridge.get_prg_rom().to_vec(),
+            prg_ram: vec![0; cartridge.get_prg_ram_size()],
+            has_battery: cartridge.has_battery(),
+            chr,
+            chr_is_ram,
+            shift: SHIFT_RESET,
+            // starts with the last PRG bank fixed at $C000
+            control: CONTROL_PRG_MODE,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+        };
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        match addr & 0xE000 {
+            0x8000 => self.control = val,
+            0xA000 => self.chr_bank_0 = val,
+            0xC000 => self.chr_bank_1 = val,
+            _ => self.prg_bank = val,
+        }
+    }
+
+    fn get_prg_bank(&self, addr: u16) -> usize {
+        let bank = (self.prg_bank & 0x0F) as usize;
+        // within the current 256KB
+        let bank_count = (self.prg_rom.len() / PRG_BANK_SIZE).min(16);
+        let last_bank = bank_count.saturating_sub(1);
+        let upper_half = addr >= 0xC000;
+        match (self.control & CONTROL_PRG_MODE) >> 2 {
+            0 | 1 => return (bank & !1) | upper_half as usize,
+            2 if upper_half => return bank,
+            2 => return 0,
+            _ if upper_half => return last_bank,
+            _ => return bank,
+        }
+    }
+
+    fn get_chr_offset(&self, addr: u16) -> usize {
+        let offset = (addr as usize) % CHR_BANK_SIZE;
+        let bank = if self.control & CONTROL_CHR_4KB == 0 {
+            (self.chr_bank_0 & !1) as usize | (addr as usize / CHR_BANK_SIZE)
+        } else if addr < 0x1000 {
+            self.chr_bank_0 as usize
+        } else {
+            self.chr_bank_1 as usize
+        };
+        return (bank * CHR_BANK_SIZE + offset) % self.chr.len();
+    }
+
+    fn is_prg_ram_enabled(&self) -> bool {
+        return !self.prg_ram.is_empty() && self.prg_bank & PRG_RAM_DISABLE == 0;
+    }
+}
+
+impl Mapper for MMC1 {
+    fn read_prg(&mut self, addr: u16) -> u8 {
+        if let Some(offset) = self.get_prg_rom_offset(addr) {
+            return self.prg_rom[offset];
+        }
+        if !self.is_prg_ram_enabled() {
+            return 0;
+        }
+        return self.prg_ram[(addr - START_CARTRIDGE_RAM) as usize % self.prg_ram.len()];
+    }
+
+    fn write_prg(&mut self, addr: u16, val: u8) {
+        if addr < START_CARTRIDGE_ROM {
+            if self.is_prg_ram_enabled() {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr - START_CARTRIDGE_RAM) as usize % len] = val;
+            }
+            return;
+        }
+        if val & 0x80 != 0 {
+            self.shift = SHIFT_RESET;
+            self.control |= CONTROL_PRG_MODE;
+            return;
+        }
+        let full = self.shift & 1 != 0;
+        self.shift = (self.shift >> 1) | ((val & 1) << 4);
+        if full {
+            self.write_register(addr, self.shift);
+            self.shift = SHIFT_RESET;
+        }
+    }
+
+    fn read_chr(&mut self, addr: u16) -> u8 {
+        return self.chr[self.get_chr_offset(addr)];
+    }
+
+    fn write_chr(&mut self, addr: u16, val: u8) {
+        if self.chr_is_ram {
+            let offset = self.get_chr_offset(addr);
+            self.chr[offset] = val;
+        }
+    }
+
+    fn get_mirroring(&self) -> Mirroring {
+        match self.control & CONTROL_MIRRORING {
+            0 => return Mirroring::SingleScreenA,
+            1 => return Mirroring::SingleScreenB,
+            2 => return Mirroring::Vertical,
+            _ => return Mirroring::Horizontal,
+        }
+    }
+
+    fn get_prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr < START_CARTRIDGE_ROM {
+            return None;
+        }
+        let outer = if self.prg_rom.len() > PRG_OUTER_BANK_SIZE {
+            (self.chr_bank_0 & 0x10) as usize >> 4
+        } else {
+            0
+        };
+        let offset = outer * PRG_OUTER_BANK_SIZE
+            + self.get_prg_bank(addr) * PRG_BANK_SIZE
+            + (addr as usize % PRG_BANK_SIZE);
+        return Some(offset % self.prg_rom.len());
+    }
+
+    fn get_prg_rom_mut(&mut self) -> Option<&mut [u8]> {
+        return Some(&mut self.prg_rom);
+    }
+
+    fn get_chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        if self.chr_is_ram {
+            return None;
+        }
+        return Some(self.get_chr_offset(addr));
+    }
+
+    fn get_battery_ram(&self) -> Option<&[u8]> {
+        if self.has_battery && !self.prg_ram.is_empty() {
+            return Some(&self.prg_ram);
+        }
+        return None;
+    }
+
+    fn get_battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        if self.has_battery && !self.prg_ram.is_empty() {
+            return Some(&mut self.prg_ram);
+        }
+        return None;
+    }
+}
+
+impl SaveState for MMC1 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.prg_ram);
+        if self.chr_is_ram {
+            state.write(&self.chr);
+        }
+        state.write(&self.shift);
+        state.write(&self.control);
+        state.write(&self.chr_bank_0);
+        state.write(&self.chr_bank_1);
+        state.write(&self.prg_bank);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read(&mut self.prg_ram)?;
+        if self.chr_is_ram {
+            state.read(&mut self.chr)?;
+        }
+        state.read(&mut self.shift)?;
+        state.read(&mut self.control)?;
+        state.read(&mut self.chr_bank_0)?;
+        state.read(&mut self.chr_bank_1)?;
+        state.read(&mut self.prg_bank)?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::ines_rom;
+
+    fn write_register(mmc1: &mut MMC1, addr: u16, val: u8) {
+        for bit in 0..5 {
+            mmc1.write_prg(addr, (val >> bit) & 1);
+        }
+    }
+
+    #[test]
+    fn test_banking_and_mirroring() {
+        // 8 PRG banks that each start with their own number
+        let mut data = ines_rom(1, 8, 2, 0);
+        for bank in 0..8 {
+            data[16 + bank * PRG_BANK_SIZE] = bank as u8;
+        }
+        let mut mmc1 = MMC1::init(&Cartridge::from_ines(&data).unwrap());
+        assert_eq!(mmc1.read_prg(0x8000), 0);
+        assert_eq!(mmc1.read_prg(0xC000), 7);
+
+        write_register(&mut mmc1, 0xE000, 3);
+        assert_eq!(mmc1.read_prg(0x8000), 3);
+        // fixed first bank, switchable last
+        write_register(&mut mmc1, 0x8000, 0b01000);
+        assert_eq!(mmc1.read_prg(0x8000), 0);
+        assert_eq!(mmc1.read_prg(0xC000), 3);
+        assert_eq!(mmc1.get_mirroring(), Mirroring::SingleScreenA);
+        write_register(&mut mmc1, 0x8000, 0b10001);
+        assert_eq!(mmc1.get_mirroring(), Mirroring::SingleScreenB);
+        write_register(&mut mmc1, 0x8000, 0b10010);
+        assert_eq!(mmc1.get_mirroring(), Mirroring::Vertical);
+
+        // 4KB CHR banks
+        write_register(&mut mmc1, 0xC000, 3);
+        assert_eq!(mmc1.get_chr_rom_offset(0x1004), Some(0x3004));
+        // a write with bit 7 set resets the shift register
+        mmc1.write_prg(0x8000, 1);
+        mmc1.write_prg(0x8000, 0x80);
+        assert_eq!(mmc1.read_prg(0xC000), 7);
+    }
+}
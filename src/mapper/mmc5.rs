@@ -0,0 +1,727 @@
+use crate::apu::Pulse;
+use crate::cartridge::{Cartridge, Mirroring};
+use crate::mapper::{Mapper, PPUEvent};
+use crate::ram::START_CARTRIDGE_RAM;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x400;
+const SPLIT_BANK_SIZE: usize = 0x1000;
+const EXRAM_SIZE: usize = 0x400;
+const ATTRIBUTE_TABLE: usize = 0x3C0;
+
+const START_PULSE_1: u16 = 0x5000;
+const END_PULSE_1: u16 = 0x5003;
+const START_PULSE_2: u16 = 0x5004;
+const END_PULSE_2: u16 = 0x5007;
+const PCM_OUTPUT: u16 = 0x5011;
+const AUDIO_STATUS: u16 = 0x5015;
+const PRG_MODE: u16 = 0x5100;
+const CHR_MODE: u16 = 0x5101;
+const PRG_RAM_PROTECT_1: u16 = 0x5102;
+const PRG_RAM_PROTECT_2: u16 = 0x5103;
+const EXRAM_MODE: u16 = 0x5104;
+const NAMETABLE_MAPPING: u16 = 0x5105;
+const FILL_TILE: u16 = 0x5106;
+const FILL_ATTRIBUTE: u16 = 0x5107;
+const START_PRG_BANKS: u16 = 0x5113;
+const END_PRG_BANKS: u16 = 0x5117;
+const START_CHR_BANKS: u16 = 0x5120;
+const START_CHR_BANKS_B: u16 = 0x5128;
+const END_CHR_BANKS: u16 = 0x512B;
+const CHR_UPPER_BITS: u16 = 0x5130;
+const SPLIT_CONTROL: u16 = 0x5200;
+const SPLIT_SCROLL: u16 = 0x5201;
+const SPLIT_BANK: u16 = 0x5202;
+const IRQ_SCANLINE: u16 = 0x5203;
+const IRQ_STATUS: u16 = 0x5204;
+const MULTIPLIER_LO: u16 = 0x5205;
+const MULTIPLIER_HI: u16 = 0x5206;
+const START_EXRAM: u16 = 0x5C00;
+const END_EXRAM: u16 = 0x5FFF;
+
+const PRG_BANK_ROM: u8 = 0x80;
+// written to both protect registers to allow PRG RAM writes
+const PRG_RAM_UNLOCK: [u8; 2] = [0b10, 0b01];
+const EXRAM_NAMETABLE: u8 = 0;
+const EXRAM_EXTENDED_ATTRIBUTES: u8 = 1;
+const EXRAM_READ_ONLY: u8 = 3;
+const SPLIT_ENABLE: u8 = 0x80;
+const SPLIT_RIGHT_SIDE: u8 = 0x40;
+const SPLIT_TILE: u8 = 0x1F;
+const IRQ_ENABLE: u8 = 0x80;
+const IRQ_STATUS_PENDING: u8 = 0x80;
+const IRQ_STATUS_IN_FRAME: u8 = 0x40;
+// the pulse envelopes and length counters run at a fixed 240Hz
+const AUDIO_FRAME_CYCLES: u16 = 7457;
+// PCM steps in pulse volume steps, giving about the DMC's full-scale range
+const PCM_LEVEL: f32 = 0.3;
+const PPU_CTRL: u16 = 0x2000;
+const PPU_CTRL_TALL_SPRITES: u8 = 0x20;
+
+/// Mapper 5: PRG and CHR banking down to 8KB and 1KB, a second set of CHR
+/// banks for the background with 8x16 sprites, 1KB of ExRAM for nametables
+/// or per-tile attributes, a vertical split, a scanline IRQ, a multiplier
+/// and two extra pulse channels with a PCM channel.
+#[derive(Debug)]
+pub struct MMC5 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    has_battery: bool,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    exram: [u8; EXRAM_SIZE],
+
+    prg_mode: u8,
+    chr_mode: u8,
+    prg_ram_protect: [u8; 2],
+    exram_mode: u8,
+    // two bits per nametable: CIRAM page 0 or 1, ExRAM or the fill tile
+    nametable_mapping: u8,
+    fill_tile: u8,
+    fill_attribute: u8,
+    // $5113-$5117, bit 7 picks ROM over RAM
+    prg_banks: [u8; 5],
+    // $5120-$5127 for sprites, $5128-$512B for the background, with the
+    // upper bits from $5130 at the time of the write
+    chr_banks: [u16; 12],
+    chr_upper: u8,
+    last_chr_set_b: bool,
+    tall_sprites: bool,
+    split_control: u8,
+    split_scroll: u8,
+    split_bank: u8,
+    irq_scanline: u8,
+    irq_enabled: bool,
+    irq_pending: bool,
+    multiplicand: u8,
+    multiplier: u8,
+
+    // following the PPU
+    in_frame: bool,
+    scanline: u8,
+    fetching_sprites: bool,
+    next_tile: u8,
+    tile: u8,
+    in_split: bool,
+    split_y: u8,
+    ext_attribute: u8,
+
+    pulse_1: Pulse,
+    pulse_2: Pulse,
+    pcm: u8,
+    audio_cycle: u16,
+}
+
+impl MMC5 {
+    pub fn init(cartridge: &Cartridge) -> Self {
+        let chr_is_ram = cartridge.get_chr_rom().is_empty();
+        let chr = if chr_is_ram {
+            vec![0; cartridge.get_chr_ram_size()]
+        } else {
+            cartridge.get_chr_rom().to_vec()
+        };
+        return MMC5 {
+            prg_rom: cartridge.get_prg_rom().to_vec(),
+            prg_ram: vec![0; cartridge.get_prg_ram_size()],
+            has_battery: cartridge.has_battery(),
+            chr,
+            chr_is_ram,
+            exram: [0; EXRAM_SIZE],
+            // 8KB banks with the last one at $E000
+            prg_mode: 3,
+            chr_mode: 3,
+            prg_ram_protect: [0; 2],
+            exram_mode: EXRAM_NAMETABLE,
+            nametable_mapping: 0,
+            fill_tile: 0,
+            fill_attribute: 0,
+            prg_banks: [0, 0, 0, 0, 0xFF],
+            chr_banks: [0; 12],
+            chr_upper: 0,
+            last_chr_set_b: false,
+            tall_sprites: false,
+            split_control: 0,
+            split_scroll: 0,
+            split_bank: 0,
+            irq_scanline: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            multiplicand: 0xFF,
+            multiplier: 0xFF,
+            in_frame: false,
+            scanline: 0,
+            fetching_sprites: false,
+            next_tile: 0,
+            tile: 0,
+            in_split: false,
+            split_y: 0,
+            ext_attribute: 0,
+            pulse_1: Pulse::init_without_sweep(),
+            pulse_2: Pulse::init_without_sweep(),
+            pcm: 0,
+            audio_cycle: 0,
+        };
+    }
+
+    // the 8KB bank at `addr` in $6000-$FFFF, and whether it's ROM
+    fn get_prg_bank(&self, addr: u16) -> (usize, bool) {
+        let slot = ((addr - START_CARTRIDGE_RAM) as usize) / PRG_BANK_SIZE;
+        if slot == 0 {
+            return ((self.prg_banks[0] & 0x07) as usize, false);
+        }
+        let (index, banks) = match (self.prg_mode, slot) {
+            (0, _) => (4, 4),
+            (1 | 2, 1 | 2) => (2, 2),
+            (1, _) => (4, 2),
+            _ => (slot, 1),
+        };
+        let reg = self.prg_banks[index];
+        let bank = (reg & !PRG_BANK_ROM) as usize & !(banks - 1) | ((slot - 1) & (banks - 1));
+        // $5117 always maps ROM
+        let is_rom = index == 4 || reg & PRG_BANK_ROM != 0;
+        if is_rom {
+            return (bank, true);
+        }
+        return (bank & 0x07, false);
+    }
+
+    fn get_prg_ram_offset(&self, bank: usize, addr: u16) -> Option<usize> {
+        if self.prg_ram.is_empty() {
+            return None;
+        }
+        let offset = bank * PRG_BANK_SIZE + (addr as usize % PRG_BANK_SIZE);
+        return Some(offset % self.prg_ram.len());
+    }
+
+    fn is_fetching_background(&self) -> bool {
+        return self.in_frame && !self.fetching_sprites;
+    }
+
+    fn get_chr_offset(&self, addr: u16) -> usize {
+        let addr = addr as usize;
+        if self.is_fetching_background() {
+            if self.in_split {
+                let row = addr & 0xFF8 | (self.split_y & 0x07) as usize;
+                return (self.split_bank as usize * SPLIT_BANK_SIZE + row) % self.chr.len();
+            }
+            if self.exram_mode == EXRAM_EXTENDED_ATTRIBUTES {
+                let bank = (self.chr_upper as usize) << 6 | (self.ext_attribute & 0x3F) as usize;
+                return (bank * SPLIT_BANK_SIZE + (addr & 0xFFF)) % self.chr.len();
+            }
+        }
+        // with 8x8 sprites everything uses the first set
+        let use_set_b = self.tall_sprites
+            && if self.in_frame {
+                !self.fetching_sprites
+            } else {
+                self.last_chr_set_b
+            };
+        let regs_per_bank = 8 >> self.chr_mode;
+        let bank_size = CHR_BANK_SIZE * regs_per_bank;
+        let (reg, addr) = if use_set_b {
+            // the second set covers $0000-$0FFF and repeats at $1000
+            let addr = addr & 0xFFF;
+            let slot = addr / bank_size;
+            ((8 + (slot + 1) * regs_per_bank - 1).min(11), addr)
+        } else {
+            ((addr / bank_size + 1) * regs_per_bank - 1, addr)
+        };
+        let offset = self.chr_banks[reg] as usize * bank_size + addr % bank_size;
+        return offset % self.chr.len();
+    }
+
+    // called on each background tile fetch while rendering
+    fn start_tile(&mut self, offset: usize) {
+        self.tile = self.next_tile;
+        self.next_tile = self.next_tile.saturating_add(1);
+        let threshold = self.split_control & SPLIT_TILE;
+        let right_side = self.split_control & SPLIT_RIGHT_SIDE != 0;
+        self.in_split = self.split_control & SPLIT_ENABLE != 0
+            && self.exram_mode <= EXRAM_EXTENDED_ATTRIBUTES
+            && (self.tile >= threshold) == right_side;
+        if self.exram_mode == EXRAM_EXTENDED_ATTRIBUTES {
+            self.ext_attribute = self.exram[offset];
+        }
+    }
+
+    fn read_split(&self, is_attribute: bool) -> u8 {
+        let row = (self.split_y / 8) as usize;
+        let column = (self.tile % 32) as usize;
+        if !is_attribute {
+            return self.exram[row * 32 + column];
+        }
+        let attribute = self.exram[ATTRIBUTE_TABLE + row / 4 * 8 + column / 4];
+        let shift = ((row & 2) * 2) | (column & 2);
+        return ((attribute >> shift) & 0b11) * 0x55;
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            START_PULSE_1..=END_PULSE_1 => self.pulse_1.write_register(addr - START_PULSE_1, val),
+            START_PULSE_2..=END_PULSE_2 => self.pulse_2.write_register(addr - START_PULSE_2, val),
+            // only the write mode of the PCM channel, zeros are ignored
+            PCM_OUTPUT if val != 0 => self.pcm = val,
+            AUDIO_STATUS => {
+                self.pulse_1.set_enabled(val & 0x01 != 0);
+                self.pulse_2.set_enabled(val & 0x02 != 0);
+            }
+            PRG_MODE => self.prg_mode = val & 0b11,
+            CHR_MODE => self.chr_mode = val & 0b11,
+            PRG_RAM_PROTECT_1 => self.prg_ram_protect[0] = val & 0b11,
+            PRG_RAM_PROTECT_2 => self.prg_ram_protect[1] = val & 0b11,
+            EXRAM_MODE => self.exram_mode = val & 0b11,
+            NAMETABLE_MAPPING => self.nametable_mapping = val,
+            FILL_TILE => self.fill_tile = val,
+            FILL_ATTRIBUTE => self.fill_attribute = val & 0b11,
+            START_PRG_BANKS..=END_PRG_BANKS => {
+                self.prg_banks[(addr - START_PRG_BANKS) as usize] = val;
+            }
+            START_CHR_BANKS..=END_CHR_BANKS => {
+                let bank = (self.chr_upper as u16) << 8 | val as u16;
+                self.chr_banks[(addr - START_CHR_BANKS) as usize] = bank;
+                self.last_chr_set_b = addr >= START_CHR_BANKS_B;
+            }
+            CHR_UPPER_BITS => self.chr_upper = val & 0b11,
+            SPLIT_CONTROL => self.split_control = val,
+            SPLIT_SCROLL => self.split_scroll = val,
+            SPLIT_BANK => self.split_bank = val,
+            IRQ_SCANLINE => self.irq_scanline = val,
+            IRQ_STATUS => self.irq_enabled = val & IRQ_ENABLE != 0,
+            MULTIPLIER_LO => self.multiplicand = val,
+            MULTIPLIER_HI => self.multiplier = val,
+            START_EXRAM..=END_EXRAM => {
+                let index = (addr - START_EXRAM) as usize;
+                match self.exram_mode {
+                    // only writable while rendering, zeros get written otherwise
+                    EXRAM_NAMETABLE | EXRAM_EXTENDED_ATTRIBUTES => {
+                        self.exram[index] = if self.in_frame { val } else { 0 };
+                    }
+                    EXRAM_READ_ONLY => {}
+                    _ => self.exram[index] = val,
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Mapper for MMC5 {
+    fn read_prg(&mut self, addr: u16) -> u8 {
+        if let Some(offset) = self.get_prg_rom_offset(addr) {
+            return self.prg_rom[offset];
+        }
+        let (bank, _) = self.get_prg_bank(addr);
+        match self.get_prg_ram_offset(bank, addr) {
+            Some(offset) => return self.prg_ram[offset],
+            None => return 0,
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, val: u8) {
+        let (bank, is_rom) = self.get_prg_bank(addr);
+        if is_rom || self.prg_ram_protect != PRG_RAM_UNLOCK {
+            return;
+        }
+        if let Some(offset) = self.get_prg_ram_offset(bank, addr) {
+            self.prg_ram[offset] = val;
+        }
+    }
+
+    fn read_chr(&mut self, addr: u16) -> u8 {
+        return self.chr[self.get_chr_offset(addr)];
+    }
+
+    fn write_chr(&mut self, addr: u16, val: u8) {
+        if self.chr_is_ram {
+            let offset = self.get_chr_offset(addr);
+            self.chr[offset] = val;
+        }
+    }
+
+    /// The closest standard mirroring to the nametable mapping; the
+    /// nametable accesses themselves follow the mapping exactly.
+    fn get_mirroring(&self) -> Mirroring {
+        match self.nametable_mapping {
+            0x00 => return Mirroring::SingleScreenA,
+            0x55 => return Mirroring::SingleScreenB,
+            0x44 => return Mirroring::Vertical,
+            _ => return Mirroring::Horizontal,
+        }
+    }
+
+    fn read_nametable(&mut self, addr: u16, vram: &[u8]) -> u8 {
+        let offset = (addr & 0x3FF) as usize;
+        let is_attribute = offset >= ATTRIBUTE_TABLE;
+        if self.is_fetching_background() {
+            // the PPU fetches the tile, then its attribute, then its pattern
+            if !is_attribute {
+                self.start_tile(offset);
+            }
+            if self.in_split {
+                return self.read_split(is_attribute);
+            }
+            if is_attribute && self.exram_mode == EXRAM_EXTENDED_ATTRIBUTES {
+                return (self.ext_attribute >> 6) * 0x55;
+            }
+        }
+        let table = (addr & 0x0FFF) / 0x400;
+        match (self.nametable_mapping >> (table * 2)) & 0b11 {
+            0 => return vram[offset],
+            1 => return vram[0x400 + offset],
+            2 if self.exram_mode <= EXRAM_EXTENDED_ATTRIBUTES => return self.exram[offset],
+            2 => return 0,
+            _ if is_attribute => return self.fill_attribute * 0x55,
+            _ => return self.fill_tile,
+        }
+    }
+
+    fn write_nametable(&mut self, addr: u16, val: u8, vram: &mut [u8]) {
+        let offset = (addr & 0x3FF) as usize;
+        let table = (addr & 0x0FFF) / 0x400;
+        match (self.nametable_mapping >> (table * 2)) & 0b11 {
+            0 => vram[offset] = val,
+            1 => vram[0x400 + offset] = val,
+            2 if self.exram_mode <= EXRAM_EXTENDED_ATTRIBUTES => self.exram[offset] = val,
+            _ => {}
+        }
+    }
+
+    fn notify_ppu(&mut self, event: PPUEvent) {
+        match event {
+            PPUEvent::Scanline(_) => {
+                if self.in_frame {
+                    self.scanline = self.scanline.wrapping_add(1);
+                    if self.scanline == self.irq_scanline {
+                        self.irq_pending = true;
+                    }
+                } else {
+                    self.in_frame = true;
+                    self.scanline = 0;
+                }
+                let y = self.split_scroll as u16 + self.scanline as u16;
+                self.split_y = if y >= 240 { y - 240 } else { y } as u8;
+                self.fetching_sprites = false;
+                self.next_tile = 0;
+            }
+            PPUEvent::SpriteFetches => self.fetching_sprites = true,
+            PPUEvent::RenderingStopped => {
+                self.in_frame = false;
+                self.fetching_sprites = false;
+                self.in_split = false;
+            }
+        }
+    }
+
+    fn notify_ppu_write(&mut self, addr: u16, val: u8) {
+        if addr == PPU_CTRL {
+            self.tall_sprites = val & PPU_CTRL_TALL_SPRITES != 0;
+        }
+    }
+
+    fn tick(&mut self) {
+        self.audio_cycle += 1;
+        if self.audio_cycle.is_multiple_of(2) {
+            self.pulse_1.clock_timer();
+            self.pulse_2.clock_timer();
+        }
+        if self.audio_cycle == AUDIO_FRAME_CYCLES {
+            self.audio_cycle = 0;
+            for pulse in [&mut self.pulse_1, &mut self.pulse_2] {
+                pulse.clock_envelope();
+                pulse.clock_length();
+            }
+        }
+    }
+
+    fn get_audio_output(&self) -> f32 {
+        let pulses = self.pulse_1.output() + self.pulse_2.output();
+        return pulses as f32 + self.pcm as f32 * PCM_LEVEL;
+    }
+
+    fn irq_pending(&self) -> bool {
+        return self.irq_enabled && self.irq_pending;
+    }
+
+    fn read_expansion(&mut self, addr: u16) -> Option<u8> {
+        let val = self.peek_expansion(addr);
+        if addr == IRQ_STATUS {
+            self.irq_pending = false;
+        }
+        return val;
+    }
+
+    fn peek_expansion(&self, addr: u16) -> Option<u8> {
+        match addr {
+            AUDIO_STATUS => {
+                let pulse_1 = self.pulse_1.is_playing() as u8;
+                let pulse_2 = self.pulse_2.is_playing() as u8;
+                return Some(pulse_1 | (pulse_2 << 1));
+            }
+            IRQ_STATUS => {
+                let mut status = 0;
+                if self.irq_pending {
+                    status |= IRQ_STATUS_PENDING;
+                }
+                if self.in_frame {
+                    status |= IRQ_STATUS_IN_FRAME;
+                }
+                return Some(status);
+            }
+            MULTIPLIER_LO | MULTIPLIER_HI => {
+                let product = self.multiplicand as u16 * self.multiplier as u16;
+                let shift = (addr - MULTIPLIER_LO) * 8;
+                return Some((product >> shift) as u8);
+            }
+            START_EXRAM..=END_EXRAM if self.exram_mode > EXRAM_EXTENDED_ATTRIBUTES => {
+                return Some(self.exram[(addr - START_EXRAM) as usize]);
+            }
+            _ => return None,
+        }
+    }
+
+    fn write_expansion(&mut self, addr: u16, val: u8) {
+        self.write_register(addr, val);
+    }
+
+    fn get_prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        let (bank, is_rom) = self.get_prg_bank(addr);
+        if !is_rom {
+            return None;
+        }
+        let offset = bank * PRG_BANK_SIZE + (addr as usize % PRG_BANK_SIZE);
+        return Some(offset % self.prg_rom.len());
+    }
+
+    fn get_prg_rom_mut(&mut self) -> Option<&mut [u8]> {
+        return Some(&mut self.prg_rom);
+    }
+
+    fn get_chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        if self.chr_is_ram {
+            return None;
+        }
+        return Some(self.get_chr_offset(addr));
+    }
+
+    fn get_battery_ram(&self) -> Option<&[u8]> {
+        if self.has_battery && !self.prg_ram.is_empty() {
+            return Some(&self.prg_ram);
+        }
+        return None;
+    }
+
+    fn get_battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        if self.has_battery && !self.prg_ram.is_empty() {
+            return Some(&mut self.prg_ram);
+        }
+        return None;
+    }
+}
+
+impl SaveState for MMC5 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.prg_ram);
+        if self.chr_is_ram {
+            state.write(&self.chr);
+        }
+        state.write(&self.exram);
+        state.write(&self.prg_mode);
+        state.write(&self.chr_mode);
+        state.write(&self.prg_ram_protect);
+        state.write(&self.exram_mode);
+        state.write(&self.nametable_mapping);
+        state.write(&self.fill_tile);
+        state.write(&self.fill_attribute);
+        state.write(&self.prg_banks);
+        for bank in &self.chr_banks {
+            state.write(bank);
+        }
+        state.write(&self.chr_upper);
+        state.write(&self.last_chr_set_b);
+        state.write(&self.tall_sprites);
+        state.write(&self.split_control);
+        state.write(&self.split_scroll);
+        state.write(&self.split_bank);
+        state.write(&self.irq_scanline);
+        state.write(&self.irq_enabled);
+        state.write(&self.irq_pending);
+        state.write(&self.multiplicand);
+        state.write(&self.multiplier);
+        state.write(&self.in_frame);
+        state.write(&self.scanline);
+        state.write(&self.fetching_sprites);
+        state.write(&self.next_tile);
+        state.write(&self.tile);
+        state.write(&self.in_split);
+        state.write(&self.split_y);
+        state.write(&self.ext_attribute);
+        state.write(&self.pulse_1);
+        state.write(&self.pulse_2);
+        state.write(&self.pcm);
+        state.write(&self.audio_cycle);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read(&mut self.prg_ram)?;
+        if self.chr_is_ram {
+            state.read(&mut self.chr)?;
+        }
+        state.read(&mut self.exram)?;
+        state.read(&mut self.prg_mode)?;
+        state.read(&mut self.chr_mode)?;
+        if self.chr_mode > 3 {
+            return Err(StateError::Invalid("chr mode"));
+        }
+        state.read(&mut self.prg_ram_protect)?;
+        state.read(&mut self.exram_mode)?;
+        state.read(&mut self.nametable_mapping)?;
+        state.read(&mut self.fill_tile)?;
+        state.read(&mut self.fill_attribute)?;
+        state.read(&mut self.prg_banks)?;
+        for bank in &mut self.chr_banks {
+            state.read(bank)?;
+        }
+        state.read(&mut self.chr_upper)?;
+        state.read(&mut self.last_chr_set_b)?;
+        state.read(&mut self.tall_sprites)?;
+        state.read(&mut self.split_control)?;
+        state.read(&mut self.split_scroll)?;
+        state.read(&mut self.split_bank)?;
+        state.read(&mut self.irq_scanline)?;
+        state.read(&mut self.irq_enabled)?;
+        state.read(&mut self.irq_pending)?;
+        state.read(&mut self.multiplicand)?;
+        state.read(&mut self.multiplier)?;
+        state.read(&mut self.in_frame)?;
+        state.read(&mut self.scanline)?;
+        state.read(&mut self.fetching_sprites)?;
+        state.read(&mut self.next_tile)?;
+        state.read(&mut self.tile)?;
+        state.read(&mut self.in_split)?;
+        state.read(&mut self.split_y)?;
+        state.read(&mut self.ext_attribute)?;
+        state.read(&mut self.pulse_1)?;
+        state.read(&mut self.pulse_2)?;
+        state.read(&mut self.pcm)?;
+        state.read(&mut self.audio_cycle)?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::ines_rom;
+
+    fn mmc5_with_numbered_banks() -> MMC5 {
+        // 16 PRG banks of 8KB and 64 CHR banks of 1KB that each start with
+        // their own number
+        let mut data = ines_rom(5, 8, 8, 0);
+        for bank in 0..16 {
+            data[16 + bank * PRG_BANK_SIZE] = bank as u8;
+        }
+        for bank in 0..64 {
+            data[16 + 0x20000 + bank * CHR_BANK_SIZE] = bank as u8;
+        }
+        return MMC5::init(&Cartridge::from_ines(&data).unwrap());
+    }
+
+    #[test]
+    fn test_load_state_checks_chr_mode() {
+        let mut mmc5 = mmc5_with_numbered_banks();
+        mmc5.chr_mode = 4;
+        let mut state = StateWriter::init();
+        state.write(&mmc5);
+        let data = state.into_bytes();
+        assert_eq!(
+            StateReader::init(&data).read(&mut mmc5),
+            Err(StateError::Invalid("chr mode"))
+        );
+    }
+
+    #[test]
+    fn test_prg_banking_and_multiplier() {
+        let mut mmc5 = mmc5_with_numbered_banks();
+        assert_eq!(mmc5.read_prg(0xE000), 15);
+        mmc5.write_expansion(0x5114, PRG_BANK_ROM | 3);
+        assert_eq!(mmc5.read_prg(0x8000), 3);
+
+        // 16KB at $8000 ignores the low bit of the bank
+        mmc5.write_expansion(PRG_MODE, 1);
+        mmc5.write_expansion(0x5115, PRG_BANK_ROM | 5);
+        assert_eq!(mmc5.read_prg(0x8000), 4);
+        assert_eq!(mmc5.read_prg(0xA000), 5);
+        assert_eq!(mmc5.read_prg(0xC000), 14);
+
+        // RAM at $8000 only takes writes once unlocked
+        mmc5.write_expansion(0x5115, 2);
+        mmc5.write_prg(0x8000, 0x42);
+        assert_eq!(mmc5.read_prg(0x8000), 0);
+        mmc5.write_expansion(PRG_RAM_PROTECT_1, 0b10);
+        mmc5.write_expansion(PRG_RAM_PROTECT_2, 0b01);
+        mmc5.write_prg(0x8000, 0x42);
+        assert_eq!(mmc5.read_prg(0x8000), 0x42);
+
+        mmc5.write_expansion(MULTIPLIER_LO, 200);
+        mmc5.write_expansion(MULTIPLIER_HI, 100);
+        // 200 * 100 = $4E20
+        assert_eq!(mmc5.read_expansion(MULTIPLIER_LO), Some(0x20));
+        assert_eq!(mmc5.read_expansion(MULTIPLIER_HI), Some(0x4E));
+    }
+
+    #[test]
+    fn test_nametables_and_chr_sets() {
+        let mut mmc5 = mmc5_with_numbered_banks();
+        let mut vram = [0; 0x800];
+        vram[0x400] = 1;
+        // page 1, ExRAM, fill, page 0
+        mmc5.write_expansion(NAMETABLE_MAPPING, 0b00_11_10_01);
+        mmc5.write_expansion(FILL_TILE, 0x33);
+        mmc5.write_expansion(FILL_ATTRIBUTE, 2);
+        mmc5.write_expansion(EXRAM_MODE, 2);
+        mmc5.write_expansion(START_EXRAM, 0x77);
+        mmc5.write_expansion(EXRAM_MODE, EXRAM_NAMETABLE);
+        assert_eq!(mmc5.read_nametable(0x2000, &vram), 1);
+        assert_eq!(mmc5.read_nametable(0x2400, &vram), 0x77);
+        assert_eq!(mmc5.read_nametable(0x2800, &vram), 0x33);
+        assert_eq!(mmc5.read_nametable(0x2BC0, &vram), 0xAA);
+        assert_eq!(mmc5.read_nametable(0x2C00, &vram), 0);
+
+        // 8x16 sprites fetch from the first set, the background from the
+        // second
+        mmc5.write_expansion(0x5127, 9);
+        mmc5.write_expansion(0x512B, 20);
+        mmc5.notify_ppu_write(PPU_CTRL, PPU_CTRL_TALL_SPRITES);
+        assert_eq!(mmc5.read_chr(0x1C00), 20);
+        mmc5.notify_ppu(PPUEvent::Scanline(0));
+        assert_eq!(mmc5.read_chr(0x1C00), 20);
+        mmc5.notify_ppu(PPUEvent::SpriteFetches);
+        assert_eq!(mmc5.read_chr(0x1C00), 9);
+
+        // with one 8KB bank the second set still repeats its first 4KB
+        mmc5.write_expansion(CHR_MODE, 0);
+        mmc5.write_expansion(0x512B, 1);
+        mmc5.notify_ppu(PPUEvent::Scanline(1));
+        assert_eq!(mmc5.read_chr(0x0400), 9);
+        assert_eq!(mmc5.read_chr(0x1400), 9);
+    }
+
+    #[test]
+    fn test_scanline_irq() {
+        let mut mmc5 = mmc5_with_numbered_banks();
+        mmc5.write_expansion(IRQ_SCANLINE, 2);
+        mmc5.write_expansion(IRQ_STATUS, IRQ_ENABLE);
+        for scanline in 0..2 {
+            mmc5.notify_ppu(PPUEvent::Scanline(scanline));
+            assert!(!mmc5.irq_pending());
+        }
+        mmc5.notify_ppu(PPUEvent::Scanline(2));
+        assert!(mmc5.irq_pending());
+        let status = IRQ_STATUS_PENDING | IRQ_STATUS_IN_FRAME;
+        assert_eq!(mmc5.read_expansion(IRQ_STATUS), Some(status));
+        assert!(!mmc5.irq_pending());
+        mmc5.notify_ppu(PPUEvent::RenderingStopped);
+        assert_eq!(mmc5.read_expansion(IRQ_STATUS), Some(0));
+    }
+}
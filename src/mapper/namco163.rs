@@ -0,0 +1,425 @@
+use crate::cartridge::{Cartridge, Mirroring};
+use crate::mapper::Mapper;
+use crate::ram::{START_CARTRIDGE_RAM, START_CARTRIDGE_ROM};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x400;
+const PRG_RAM_WINDOW_SIZE: usize = 0x800;
+
+const SOUND_DATA: u16 = 0x4800;
+const IRQ_LOW: u16 = 0x5000;
+const IRQ_HIGH: u16 = 0x5800;
+const IRQ_ENABLE: u8 = 0x80;
+const IRQ_MAX: u16 = 0x7FFF;
+
+// CHR and nametable registers at or above this select the console's VRAM
+const CIRAM_BANKS: u8 = 0xE0;
+const PRG_SOUND_DISABLE: u8 = 0x40;
+
+const SOUND_ADDRESS: u8 = 0x7F;
+const SOUND_AUTO_INCREMENT: u8 = 0x80;
+const PRG_RAM_WRITE_KEY: u8 = 0x40;
+
+const SOUND_RAM_SIZE: usize = 0x80;
+const CHANNEL_REGISTERS: usize = 0x40;
+const CHANNEL_COUNT_REGISTER: usize = 0x7F;
+// each channel in turn gets updated and put on the output for this long
+const CYCLES_PER_CHANNEL: u8 = 15;
+// a full-volume channel swings by 120 steps, far louder than the APU
+const WAVETABLE_LEVEL: f32 = 0.25;
+
+/// Mapper 19, Namco's 163: 8KB PRG banks, 1KB CHR banks, nametables that
+/// can come from CHR ROM, a cycle counting IRQ and up to eight wavetable
+/// channels kept in 128 bytes of sound RAM.
+///
+/// Pattern table banks that select the console's VRAM are read from CHR
+/// ROM instead, as the mapper can't see VRAM from `read_chr`.
+#[derive(Debug)]
+pub struct Namco163 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    has_battery: bool,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    mirroring: Mirroring,
+
+    prg_banks: [u8; 3],
+    chr_banks: [u8; 8],
+    nametable_banks: [u8; 4],
+    sound_address: u8,
+    prg_ram_protect: u8,
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq_pending: bool,
+
+    sound_ram: [u8; SOUND_RAM_SIZE],
+    channel: u8,
+    channel_cycle: u8,
+    channel_outputs: [i16; 8],
+}
+
+impl Namco163 {
+    pub fn init(cartridge: &Cartridge) -> Self {
+        let chr_is_ram = cartridge.get_chr_rom().is_empty();
+        let chr = if chr_is_ram {
+            vec![0; cartridge.get_chr_ram_size()]
+        } else {
+            cartridge.get_chr_rom().to_vec()
+        };
+        return Namco163 {
+            prg_rom: cartridge.get_prg_rom().to_vec(),
+            prg_ram: vec![0; cartridge.get_prg_ram_size()],
+            has_battery: cartridge.has_battery(),
+            chr,
+            chr_is_ram,
+            mirroring: cartridge.get_mirroring(),
+            prg_banks: [0; 3],
+            chr_banks: [0; 8],
+            nametable_banks: [CIRAM_BANKS; 4],
+            sound_address: 0,
+            prg_ram_protect: 0,
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            sound_ram: [0; SOUND_RAM_SIZE],
+            channel: 7,
+            channel_cycle: 0,
+            channel_outputs: [0; 8],
+        };
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        let index = (addr as usize - 0x8000) / 0x800;
+        match index {
+            0..=7 => self.chr_banks[index] = val,
+            8..=11 => self.nametable_banks[index - 8] = val,
+            12..=14 => self.prg_banks[index - 12] = val,
+            _ => {
+                self.sound_address = val;
+                self.prg_ram_protect = val;
+            }
+        }
+    }
+
+    fn get_chr_offset(&self, bank: u8, addr: u16) -> usize {
+        return (bank as usize * CHR_BANK_SIZE + (addr & 0x3FF) as usize) % self.chr.len();
+    }
+
+    fn get_pattern_offset(&self, addr: u16) -> usize {
+        let slot = addr as usize / CHR_BANK_SIZE;
+        return self.get_chr_offset(self.chr_banks[slot], addr);
+    }
+
+    // the upper nibble unlocks writes, then each low bit protects a 2KB window
+    fn is_prg_ram_writable(&self, addr: u16) -> bool {
+        let window = (addr - START_CARTRIDGE_RAM) as usize / PRG_RAM_WINDOW_SIZE;
+        return self.prg_ram_protect & 0xF0 == PRG_RAM_WRITE_KEY
+            && self.prg_ram_protect & (1 << window) == 0;
+    }
+
+    fn access_sound_ram(&mut self) -> usize {
+        let addr = (self.sound_address & SOUND_ADDRESS) as usize;
+        if self.sound_address & SOUND_AUTO_INCREMENT != 0 {
+            let next = self.sound_address.wrapping_add(1) & SOUND_ADDRESS;
+            self.sound_address = SOUND_AUTO_INCREMENT | next;
+        }
+        return addr;
+    }
+
+    fn get_channel_count(&self) -> u8 {
+        return ((self.sound_ram[CHANNEL_COUNT_REGISTER] >> 4) & 0b111) + 1;
+    }
+
+    // a channel's registers hold a 24-bit phase and frequency, the wave's
+    // length and position in 4-bit samples, and its volume
+    fn update_channel(&mut self, channel: u8) {
+        let base = CHANNEL_REGISTERS + channel as usize * 8;
+        let regs = &mut self.sound_ram[base..base + 8];
+        let frequency = regs[0] as u32 | (regs[2] as u32) << 8 | ((regs[4] & 0b11) as u32) << 16;
+        let length = 256 - (regs[4] & 0xFC) as u32;
+        let mut phase = regs[1] as u32 | (regs[3] as u32) << 8 | (regs[5] as u32) << 16;
+        phase = (phase + frequency) % (length << 16);
+        regs[1] = phase as u8;
+        regs[3] = (phase >> 8) as u8;
+        regs[5] = (phase >> 16) as u8;
+
+        let sample_addr = (regs[6] as u32 + (phase >> 16)) as u8;
+        let volume = (regs[7] & 0x0F) as i16;
+        let byte = self.sound_ram[sample_addr as usize / 2 % SOUND_RAM_SIZE];
+        let sample = if sample_addr.is_multiple_of(2) {
+            byte & 0x0F
+        } else {
+            byte >> 4
+        };
+        self.channel_outputs[channel as usize] = (sample as i16 - 8) * volume;
+    }
+}
+
+impl Mapper for Namco163 {
+    fn read_prg(&mut self, addr: u16) -> u8 {
+        if let Some(offset) = self.get_prg_rom_offset(addr) {
+            return self.prg_rom[offset];
+        }
+        if self.prg_ram.is_empty() {
+            return 0;
+        }
+        return self.prg_ram[(addr - START_CARTRIDGE_RAM) as usize % self.prg_ram.len()];
+    }
+
+    fn write_prg(&mut self, addr: u16, val: u8) {
+        if addr >= START_CARTRIDGE_ROM {
+            self.write_register(addr, val);
+        } else if !self.prg_ram.is_empty() && self.is_prg_ram_writable(addr) {
+            let len = self.prg_ram.len();
+            self.prg_ram[(addr - START_CARTRIDGE_RAM) as usize % len] = val;
+        }
+    }
+
+    fn read_chr(&mut self, addr: u16) -> u8 {
+        return self.chr[self.get_pattern_offset(addr)];
+    }
+
+    fn write_chr(&mut self, addr: u16, val: u8) {
+        if self.chr_is_ram {
+            let offset = self.get_pattern_offset(addr);
+            self.chr[offset] = val;
+        }
+    }
+
+    fn get_mirroring(&self) -> Mirroring {
+        return self.mirroring;
+    }
+
+    fn read_nametable(&mut self, addr: u16, vram: &[u8]) -> u8 {
+        let bank = self.nametable_banks[(addr as usize & 0x0FFF) / 0x400];
+        if bank >= CIRAM_BANKS {
+            return vram[(bank as usize & 1) * 0x400 + (addr & 0x3FF) as usize];
+        }
+        return self.chr[self.get_chr_offset(bank, addr)];
+    }
+
+    fn write_nametable(&mut self, addr: u16, val: u8, vram: &mut [u8]) {
+        let bank = self.nametable_banks[(addr as usize & 0x0FFF) / 0x400];
+        if bank >= CIRAM_BANKS {
+            vram[(bank as usize & 1) * 0x400 + (addr & 0x3FF) as usize] = val;
+        } else if self.chr_is_ram {
+            let offset = self.get_chr_offset(bank, addr);
+            self.chr[offset] = val;
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        return self.irq_pending;
+    }
+
+    fn tick(&mut self) {
+        if self.irq_enabled && self.irq_counter < IRQ_MAX {
+            self.irq_counter += 1;
+            if self.irq_counter == IRQ_MAX {
+                self.irq_pending = true;
+            }
+        }
+
+        self.channel_cycle += 1;
+        if self.channel_cycle < CYCLES_PER_CHANNEL {
+            return;
+        }
+        self.channel_cycle = 0;
+        // the active channels run from 7 downwards
+        let channel = self.channel;
+        self.update_channel(channel);
+        let last = 8 - self.get_channel_count();
+        self.channel = if channel <= last { 7 } else { channel - 1 };
+    }
+
+    // the chip plays one channel at a time, which the ear hears as their
+    // average
+    fn get_audio_output(&self) -> f32 {
+        if self.prg_banks[0] & PRG_SOUND_DISABLE != 0 {
+            return 0.0;
+        }
+        let count = self.get_channel_count();
+        let first = (8 - count) as usize;
+        let sum: i16 = self.channel_outputs[first..].iter().sum();
+        return sum as f32 / count as f32 * WAVETABLE_LEVEL;
+    }
+
+    fn get_prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr < START_CARTRIDGE_ROM {
+            return None;
+        }
+        let slot = (addr - START_CARTRIDGE_ROM) as usize / PRG_BANK_SIZE;
+        let bank = match self.prg_banks.get(slot) {
+            Some(&bank) => (bank & 0x3F) as usize,
+            None => self.prg_rom.len() / PRG_BANK_SIZE - 1,
+        };
+        let offset = bank * PRG_BANK_SIZE + addr as usize % PRG_BANK_SIZE;
+        return Some(offset % self.prg_rom.len());
+    }
+
+    fn get_prg_rom_mut(&mut self) -> Option<&mut [u8]> {
+        return Some(&mut self.prg_rom);
+    }
+
+    fn get_chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        if self.chr_is_ram {
+            return None;
+        }
+        return Some(self.get_pattern_offset(addr));
+    }
+
+    fn read_expansion(&mut self, addr: u16) -> Option<u8> {
+        let val = self.peek_expansion(addr);
+        if addr & 0xF800 == SOUND_DATA {
+            self.access_sound_ram();
+        }
+        return val;
+    }
+
+    fn peek_expansion(&self, addr: u16) -> Option<u8> {
+        match addr & 0xF800 {
+            SOUND_DATA => {
+                return Some(self.sound_ram[(self.sound_address & SOUND_ADDRESS) as usize]);
+            }
+            IRQ_LOW => return Some(self.irq_counter as u8),
+            IRQ_HIGH => {
+                let enable = if self.irq_enabled { IRQ_ENABLE } else { 0 };
+                return Some((self.irq_counter >> 8) as u8 | enable);
+            }
+            _ => return None,
+        }
+    }
+
+    fn write_expansion(&mut self, addr: u16, val: u8) {
+        match addr & 0xF800 {
+            SOUND_DATA => {
+                let addr = self.access_sound_ram();
+                self.sound_ram[addr] = val;
+            }
+            IRQ_LOW => {
+                self.irq_counter = (self.irq_counter & 0x7F00) | val as u16;
+                self.irq_pending = false;
+            }
+            IRQ_HIGH => {
+                self.irq_counter = (self.irq_counter & 0x00FF) | ((val & 0x7F) as u16) << 8;
+                self.irq_enabled = val & IRQ_ENABLE != 0;
+                self.irq_pending = false;
+            }
+            _ => {}
+        }
+    }
+
+    fn get_battery_ram(&self) -> Option<&[u8]> {
+        if self.has_battery && !self.prg_ram.is_empty() {
+            return Some(&self.prg_ram);
+        }
+        return None;
+    }
+
+    fn get_battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        if self.has_battery && !self.prg_ram.is_empty() {
+            return Some(&mut self.prg_ram);
+        }
+        return None;
+    }
+}
+
+impl SaveState for Namco163 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.prg_ram);
+        if self.chr_is_ram {
+            state.write(&self.chr);
+        }
+        state.write(&self.prg_banks);
+        state.write(&self.chr_banks);
+        state.write(&self.nametable_banks);
+        state.write(&self.sound_address);
+        state.write(&self.prg_ram_protect);
+        state.write(&self.irq_counter);
+        state.write(&self.irq_enabled);
+        state.write(&self.irq_pending);
+        state.write(&self.sound_ram);
+        state.write(&self.channel);
+        state.write(&self.channel_cycle);
+        for output in &self.channel_outputs {
+            state.write(&(*output as u16));
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read(&mut self.prg_ram)?;
+        if self.chr_is_ram {
+            state.read(&mut self.chr)?;
+        }
+        state.read(&mut self.prg_banks)?;
+        state.read(&mut self.chr_banks)?;
+        state.read(&mut self.nametable_banks)?;
+        state.read(&mut self.sound_address)?;
+        state.read(&mut self.prg_ram_protect)?;
+        state.read(&mut self.irq_counter)?;
+        state.read(&mut self.irq_enabled)?;
+        state.read(&mut self.irq_pending)?;
+        state.read(&mut self.sound_ram)?;
+        state.read(&mut self.channel)?;
+        state.read(&mut self.channel_cycle)?;
+        for output in &mut self.channel_outputs {
+            let mut val: u16 = 0;
+            state.read(&mut val)?;
+            *output = val as i16;
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::ines_rom;
+
+    #[test]
+    fn test_nametables_from_chr_rom() {
+        let mut data = ines_rom(19, 8, 2, 0);
+        for bank in 0..16 {
+            data[16 + 0x20000 + bank * CHR_BANK_SIZE] = bank as u8;
+        }
+        let mut namco = Namco163::init(&Cartridge::from_ines(&data).unwrap());
+        let mut vram = [0; 0x800];
+        vram[0x400] = 0xAA;
+        namco.write_prg(0xC000, 0xE1);
+        assert_eq!(namco.read_nametable(0x2000, &vram), 0xAA);
+        namco.write_prg(0xC800, 5);
+        assert_eq!(namco.read_nametable(0x2400, &vram), 5);
+        namco.write_prg(0x9800, 3);
+        assert_eq!(namco.read_chr(0x0C00), 3);
+    }
+
+    #[test]
+    fn test_irq_and_sound_ram() {
+        let data = ines_rom(19, 8, 2, 0);
+        let mut namco = Namco163::init(&Cartridge::from_ines(&data).unwrap());
+        namco.write_expansion(IRQ_LOW, 0xFD);
+        namco.write_expansion(IRQ_HIGH, IRQ_ENABLE | 0x7F);
+        namco.tick();
+        assert!(!namco.irq_pending());
+        namco.tick();
+        assert!(namco.irq_pending());
+        assert_eq!(namco.read_expansion(IRQ_HIGH), Some(0xFF));
+
+        // auto-increment through the sound RAM
+        namco.write_prg(0xF800, SOUND_AUTO_INCREMENT | 0x10);
+        namco.write_expansion(SOUND_DATA, 1);
+        namco.write_expansion(SOUND_DATA, 2);
+        namco.write_prg(0xF800, 0x11);
+        assert_eq!(namco.read_expansion(SOUND_DATA), Some(2));
+        assert_eq!(namco.read_expansion(SOUND_DATA), Some(2));
+
+        // and from the channel count register at $7F back to $00
+        namco.write_prg(0xF800, SOUND_AUTO_INCREMENT | 0x7F);
+        namco.write_expansion(SOUND_DATA, 0x70);
+        namco.write_expansion(SOUND_DATA, 3);
+        namco.write_prg(0xF800, 0x00);
+        assert_eq!(namco.read_expansion(SOUND_DATA), Some(3));
+        assert_eq!(namco.get_channel_count(), 8);
+    }
+}
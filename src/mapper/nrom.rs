@@ -0,0 +1,118 @@
+use crate::cartridge::{Cartridge, Mirroring};
+use crate::mapper::Mapper;
+use crate::ram::{START_CARTRIDGE_RAM, START_CARTRIDGE_ROM};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+/// Mapper 0: no banking, 16KB or 32KB of PRG ROM and 8KB of CHR.
+#[derive(Debug)]
+pub struct NROM {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    has_battery: bool,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    mirroring: Mirroring,
+}
+
+impl NROM {
+    pub fn init(cartridge: &Cartridge) -> Self {
+        let chr_is_ram = cartridge.get_chr_rom().is_empty();
+        let chr = if chr_is_ram {
+            vec![0; cartridge.get_chr_ram_size()]
+        } else {
+            cartridge.get_chr_rom().to_vec()
+        };
+        return NROM {
+            prg_rom: cartridge.get_prg_rom().to_vec(),
+            prg_ram: vec![0; cartridge.get_prg_ram_size()],
+            has_battery: cartridge.has_battery(),
+            chr,
+            chr_is_ram,
+            mirroring: cartridge.get_mirroring(),
+        };
+    }
+}
+
+impl Mapper for NROM {
+    fn read_prg(&mut self, addr: u16) -> u8 {
+        if addr >= START_CARTRIDGE_ROM {
+            // a 16KB image is mirrored into both halves
+            return self.prg_rom[(addr - START_CARTRIDGE_ROM) as usize % self.prg_rom.len()];
+        }
+        if self.prg_ram.is_empty() {
+            return 0;
+        }
+        return self.prg_ram[(addr - START_CARTRIDGE_RAM) as usize % self.prg_ram.len()];
+    }
+
+    fn write_prg(&mut self, addr: u16, val: u8) {
+        if addr < START_CARTRIDGE_ROM && !self.prg_ram.is_empty() {
+            let len = self.prg_ram.len();
+            self.prg_ram[(addr - START_CARTRIDGE_RAM) as usize % len] = val;
+        }
+    }
+
+    fn read_chr(&mut self, addr: u16) -> u8 {
+        return self.chr[addr as usize % self.chr.len()];
+    }
+
+    fn write_chr(&mut self, addr: u16, val: u8) {
+        if self.chr_is_ram {
+            let len = self.chr.len();
+            self.chr[addr as usize % len] = val;
+        }
+    }
+
+    fn get_mirroring(&self) -> Mirroring {
+        return self.mirroring;
+    }
+
+    fn get_prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr < START_CARTRIDGE_ROM {
+            return None;
+        }
+        return Some((addr - START_CARTRIDGE_ROM) as usize % self.prg_rom.len());
+    }
+
+    fn get_prg_rom_mut(&mut self) -> Option<&mut [u8]> {
+        return Some(&mut self.prg_rom);
+    }
+
+    fn get_chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        if self.chr_is_ram {
+            return None;
+        }
+        return Some(addr as usize % self.chr.len());
+    }
+
+    fn get_battery_ram(&self) -> Option<&[u8]> {
+        if self.has_battery && !self.prg_ram.is_empty() {
+            return Some(&self.prg_ram);
+        }
+        return None;
+    }
+
+    fn get_battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        if self.has_battery && !self.prg_ram.is_empty() {
+            return Some(&mut self.prg_ram);
+        }
+        return None;
+    }
+}
+
+impl SaveState for NROM {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.prg_ram);
+        if self.chr_is_ram {
+            state.write(&self.chr);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read(&mut self.prg_ram)?;
+        if self.chr_is_ram {
+            state.read(&mut self.chr)?;
+        }
+        return Ok(());
+    }
+}
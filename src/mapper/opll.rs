@@ -0,0 +1,543 @@
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+use std::f32::consts::TAU;
+
+/// The chip produces a sample every 36 CPU cycles, about 49.7kHz.
+const CYCLES_PER_SAMPLE: u8 = 36;
+const SAMPLE_RATE: f32 = 49_716.0;
+const CHANNELS: usize = 6;
+
+// phase accumulators count 2^20 per cycle of the sine
+const PHASE_BITS: u32 = 20;
+const PHASE_MASK: u32 = (1 << PHASE_BITS) - 1;
+
+// attenuation below which an operator is silent
+const MAX_ATTENUATION: f32 = 48.0;
+// seconds to fall the full 48dB and to attack from it at rate 1 (RKS 4),
+// halving every 4 steps of the rate
+const DECAY_SECONDS: f32 = 19.64;
+const ATTACK_SECONDS: f32 = 2.826;
+// a full-scale modulator moves the carrier by up to two cycles
+const MODULATION_CYCLES: f32 = 2.0;
+
+const TREMOLO_HZ: f32 = 3.7;
+const TREMOLO_DB: f32 = 4.8;
+const VIBRATO_HZ: f32 = 6.4;
+// about 14 cents
+const VIBRATO_DEPTH: f32 = 0.008;
+
+// frequency multipliers, doubled
+const MULTIPLIERS: [u32; 16] = [1, 2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 20, 24, 24, 30, 30];
+
+/// The VRC7's built-in instruments 1-15; 0 is the custom one in registers
+/// $00-$07.
+const PATCHES: [[u8; 8]; 15] = [
+    [0x03, 0x21, 0x05, 0x06, 0xE8, 0x81, 0x42, 0x27],
+    [0x13, 0x41, 0x14, 0x0D, 0xD8, 0xF6, 0x23, 0x12],
+    [0x11, 0x11, 0x08, 0x08, 0xFA, 0xB2, 0x20, 0x12],
+    [0x31, 0x61, 0x0C, 0x07, 0xA8, 0x64, 0x61, 0x27],
+    [0x32, 0x21, 0x1E, 0x06, 0xE1, 0x76, 0x01, 0x28],
+    [0x02, 0x01, 0x06, 0x00, 0xA3, 0xE2, 0xF4, 0xF4],
+    [0x21, 0x61, 0x1D, 0x07, 0x82, 0x81, 0x11, 0x07],
+    [0x23, 0x21, 0x22, 0x17, 0xA2, 0x72, 0x01, 0x17],
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01],
+    [0xB5, 0x01, 0x0F, 0x0F, 0xA8, 0xA5, 0x51, 0x02],
+    [0x17, 0xC1, 0x24, 0x07, 0xF8, 0xF8, 0x22, 0x12],
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16],
+    [0x01, 0x02, 0xD3, 0x05, 0xC9, 0x95, 0x03, 0x02],
+    [0x61, 0x63, 0x0C, 0x00, 0x94, 0xC0, 0x33, 0xF6],
+    [0x21, 0x72, 0x0D, 0x00, 0xC1, 0xD5, 0x56, 0x06],
+];
+
+const PATCH_TREMOLO: u8 = 0x80;
+const PATCH_VIBRATO: u8 = 0x40;
+const PATCH_SUSTAINED: u8 = 0x20;
+const PATCH_KEY_SCALE_RATE: u8 = 0x10;
+const PATCH_CARRIER_RECTIFIED: u8 = 0x10;
+const PATCH_MODULATOR_RECTIFIED: u8 = 0x08;
+
+const KEY_ON: u8 = 0x10;
+const SUSTAIN_ON: u8 = 0x20;
+// release rate used after key off with the channel's sustain bit set
+const SUSTAIN_RELEASE_RATE: u8 = 5;
+// and for percussive instruments without it
+const PERCUSSIVE_RELEASE_RATE: u8 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EnvelopeStage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Off,
+}
+
+impl EnvelopeStage {
+    fn from_u8(val: u8) -> Self {
+        match val {
+            0 => return EnvelopeStage::Attack,
+            1 => return EnvelopeStage::Decay,
+            2 => return EnvelopeStage::Sustain,
+            3 => return EnvelopeStage::Release,
+            _ => return EnvelopeStage::Off,
+        }
+    }
+}
+
+// the operator half of a patch: 0 for the modulator, 1 for the carrier
+#[derive(Debug, Clone, Copy)]
+struct OperatorPatch {
+    flags: u8,
+    attack_rate: u8,
+    decay_rate: u8,
+    sustain_level: u8,
+    release_rate: u8,
+}
+
+impl OperatorPatch {
+    fn from_patch(patch: &[u8; 8], operator: usize) -> Self {
+        return OperatorPatch {
+            flags: patch[operator],
+            attack_rate: patch[4 + operator] >> 4,
+            decay_rate: patch[4 + operator] & 0x0F,
+            sustain_level: patch[6 + operator] >> 4,
+            release_rate: patch[6 + operator] & 0x0F,
+        };
+    }
+}
+
+#[derive(Debug)]
+struct Operator {
+    phase: u32,
+    stage: EnvelopeStage,
+    // envelope attenuation in dB
+    attenuation: f32,
+}
+
+impl Operator {
+    fn init() -> Self {
+        return Operator {
+            phase: 0,
+            stage: EnvelopeStage::Off,
+            attenuation: MAX_ATTENUATION,
+        };
+    }
+
+    fn key_on(&mut self) {
+        self.phase = 0;
+        self.stage = EnvelopeStage::Attack;
+    }
+
+    fn key_off(&mut self) {
+        if self.stage != EnvelopeStage::Off {
+            self.stage = EnvelopeStage::Release;
+        }
+    }
+
+    // dB per sample for `rate` 1-15 adjusted by the key scale offset
+    fn get_envelope_step(rate: u8, key_scale: u8, seconds: f32) -> f32 {
+        if rate == 0 {
+            return 0.0;
+        }
+        let scaled_rate = (rate * 4 + key_scale).min(63) as f32;
+        let seconds = seconds / 2f32.powf((scaled_rate - 4.0) / 4.0);
+        return MAX_ATTENUATION / (seconds * SAMPLE_RATE);
+    }
+
+    fn clock_envelope(&mut self, patch: &OperatorPatch, key_scale: u8, release_rate: u8) {
+        match self.stage {
+            EnvelopeStage::Attack => {
+                if patch.attack_rate == 15 {
+                    self.attenuation = 0.0;
+                } else {
+                    let step =
+                        Operator::get_envelope_step(patch.attack_rate, key_scale, ATTACK_SECONDS);
+                    self.attenuation -= step;
+                }
+                if self.attenuation <= 0.0 {
+                    self.attenuation = 0.0;
+                    self.stage = EnvelopeStage::Decay;
+                }
+            }
+            EnvelopeStage::Decay => {
+                self.attenuation +=
+                    Operator::get_envelope_step(patch.decay_rate, key_scale, DECAY_SECONDS);
+                let sustain_level = patch.sustain_level as f32 * 3.0;
+                if self.attenuation >= sustain_level {
+                    self.attenuation = sustain_level;
+                    self.stage = EnvelopeStage::Sustain;
+                }
+            }
+            EnvelopeStage::Sustain => {
+                // percussive instruments keep fading while the key is held
+                if patch.flags & PATCH_SUSTAINED == 0 {
+                    self.attenuation +=
+                        Operator::get_envelope_step(patch.release_rate, key_scale, DECAY_SECONDS);
+                }
+            }
+            EnvelopeStage::Release => {
+                self.attenuation +=
+                    Operator::get_envelope_step(release_rate, key_scale, DECAY_SECONDS);
+            }
+            EnvelopeStage::Off => {}
+        }
+        if self.attenuation >= MAX_ATTENUATION {
+            self.attenuation = MAX_ATTENUATION;
+            if self.stage != EnvelopeStage::Attack {
+                self.stage = EnvelopeStage::Off;
+            }
+        }
+    }
+
+    // the operator's output in [-1, 1] with `modulation` cycles added to
+    // its phase
+    fn output(&self, attenuation: f32, modulation: f32, rectified: bool) -> f32 {
+        if self.stage == EnvelopeStage::Off {
+            return 0.0;
+        }
+        let phase = self.phase as f32 / (1 << PHASE_BITS) as f32 + modulation;
+        let wave = (TAU * phase).sin();
+        if rectified && wave < 0.0 {
+            return 0.0;
+        }
+        let attenuation = self.attenuation + attenuation;
+        if attenuation >= MAX_ATTENUATION {
+            return 0.0;
+        }
+        return wave * 10f32.powf(-attenuation / 20.0);
+    }
+}
+
+#[derive(Debug)]
+struct FMChannel {
+    fnum: u16,
+    block: u8,
+    key_on: bool,
+    sustain: bool,
+    instrument: u8,
+    volume: u8,
+    modulator: Operator,
+    carrier: Operator,
+    // the modulator's last two outputs, for its feedback
+    feedback: [f32; 2],
+}
+
+impl FMChannel {
+    fn init() -> Self {
+        return FMChannel {
+            fnum: 0,
+            block: 0,
+            key_on: false,
+            sustain: false,
+            instrument: 0,
+            volume: 0,
+            modulator: Operator::init(),
+            carrier: Operator::init(),
+            feedback: [0.0; 2],
+        };
+    }
+
+    fn write_control(&mut self, val: u8) {
+        self.fnum = (self.fnum & 0xFF) | (((val & 1) as u16) << 8);
+        self.block = (val >> 1) & 0b111;
+        self.sustain = val & SUSTAIN_ON != 0;
+        let key_on = val & KEY_ON != 0;
+        if key_on && !self.key_on {
+            self.modulator.key_on();
+            self.carrier.key_on();
+        } else if !key_on && self.key_on {
+            self.modulator.key_off();
+            self.carrier.key_off();
+        }
+        self.key_on = key_on;
+    }
+
+    // the top bits of the frequency speed up envelopes on higher notes
+    fn get_key_scale(&self, flags: u8) -> u8 {
+        let key_scale = (self.block << 1) | (self.fnum >> 8) as u8;
+        if flags & PATCH_KEY_SCALE_RATE != 0 {
+            return key_scale;
+        }
+        return key_scale >> 2;
+    }
+
+    fn get_release_rate(&self, patch: &OperatorPatch) -> u8 {
+        if self.sustain {
+            return SUSTAIN_RELEASE_RATE;
+        }
+        if patch.flags & PATCH_SUSTAINED != 0 {
+            return patch.release_rate;
+        }
+        return PERCUSSIVE_RELEASE_RATE;
+    }
+
+    fn clock(&mut self, patch: &[u8; 8], tremolo: f32, vibrato: f32) -> f32 {
+        let operators = [
+            OperatorPatch::from_patch(patch, 0),
+            OperatorPatch::from_patch(patch, 1),
+        ];
+        for (operator, op_patch) in [&mut self.modulator, &mut self.carrier]
+            .into_iter()
+            .zip(&operators)
+        {
+            // 2^19 per cycle with the real multipliers
+            let mut increment =
+                ((self.fnum as u32) << self.block) * MULTIPLIERS[(op_patch.flags & 0x0F) as usize];
+            if op_patch.flags & PATCH_VIBRATO != 0 {
+                increment = (increment as f32 * (1.0 + vibrato)) as u32;
+            }
+            operator.phase = (operator.phase + increment) & PHASE_MASK;
+        }
+        let modulator_scale = self.get_key_scale(operators[0].flags);
+        let carrier_scale = self.get_key_scale(operators[1].flags);
+        let modulator_release = self.get_release_rate(&operators[0]);
+        let carrier_release = self.get_release_rate(&operators[1]);
+        self.modulator
+            .clock_envelope(&operators[0], modulator_scale, modulator_release);
+        self.carrier
+            .clock_envelope(&operators[1], carrier_scale, carrier_release);
+
+        let feedback_level = patch[3] & 0b111;
+        let feedback = if feedback_level == 0 {
+            0.0
+        } else {
+            (self.feedback[0] + self.feedback[1]) / 2.0 * 2f32.powi(feedback_level as i32 - 6)
+        };
+        let mut modulator_attenuation = (patch[2] & 0x3F) as f32 * 0.75;
+        if operators[0].flags & PATCH_TREMOLO != 0 {
+            modulator_attenuation += tremolo;
+        }
+        let modulator = self.modulator.output(
+            modulator_attenuation,
+            feedback,
+            patch[3] & PATCH_MODULATOR_RECTIFIED != 0,
+        );
+        self.feedback = [self.feedback[1], modulator];
+
+        let mut carrier_attenuation = self.volume as f32 * 3.0;
+        if operators[1].flags & PATCH_TREMOLO != 0 {
+            carrier_attenuation += tremolo;
+        }
+        return self.carrier.output(
+            carrier_attenuation,
+            modulator * MODULATION_CYCLES,
+            patch[3] & PATCH_CARRIER_RECTIFIED != 0,
+        );
+    }
+}
+
+/// The VRC7's six channel FM synthesizer, a cut down YM2413 (OPLL). Covers
+/// the two operator voices with their envelopes, feedback, tremolo and
+/// vibrato, though not cycle for cycle.
+#[derive(Debug)]
+pub(crate) struct OPLL {
+    address: u8,
+    custom_patch: [u8; 8],
+    channels: [FMChannel; CHANNELS],
+    cycle: u8,
+    // tremolo and vibrato positions, in cycles
+    tremolo_phase: f32,
+    vibrato_phase: f32,
+    output: f32,
+}
+
+impl OPLL {
+    pub(crate) fn init() -> Self {
+        return OPLL {
+            address: 0,
+            custom_patch: [0; 8],
+            channels: std::array::from_fn(|_| FMChannel::init()),
+            cycle: 0,
+            tremolo_phase: 0.0,
+            vibrato_phase: 0.0,
+            output: 0.0,
+        };
+    }
+
+    pub(crate) fn write_address(&mut self, val: u8) {
+        self.address = val;
+    }
+
+    pub(crate) fn write_data(&mut self, val: u8) {
+        let channel = (self.address & 0x0F) as usize;
+        match self.address {
+            0x00..=0x07 => self.custom_patch[self.address as usize] = val,
+            0x10..=0x15 => {
+                let channel = &mut self.channels[channel];
+                channel.fnum = (channel.fnum & 0x100) | val as u16;
+            }
+            0x20..=0x25 => self.channels[channel].write_control(val),
+            0x30..=0x35 => {
+                self.channels[channel].instrument = val >> 4;
+                self.channels[channel].volume = val & 0x0F;
+            }
+            _ => {}
+        }
+    }
+
+    /// Advances by one CPU cycle.
+    pub(crate) fn tick(&mut self) {
+        self.cycle += 1;
+        if self.cycle < CYCLES_PER_SAMPLE {
+            return;
+        }
+        self.cycle = 0;
+        self.tremolo_phase = (self.tremolo_phase + TREMOLO_HZ / SAMPLE_RATE).fract();
+        self.vibrato_phase = (self.vibrato_phase + VIBRATO_HZ / SAMPLE_RATE).fract();
+        let tremolo = (1.0 + (TAU * self.tremolo_phase).sin()) / 2.0 * TREMOLO_DB;
+        let vibrato = (TAU * self.vibrato_phase).sin() * VIBRATO_DEPTH;
+        let mut output = 0.0;
+        for channel in &mut self.channels {
+            let patch = match channel.instrument {
+                0 => &self.custom_patch,
+                instrument => &PATCHES[instrument as usize - 1],
+            };
+            output += channel.clock(patch, tremolo, vibrato);
+        }
+        self.output = output;
+    }
+
+    /// The sum of the channels, each in [-1, 1].
+    pub(crate) fn output(&self) -> f32 {
+        return self.output;
+    }
+}
+
+impl SaveState for Operator {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.phase);
+        state.write(&(self.stage as u8));
+        state.write(&self.attenuation);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read(&mut self.phase)?;
+        let mut stage = 0u8;
+        state.read(&mut stage)?;
+        self.stage = EnvelopeStage::from_u8(stage);
+        state.read(&mut self.attenuation)?;
+        return Ok(());
+    }
+}
+
+impl SaveState for FMChannel {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.fnum);
+        state.write(&self.block);
+        state.write(&self.key_on);
+        state.write(&self.sustain);
+        state.write(&self.instrument);
+        state.write(&self.volume);
+        state.write(&self.modulator);
+        state.write(&self.carrier);
+        state.write(&self.feedback[0]);
+        state.write(&self.feedback[1]);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read(&mut self.fnum)?;
+        state.read(&mut self.block)?;
+        state.read(&mut self.key_on)?;
+        state.read(&mut self.sustain)?;
+        state.read(&mut self.instrument)?;
+        if self.instrument as usize > PATCHES.len() {
+            return Err(StateError::Invalid("instrument"));
+        }
+        state.read(&mut self.volume)?;
+        state.read(&mut self.modulator)?;
+        state.read(&mut self.carrier)?;
+        state.read(&mut self.feedback[0])?;
+        state.read(&mut self.feedback[1])?;
+        return Ok(());
+    }
+}
+
+impl SaveState for OPLL {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.address);
+        state.write(&self.custom_patch);
+        for channel in &self.channels {
+            state.write(channel);
+        }
+        state.write(&self.cycle);
+        state.write(&self.tremolo_phase);
+        state.write(&self.vibrato_phase);
+        state.write(&self.output);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read(&mut self.address)?;
+        state.read(&mut self.custom_patch)?;
+        for channel in &mut self.channels {
+            state.read(channel)?;
+        }
+        state.read(&mut self.cycle)?;
+        state.read(&mut self.tremolo_phase)?;
+        state.read(&mut self.vibrato_phase)?;
+        state.read(&mut self.output)?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(opll: &mut OPLL, samples: usize) -> Vec<f32> {
+        let mut outputs = Vec::new();
+        for _ in 0..samples {
+            for _ in 0..CYCLES_PER_SAMPLE {
+                opll.tick();
+            }
+            outputs.push(opll.output());
+        }
+        return outputs;
+    }
+
+    fn write(opll: &mut OPLL, reg: u8, val: u8) {
+        opll.write_address(reg);
+        opll.write_data(val);
+    }
+
+    #[test]
+    fn test_key_on_plays_until_released() {
+        let mut opll = OPLL::init();
+        assert!(run(&mut opll, 100).iter().all(|&sample| sample == 0.0));
+
+        // instrument 3 at full volume, a 440Hz A
+        write(&mut opll, 0x30, 0x30);
+        write(&mut opll, 0x10, 0x20);
+        write(&mut opll, 0x20, KEY_ON | (4 << 1) | 1);
+        let playing = run(&mut opll, 2000);
+        let peak = playing
+            .iter()
+            .fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        assert!(peak > 0.1 && peak <= 1.0);
+
+        write(&mut opll, 0x20, (4 << 1) | 1);
+        let released = run(&mut opll, 50_000);
+        assert!(released[released.len() - 100..]
+            .iter()
+            .all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn test_load_state_checks_instruments() {
+        let mut opll = OPLL::init();
+        write(&mut opll, 0x30, 0xF0);
+        // any address is fine, only $00-$07 write the custom patch
+        opll.write_address(0xFF);
+        let mut state = StateWriter::init();
+        state.write(&opll);
+        let data = state.into_bytes();
+        assert_eq!(StateReader::init(&data).read(&mut opll), Ok(()));
+
+        opll.channels[0].instrument = 16;
+        let mut state = StateWriter::init();
+        state.write(&opll);
+        let data = state.into_bytes();
+        assert_eq!(
+            StateReader::init(&data).read(&mut opll),
+            Err(StateError::Invalid("instrument"))
+        );
+    }
+}
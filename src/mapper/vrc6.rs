@@ -0,0 +1,435 @@
+use crate::cartridge::{Cartridge, Mirroring};
+use crate::mapper::vrc_irq::VRCIRQ;
+use crate::mapper::Mapper;
+use crate::ram::{START_CARTRIDGE_RAM, START_CARTRIDGE_ROM};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x400;
+
+const CONTROL_MIRRORING: u8 = 0b1100;
+const CONTROL_PRG_RAM_ENABLE: u8 = 0x80;
+
+const FREQUENCY_HALT: u8 = 0b001;
+const FREQUENCY_SHIFT_4: u8 = 0b010;
+const FREQUENCY_SHIFT_8: u8 = 0b100;
+const CHANNEL_ENABLE: u8 = 0x80;
+
+#[derive(Debug, Default)]
+struct VRC6Pulse {
+    volume: u8,
+    duty: u8,
+    // ignores the duty and outputs the volume constantly
+    digitized: bool,
+    period: u16,
+    enabled: bool,
+    timer: u16,
+    step: u8,
+}
+
+impl VRC6Pulse {
+    fn write_register(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.digitized = val & 0x80 != 0;
+                self.duty = (val >> 4) & 0b111;
+                self.volume = val & 0x0F;
+            }
+            1 => self.period = (self.period & 0x0F00) | val as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | (((val & 0x0F) as u16) << 8);
+                self.enabled = val & CHANNEL_ENABLE != 0;
+                if !self.enabled {
+                    self.step = 15;
+                }
+            }
+        }
+    }
+
+    fn clock_timer(&mut self, shift: u8) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period >> shift;
+        self.step = self.step.checked_sub(1).unwrap_or(15);
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled || !(self.digitized || self.step <= self.duty) {
+            return 0;
+        }
+        return self.volume;
+    }
+}
+
+#[derive(Debug, Default)]
+struct VRC6Saw {
+    rate: u8,
+    period: u16,
+    enabled: bool,
+    timer: u16,
+    step: u8,
+    accumulator: u8,
+}
+
+impl VRC6Saw {
+    fn write_register(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => self.rate = val & 0x3F,
+            1 => self.period = (self.period & 0x0F00) | val as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | (((val & 0x0F) as u16) << 8);
+                self.enabled = val & CHANNEL_ENABLE != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+
+    // the accumulator adds the rate on every other step, and resets instead
+    // of adding the seventh time
+    fn clock_timer(&mut self, shift: u8) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period >> shift;
+        self.step += 1;
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if self.step.is_multiple_of(2) {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        return self.accumulator >> 3;
+    }
+}
+
+/// Mappers 24 and 26, Konami's VRC6: 16KB and 8KB PRG banks, 1KB CHR banks,
+/// the VRC IRQ counter and two pulse channels with a sawtooth. Mapper 26
+/// has the two low address lines swapped.
+#[derive(Debug)]
+pub struct VRC6 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    has_battery: bool,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    swap_address_lines: bool,
+
+    prg_bank_16k: u8,
+    prg_bank_8k: u8,
+    chr_banks: [u8; 8],
+    control: u8,
+    irq: VRCIRQ,
+
+    frequency_control: u8,
+    pulse_1: VRC6Pulse,
+    pulse_2: VRC6Pulse,
+    saw: VRC6Saw,
+}
+
+impl VRC6 {
+    pub fn init(cartridge: &Cartridge, swap_address_lines: bool) -> Self {
+        let chr_is_ram = cartridge.get_chr_rom().is_empty();
+        let chr = if chr_is_ram {
+            vec![0; cartridge.get_chr_ram_size()]
+        } else {
+            cartridge.get_chr_rom().to_vec()
+        };
+        return VRC6 {
+            prg_rom: cartridge.get_prg_rom().to_vec(),
+            prg_ram: vec![0; cartridge.get_prg_ram_size()],
+            has_battery: cartridge.has_battery(),
+            chr,
+            chr_is_ram,
+            swap_address_lines,
+            prg_bank_16k: 0,
+            prg_bank_8k: 0,
+            chr_banks: [0; 8],
+            control: 0,
+            irq: VRCIRQ::default(),
+            frequency_control: 0,
+            pulse_1: VRC6Pulse::default(),
+            pulse_2: VRC6Pulse::default(),
+            saw: VRC6Saw::default(),
+        };
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        let mut reg = addr & 0b11;
+        if self.swap_address_lines {
+            reg = (reg >> 1) | ((reg & 1) << 1);
+        }
+        match (addr & 0xF000, reg) {
+            (0x8000, _) => self.prg_bank_16k = val & 0x0F,
+            (0x9000, 3) => self.frequency_control = val,
+            (0x9000, _) => self.pulse_1.write_register(reg, val),
+            (0xA000, 3) => {}
+            (0xA000, _) => self.pulse_2.write_register(reg, val),
+            (0xB000, 3) => self.control = val,
+            (0xB000, _) => self.saw.write_register(reg, val),
+            (0xC000, _) => self.prg_bank_8k = val & 0x1F,
+            (0xD000, _) => self.chr_banks[reg as usize] = val,
+            (0xE000, _) => self.chr_banks[4 + reg as usize] = val,
+            (_, 0) => self.irq.write_latch(val),
+            (_, 1) => self.irq.write_control(val),
+            (_, 2) => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn get_chr_offset(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[addr as usize / CHR_BANK_SIZE] as usize;
+        return (bank * CHR_BANK_SIZE + addr as usize % CHR_BANK_SIZE) % self.chr.len();
+    }
+
+    fn is_prg_ram_enabled(&self) -> bool {
+        return !self.prg_ram.is_empty() && self.control & CONTROL_PRG_RAM_ENABLE != 0;
+    }
+}
+
+impl Mapper for VRC6 {
+    fn read_prg(&mut self, addr: u16) -> u8 {
+        if let Some(offset) = self.get_prg_rom_offset(addr) {
+            return self.prg_rom[offset];
+        }
+        if !self.is_prg_ram_enabled() {
+            return 0;
+        }
+        return self.prg_ram[(addr - START_CARTRIDGE_RAM) as usize % self.prg_ram.len()];
+    }
+
+    fn write_prg(&mut self, addr: u16, val: u8) {
+        if addr >= START_CARTRIDGE_ROM {
+            self.write_register(addr, val);
+        } else if self.is_prg_ram_enabled() {
+            let len = self.prg_ram.len();
+            self.prg_ram[(addr - START_CARTRIDGE_RAM) as usize % len] = val;
+        }
+    }
+
+    fn read_chr(&mut self, addr: u16) -> u8 {
+        return self.chr[self.get_chr_offset(addr)];
+    }
+
+    fn write_chr(&mut self, addr: u16, val: u8) {
+        if self.chr_is_ram {
+            let offset = self.get_chr_offset(addr);
+            self.chr[offset] = val;
+        }
+    }
+
+    fn get_mirroring(&self) -> Mirroring {
+        match (self.control & CONTROL_MIRRORING) >> 2 {
+            0 => return Mirroring::Vertical,
+            1 => return Mirroring::Horizontal,
+            2 => return Mirroring::SingleScreenA,
+            _ => return Mirroring::SingleScreenB,
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        return self.irq.is_pending();
+    }
+
+    fn tick(&mut self) {
+        self.irq.tick();
+        if self.frequency_control & FREQUENCY_HALT != 0 {
+            return;
+        }
+        let shift = if self.frequency_control & FREQUENCY_SHIFT_8 != 0 {
+            8
+        } else if self.frequency_control & FREQUENCY_SHIFT_4 != 0 {
+            4
+        } else {
+            0
+        };
+        self.pulse_1.clock_timer(shift);
+        self.pulse_2.clock_timer(shift);
+        self.saw.clock_timer(shift);
+    }
+
+    // the pulses are about as loud as the APU's, step for step
+    fn get_audio_output(&self) -> f32 {
+        let output = self.pulse_1.output() + self.pulse_2.output() + self.saw.output();
+        return output as f32;
+    }
+
+    fn get_prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr < START_CARTRIDGE_ROM {
+            return None;
+        }
+        let bank = match addr {
+            0x8000..=0xBFFF => self.prg_bank_16k as usize * 2 + (addr as usize - 0x8000) / 0x2000,
+            0xC000..=0xDFFF => self.prg_bank_8k as usize,
+            _ => self.prg_rom.len() / PRG_BANK_SIZE - 1,
+        };
+        let offset = bank * PRG_BANK_SIZE + addr as usize % PRG_BANK_SIZE;
+        return Some(offset % self.prg_rom.len());
+    }
+
+    fn get_prg_rom_mut(&mut self) -> Option<&mut [u8]> {
+        return Some(&mut self.prg_rom);
+    }
+
+    fn get_chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        if self.chr_is_ram {
+            return None;
+        }
+        return Some(self.get_chr_offset(addr));
+    }
+
+    fn get_battery_ram(&self) -> Option<&[u8]> {
+        if self.has_battery && !self.prg_ram.is_empty() {
+            return Some(&self.prg_ram);
+        }
+        return None;
+    }
+
+    fn get_battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        if self.has_battery && !self.prg_ram.is_empty() {
+            return Some(&mut self.prg_ram);
+        }
+        return None;
+    }
+}
+
+impl SaveState for VRC6Pulse {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.volume);
+        state.write(&self.duty);
+        state.write(&self.digitized);
+        state.write(&self.period);
+        state.write(&self.enabled);
+        state.write(&self.timer);
+        state.write(&self.step);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read(&mut self.volume)?;
+        state.read(&mut self.duty)?;
+        state.read(&mut self.digitized)?;
+        state.read(&mut self.period)?;
+        state.read(&mut self.enabled)?;
+        state.read(&mut self.timer)?;
+        state.read(&mut self.step)?;
+        return Ok(());
+    }
+}
+
+impl SaveState for VRC6Saw {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.rate);
+        state.write(&self.period);
+        state.write(&self.enabled);
+        state.write(&self.timer);
+        state.write(&self.step);
+        state.write(&self.accumulator);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read(&mut self.rate)?;
+        state.read(&mut self.period)?;
+        state.read(&mut self.enabled)?;
+        state.read(&mut self.timer)?;
+        state.read(&mut self.step)?;
+        state.read(&mut self.accumulator)?;
+        return Ok(());
+    }
+}
+
+impl SaveState for VRC6 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.prg_ram);
+        if self.chr_is_ram {
+            state.write(&self.chr);
+        }
+        state.write(&self.prg_bank_16k);
+        state.write(&self.prg_bank_8k);
+        state.write(&self.chr_banks);
+        state.write(&self.control);
+        state.write(&self.irq);
+        state.write(&self.frequency_control);
+        state.write(&self.pulse_1);
+        state.write(&self.pulse_2);
+        state.write(&self.saw);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read(&mut self.prg_ram)?;
+        if self.chr_is_ram {
+            state.read(&mut self.chr)?;
+        }
+        state.read(&mut self.prg_bank_16k)?;
+        state.read(&mut self.prg_bank_8k)?;
+        state.read(&mut self.chr_banks)?;
+        state.read(&mut self.control)?;
+        state.read(&mut self.irq)?;
+        state.read(&mut self.frequency_control)?;
+        state.read(&mut self.pulse_1)?;
+        state.read(&mut self.pulse_2)?;
+        state.read(&mut self.saw)?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::ines_rom;
+
+    #[test]
+    fn test_banking_and_swapped_address_lines() {
+        // 8KB PRG banks and 1KB CHR banks that each start with their number
+        let mut data = ines_rom(26, 8, 2, 0);
+        for bank in 0..16 {
+            data[16 + bank * PRG_BANK_SIZE] = bank as u8;
+        }
+        for bank in 0..16 {
+            data[16 + 0x20000 + bank * CHR_BANK_SIZE] = bank as u8;
+        }
+        let mut vrc6 = VRC6::init(&Cartridge::from_ines(&data).unwrap(), true);
+        assert_eq!(vrc6.read_prg(0xE000), 15);
+        vrc6.write_prg(0x8000, 3);
+        assert_eq!(vrc6.read_prg(0x8000), 6);
+        assert_eq!(vrc6.read_prg(0xA000), 7);
+        vrc6.write_prg(0xC000, 9);
+        assert_eq!(vrc6.read_prg(0xC000), 9);
+        // $D001 on mapper 26 is the third CHR register
+        vrc6.write_prg(0xD001, 5);
+        assert_eq!(vrc6.read_chr(0x0800), 5);
+        vrc6.write_prg(0xB003, 0x84);
+        assert_eq!(vrc6.get_mirroring(), Mirroring::Horizontal);
+    }
+
+    #[test]
+    fn test_pulse_and_saw_output() {
+        let data = ines_rom(24, 8, 2, 0);
+        let mut vrc6 = VRC6::init(&Cartridge::from_ines(&data).unwrap(), false);
+        // constant volume 9 on pulse 1
+        vrc6.write_prg(0x9000, 0x89);
+        vrc6.write_prg(0x9002, CHANNEL_ENABLE);
+        assert_eq!(vrc6.get_audio_output(), 9.0);
+
+        // the saw adds its rate every other step
+        vrc6.write_prg(0x9002, 0);
+        vrc6.write_prg(0xB000, 16);
+        vrc6.write_prg(0xB002, CHANNEL_ENABLE);
+        let mut outputs = Vec::new();
+        for _ in 0..14 {
+            vrc6.tick();
+            outputs.push(vrc6.get_audio_output() as u8);
+        }
+        assert_eq!(outputs, [0, 2, 2, 4, 4, 6, 6, 8, 8, 10, 10, 12, 12, 0]);
+    }
+}
@@ -0,0 +1,249 @@
+use crate::cartridge::{Cartridge, Mirroring};
+use crate::mapper::opll::OPLL;
+use crate::mapper::vrc_irq::VRCIRQ;
+use crate::mapper::Mapper;
+use crate::ram::{START_CARTRIDGE_RAM, START_CARTRIDGE_ROM};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x400;
+
+const CONTROL_MIRRORING: u8 = 0b11;
+const CONTROL_AUDIO_RESET: u8 = 0x40;
+const CONTROL_PRG_RAM_ENABLE: u8 = 0x80;
+
+// the boards wire the second register of each pair to A4 or A3
+const SECOND_REGISTER: u16 = 0x18;
+const AUDIO_DATA: u16 = 0x20;
+
+// a full-scale FM channel in pulse volume steps, about as loud as a
+// full-volume APU pulse
+const FM_LEVEL: f32 = 10.0;
+
+/// Mapper 85, Konami's VRC7: three switchable 8KB PRG banks, 1KB CHR banks,
+/// the VRC IRQ counter and a six channel FM synthesizer.
+#[derive(Debug)]
+pub struct VRC7 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    has_battery: bool,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+
+    prg_banks: [u8; 3],
+    chr_banks: [u8; 8],
+    control: u8,
+    irq: VRCIRQ,
+    opll: OPLL,
+}
+
+impl VRC7 {
+    pub fn init(cartridge: &Cartridge) -> Self {
+        let chr_is_ram = cartridge.get_chr_rom().is_empty();
+        let chr = if chr_is_ram {
+            vec![0; cartridge.get_chr_ram_size()]
+        } else {
+            cartridge.get_chr_rom().to_vec()
+        };
+        return VRC7 {
+            prg_rom: cartridge.get_prg_rom().to_vec(),
+            prg_ram: vec![0; cartridge.get_prg_ram_size()],
+            has_battery: cartridge.has_battery(),
+            chr,
+            chr_is_ram,
+            prg_banks: [0; 3],
+            chr_banks: [0; 8],
+            control: 0,
+            irq: VRCIRQ::default(),
+            opll: OPLL::init(),
+        };
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        let second = addr & SECOND_REGISTER != 0;
+        match (addr & 0xF000, second) {
+            (0x8000, false) => self.prg_banks[0] = val & 0x3F,
+            (0x8000, true) => self.prg_banks[1] = val & 0x3F,
+            (0x9000, _) if addr & AUDIO_DATA != 0 => self.opll.write_data(val),
+            (0x9000, true) => self.opll.write_address(val),
+            (0x9000, false) => self.prg_banks[2] = val & 0x3F,
+            (0xA000..=0xD000, _) => {
+                let index = ((addr - 0xA000) >> 12) as usize * 2 + second as usize;
+                self.chr_banks[index] = val;
+            }
+            (0xE000, false) => {
+                if val & CONTROL_AUDIO_RESET != 0 {
+                    self.opll = OPLL::init();
+                }
+                self.control = val;
+            }
+            (0xE000, true) => self.irq.write_latch(val),
+            (_, false) => self.irq.write_control(val),
+            (_, true) => self.irq.acknowledge(),
+        }
+    }
+
+    fn get_chr_offset(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[addr as usize / CHR_BANK_SIZE] as usize;
+        return (bank * CHR_BANK_SIZE + addr as usize % CHR_BANK_SIZE) % self.chr.len();
+    }
+
+    fn is_prg_ram_enabled(&self) -> bool {
+        return !self.prg_ram.is_empty() && self.control & CONTROL_PRG_RAM_ENABLE != 0;
+    }
+}
+
+impl Mapper for VRC7 {
+    fn read_prg(&mut self, addr: u16) -> u8 {
+        if let Some(offset) = self.get_prg_rom_offset(addr) {
+            return self.prg_rom[offset];
+        }
+        if !self.is_prg_ram_enabled() {
+            return 0;
+        }
+        return self.prg_ram[(addr - START_CARTRIDGE_RAM) as usize % self.prg_ram.len()];
+    }
+
+    fn write_prg(&mut self, addr: u16, val: u8) {
+        if addr >= START_CARTRIDGE_ROM {
+            self.write_register(addr, val);
+        } else if self.is_prg_ram_enabled() {
+            let len = self.prg_ram.len();
+            self.prg_ram[(addr - START_CARTRIDGE_RAM) as usize % len] = val;
+        }
+    }
+
+    fn read_chr(&mut self, addr: u16) -> u8 {
+        return self.chr[self.get_chr_offset(addr)];
+    }
+
+    fn write_chr(&mut self, addr: u16, val: u8) {
+        if self.chr_is_ram {
+            let offset = self.get_chr_offset(addr);
+            self.chr[offset] = val;
+        }
+    }
+
+    fn get_mirroring(&self) -> Mirroring {
+        match self.control & CONTROL_MIRRORING {
+            0 => return Mirroring::Vertical,
+            1 => return Mirroring::Horizontal,
+            2 => return Mirroring::SingleScreenA,
+            _ => return Mirroring::SingleScreenB,
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        return self.irq.is_pending();
+    }
+
+    fn tick(&mut self) {
+        self.irq.tick();
+        self.opll.tick();
+    }
+
+    fn get_audio_output(&self) -> f32 {
+        if self.control & CONTROL_AUDIO_RESET != 0 {
+            return 0.0;
+        }
+        return self.opll.output() * FM_LEVEL;
+    }
+
+    fn get_prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr < START_CARTRIDGE_ROM {
+            return None;
+        }
+        let slot = (addr - START_CARTRIDGE_ROM) as usize / PRG_BANK_SIZE;
+        let bank = match self.prg_banks.get(slot) {
+            Some(&bank) => bank as usize,
+            None => self.prg_rom.len() / PRG_BANK_SIZE - 1,
+        };
+        let offset = bank * PRG_BANK_SIZE + addr as usize % PRG_BANK_SIZE;
+        return Some(offset % self.prg_rom.len());
+    }
+
+    fn get_prg_rom_mut(&mut self) -> Option<&mut [u8]> {
+        return Some(&mut self.prg_rom);
+    }
+
+    fn get_chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        if self.chr_is_ram {
+            return None;
+        }
+        return Some(self.get_chr_offset(addr));
+    }
+
+    fn get_battery_ram(&self) -> Option<&[u8]> {
+        if self.has_battery && !self.prg_ram.is_empty() {
+            return Some(&self.prg_ram);
+        }
+        return None;
+    }
+
+    fn get_battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        if self.has_battery && !self.prg_ram.is_empty() {
+            return Some(&mut self.prg_ram);
+        }
+        return None;
+    }
+}
+
+impl SaveState for VRC7 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.prg_ram);
+        if self.chr_is_ram {
+            state.write(&self.chr);
+        }
+        state.write(&self.prg_banks);
+        state.write(&self.chr_banks);
+        state.write(&self.control);
+        state.write(&self.irq);
+        state.write(&self.opll);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read(&mut self.prg_ram)?;
+        if self.chr_is_ram {
+            state.read(&mut self.chr)?;
+        }
+        state.read(&mut self.prg_banks)?;
+        state.read(&mut self.chr_banks)?;
+        state.read(&mut self.control)?;
+        state.read(&mut self.irq)?;
+        state.read(&mut self.opll)?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::ines_rom;
+
+    #[test]
+    fn test_banking_on_either_address_line() {
+        let mut data = ines_rom(85, 8, 2, 0);
+        for bank in 0..16 {
+            data[16 + bank * PRG_BANK_SIZE] = bank as u8;
+        }
+        for bank in 0..16 {
+            data[16 + 0x20000 + bank * CHR_BANK_SIZE] = bank as u8;
+        }
+        let mut vrc7 = VRC7::init(&Cartridge::from_ines(&data).unwrap());
+        assert_eq!(vrc7.read_prg(0xE000), 15);
+        vrc7.write_prg(0x8000, 4);
+        vrc7.write_prg(0x8010, 5);
+        vrc7.write_prg(0x9000, 6);
+        assert_eq!(vrc7.read_prg(0x8000), 4);
+        assert_eq!(vrc7.read_prg(0xA000), 5);
+        assert_eq!(vrc7.read_prg(0xC000), 6);
+        // the A3 variant
+        vrc7.write_prg(0x8008, 7);
+        assert_eq!(vrc7.read_prg(0xA000), 7);
+
+        vrc7.write_prg(0xB010, 9);
+        assert_eq!(vrc7.read_chr(0x0C00), 9);
+        vrc7.write_prg(0xE000, 1);
+        assert_eq!(vrc7.get_mirroring(), Mirroring::Horizontal);
+    }
+}
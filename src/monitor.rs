@@ -0,0 +1,547 @@
+use rustes::asm::assemble;
+use rustes::debugger::{Debugger, RunLimit, StopReason, WatchKind, Watchpoint};
+use rustes::disasm::{disassemble_at, disassemble_range, Instruction};
+use rustes::symbols::SymbolTable;
+use rustes::{Console, MemoryBus};
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "commands (addresses and values in hex, counts in decimal; addresses
+can also be labels from loaded symbol files):
+    s, step [n]             run n instructions (default 1)
+    u, until <addr>         run until PC reaches addr
+    c, continue [frames]    run until a breakpoint or watchpoint, or for
+                            that many frames (at most 3600)
+    b <addr>                set a breakpoint
+    bd <addr>               delete a breakpoint
+    bl                      list breakpoints and watchpoints
+    w <start>[-<end>] [r|w|rw]
+                            watch reads, writes or both (default rw)
+    wd <n>                  delete watchpoint n as numbered by bl
+    r, regs                 show the registers
+    r <reg> <val>           set a, x, y, sp, p or pc
+    m <addr> [len]          dump memory (default 64 bytes)
+    e <addr> <bytes...>     write bytes to memory, patching PRG ROM in place
+    a <addr> <instruction>  assemble an instruction and write it at addr
+    d [addr] [count]        disassemble (default around PC)
+    sym <file>              load labels from a .dbg, .nl or .mlb file
+    reset                   press the reset button
+    q, quit                 leave";
+
+/// How long `continue` runs at most without a limit, one minute of NTSC.
+const MAX_CONTINUE_FRAMES: u64 = 3600;
+
+const DEFAULT_DUMP_LEN: u64 = 64;
+const DUMP_WIDTH: u32 = 16;
+const DEFAULT_DISASM_COUNT: usize = 10;
+// instructions shown before PC when disassembling around it
+const DISASM_CONTEXT: usize = 4;
+
+/// Parses `$12ab`, `0x12ab` or `12ab`.
+fn parse_hex(text: &str) -> Result<u16, String> {
+    let digits = text
+        .strip_prefix('$')
+        .or_else(|| text.strip_prefix("0x"))
+        .unwrap_or(text);
+    return u16::from_str_radix(digits, 16).map_err(|_| format!("'{}' is not a hex value", text));
+}
+
+fn parse_byte(text: &str) -> Result<u8, String> {
+    let val = parse_hex(text)?;
+    if val > 0xFF {
+        return Err(format!("'{}' doesn't fit in a byte", text));
+    }
+    return Ok(val as u8);
+}
+
+fn parse_count(text: Option<&str>, default: u64) -> Result<u64, String> {
+    match text {
+        Some(text) => {
+            return text
+                .parse()
+                .map_err(|_| format!("'{}' is not a number", text))
+        }
+        None => return Ok(default),
+    }
+}
+
+fn required<'a>(arg: Option<&'a str>, what: &str) -> Result<&'a str, String> {
+    return arg.ok_or_else(|| format!("missing {}", what));
+}
+
+fn format_flags(p: u8) -> String {
+    return "NV-BDIZC"
+        .chars()
+        .enumerate()
+        .map(|(i, flag)| if p & (0x80 >> i) != 0 { flag } else { '.' })
+        .collect();
+}
+
+/// An interactive debugger on stdin/stdout, started with `rustes debug`.
+pub struct Monitor {
+    console: Console,
+    debugger: Debugger,
+    symbols: SymbolTable,
+}
+
+impl Monitor {
+    pub fn init(console: Console) -> Self {
+        return Monitor {
+            console,
+            debugger: Debugger::init(),
+            symbols: SymbolTable::init(),
+        };
+    }
+
+    pub fn get_symbols_mut(&mut self) -> &mut SymbolTable {
+        return &mut self.symbols;
+    }
+
+    /// A label, or a hex address. `$` or `0x` forces hex for labels that
+    /// look like numbers.
+    fn parse_addr(&self, text: &str) -> Result<u16, String> {
+        if text.starts_with('$') || text.starts_with("0x") {
+            return parse_hex(text);
+        }
+        let bus = self.console.get_cpu().get_bus();
+        if let Some(addr) = self.symbols.resolve(bus, text) {
+            return Ok(addr);
+        }
+        return parse_hex(text).map_err(|_| format!("'{}' is not a label or hex address", text));
+    }
+
+    /// Reads commands until `quit` or the end of `input`.
+    pub fn run<R: BufRead, W: Write>(&mut self, input: R, mut output: W) -> io::Result<()> {
+        writeln!(output, "{}", self.format_location())?;
+        write!(output, "> ")?;
+        output.flush()?;
+        for line in input.lines() {
+            match self.execute(&line?) {
+                Ok(Some(text)) if text.is_empty() => {}
+                Ok(Some(text)) => writeln!(output, "{}", text)?,
+                Ok(None) => return Ok(()),
+                Err(err) => writeln!(output, "error: {}", err)?,
+            }
+            write!(output, "> ")?;
+            output.flush()?;
+        }
+        return Ok(());
+    }
+
+    /// Runs one command line and returns what to print, or None to quit.
+    pub fn execute(&mut self, line: &str) -> Result<Option<String>, String> {
+        let mut args = line.split_whitespace();
+        let command = match args.next() {
+            Some(command) => command,
+            None => return Ok(Some(String::new())),
+        };
+        let args: Vec<&str> = args.collect();
+        let arg = |i: usize| args.get(i).copied();
+        let text = match command {
+            "s" | "step" => {
+                let count = parse_count(arg(0), 1)?;
+                self.resume(RunLimit {
+                    instructions: Some(count),
+                    ..RunLimit::default()
+                })
+            }
+            "u" | "until" => {
+                let addr = self.parse_addr(required(arg(0), "address")?)?;
+                self.resume(RunLimit {
+                    until_pc: Some(addr),
+                    frames: Some(MAX_CONTINUE_FRAMES),
+                    ..RunLimit::default()
+                })
+            }
+            "c" | "continue" => {
+                let frames = parse_count(arg(0), MAX_CONTINUE_FRAMES)?;
+                self.resume(RunLimit {
+                    frames: Some(frames.min(MAX_CONTINUE_FRAMES)),
+                    ..RunLimit::default()
+                })
+            }
+            "b" => {
+                let addr = self.parse_addr(required(arg(0), "address")?)?;
+                self.debugger.add_breakpoint(addr);
+                format!("breakpoint at ${:04X}", addr)
+            }
+            "bd" => {
+                let addr = self.parse_addr(required(arg(0), "address")?)?;
+                if !self.debugger.remove_breakpoint(addr) {
+                    return Err(format!("no breakpoint at ${:04X}", addr));
+                }
+                String::new()
+            }
+            "bl" => self.format_breakpoints(),
+            "w" => {
+                let watchpoint = self.parse_watchpoint(&args)?;
+                self.console
+                    .get_cpu_mut()
+                    .get_bus_mut()
+                    .add_watchpoint(watchpoint);
+                String::new()
+            }
+            "wd" => {
+                let index = parse_count(Some(required(arg(0), "watchpoint number")?), 0)?;
+                let bus = self.console.get_cpu_mut().get_bus_mut();
+                if bus.remove_watchpoint(index as usize).is_none() {
+                    return Err(format!("no watchpoint {}", index));
+                }
+                String::new()
+            }
+            "r" | "regs" if args.is_empty() => self.format_registers(),
+            "r" | "regs" => {
+                self.set_register(required(arg(0), "register")?, arg(1))?;
+                self.format_registers()
+            }
+            "m" => {
+                let addr = self.parse_addr(required(arg(0), "address")?)?;
+                let len = parse_count(arg(1), DEFAULT_DUMP_LEN)?;
+                self.format_memory(addr, len.min(0x10000) as u32)
+            }
+            "e" => {
+                let addr = self.parse_addr(required(arg(0), "address")?)?;
+                let bytes = args[1..]
+                    .iter()
+                    .map(|byte| parse_byte(byte))
+                    .collect::<Result<Vec<u8>, String>>()?;
+                let bus = self.console.get_cpu_mut().get_bus_mut();
+                for (i, byte) in bytes.iter().enumerate() {
+                    bus.poke_memory_byte(addr.wrapping_add(i as u16), *byte);
+                }
+                bus.take_watch_hit();
+                String::new()
+            }
+            "a" => {
+                let addr = self.parse_addr(required(arg(0), "address")?)?;
+                let source = args[1..].join(" ");
+                let program = assemble(&source, addr).map_err(|err| err.msg)?;
+                let bus = self.console.get_cpu_mut().get_bus_mut();
+                for (i, byte) in program.bytes.iter().enumerate() {
+                    bus.poke_memory_byte(addr.wrapping_add(i as u16), *byte);
+                }
+                bus.take_watch_hit();
+                self.format_disassembly(addr, 1)
+            }
+            "d" => {
+                let count = parse_count(arg(1), DEFAULT_DISASM_COUNT as u64)? as usize;
+                match arg(0) {
+                    Some(addr) => self.format_disassembly(self.parse_addr(addr)?, count),
+                    None => {
+                        let start = self.find_start_before_pc(DISASM_CONTEXT);
+                        self.format_disassembly(start, count)
+                    }
+                }
+            }
+            "sym" => {
+                let path = required(arg(0), "file")?;
+                let before = self.symbols.len();
+                self.symbols
+                    .load(path)
+                    .map_err(|err| format!("{}: {}", path, err))?;
+                format!("loaded {} labels", self.symbols.len() - before)
+            }
+            "reset" => {
+                self.console.reset();
+                self.format_location()
+            }
+            "h" | "help" | "?" => String::from(HELP),
+            "q" | "quit" => return Ok(None),
+            _ => return Err(format!("unknown command '{}', try help", command)),
+        };
+        return Ok(Some(text));
+    }
+
+    fn resume(&mut self, limit: RunLimit) -> String {
+        let reason = match self.debugger.run(&mut self.console, limit) {
+            StopReason::Breakpoint(addr) => format!("breakpoint at ${:04X}", addr),
+            StopReason::Watchpoint(hit) => format!(
+                "watchpoint: {} ${:02X} {} ${:04X}",
+                if hit.is_write { "wrote" } else { "read" },
+                hit.val,
+                if hit.is_write { "to" } else { "from" },
+                hit.addr
+            ),
+            StopReason::ReachedPc(_) | StopReason::InstructionLimit => String::new(),
+            StopReason::FrameLimit => String::from("frame limit reached"),
+        };
+        if reason.is_empty() {
+            return self.format_location();
+        }
+        return format!("{}\n{}", reason, self.format_location());
+    }
+
+    fn parse_watchpoint(&self, args: &[&str]) -> Result<Watchpoint, String> {
+        let range = required(args.first().copied(), "address range")?;
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (self.parse_addr(start)?, self.parse_addr(end)?),
+            None => (self.parse_addr(range)?, self.parse_addr(range)?),
+        };
+        if end < start {
+            return Err(format!("empty range {}", range));
+        }
+        let kind = match args.get(1).copied() {
+            Some("r") => WatchKind::Read,
+            Some("w") => WatchKind::Write,
+            Some("rw") | None => WatchKind::Access,
+            Some(kind) => return Err(format!("unknown watch kind '{}'", kind)),
+        };
+        return Ok(Watchpoint { start, end, kind });
+    }
+
+    fn set_register(&mut self, name: &str, val: Option<&str>) -> Result<(), String> {
+        let val = parse_hex(required(val, "value")?)?;
+        let cpu = self.console.get_cpu_mut();
+        if name == "pc" {
+            cpu.set_pc(val);
+            return Ok(());
+        }
+        if val > 0xFF {
+            return Err(format!("{} is an 8 bit register", name));
+        }
+        match name {
+            "a" => cpu.set_a(val as u8),
+            "x" => cpu.set_x(val as u8),
+            "y" => cpu.set_y(val as u8),
+            "sp" => cpu.set_sp(val as u8),
+            "p" => cpu.set_ps(val as u8),
+            _ => return Err(format!("unknown register '{}'", name)),
+        }
+        return Ok(());
+    }
+
+    /// The registers followed by the next instruction.
+    fn format_location(&mut self) -> String {
+        let pc = self.console.get_cpu().get_pc();
+        let instruction = disassemble_at(self.console.get_cpu_mut().get_bus_mut(), pc);
+        return format!(
+            "{}\n{}",
+            self.format_registers(),
+            self.format_instruction(&instruction)
+        );
+    }
+
+    fn get_label(&self, addr: u16) -> Option<&str> {
+        return self
+            .symbols
+            .get_label(self.console.get_cpu().get_bus(), addr);
+    }
+
+    fn format_instruction(&self, instruction: &Instruction) -> String {
+        let bytes: Vec<String> = instruction
+            .get_bytes()
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+        return format!(
+            "{:04X}  {:<8}  {}",
+            instruction.addr,
+            bytes.join(" "),
+            instruction.format_with_labels(|addr| self.get_label(addr))
+        );
+    }
+
+    fn format_registers(&self) -> String {
+        let cpu = self.console.get_cpu();
+        let ppu = cpu.get_bus().get_ppu();
+        return format!(
+            "PC:{:04X} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} [{}] SP:{:02X} CYC:{} FRAME:{}",
+            cpu.get_pc(),
+            cpu.get_a(),
+            cpu.get_x(),
+            cpu.get_y(),
+            cpu.get_ps(),
+            format_flags(cpu.get_ps()),
+            cpu.get_sp(),
+            cpu.get_cycles(),
+            ppu.get_frame()
+        );
+    }
+
+    fn format_breakpoints(&self) -> String {
+        let mut lines: Vec<String> = self
+            .debugger
+            .get_breakpoints()
+            .map(|addr| format!("break ${:04X}", addr))
+            .collect();
+        let watchpoints = self.console.get_cpu().get_bus().get_watchpoints();
+        for (i, watchpoint) in watchpoints.iter().enumerate() {
+            let kind = match watchpoint.kind {
+                WatchKind::Read => "r",
+                WatchKind::Write => "w",
+                WatchKind::Access => "rw",
+            };
+            lines.push(format!(
+                "watch {}: ${:04X}-${:04X} {}",
+                i, watchpoint.start, watchpoint.end, kind
+            ));
+        }
+        if lines.is_empty() {
+            return String::from("no breakpoints or watchpoints");
+        }
+        return lines.join("\n");
+    }
+
+    fn format_memory(&mut self, addr: u16, len: u32) -> String {
+        let bus = self.console.get_cpu_mut().get_bus_mut();
+        let mut lines = Vec::new();
+        let mut offset = 0;
+        while offset < len {
+            let row_addr = addr.wrapping_add(offset as u16);
+            let row: Vec<u8> = (0..DUMP_WIDTH.min(len - offset))
+                .map(|i| bus.peek_memory_byte(row_addr.wrapping_add(i as u16)))
+                .collect();
+            let hex: Vec<String> = row.iter().map(|byte| format!("{:02X}", byte)).collect();
+            let ascii: String = row
+                .iter()
+                .map(|&byte| {
+                    if byte.is_ascii_graphic() || byte == b' ' {
+                        byte as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            lines.push(format!(
+                "{:04X}  {:<47}  {}",
+                row_addr,
+                hex.join(" "),
+                ascii
+            ));
+            offset += DUMP_WIDTH;
+        }
+        return lines.join("\n");
+    }
+
+    fn format_disassembly(&mut self, addr: u16, count: usize) -> String {
+        let pc = self.console.get_cpu().get_pc();
+        let bus = self.console.get_cpu_mut().get_bus_mut();
+        let mut lines = Vec::new();
+        for instruction in disassemble_range(bus, addr, count) {
+            if let Some(label) = self.get_label(instruction.addr) {
+                lines.push(format!("{}:", label));
+            }
+            let marker = if instruction.addr == pc { ">" } else { " " };
+            lines.push(format!(
+                "{} {}",
+                marker,
+                self.format_instruction(&instruction)
+            ));
+        }
+        return lines.join("\n");
+    }
+
+    /// Code can't be decoded backwards, so this tries start addresses further
+    /// and further back until one decodes into PC after `context`
+    /// instructions. Falls back to PC itself.
+    fn find_start_before_pc(&mut self, context: usize) -> u16 {
+        let pc = self.console.get_cpu().get_pc();
+        let bus = self.console.get_cpu_mut().get_bus_mut();
+        for back in context as u16..=context as u16 * 3 {
+            let start = pc.wrapping_sub(back);
+            let instructions = disassemble_range(bus, start, context);
+            let last = &instructions[context - 1];
+            if last.addr.wrapping_add(last.get_length()) == pc {
+                return start;
+            }
+        }
+        return pc;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rustes::Cartridge;
+
+    // the library's test ROM helpers are cfg(test) there and can't be seen
+    // from here, so the NROM image is put together from the assembler output
+    fn monitor() -> Monitor {
+        let program = assemble(
+            "       LDX #0
+            loop:   INX
+                    STX $10
+                    JMP loop
+                    .org $BFFC      ; the reset vector, mirrored at $FFFC
+                    .word $8000",
+            0x8000,
+        )
+        .unwrap();
+        let mut data = vec![b'N', b'E', b'S', 0x1A, 1, 1];
+        data.resize(16, 0);
+        data.extend(&program.bytes);
+        data.resize(16 + 0x4000 + 0x2000, 0);
+        let console = Console::init(&Cartridge::from_ines(&data).unwrap()).unwrap();
+        return Monitor::init(console);
+    }
+
+    fn execute(monitor: &mut Monitor, line: &str) -> String {
+        return monitor.execute(line).unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_step_breakpoint_and_watchpoint() {
+        let mut monitor = monitor();
+        assert!(execute(&mut monitor, "s 3").ends_with("8005  4C 02 80  JMP $8002"));
+        execute(&mut monitor, "b $8003");
+        assert!(execute(&mut monitor, "c").starts_with("breakpoint at $8003"));
+        execute(&mut monitor, "w 10 w");
+        assert!(execute(&mut monitor, "c").starts_with("watchpoint: wrote $02 to $0010"));
+        assert_eq!(
+            execute(&mut monitor, "bl"),
+            "break $8003\nwatch 0: $0010-$0010 w"
+        );
+        execute(&mut monitor, "wd 0");
+        execute(&mut monitor, "bd 8003");
+        assert!(execute(&mut monitor, "u 8005").contains("PC:8005"));
+        assert_eq!(monitor.execute("q"), Ok(None));
+    }
+
+    #[test]
+    fn test_registers_memory_and_disassembly() {
+        let mut monitor = monitor();
+        assert!(execute(&mut monitor, "r x 7f").contains("X:7F"));
+        assert!(monitor.execute("r x 100").is_err());
+        execute(&mut monitor, "e 0200 48 69 21");
+        assert!(execute(&mut monitor, "m 200 3").starts_with("0200  48 69 21"));
+        assert!(execute(&mut monitor, "m 200 3").ends_with("Hi!"));
+
+        let disassembly = execute(&mut monitor, "d 8000 4");
+        let lines: Vec<&str> = disassembly.lines().collect();
+        assert_eq!(lines[0], "> 8000  A2 00     LDX #$00");
+        assert_eq!(lines[3], "  8005  4C 02 80  JMP $8002");
+        execute(&mut monitor, "s 3");
+        let disassembly = execute(&mut monitor, "d");
+        assert!(disassembly.contains("\n> 8005  4C 02 80  JMP $8002\n"));
+        assert!(monitor.execute("frobnicate").is_err());
+
+        assert_eq!(
+            execute(&mut monitor, "a 0300 STA $0210,X"),
+            "  0300  9D 10 02  STA $0210,X"
+        );
+        assert!(monitor.execute("a 0300 FOO").is_err());
+        // the echo is disassembled from memory, so it shows the new ROM bytes
+        assert_eq!(
+            execute(&mut monitor, "a 8000 LDX #$05"),
+            "  8000  A2 05     LDX #$05"
+        );
+        // e changes the operand of the instruction just assembled into ROM
+        execute(&mut monitor, "e 8001 06");
+        assert!(execute(&mut monitor, "m 8000 2").starts_with("8000  A2 06"));
+    }
+
+    #[test]
+    fn test_labels() {
+        let mut monitor = monitor();
+        monitor
+            .get_symbols_mut()
+            .parse_nl("$8002#loop#\n$0010#counter#\n", None)
+            .unwrap();
+        execute(&mut monitor, "b loop");
+        assert_eq!(execute(&mut monitor, "bl"), "break $8002");
+        let disassembly = execute(&mut monitor, "d 8000 4");
+        let lines: Vec<&str> = disassembly.lines().collect();
+        assert_eq!(lines[1], "loop:");
+        assert_eq!(lines[3], "  8003  86 10     STX counter");
+        assert_eq!(lines[4], "  8005  4C 02 80  JMP loop");
+    }
+}
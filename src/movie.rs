@@ -0,0 +1,444 @@
+//! Input movies in FCEUX's text `.fm2` format.
+//!
+//! A movie is a header of `key value` lines followed by one input line per
+//! frame, `|commands|RLDUTSBA|RLDUTSBA||`, where a `.` or space is a released
+//! button and anything else a held one. Movies start at power-on unless the
+//! header carries a `savestate`, which for movies recorded here is one of our
+//! own save states; FCEUX save states can't be loaded.
+
+use crate::console::Console;
+use crate::savestate::StateError;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const FM2_VERSION: &str = "3";
+// FM2 orders buttons from bit 7 down to bit 0 of our masks
+const FM2_BUTTONS: &[u8; 8] = b"RLDUTSBA";
+
+pub const COMMAND_SOFT_RESET: u8 = 0x01;
+pub const COMMAND_HARD_RESET: u8 = 0x02;
+
+const BASE64_CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[derive(Debug)]
+pub enum MovieError {
+    Io(io::Error),
+    Parse(usize, String),
+    Unsupported(String),
+    State(StateError),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::Io(err) => return write!(f, "could not read movie: {}", err),
+            MovieError::Parse(line, msg) => return write!(f, "line {}: {}", line, msg),
+            MovieError::Unsupported(what) => return write!(f, "{} is not supported", what),
+            MovieError::State(err) => return write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+impl From<io::Error> for MovieError {
+    fn from(err: io::Error) -> Self {
+        return MovieError::Io(err);
+    }
+}
+
+impl From<StateError> for MovieError {
+    fn from(err: StateError) -> Self {
+        return MovieError::State(err);
+    }
+}
+
+fn encode_base64(data: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let bits = ((bytes[0] as u32) << 16) | ((bytes[1] as u32) << 8) | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_CHARS[(bits >> (18 - i * 6)) as usize & 0x3F] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    return out;
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut bits = 0u32;
+    let mut bit_count = 0;
+    for c in text.bytes().filter(|c| *c != b'=') {
+        let val = BASE64_CHARS.iter().position(|b| *b == c)? as u32;
+        bits = (bits << 6) | val;
+        bit_count += 6;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            out.push((bits >> bit_count) as u8);
+        }
+    }
+    return Some(out);
+}
+
+fn parse_buttons(field: &str) -> Option<u8> {
+    if field.is_empty() {
+        return Some(0);
+    }
+    if field.len() != FM2_BUTTONS.len() {
+        return None;
+    }
+    let mut buttons = 0;
+    for (i, c) in field.bytes().enumerate() {
+        if c != b'.' && c != b' ' {
+            buttons |= 0x80 >> i;
+        }
+    }
+    return Some(buttons);
+}
+
+fn format_buttons(buttons: u8) -> String {
+    let mut out = String::new();
+    for (i, c) in FM2_BUTTONS.iter().enumerate() {
+        out.push(if buttons & (0x80 >> i) != 0 {
+            *c as char
+        } else {
+            '.'
+        });
+    }
+    return out;
+}
+
+/// Input for a single frame.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MovieFrame {
+    pub commands: u8,
+    pub ports: [u8; 2],
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Movie {
+    // header lines other than the ones below, kept so they survive a rewrite
+    header: Vec<(String, String)>,
+    rerecord_count: u32,
+    start_state: Option<Vec<u8>>,
+    frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    /// An empty movie starting at power-on.
+    pub fn init() -> Self {
+        return Movie::default();
+    }
+
+    /// An empty movie starting from a save state.
+    pub fn from_save_state(state: Vec<u8>) -> Self {
+        let mut movie = Movie::init();
+        movie.start_state = Some(state);
+        return movie;
+    }
+
+    pub fn get_start_state(&self) -> Option<&[u8]> {
+        return self.start_state.as_deref();
+    }
+
+    pub fn get_frames(&self) -> &[MovieFrame] {
+        return &self.frames;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.frames.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.frames.is_empty();
+    }
+
+    pub fn get_rerecord_count(&self) -> u32 {
+        return self.rerecord_count;
+    }
+
+    /// The value of a header line such as `romFilename` or `comment`.
+    pub fn get_header(&self, key: &str) -> Option<&str> {
+        let (_, val) = self.header.iter().find(|(k, _)| k == key)?;
+        return Some(val);
+    }
+
+    pub fn set_header(&mut self, key: &str, val: &str) {
+        match self.header.iter_mut().find(|(k, _)| k == key) {
+            Some((_, old)) => *old = val.to_string(),
+            None => self.header.push((key.to_string(), val.to_string())),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, MovieError> {
+        let mut movie = Movie::init();
+        for (line_number, line) in text.lines().enumerate() {
+            let line_number = line_number + 1;
+            if line.starts_with('|') {
+                movie.frames.push(Movie::parse_frame(line, line_number)?);
+                continue;
+            }
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, val) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "version" if val != FM2_VERSION => {
+                    return Err(MovieError::Unsupported(format!("fm2 version {}", val)));
+                }
+                "binary" if val != "0" => {
+                    return Err(MovieError::Unsupported(String::from("binary fm2")));
+                }
+                "fourscore" if val != "0" => {
+                    return Err(MovieError::Unsupported(String::from("four score")));
+                }
+                "port0" | "port1" if val != "0" && val != "1" => {
+                    return Err(MovieError::Unsupported(format!("{} device {}", key, val)));
+                }
+                "rerecordCount" => {
+                    movie.rerecord_count = val.parse().map_err(|_| {
+                        MovieError::Parse(line_number, String::from("bad rerecord count"))
+                    })?;
+                }
+                "savestate" => {
+                    let data = val
+                        .strip_prefix("base64:")
+                        .and_then(decode_base64)
+                        .ok_or_else(|| {
+                            MovieError::Parse(line_number, String::from("bad savestate"))
+                        })?;
+                    movie.start_state = Some(data);
+                }
+                "version" | "binary" | "fourscore" => {}
+                _ => movie.header.push((key.to_string(), val.to_string())),
+            }
+        }
+        return Ok(movie);
+    }
+
+    fn parse_frame(line: &str, line_number: usize) -> Result<MovieFrame, MovieError> {
+        let error = || MovieError::Parse(line_number, String::from("bad input line"));
+        let mut fields = line.split('|').skip(1);
+        let commands = fields.next().ok_or_else(error)?;
+        let mut frame = MovieFrame {
+            commands: commands.trim().parse().map_err(|_| error())?,
+            ports: [0, 0],
+        };
+        for port in &mut frame.ports {
+            *port = parse_buttons(fields.next().unwrap_or("")).ok_or_else(error)?;
+        }
+        return Ok(frame);
+    }
+
+    pub fn to_fm2(&self) -> String {
+        let mut out = format!("version {}\n", FM2_VERSION);
+        out += &format!("rerecordCount {}\n", self.rerecord_count);
+        out += "fourscore 0\n";
+        let defaults = [
+            ("emuVersion", "0"),
+            ("palFlag", "0"),
+            ("port0", "1"),
+            ("port1", "1"),
+            ("port2", "0"),
+        ];
+        for (key, val) in defaults {
+            if self.get_header(key).is_none() {
+                out += &format!("{} {}\n", key, val);
+            }
+        }
+        for (key, val) in &self.header {
+            out += &format!("{} {}\n", key, val);
+        }
+        if let Some(state) = &self.start_state {
+            out += &format!("savestate base64:{}\n", encode_base64(state));
+        }
+        for frame in &self.frames {
+            out += &format!(
+                "|{}|{}|{}||\n",
+                frame.commands,
+                format_buttons(frame.ports[0]),
+                format_buttons(frame.ports[1])
+            );
+        }
+        return out;
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, MovieError> {
+        return Movie::parse(&fs::read_to_string(path)?);
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        return fs::write(path, self.to_fm2());
+    }
+
+    /// Puts `console` where the movie starts. For movies from power-on the
+    /// console has to be freshly made from the cartridge.
+    pub fn start(&self, console: &mut Console) -> Result<(), StateError> {
+        if let Some(state) = &self.start_state {
+            console.load_state(state)?;
+        }
+        return Ok(());
+    }
+
+    /// Applies the input for frame `frame`; call before running that frame.
+    /// Returns false once the movie has ended.
+    pub fn play_frame(&self, frame: usize, console: &mut Console) -> bool {
+        let input = match self.frames.get(frame) {
+            Some(input) => *input,
+            None => return false,
+        };
+        // a hard reset would need the cartridge again, a soft one is the
+        // closest we can get
+        if input.commands & (COMMAND_SOFT_RESET | COMMAND_HARD_RESET) != 0 {
+            console.reset();
+        }
+        for (port, buttons) in input.ports.iter().enumerate() {
+            console.get_controller_mut(port).set_buttons(*buttons);
+        }
+        return true;
+    }
+
+    /// Appends the controllers' current state as the next frame; call before
+    /// running that frame.
+    pub fn record_frame(&mut self, console: &Console) {
+        let bus = console.get_cpu().get_bus();
+        self.frames.push(MovieFrame {
+            commands: 0,
+            ports: [
+                bus.get_controller(0).get_buttons(),
+                bus.get_controller(1).get_buttons(),
+            ],
+        });
+    }
+
+    /// Appends a frame with a soft reset, as when reset is pressed mid-movie.
+    pub fn record_reset(&mut self, console: &Console) {
+        self.record_frame(console);
+        if let Some(frame) = self.frames.last_mut() {
+            frame.commands |= COMMAND_SOFT_RESET;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::nrom_with_program;
+    use crate::cartridge::Cartridge;
+    use crate::input::Button;
+
+    const FM2: &str = "version 3
+emuVersion 22020
+rerecordCount 12
+palFlag 0
+romFilename smb
+romChecksum base64:jjYwGG411HcjG/j9UOVM3Q==
+guid 00000000-0000-0000-0000-000000000000
+fourscore 0
+port0 1
+port1 1
+port2 0
+comment author someone
+|0|........|........||
+|0|.......A|........||
+|1|R..U....|....T...||
+";
+
+    #[test]
+    fn test_parse_fm2() {
+        let movie = Movie::parse(FM2).unwrap();
+        assert_eq!(movie.get_rerecord_count(), 12);
+        assert_eq!(movie.get_header("romFilename"), Some("smb"));
+        assert_eq!(movie.get_header("comment"), Some("author someone"));
+        assert_eq!(
+            movie.get_frames(),
+            &[
+                MovieFrame::default(),
+                MovieFrame {
+                    commands: 0,
+                    ports: [Button::A.get_mask(), 0]
+                },
+                MovieFrame {
+                    commands: COMMAND_SOFT_RESET,
+                    ports: [
+                        Button::Right.get_mask() | Button::Up.get_mask(),
+                        Button::Start.get_mask()
+                    ]
+                },
+            ]
+        );
+        let reparsed = Movie::parse(&movie.to_fm2()).unwrap();
+        assert_eq!(reparsed.get_frames(), movie.get_frames());
+        assert_eq!(
+            reparsed.get_header("romChecksum"),
+            movie.get_header("romChecksum")
+        );
+    }
+
+    #[test]
+    fn test_parse_fm2_errors() {
+        assert!(Movie::parse("version 2\n").is_err());
+        assert!(Movie::parse("binary 1\n").is_err());
+        assert!(Movie::parse("|0|RLD|........||\n").is_err());
+        assert!(Movie::parse("|x|........|........||\n").is_err());
+    }
+
+    #[test]
+    fn test_base64() {
+        for data in [&b""[..], b"f", b"fo", b"foo", b"foob", b"\xff\x00\x10"] {
+            assert_eq!(decode_base64(&encode_base64(data)).unwrap(), data);
+        }
+        assert_eq!(encode_base64(b"foob"), "Zm9vYg==");
+    }
+
+    #[test]
+    fn test_playback_is_deterministic() {
+        // adds controller 1's A button into $10 every loop
+        let data = nrom_with_program(
+            "loop:   LDA #1
+                    STA $4016
+                    LDA #0
+                    STA $4016
+                    LDA $4016
+                    ADC $10
+                    STA $10
+                    JMP loop",
+        );
+        let cartridge = Cartridge::from_ines(&data).unwrap();
+
+        let mut console = Console::init(&cartridge).unwrap();
+        console.run_frame();
+        let mut movie = Movie::from_save_state(console.save_state());
+        for frame in 0..20 {
+            console
+                .get_controller_mut(0)
+                .set_button(Button::A, frame % 3 == 0);
+            movie.record_frame(&console);
+            console.run_frame();
+        }
+        let recorded = console.save_state();
+
+        let movie = Movie::parse(&movie.to_fm2()).unwrap();
+        let mut console = Console::init(&cartridge).unwrap();
+        movie.start(&mut console).unwrap();
+        let mut frame = 0;
+        while movie.play_frame(frame, &mut console) {
+            console.run_frame();
+            frame += 1;
+        }
+        assert_eq!(frame, 20);
+        assert_eq!(console.save_state(), recorded);
+    }
+}
@@ -0,0 +1,188 @@
+//! Opcode metadata shared by the CPU, the disassembler and the assembler.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressingModes {
+    Implicit,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Relative,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndirectX,
+    IndirectY,
+}
+
+pub fn get_mode_from_opcode(opcode: u8) -> AddressingModes {
+    match opcode {
+        0x00 | 0x08 | 0x18 | 0x28 | 0x38 | 0x40 | 0x48 | 0x58 | 0x60 | 0x68 | 0x78 | 0x88
+        | 0x8A | 0x98 | 0x9A | 0xA8 | 0xAA | 0xBA | 0xB8 | 0xC8 | 0xCA | 0xD8 | 0xE8 | 0xEA
+        | 0xF8 => {
+            return AddressingModes::Implicit;
+        }
+        0x01 | 0x21 | 0x41 | 0x61 | 0x81 | 0xA1 | 0xC1 | 0xE1 => {
+            return AddressingModes::IndirectX;
+        }
+        0x05 | 0x06 | 0x65 | 0x24 | 0x25 | 0x26 | 0x45 | 0x46 | 0x66 | 0x84 | 0x85 | 0x86
+        | 0xA4 | 0xA5 | 0xA6 | 0xC4 | 0xC5 | 0xC6 | 0xE4 | 0xE5 | 0xE6 => {
+            return AddressingModes::ZeroPage;
+        }
+        0x09 | 0x29 | 0x49 | 0x69 | 0xA0 | 0xA2 | 0xA9 | 0xC0 | 0xC9 | 0xE0 | 0xE9 => {
+            return AddressingModes::Immediate;
+        }
+        0x0A | 0x2A | 0x4A | 0x6A => {
+            return AddressingModes::Accumulator;
+        }
+        0x0D | 0x0E | 0x6D | 0x20 | 0x2C | 0x2D | 0x2E | 0x4C | 0x4D | 0x4E | 0x6E | 0x8C
+        | 0x8D | 0x8E | 0xAC | 0xAD | 0xAE | 0xCC | 0xCD | 0xCE | 0xEC | 0xED | 0xEE => {
+            return AddressingModes::Absolute;
+        }
+        0x10 | 0x30 | 0x50 | 0x70 | 0x90 | 0xB0 | 0xD0 | 0xF0 => {
+            return AddressingModes::Relative;
+        }
+        0x11 | 0x31 | 0x51 | 0x71 | 0x91 | 0xB1 | 0xD1 | 0xF1 => {
+            return AddressingModes::IndirectY;
+        }
+        0x15 | 0x16 | 0x35 | 0x36 | 0x55 | 0x56 | 0x75 | 0x76 | 0x94 | 0x95 | 0xB4 | 0xB5
+        | 0xD5 | 0xD6 | 0xF5 | 0xF6 => {
+            return AddressingModes::ZeroPageX;
+        }
+        0x1D | 0x1E | 0x7D | 0x3D | 0x3E | 0x5E | 0x5D | 0x7E | 0x9D | 0xBC | 0xBD | 0xDD
+        | 0xDE | 0xFD | 0xFE => {
+            return AddressingModes::AbsoluteX;
+        }
+        0x6C => {
+            return AddressingModes::Indirect;
+        }
+        0x79 | 0x39 | 0x59 | 0x99 | 0xB9 | 0xD9 | 0xF9 => {
+            return AddressingModes::AbsoluteY;
+        }
+        0xB6 | 0x96 => {
+            return AddressingModes::ZeroPageY;
+        }
+        0x19 | 0xBE => {
+            return AddressingModes::AbsoluteY;
+        }
+        _ => {
+            // unofficial opcodes are treated as a 2 cycle NOP for now
+            return AddressingModes::Implicit;
+        }
+    }
+}
+
+impl AddressingModes {
+    /// Length of an instruction using this mode, opcode included.
+    pub fn get_length(self) -> u16 {
+        match self {
+            AddressingModes::Implicit | AddressingModes::Accumulator => return 1,
+            AddressingModes::Absolute
+            | AddressingModes::AbsoluteX
+            | AddressingModes::AbsoluteY
+            | AddressingModes::Indirect => return 3,
+            _ => return 2,
+        }
+    }
+}
+
+/// Mnemonic of an official opcode. The CPU runs every other opcode as a
+/// one byte NOP.
+pub fn get_mnemonic(opcode: u8) -> Option<&'static str> {
+    let mnemonic = match opcode {
+        0x00 => "BRK",
+        0x01 | 0x05 | 0x09 | 0x0D | 0x11 | 0x15 | 0x19 | 0x1D => "ORA",
+        0x06 | 0x0A | 0x0E | 0x16 | 0x1E => "ASL",
+        0x08 => "PHP",
+        0x10 => "BPL",
+        0x18 => "CLC",
+        0x20 => "JSR",
+        0x21 | 0x25 | 0x29 | 0x2D | 0x31 | 0x35 | 0x39 | 0x3D => "AND",
+        0x24 | 0x2C => "BIT",
+        0x26 | 0x2A | 0x2E | 0x36 | 0x3E => "ROL",
+        0x28 => "PLP",
+        0x30 => "BMI",
+        0x38 => "SEC",
+        0x40 => "RTI",
+        0x41 | 0x45 | 0x49 | 0x4D | 0x51 | 0x55 | 0x59 | 0x5D => "EOR",
+        0x48 => "PHA",
+        0x4A | 0x46 | 0x4E | 0x56 | 0x5E => "LSR",
+        0x4C | 0x6C => "JMP",
+        0x50 => "BVC",
+        0x58 => "CLI",
+        0x60 => "RTS",
+        0x61 | 0x65 | 0x69 | 0x6D | 0x71 | 0x75 | 0x79 | 0x7D => "ADC",
+        0x66 | 0x6A | 0x6E | 0x76 | 0x7E => "ROR",
+        0x68 => "PLA",
+        0x70 => "BVS",
+        0x78 => "SEI",
+        0x81 | 0x85 | 0x8D | 0x91 | 0x95 | 0x99 | 0x9D => "STA",
+        0x84 | 0x8C | 0x94 => "STY",
+        0x86 | 0x96 | 0x8E => "STX",
+        0x88 => "DEY",
+        0x8A => "TXA",
+        0x90 => "BCC",
+        0x98 => "TYA",
+        0x9A => "TXS",
+        0xA0 | 0xA4 | 0xAC | 0xB4 | 0xBC => "LDY",
+        0xA1 | 0xA5 | 0xA9 | 0xAD | 0xB1 | 0xB5 | 0xB9 | 0xBD => "LDA",
+        0xA2 | 0xA6 | 0xAE | 0xB6 | 0xBE => "LDX",
+        0xA8 => "TAY",
+        0xAA => "TAX",
+        0xB0 => "BCS",
+        0xB8 => "CLV",
+        0xBA => "TSX",
+        0xC0 | 0xC4 | 0xCC => "CPY",
+        0xC1 | 0xC5 | 0xC9 | 0xCD | 0xD1 | 0xD5 | 0xD9 | 0xDD => "CMP",
+        0xC6 | 0xD6 | 0xCE | 0xDE => "DEC",
+        0xCA => "DEX",
+        0xC8 => "INY",
+        0xD0 => "BNE",
+        0xD8 => "CLD",
+        0xE0 | 0xE4 | 0xEC => "CPX",
+        0xE1 | 0xE5 | 0xE9 | 0xED | 0xF1 | 0xF5 | 0xF9 | 0xFD => "SBC",
+        0xE6 | 0xEE | 0xF6 | 0xFE => "INC",
+        0xE8 => "INX",
+        0xEA => "NOP",
+        0xF0 => "BEQ",
+        0xF8 => "SED",
+        _ => return None,
+    };
+    return Some(mnemonic);
+}
+
+/// The official opcode for `mnemonic` (upper case) in `mode`, if there is
+/// one.
+pub fn get_opcode(mnemonic: &str, mode: AddressingModes) -> Option<u8> {
+    return (0..=255).find(|&opcode| {
+        get_mnemonic(opcode) == Some(mnemonic) && get_mode_from_opcode(opcode) == mode
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_official_opcode_count() {
+        let official: Vec<u8> = (0..=255).filter(|op| get_mnemonic(*op).is_some()).collect();
+        assert_eq!(official.len(), 151);
+        // unofficial opcodes decode as one byte instructions
+        assert_eq!(get_mode_from_opcode(0x80).get_length(), 1);
+        assert_eq!(get_mode_from_opcode(0x6C).get_length(), 3);
+        assert_eq!(get_mode_from_opcode(0xB1).get_length(), 2);
+    }
+
+    #[test]
+    fn test_get_opcode_inverts_tables() {
+        for opcode in 0..=255 {
+            if let Some(mnemonic) = get_mnemonic(opcode) {
+                let mode = get_mode_from_opcode(opcode);
+                assert_eq!(get_opcode(mnemonic, mode), Some(opcode));
+            }
+        }
+        assert_eq!(get_opcode("LDA", AddressingModes::Indirect), None);
+    }
+}
@@ -0,0 +1,212 @@
+//! Colour palettes for turning the PPU's pixels into RGB, built in or read
+//! from `.pal` files.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Colours with every combination of the three emphasis bits.
+pub const PALETTE_SIZE: usize = 512;
+const BASE_COLOURS: usize = 64;
+// how much each emphasis bit dims the two colours it doesn't emphasize
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+
+/// The built-in 2C02 colours as RGB, indexed by the 6-bit colour index.
+pub const SYSTEM_PALETTE: [[u8; 3]; 64] = [
+    [0x62, 0x62, 0x62],
+    [0x00, 0x1F, 0xB2],
+    [0x24, 0x04, 0xC8],
+    [0x52, 0x00, 0xB2],
+    [0x73, 0x00, 0x76],
+    [0x80, 0x00, 0x24],
+    [0x73, 0x0B, 0x00],
+    [0x52, 0x28, 0x00],
+    [0x24, 0x44, 0x00],
+    [0x00, 0x57, 0x00],
+    [0x00, 0x5C, 0x00],
+    [0x00, 0x53, 0x24],
+    [0x00, 0x3C, 0x76],
+    [0x00, 0x00, 0x00],
+    [0x00, 0x00, 0x00],
+    [0x00, 0x00, 0x00],
+    [0xAB, 0xAB, 0xAB],
+    [0x0D, 0x57, 0xFF],
+    [0x4B, 0x30, 0xFF],
+    [0x8A, 0x13, 0xFF],
+    [0xBC, 0x08, 0xD6],
+    [0xD2, 0x12, 0x69],
+    [0xC7, 0x2E, 0x00],
+    [0x9D, 0x54, 0x00],
+    [0x60, 0x7B, 0x00],
+    [0x20, 0x98, 0x00],
+    [0x00, 0xA3, 0x00],
+    [0x00, 0x99, 0x42],
+    [0x00, 0x7D, 0xB4],
+    [0x00, 0x00, 0x00],
+    [0x00, 0x00, 0x00],
+    [0x00, 0x00, 0x00],
+    [0xFF, 0xFF, 0xFF],
+    [0x53, 0xAE, 0xFF],
+    [0x90, 0x85, 0xFF],
+    [0xD3, 0x65, 0xFF],
+    [0xFF, 0x57, 0xFF],
+    [0xFF, 0x5D, 0xCF],
+    [0xFF, 0x77, 0x57],
+    [0xFA, 0x9E, 0x00],
+    [0xBD, 0xC7, 0x00],
+    [0x7A, 0xE7, 0x00],
+    [0x43, 0xF6, 0x11],
+    [0x26, 0xEF, 0x7E],
+    [0x2C, 0xD5, 0xF6],
+    [0x4E, 0x4E, 0x4E],
+    [0x00, 0x00, 0x00],
+    [0x00, 0x00, 0x00],
+    [0xFF, 0xFF, 0xFF],
+    [0xB6, 0xE1, 0xFF],
+    [0xCE, 0xD1, 0xFF],
+    [0xE9, 0xC3, 0xFF],
+    [0xFF, 0xBC, 0xFF],
+    [0xFF, 0xBD, 0xF4],
+    [0xFF, 0xC6, 0xC3],
+    [0xFF, 0xD5, 0x9A],
+    [0xE9, 0xE6, 0x81],
+    [0xCE, 0xF4, 0x81],
+    [0xB6, 0xFB, 0x9A],
+    [0xA9, 0xFA, 0xC3],
+    [0xA9, 0xF0, 0xF4],
+    [0xB8, 0xB8, 0xB8],
+    [0x00, 0x00, 0x00],
+    [0x00, 0x00, 0x00],
+];
+
+#[derive(Debug)]
+pub enum PaletteError {
+    Io(io::Error),
+    /// Not 64 or 512 RGB triples.
+    WrongSize(usize),
+}
+
+impl fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PaletteError::Io(err) => return write!(f, "could not read palette: {}", err),
+            PaletteError::WrongSize(len) => {
+                return write!(
+                    f,
+                    "palette is {} bytes, expected {} or {}",
+                    len,
+                    BASE_COLOURS * 3,
+                    PALETTE_SIZE * 3
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for PaletteError {}
+
+impl From<io::Error> for PaletteError {
+    fn from(err: io::Error) -> Self {
+        return PaletteError::Io(err);
+    }
+}
+
+/// RGB for every 9-bit pixel: a colour index in the low 6 bits and the
+/// red, green and blue emphasis bits above it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palette {
+    colours: Vec<[u8; 3]>,
+}
+
+impl Palette {
+    /// The built-in palette, [`SYSTEM_PALETTE`] with emphasis applied.
+    pub fn init() -> Self {
+        return Palette::from_base_colours(&SYSTEM_PALETTE);
+    }
+
+    fn from_base_colours(base: &[[u8; 3]]) -> Self {
+        let mut colours = Vec::with_capacity(PALETTE_SIZE);
+        for emphasis in 0..PALETTE_SIZE / BASE_COLOURS {
+            for colour in base {
+                colours.push(apply_emphasis(*colour, emphasis as u8));
+            }
+        }
+        return Palette { colours };
+    }
+
+    /// Parses a `.pal` file: 64 RGB triples, with emphasis worked out like
+    /// the built-in palette, or 512 with the emphasised colours given.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, PaletteError> {
+        let colours: Vec<[u8; 3]> = data
+            .chunks_exact(3)
+            .map(|rgb| [rgb[0], rgb[1], rgb[2]])
+            .collect();
+        match data.len() {
+            len if len == BASE_COLOURS * 3 => return Ok(Palette::from_base_colours(&colours)),
+            len if len == PALETTE_SIZE * 3 => return Ok(Palette { colours }),
+            len => return Err(PaletteError::WrongSize(len)),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, PaletteError> {
+        let data = fs::read(path)?;
+        return Palette::from_bytes(&data);
+    }
+
+    pub fn get_colour(&self, pixel: u16) -> [u8; 3] {
+        return self.colours[pixel as usize % PALETTE_SIZE];
+    }
+
+    /// Converts a framebuffer of 9-bit pixels into packed RGB24.
+    pub fn pixels_to_rgb(&self, pixels: &[u16], rgb: &mut [u8]) {
+        for (pixel, out) in pixels.iter().zip(rgb.chunks_exact_mut(3)) {
+            out.copy_from_slice(&self.get_colour(*pixel));
+        }
+    }
+}
+
+// Each set emphasis bit (red, green, blue from bit 0) dims the other two
+// channels.
+fn apply_emphasis(colour: [u8; 3], emphasis: u8) -> [u8; 3] {
+    let mut out = colour;
+    for (channel, val) in out.iter_mut().enumerate() {
+        let dimmed_by = (0..3)
+            .filter(|bit| *bit != channel && emphasis & (1 << bit) != 0)
+            .count();
+        let scale = EMPHASIS_ATTENUATION.powi(dimmed_by as i32);
+        *val = (*val as f32 * scale).round() as u8;
+    }
+    return out;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_builtin_emphasis() {
+        let palette = Palette::init();
+        assert_eq!(palette.get_colour(0x30), [0xFF, 0xFF, 0xFF]);
+        // red emphasis on white dims green and blue
+        assert_eq!(palette.get_colour(0x30 | 0b001 << 6), [0xFF, 190, 190]);
+        let [r, g, b] = palette.get_colour(0x30 | 0b111 << 6);
+        assert!(r == g && g == b && r < 190);
+    }
+
+    #[test]
+    fn test_pal_files() {
+        let mut data: Vec<u8> = (0..64 * 3).map(|i| i as u8).collect();
+        let palette = Palette::from_bytes(&data).unwrap();
+        assert_eq!(palette.get_colour(0x01), [3, 4, 5]);
+        assert_eq!(palette.get_colour(0x01 | 0b100 << 6), [2, 3, 5]);
+
+        data.resize(512 * 3, 7);
+        let palette = Palette::from_bytes(&data).unwrap();
+        assert_eq!(palette.get_colour(0x01 | 0b100 << 6), [7, 7, 7]);
+        assert!(matches!(
+            Palette::from_bytes(&data[..100]),
+            Err(PaletteError::WrongSize(100))
+        ));
+    }
+}
@@ -0,0 +1,947 @@
+use crate::mapper::{Mapper, PPUEvent};
+use crate::region::Region;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+const DOTS_PER_SCANLINE: u16 = 341;
+
+const PPUCTRL: u16 = 0;
+const PPUMASK: u16 = 1;
+const PPUSTATUS: u16 = 2;
+const OAMADDR: u16 = 3;
+const OAMDATA: u16 = 4;
+const PPUSCROLL: u16 = 5;
+const PPUADDR: u16 = 6;
+const PPUDATA: u16 = 7;
+
+const START_NAMETABLES: u16 = 0x2000;
+const START_PALETTE: u16 = 0x3F00;
+
+const CTRL_VRAM_INCREMENT: u8 = 0b00000100;
+const CTRL_SPRITE_TABLE: u8 = 0b00001000;
+const CTRL_BACKGROUND_TABLE: u8 = 0b00010000;
+const CTRL_SPRITE_SIZE: u8 = 0b00100000;
+const CTRL_NMI_ENABLE: u8 = 0b10000000;
+
+const MASK_GREYSCALE: u8 = 0b00000001;
+const MASK_SHOW_BACKGROUND_LEFT: u8 = 0b00000010;
+const MASK_SHOW_SPRITES_LEFT: u8 = 0b00000100;
+const MASK_SHOW_BACKGROUND: u8 = 0b00001000;
+const MASK_SHOW_SPRITES: u8 = 0b00010000;
+const MASK_EMPHASIS: u8 = 0b11100000;
+const MASK_EMPHASIS_RED: u8 = 0b00100000;
+const MASK_EMPHASIS_GREEN: u8 = 0b01000000;
+const MASK_EMPHASIS_BLUE: u8 = 0b10000000;
+
+const STATUS_SPRITE_OVERFLOW: u8 = 0b00100000;
+const STATUS_SPRITE_ZERO_HIT: u8 = 0b01000000;
+const STATUS_VBLANK: u8 = 0b10000000;
+
+const MAX_SPRITES_PER_SCANLINE: usize = 8;
+// dots between the vblank flag going up and the CPU seeing the NMI, during
+// which reading PPUSTATUS or disabling NMI still stops it
+const NMI_DELAY_DOTS: u8 = 3;
+
+// bits of the I/O latch fade to 0 roughly 600ms after they were last driven
+const IO_LATCH_DECAY_FRAMES: u64 = 36;
+
+/// The 2C02 picture processing unit, stepped one dot at a time.
+///
+/// The framebuffer holds 9-bit pixels: the 6-bit colour index with the three
+/// colour emphasis bits of PPUMASK above it. See [`crate::video`] for turning
+/// them into RGB.
+#[derive(Debug)]
+pub struct PPU {
+    ctrl: u8,
+    mask: u8,
+    status: u8,
+    oam_addr: u8,
+
+    // loopy's internal registers: current/temporary VRAM address, fine X
+    // scroll and the shared write toggle for $2005/$2006
+    v: u16,
+    t: u16,
+    fine_x: u8,
+    w: bool,
+    read_buffer: u8,
+
+    // the data bus between the CPU and the PPU holds the last value driven
+    // onto it, which is what write-only registers read back as
+    io_latch: u8,
+    io_latch_refreshed: [u64; 8],
+    // a PPUSTATUS read just before vblank starts stops it being flagged
+    suppress_vblank: bool,
+    pending_write: Option<(u16, u8)>,
+
+    // the console's 2KB of nametable RAM, then the 2KB four-screen
+    // cartridges add
+    vram: [u8; 0x1000],
+    palette: [u8; 32],
+    oam: [u8; 256],
+
+    scanline: u16,
+    dot: u16,
+    frame: u64,
+    nmi_pending: bool,
+    nmi_delay: u8,
+    frame_complete: bool,
+
+    bg_next_tile_id: u8,
+    bg_next_attribute: u8,
+    bg_next_pattern_lo: u8,
+    bg_next_pattern_hi: u8,
+    bg_shift_pattern_lo: u16,
+    bg_shift_pattern_hi: u16,
+    bg_shift_attribute_lo: u16,
+    bg_shift_attribute_hi: u16,
+
+    sprite_count: usize,
+    sprite_patterns_lo: [u8; MAX_SPRITES_PER_SCANLINE],
+    sprite_patterns_hi: [u8; MAX_SPRITES_PER_SCANLINE],
+    sprite_attributes: [u8; MAX_SPRITES_PER_SCANLINE],
+    sprite_x: [u8; MAX_SPRITES_PER_SCANLINE],
+    sprite_zero_on_scanline: bool,
+
+    framebuffer: Vec<u16>,
+    region: Region,
+}
+
+impl PPU {
+    pub fn init() -> Self {
+        return PPU {
+            ctrl: 0,
+            mask: 0,
+            status: 0,
+            oam_addr: 0,
+            v: 0,
+            t: 0,
+            fine_x: 0,
+            w: false,
+            read_buffer: 0,
+            io_latch: 0,
+            io_latch_refreshed: [0; 8],
+            suppress_vblank: false,
+            pending_write: None,
+            vram: [0; 0x1000],
+            palette: [0; 32],
+            oam: [0; 256],
+            scanline: 0,
+            dot: 0,
+            frame: 0,
+            nmi_pending: false,
+            nmi_delay: 0,
+            frame_complete: false,
+            bg_next_tile_id: 0,
+            bg_next_attribute: 0,
+            bg_next_pattern_lo: 0,
+            bg_next_pattern_hi: 0,
+            bg_shift_pattern_lo: 0,
+            bg_shift_pattern_hi: 0,
+            bg_shift_attribute_lo: 0,
+            bg_shift_attribute_hi: 0,
+            sprite_count: 0,
+            sprite_patterns_lo: [0; MAX_SPRITES_PER_SCANLINE],
+            sprite_patterns_hi: [0; MAX_SPRITES_PER_SCANLINE],
+            sprite_attributes: [0; MAX_SPRITES_PER_SCANLINE],
+            sprite_x: [0; MAX_SPRITES_PER_SCANLINE],
+            sprite_zero_on_scanline: false,
+            framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            region: Region::NTSC,
+        };
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+
+    pub fn get_framebuffer(&self) -> &[u16] {
+        return &self.framebuffer;
+    }
+
+    pub fn get_scanline(&self) -> u16 {
+        return self.scanline;
+    }
+
+    pub fn get_dot(&self) -> u16 {
+        return self.dot;
+    }
+
+    pub fn get_frame(&self) -> u64 {
+        return self.frame;
+    }
+
+    /// Returns true once per NMI edge.
+    pub fn poll_nmi(&mut self) -> bool {
+        let nmi = self.nmi_pending;
+        self.nmi_pending = false;
+        return nmi;
+    }
+
+    /// Returns true once per frame, when vblank starts.
+    pub fn take_frame_complete(&mut self) -> bool {
+        let complete = self.frame_complete;
+        self.frame_complete = false;
+        return complete;
+    }
+
+    pub fn write_oam_byte(&mut self, mut val: u8) {
+        // sprite attributes have no bits 2-4
+        if self.oam_addr % 4 == 2 {
+            val &= 0xE3;
+        }
+        self.oam[self.oam_addr as usize] = val;
+        self.oam_addr = self.oam_addr.wrapping_add(1);
+    }
+
+    fn is_rendering_enabled(&self) -> bool {
+        return self.mask & (MASK_SHOW_BACKGROUND | MASK_SHOW_SPRITES) != 0;
+    }
+
+    fn get_vram_increment(&self) -> u16 {
+        if self.ctrl & CTRL_VRAM_INCREMENT != 0 {
+            return 32;
+        }
+        return 1;
+    }
+
+    fn get_sprite_height(&self) -> u16 {
+        if self.ctrl & CTRL_SPRITE_SIZE != 0 {
+            return 16;
+        }
+        return 8;
+    }
+
+    // Drives the bits in `mask` of the I/O latch with `val`.
+    fn refresh_io_latch(&mut self, val: u8, mask: u8) {
+        self.io_latch = (self.io_latch & !mask) | (val & mask);
+        for bit in 0..8 {
+            if mask & (1 << bit) != 0 {
+                self.io_latch_refreshed[bit] = self.frame;
+            }
+        }
+    }
+
+    fn get_io_latch(&mut self) -> u8 {
+        for bit in 0..8 {
+            if self.frame - self.io_latch_refreshed[bit] >= IO_LATCH_DECAY_FRAMES {
+                self.io_latch &= !(1 << bit);
+            }
+        }
+        return self.io_latch;
+    }
+
+    // `dot` is the next dot to run, so the flag goes up between reads at
+    // dots 1 and 2 of the vblank scanline. Only bits 7-5 are driven.
+    fn read_status(&mut self) -> u8 {
+        let on_vblank_scanline = self.scanline == self.region.get_vblank_scanline();
+        if on_vblank_scanline && self.dot == 1 {
+            self.suppress_vblank = true;
+        }
+        // reading just after the flag goes up returns it but still cancels
+        // the NMI
+        self.nmi_delay = 0;
+        let val = (self.status & 0xE0) | (self.get_io_latch() & 0x1F);
+        self.status &= !STATUS_VBLANK;
+        self.w = false;
+        self.refresh_io_latch(val, 0xE0);
+        return val;
+    }
+
+    pub fn read_register(&mut self, addr: u16, mapper: &mut dyn Mapper) -> u8 {
+        match addr & 7 {
+            PPUSTATUS => return self.read_status(),
+            OAMDATA => {
+                let val = self.oam[self.oam_addr as usize];
+                self.refresh_io_latch(val, 0xFF);
+                return val;
+            }
+            PPUDATA => {
+                let addr = self.v & 0x3FFF;
+                let val = self.read_vram(addr, mapper);
+                self.increment_vram_addr();
+                if addr >= START_PALETTE {
+                    // palette reads skip the buffer, which gets the nametable
+                    // byte underneath instead, and only drive the low 6 bits
+                    self.read_buffer = self.read_vram(addr - 0x1000, mapper);
+                    let val = (val & 0x3F) | (self.get_io_latch() & 0xC0);
+                    self.refresh_io_latch(val, 0x3F);
+                    return val;
+                }
+                let buffered = self.read_buffer;
+                self.read_buffer = val;
+                self.refresh_io_latch(buffered, 0xFF);
+                return buffered;
+            }
+            _ => return self.get_io_latch(),
+        }
+    }
+
+    /// Writes land a dot later than reads would see, partway into the next
+    /// CPU cycle.
+    pub fn write_register(&mut self, addr: u16, val: u8) {
+        self.pending_write = Some((addr, val));
+    }
+
+    fn apply_write(&mut self, addr: u16, val: u8, mapper: &mut dyn Mapper) {
+        self.refresh_io_latch(val, 0xFF);
+        match addr & 7 {
+            PPUCTRL => {
+                let nmi_was_enabled = self.ctrl & CTRL_NMI_ENABLE != 0;
+                self.ctrl = val;
+                // enabling NMI during vblank triggers one straight away, and
+                // disabling it drops an edge the CPU hasn't seen yet
+                if !nmi_was_enabled
+                    && val & CTRL_NMI_ENABLE != 0
+                    && self.status & STATUS_VBLANK != 0
+                {
+                    self.nmi_pending = true;
+                } else if val & CTRL_NMI_ENABLE == 0 {
+                    self.nmi_delay = 0;
+                    self.nmi_pending = false;
+                }
+                self.t = (self.t & 0xF3FF) | (((val & 0b11) as u16) << 10);
+            }
+            PPUMASK => self.mask = val,
+            OAMADDR => self.oam_addr = val,
+            OAMDATA => self.write_oam_byte(val),
+            PPUSCROLL => {
+                if !self.w {
+                    self.t = (self.t & 0xFFE0) | ((val >> 3) as u16);
+                    self.fine_x = val & 0b111;
+                } else {
+                    self.t = (self.t & 0x8C1F)
+                        | (((val & 0b111) as u16) << 12)
+                        | (((val >> 3) as u16) << 5);
+                }
+                self.w = !self.w;
+            }
+            PPUADDR => {
+                if !self.w {
+                    self.t = (self.t & 0x00FF) | (((val & 0x3F) as u16) << 8);
+                } else {
+                    self.t = (self.t & 0xFF00) | val as u16;
+                    self.v = self.t;
+                }
+                self.w = !self.w;
+            }
+            PPUDATA => {
+                let addr = self.v & 0x3FFF;
+                self.write_vram(addr, val, mapper);
+                self.increment_vram_addr();
+            }
+            _ => {}
+        }
+    }
+
+    fn increment_vram_addr(&mut self) {
+        self.v = self.v.wrapping_add(self.get_vram_increment()) & 0x7FFF;
+    }
+
+    fn mirror_palette_addr(addr: u16) -> usize {
+        let index = (addr & 0x1F) as usize;
+        // the backdrop entries of the sprite palettes mirror the background ones
+        if index >= 0x10 && index.is_multiple_of(4) {
+            return index - 0x10;
+        }
+        return index;
+    }
+
+    fn read_vram(&mut self, addr: u16, mapper: &mut dyn Mapper) -> u8 {
+        let addr = addr & 0x3FFF;
+        if addr < START_NAMETABLES {
+            return mapper.read_chr(addr);
+        }
+        if addr < START_PALETTE {
+            return mapper.read_nametable(addr, &self.vram);
+        }
+        return self.palette[PPU::mirror_palette_addr(addr)];
+    }
+
+    fn write_vram(&mut self, addr: u16, val: u8, mapper: &mut dyn Mapper) {
+        let addr = addr & 0x3FFF;
+        if addr < START_NAMETABLES {
+            mapper.write_chr(addr, val);
+        } else if addr < START_PALETTE {
+            mapper.write_nametable(addr, val, &mut self.vram);
+        } else {
+            self.palette[PPU::mirror_palette_addr(addr)] = val & 0x3F;
+        }
+    }
+
+    fn increment_coarse_x(&mut self) {
+        if self.v & 0x001F == 31 {
+            self.v &= !0x001F;
+            self.v ^= 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    fn increment_fine_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !0x7000;
+        let mut coarse_y = (self.v & 0x03E0) >> 5;
+        if coarse_y == 29 {
+            coarse_y = 0;
+            self.v ^= 0x0800;
+        } else if coarse_y == 31 {
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.v = (self.v & !0x03E0) | (coarse_y << 5);
+    }
+
+    fn copy_horizontal_bits(&mut self) {
+        self.v = (self.v & !0x041F) | (self.t & 0x041F);
+    }
+
+    fn copy_vertical_bits(&mut self) {
+        self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
+    }
+
+    fn load_background_shifters(&mut self) {
+        self.bg_shift_pattern_lo =
+            (self.bg_shift_pattern_lo & 0xFF00) | self.bg_next_pattern_lo as u16;
+        self.bg_shift_pattern_hi =
+            (self.bg_shift_pattern_hi & 0xFF00) | self.bg_next_pattern_hi as u16;
+        let attribute_lo = if self.bg_next_attribute & 0b01 != 0 {
+            0xFF
+        } else {
+            0x00
+        };
+        let attribute_hi = if self.bg_next_attribute & 0b10 != 0 {
+            0xFF
+        } else {
+            0x00
+        };
+        self.bg_shift_attribute_lo = (self.bg_shift_attribute_lo & 0xFF00) | attribute_lo;
+        self.bg_shift_attribute_hi = (self.bg_shift_attribute_hi & 0xFF00) | attribute_hi;
+    }
+
+    fn shift_background_shifters(&mut self) {
+        self.bg_shift_pattern_lo <<= 1;
+        self.bg_shift_pattern_hi <<= 1;
+        self.bg_shift_attribute_lo <<= 1;
+        self.bg_shift_attribute_hi <<= 1;
+    }
+
+    fn fetch_background(&mut self, mapper: &mut dyn Mapper) {
+        match (self.dot - 1) % 8 {
+            0 => {
+                self.load_background_shifters();
+                self.bg_next_tile_id = self.read_vram(START_NAMETABLES | (self.v & 0x0FFF), mapper);
+            }
+            2 => {
+                let addr =
+                    0x23C0 | (self.v & 0x0C00) | ((self.v >> 4) & 0x38) | ((self.v >> 2) & 0x07);
+                let mut attribute = self.read_vram(addr, mapper);
+                if self.v & 0x0040 != 0 {
+                    attribute >>= 4;
+                }
+                if self.v & 0x0002 != 0 {
+                    attribute >>= 2;
+                }
+                self.bg_next_attribute = attribute & 0b11;
+            }
+            4 => {
+                let addr = self.get_background_pattern_addr();
+                self.bg_next_pattern_lo = self.read_vram(addr, mapper);
+            }
+            6 => {
+                let addr = self.get_background_pattern_addr() + 8;
+                self.bg_next_pattern_hi = self.read_vram(addr, mapper);
+            }
+            7 => self.increment_coarse_x(),
+            _ => {}
+        }
+    }
+
+    fn get_background_pattern_addr(&self) -> u16 {
+        let table: u16 = if self.ctrl & CTRL_BACKGROUND_TABLE != 0 {
+            0x1000
+        } else {
+            0
+        };
+        let fine_y = (self.v >> 12) & 0b111;
+        return table + (self.bg_next_tile_id as u16) * 16 + fine_y;
+    }
+
+    // Finds the sprites on the next scanline and fetches their patterns. The
+    // hardware spreads this over dots 65-320; doing it in one go at dot 257 is
+    // close enough for everything but the most timing sensitive games.
+    fn evaluate_sprites(&mut self, mapper: &mut dyn Mapper) {
+        let height = self.get_sprite_height();
+        self.sprite_count = 0;
+        self.sprite_zero_on_scanline = false;
+        for i in 0..64 {
+            let y = self.oam[i * 4] as u16;
+            if self.scanline < y || self.scanline - y >= height {
+                continue;
+            }
+            if self.sprite_count == MAX_SPRITES_PER_SCANLINE {
+                self.status |= STATUS_SPRITE_OVERFLOW;
+                break;
+            }
+            if i == 0 {
+                self.sprite_zero_on_scanline = true;
+            }
+            let tile = self.oam[i * 4 + 1];
+            let attributes = self.oam[i * 4 + 2];
+            let mut row = self.scanline - y;
+            if attributes & 0x80 != 0 {
+                row = height - 1 - row;
+            }
+            let addr = if height == 16 {
+                let table = ((tile & 1) as u16) * 0x1000;
+                let tile = (tile & 0xFE) as u16 + row / 8;
+                table + tile * 16 + (row % 8)
+            } else {
+                let table: u16 = if self.ctrl & CTRL_SPRITE_TABLE != 0 {
+                    0x1000
+                } else {
+                    0
+                };
+                table + (tile as u16) * 16 + row
+            };
+            let mut pattern_lo = self.read_vram(addr, mapper);
+            let mut pattern_hi = self.read_vram(addr + 8, mapper);
+            if attributes & 0x40 != 0 {
+                pattern_lo = pattern_lo.reverse_bits();
+                pattern_hi = pattern_hi.reverse_bits();
+            }
+            let slot = self.sprite_count;
+            self.sprite_patterns_lo[slot] = pattern_lo;
+            self.sprite_patterns_hi[slot] = pattern_hi;
+            self.sprite_attributes[slot] = attributes;
+            self.sprite_x[slot] = self.oam[i * 4 + 3];
+            self.sprite_count += 1;
+        }
+    }
+
+    // the colour at `palette_index` with the emphasis bits, as it goes out
+    // to the video signal; greyscale keeps only the luma bits. The PAL PPU
+    // has the red and green emphasis bits the other way round.
+    fn get_output_pixel(&self, palette_index: usize) -> u16 {
+        let colour_mask = if self.mask & MASK_GREYSCALE != 0 {
+            0x30
+        } else {
+            0x3F
+        };
+        let mut emphasis = self.mask & MASK_EMPHASIS;
+        if self.region == Region::PAL {
+            emphasis = (emphasis & MASK_EMPHASIS_BLUE)
+                | (emphasis & MASK_EMPHASIS_RED) << 1
+                | (emphasis & MASK_EMPHASIS_GREEN) >> 1;
+        }
+        let emphasis = (emphasis as u16) << 1;
+        return (self.palette[palette_index] & colour_mask) as u16 | emphasis;
+    }
+
+    fn render_pixel(&mut self) {
+        let x = (self.dot - 1) as usize;
+        let y = self.scanline as usize;
+
+        let mut bg_pixel = 0;
+        let mut bg_palette = 0;
+        if self.mask & MASK_SHOW_BACKGROUND != 0
+            && (x >= 8 || self.mask & MASK_SHOW_BACKGROUND_LEFT != 0)
+        {
+            let bit = 15 - self.fine_x as u16;
+            bg_pixel = (((self.bg_shift_pattern_hi >> bit) & 1) << 1
+                | ((self.bg_shift_pattern_lo >> bit) & 1)) as u8;
+            bg_palette = (((self.bg_shift_attribute_hi >> bit) & 1) << 1
+                | ((self.bg_shift_attribute_lo >> bit) & 1)) as u8;
+        }
+
+        let mut sprite_pixel = 0;
+        let mut sprite_palette = 0;
+        let mut sprite_behind_background = false;
+        let mut is_sprite_zero = false;
+        if self.mask & MASK_SHOW_SPRITES != 0 && (x >= 8 || self.mask & MASK_SHOW_SPRITES_LEFT != 0)
+        {
+            for i in 0..self.sprite_count {
+                let offset = x as i16 - self.sprite_x[i] as i16;
+                if !(0..8).contains(&offset) {
+                    continue;
+                }
+                let bit = 7 - offset;
+                let pixel = ((self.sprite_patterns_hi[i] >> bit) & 1) << 1
+                    | ((self.sprite_patterns_lo[i] >> bit) & 1);
+                if pixel == 0 {
+                    continue;
+                }
+                sprite_pixel = pixel;
+                sprite_palette = (self.sprite_attributes[i] & 0b11) + 4;
+                sprite_behind_background = self.sprite_attributes[i] & 0x20 != 0;
+                is_sprite_zero = i == 0 && self.sprite_zero_on_scanline;
+                break;
+            }
+        }
+
+        if is_sprite_zero && bg_pixel != 0 && x != 255 {
+            self.status |= STATUS_SPRITE_ZERO_HIT;
+        }
+
+        let palette_index = if bg_pixel == 0 && sprite_pixel == 0 {
+            0
+        } else if sprite_pixel != 0 && (bg_pixel == 0 || !sprite_behind_background) {
+            sprite_palette * 4 + sprite_pixel
+        } else {
+            bg_palette * 4 + bg_pixel
+        };
+        self.framebuffer[y * SCREEN_WIDTH + x] = self.get_output_pixel(palette_index as usize);
+    }
+
+    /// Advances the PPU by one dot.
+    pub fn tick(&mut self, mapper: &mut dyn Mapper) {
+        let visible_scanline = self.scanline < SCREEN_HEIGHT as u16;
+        let last_scanline = self.region.get_scanlines() - 1;
+        let pre_render_scanline = self.scanline == last_scanline;
+
+        if pre_render_scanline && self.dot == 1 {
+            self.status &= !(STATUS_VBLANK | STATUS_SPRITE_ZERO_HIT | STATUS_SPRITE_OVERFLOW);
+        }
+
+        if self.is_rendering_enabled() && (visible_scanline || pre_render_scanline) {
+            if visible_scanline && (1..=256).contains(&self.dot) {
+                self.render_pixel();
+            }
+            if (2..=257).contains(&self.dot) || (322..=337).contains(&self.dot) {
+                self.shift_background_shifters();
+            }
+            if (1..=256).contains(&self.dot) || (321..=336).contains(&self.dot) {
+                self.fetch_background(mapper);
+            }
+            if self.dot == 256 {
+                self.increment_fine_y();
+            }
+            if self.dot == 257 {
+                self.load_background_shifters();
+                self.copy_horizontal_bits();
+                mapper.notify_ppu(PPUEvent::SpriteFetches);
+                if visible_scanline {
+                    self.evaluate_sprites(mapper);
+                } else {
+                    self.sprite_count = 0;
+                }
+            }
+            if pre_render_scanline && (280..=304).contains(&self.dot) {
+                self.copy_vertical_bits();
+            }
+        } else if visible_scanline && (1..=256).contains(&self.dot) {
+            // with rendering off the backdrop colour is shown
+            let x = (self.dot - 1) as usize;
+            self.framebuffer[self.scanline as usize * SCREEN_WIDTH + x] = self.get_output_pixel(0);
+        }
+
+        // about where scanline counters on the cartridge notice a new line
+        if (visible_scanline || pre_render_scanline) && self.dot == 320 {
+            let next_scanline = if pre_render_scanline {
+                0
+            } else {
+                self.scanline + 1
+            };
+            if self.is_rendering_enabled() && next_scanline < SCREEN_HEIGHT as u16 {
+                mapper.notify_ppu(PPUEvent::Scanline(next_scanline));
+            } else {
+                mapper.notify_ppu(PPUEvent::RenderingStopped);
+            }
+        }
+
+        if self.scanline == self.region.get_vblank_scanline() && self.dot == 1 {
+            if !self.suppress_vblank {
+                self.status |= STATUS_VBLANK;
+            }
+            self.frame_complete = true;
+            if self.ctrl & CTRL_NMI_ENABLE != 0 && !self.suppress_vblank {
+                self.nmi_delay = NMI_DELAY_DOTS;
+            }
+            self.suppress_vblank = false;
+        }
+
+        if self.nmi_delay > 0 {
+            self.nmi_delay -= 1;
+            if self.nmi_delay == 0 {
+                self.nmi_pending = true;
+            }
+        }
+
+        self.dot += 1;
+        if let Some((addr, val)) = self.pending_write.take() {
+            self.apply_write(addr, val, mapper);
+        }
+        // odd frames skip the next to last dot of the pre-render scanline when
+        // rendering, only on NTSC
+        if pre_render_scanline
+            && self.region == Region::NTSC
+            && self.dot == DOTS_PER_SCANLINE - 2
+            && self.frame % 2 == 1
+            && self.is_rendering_enabled()
+        {
+            self.dot += 1;
+        }
+        if self.dot == DOTS_PER_SCANLINE {
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline > last_scanline {
+                self.scanline = 0;
+                self.frame += 1;
+            }
+        }
+    }
+}
+
+// the framebuffer is output rather than state, the next frame redraws it
+impl SaveState for PPU {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.ctrl);
+        state.write(&self.mask);
+        state.write(&self.status);
+        state.write(&self.oam_addr);
+        state.write(&self.v);
+        state.write(&self.t);
+        state.write(&self.fine_x);
+        state.write(&self.w);
+        state.write(&self.read_buffer);
+        state.write(&self.io_latch);
+        for frame in &self.io_latch_refreshed {
+            state.write(frame);
+        }
+        state.write(&self.suppress_vblank);
+        let (write_addr, write_val) = self.pending_write.unwrap_or((0, 0));
+        state.write(&self.pending_write.is_some());
+        state.write(&write_addr);
+        state.write(&write_val);
+        state.write(&self.vram);
+        state.write(&self.palette);
+        state.write(&self.oam);
+        state.write(&self.scanline);
+        state.write(&self.dot);
+        state.write(&self.frame);
+        state.write(&self.nmi_pending);
+        state.write(&self.nmi_delay);
+        state.write(&self.frame_complete);
+        state.write(&self.bg_next_tile_id);
+        state.write(&self.bg_next_attribute);
+        state.write(&self.bg_next_pattern_lo);
+        state.write(&self.bg_next_pattern_hi);
+        state.write(&self.bg_shift_pattern_lo);
+        state.write(&self.bg_shift_pattern_hi);
+        state.write(&self.bg_shift_attribute_lo);
+        state.write(&self.bg_shift_attribute_hi);
+        state.write(&self.sprite_count);
+        state.write(&self.sprite_patterns_lo);
+        state.write(&self.sprite_patterns_hi);
+        state.write(&self.sprite_attributes);
+        state.write(&self.sprite_x);
+        state.write(&self.sprite_zero_on_scanline);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read(&mut self.ctrl)?;
+        state.read(&mut self.mask)?;
+        state.read(&mut self.status)?;
+        state.read(&mut self.oam_addr)?;
+        state.read(&mut self.v)?;
+        state.read(&mut self.t)?;
+        state.read(&mut self.fine_x)?;
+        state.read(&mut self.w)?;
+        state.read(&mut self.read_buffer)?;
+        state.read(&mut self.io_latch)?;
+        for frame in &mut self.io_latch_refreshed {
+            state.read(frame)?;
+        }
+        state.read(&mut self.suppress_vblank)?;
+        let (mut has_write, mut write_addr, mut write_val) = (false, 0u16, 0u8);
+        state.read(&mut has_write)?;
+        state.read(&mut write_addr)?;
+        state.read(&mut write_val)?;
+        self.pending_write = has_write.then_some((write_addr, write_val));
+        state.read(&mut self.vram)?;
+        state.read(&mut self.palette)?;
+        state.read(&mut self.oam)?;
+        state.read(&mut self.scanline)?;
+        state.read(&mut self.dot)?;
+        if self.dot > 340 {
+            return Err(StateError::Invalid("dot"));
+        }
+        state.read(&mut self.frame)?;
+        if self
+            .io_latch_refreshed
+            .iter()
+            .any(|&frame| frame > self.frame)
+        {
+            return Err(StateError::Invalid("open bus refresh frame"));
+        }
+        state.read(&mut self.nmi_pending)?;
+        state.read(&mut self.nmi_delay)?;
+        state.read(&mut self.frame_complete)?;
+        state.read(&mut self.bg_next_tile_id)?;
+        state.read(&mut self.bg_next_attribute)?;
+        state.read(&mut self.bg_next_pattern_lo)?;
+        state.read(&mut self.bg_next_pattern_hi)?;
+        state.read(&mut self.bg_shift_pattern_lo)?;
+        state.read(&mut self.bg_shift_pattern_hi)?;
+        state.read(&mut self.bg_shift_attribute_lo)?;
+        state.read(&mut self.bg_shift_attribute_hi)?;
+        state.read(&mut self.sprite_count)?;
+        if self.sprite_count > MAX_SPRITES_PER_SCANLINE {
+            return Err(StateError::Invalid("sprite count"));
+        }
+        state.read(&mut self.sprite_patterns_lo)?;
+        state.read(&mut self.sprite_patterns_hi)?;
+        state.read(&mut self.sprite_attributes)?;
+        state.read(&mut self.sprite_x)?;
+        state.read(&mut self.sprite_zero_on_scanline)?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::ines_rom;
+    use crate::cartridge::Cartridge;
+    use crate::mapper;
+
+    fn mapper_for(data: &[u8]) -> Box<dyn Mapper> {
+        return mapper::from_cartridge(&Cartridge::from_ines(data).unwrap()).unwrap();
+    }
+
+    // writes only take effect on the next dot
+    fn write(ppu: &mut PPU, mapper: &mut dyn Mapper, addr: u16, val: u8) {
+        ppu.write_register(addr, val);
+        ppu.tick(mapper);
+    }
+
+    fn set_vram_addr(ppu: &mut PPU, mapper: &mut dyn Mapper, addr: u16) {
+        write(ppu, mapper, 0x2006, (addr >> 8) as u8);
+        write(ppu, mapper, 0x2006, addr as u8);
+    }
+
+    fn reload(ppu: &mut PPU) -> Result<(), StateError> {
+        let mut state = StateWriter::init();
+        state.write(ppu);
+        let data = state.into_bytes();
+        return StateReader::init(&data).read(ppu);
+    }
+
+    #[test]
+    fn test_load_state_checks_ranges() {
+        let mut ppu = PPU::init();
+        assert_eq!(reload(&mut ppu), Ok(()));
+
+        ppu.dot = 341;
+        assert_eq!(reload(&mut ppu), Err(StateError::Invalid("dot")));
+
+        // the decay check subtracts the refresh frame from the current one
+        ppu.dot = 0;
+        ppu.io_latch_refreshed[3] = ppu.frame + 1;
+        assert_eq!(
+            reload(&mut ppu),
+            Err(StateError::Invalid("open bus refresh frame"))
+        );
+    }
+
+    #[test]
+    fn test_ppudata_reads_are_buffered_except_for_the_palette() {
+        let mut ppu = PPU::init();
+        let mut mapper = mapper_for(&ines_rom(0, 1, 1, 0));
+        let mapper = mapper.as_mut();
+        set_vram_addr(&mut ppu, mapper, 0x2000);
+        write(&mut ppu, mapper, 0x2007, 0x55);
+        set_vram_addr(&mut ppu, mapper, 0x2F00);
+        write(&mut ppu, mapper, 0x2007, 0x66);
+        set_vram_addr(&mut ppu, mapper, 0x3F00);
+        write(&mut ppu, mapper, 0x2007, 0x21);
+
+        // nametable reads return the byte fetched by the previous read
+        set_vram_addr(&mut ppu, mapper, 0x2000);
+        assert_eq!(ppu.read_register(0x2007, mapper), 0x00);
+        assert_eq!(ppu.read_register(0x2007, mapper), 0x55);
+
+        // palette reads come straight back and fill the buffer from the
+        // nametable underneath
+        set_vram_addr(&mut ppu, mapper, 0x3F00);
+        assert_eq!(ppu.read_register(0x2007, mapper), 0x21);
+        set_vram_addr(&mut ppu, mapper, 0x2000);
+        assert_eq!(ppu.read_register(0x2007, mapper), 0x66);
+    }
+
+    #[test]
+    fn test_io_latch_decays() {
+        let mut ppu = PPU::init();
+        let mut mapper = mapper_for(&ines_rom(0, 1, 1, 0));
+        let mapper = mapper.as_mut();
+        write(&mut ppu, mapper, 0x2003, 0xC0);
+        assert_eq!(ppu.read_register(0x2000, mapper), 0xC0);
+
+        ppu.frame += IO_LATCH_DECAY_FRAMES - 1;
+        assert_eq!(ppu.read_register(0x2000, mapper), 0xC0);
+        ppu.frame += 1;
+        assert_eq!(ppu.read_register(0x2000, mapper), 0x00);
+    }
+
+    #[test]
+    fn test_status_read_on_the_vblank_dot_suppresses_vblank_and_nmi() {
+        for read_on_vblank_dot in [false, true] {
+            let mut ppu = PPU::init();
+            let mut mapper = mapper_for(&ines_rom(0, 1, 1, 0));
+            let mapper = mapper.as_mut();
+            write(&mut ppu, mapper, 0x2000, CTRL_NMI_ENABLE);
+            while ppu.scanline != 241 || ppu.dot != 1 {
+                ppu.tick(mapper);
+            }
+            if read_on_vblank_dot {
+                assert_eq!(ppu.read_register(0x2002, mapper) & STATUS_VBLANK, 0);
+            }
+            let mut nmi = false;
+            for _ in 0..10 {
+                ppu.tick(mapper);
+                nmi |= ppu.poll_nmi();
+            }
+            assert_eq!(ppu.status & STATUS_VBLANK != 0, !read_on_vblank_dot);
+            assert_eq!(nmi, !read_on_vblank_dot);
+        }
+    }
+
+    #[test]
+    fn test_single_screen_and_four_screen_nametables() {
+        let mut screen_a = mapper_for(&ines_rom(7, 8, 0, 0));
+        let mut screen_b = mapper_for(&ines_rom(7, 8, 0, 0));
+        screen_b.write_prg(0x8000, 0x10);
+        let mut four_screen = mapper_for(&ines_rom(0, 1, 1, 0b1000));
+        // what each nametable reads back as, and the VRAM each write went to
+        for (mapper, expected, vram_offsets) in [
+            (&mut screen_a, [0x13; 4], [0x005; 4]),
+            (&mut screen_b, [0x13; 4], [0x405; 4]),
+            (
+                &mut four_screen,
+                [0x10, 0x11, 0x12, 0x13],
+                [0x005, 0x405, 0x805, 0xC05],
+            ),
+        ] {
+            let mut ppu = PPU::init();
+            let mapper = mapper.as_mut();
+            for table in 0..4u16 {
+                set_vram_addr(&mut ppu, mapper, 0x2005 + table * 0x400);
+                write(&mut ppu, mapper, 0x2007, 0x10 + table as u8);
+            }
+            for table in 0..4 {
+                let addr = 0x2005 + table as u16 * 0x400;
+                assert_eq!(mapper.read_nametable(addr, &ppu.vram), expected[table]);
+                assert_eq!(ppu.vram[vram_offsets[table]], expected[table]);
+                // and through $2007, one read behind
+                set_vram_addr(&mut ppu, mapper, addr);
+                ppu.read_register(0x2007, mapper);
+                assert_eq!(ppu.read_register(0x2007, mapper), expected[table]);
+            }
+        }
+    }
+}
@@ -1,23 +1,30 @@
-const START_SYS_RAM: u16 = 0x0000;
-const END_SYS_RAM: u16 = 0x07FF;
-const START_PPU_REGISTERS: u16 = 0x2000;
-const END_PPU_REGISTERS: u16 = 0x2007;
-const START_AUDIO_CONTROLLERS_REGISTERS: u16 = 0x4000;
-const END_AUDIO_CONTROLLERS_REGISTERS: u16 = 0x4016;
-const START_EXPANSION_MODULES: u16 = 0x5000;
-const END_EXPANSION_MODULES: u16 = 0x5FFF;
-const START_CARTRIDGE_ROM: u16 = 0x8000;
-const END_CARTRIDGE_ROM: u16 = 0xFFFF;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+pub(crate) const START_SYS_RAM: u16 = 0x0000;
+pub(crate) const END_SYS_RAM: u16 = 0x07FF;
+pub(crate) const END_SYS_RAM_MIRRORS: u16 = 0x1FFF;
+pub(crate) const START_PPU_REGISTERS: u16 = 0x2000;
+pub(crate) const END_PPU_REGISTERS: u16 = 0x2007;
+pub(crate) const END_PPU_REGISTERS_MIRRORS: u16 = 0x3FFF;
+pub(crate) const START_AUDIO_CONTROLLERS_REGISTERS: u16 = 0x4000;
+pub(crate) const END_AUDIO_CONTROLLERS_REGISTERS: u16 = 0x4017;
+// the cartridge expansion area, after the APU's test registers at $4018-$401F
+pub(crate) const START_EXPANSION_MODULES: u16 = 0x4020;
+pub(crate) const END_EXPANSION_MODULES: u16 = 0x5FFF;
+pub(crate) const START_CARTRIDGE_RAM: u16 = 0x6000;
+pub(crate) const END_CARTRIDGE_RAM: u16 = 0x7FFF;
+pub(crate) const START_CARTRIDGE_ROM: u16 = 0x8000;
+pub(crate) const END_CARTRIDGE_ROM: u16 = 0xFFFF;
 
 #[derive(Debug)]
 pub struct RAM {
-    memory: [u8; 0xFFFF],
+    memory: [u8; 0x10000],
 }
 
 impl RAM {
     pub fn init() -> Self {
         return RAM {
-            memory: [0; 0xFFFF],
+            memory: [0; 0x10000],
         };
     }
 
@@ -29,3 +36,13 @@ impl RAM {
         self.memory[addr as usize] = val;
     }
 }
+
+impl SaveState for RAM {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.memory);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        return state.read(&mut self.memory);
+    }
+}
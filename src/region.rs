@@ -0,0 +1,89 @@
+//! Timing differences between NTSC consoles, PAL consoles and the Dendy
+//! famiclone.
+
+/// NTSC frames per second (39375000 / 655171).
+pub const NTSC_FRAME_RATE: f64 = 60.0988;
+/// PAL and Dendy frames per second.
+pub const PAL_FRAME_RATE: f64 = 50.0070;
+
+pub const NTSC_CPU_CLOCK_RATE: f64 = 1_789_773.0;
+pub const PAL_CPU_CLOCK_RATE: f64 = 1_662_607.0;
+pub const DENDY_CPU_CLOCK_RATE: f64 = 1_773_448.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Region {
+    #[default]
+    NTSC,
+    PAL,
+    Dendy,
+}
+
+impl Region {
+    /// Parses `ntsc`, `pal` or `dendy`.
+    pub fn from_name(name: &str) -> Option<Region> {
+        match name.to_ascii_lowercase().as_str() {
+            "ntsc" => return Some(Region::NTSC),
+            "pal" => return Some(Region::PAL),
+            "dendy" => return Some(Region::Dendy),
+            _ => return None,
+        }
+    }
+
+    pub fn get_frame_rate(self) -> f64 {
+        match self {
+            Region::NTSC => return NTSC_FRAME_RATE,
+            Region::PAL | Region::Dendy => return PAL_FRAME_RATE,
+        }
+    }
+
+    pub fn get_cpu_clock_rate(self) -> f64 {
+        match self {
+            Region::NTSC => return NTSC_CPU_CLOCK_RATE,
+            Region::PAL => return PAL_CPU_CLOCK_RATE,
+            Region::Dendy => return DENDY_CPU_CLOCK_RATE,
+        }
+    }
+
+    /// PPU dots per CPU cycle as a fraction: 3 on NTSC and Dendy, 3.2 on PAL.
+    pub fn get_ppu_dots_per_cpu_cycle(self) -> (u8, u8) {
+        match self {
+            Region::NTSC | Region::Dendy => return (3, 1),
+            Region::PAL => return (16, 5),
+        }
+    }
+
+    /// Scanlines per frame, including vblank and the pre-render line.
+    pub fn get_scanlines(self) -> u16 {
+        match self {
+            Region::NTSC => return 262,
+            Region::PAL | Region::Dendy => return 312,
+        }
+    }
+
+    /// The scanline vblank starts on. The Dendy keeps NTSC's vblank length
+    /// and adds its extra lines before it instead.
+    pub fn get_vblank_scanline(self) -> u16 {
+        match self {
+            Region::NTSC | Region::PAL => return 241,
+            Region::Dendy => return 291,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_frame_timing_adds_up() {
+        for region in [Region::NTSC, Region::PAL, Region::Dendy] {
+            let (dots, cycles) = region.get_ppu_dots_per_cpu_cycle();
+            let dots_per_frame = region.get_scanlines() as f64 * 341.0;
+            let cpu_cycles = dots_per_frame * cycles as f64 / dots as f64;
+            let frame_rate = region.get_cpu_clock_rate() / cpu_cycles;
+            assert!((frame_rate - region.get_frame_rate()).abs() < 0.05);
+        }
+        assert_eq!(Region::from_name("PAL"), Some(Region::PAL));
+        assert_eq!(Region::from_name("secam"), None);
+    }
+}
@@ -0,0 +1,227 @@
+//! Rewind: a ring buffer of save states captured every few frames.
+//!
+//! Only the newest state is kept whole. Every older state is stored as the
+//! difference to the state after it (XOR, with runs of unchanged bytes
+//! collapsed), which is usually a few hundred bytes per capture. Stepping back
+//! applies the newest difference to the newest state. When the buffer is full
+//! the oldest differences are dropped.
+
+use std::collections::VecDeque;
+
+pub const DEFAULT_REWIND_MB: usize = 64;
+pub const DEFAULT_REWIND_INTERVAL: u64 = 2;
+
+const BYTES_PER_MB: usize = 1024 * 1024;
+
+fn write_varint(out: &mut Vec<u8>, mut val: usize) {
+    while val >= 0x80 {
+        out.push((val as u8) | 0x80);
+        val >>= 7;
+    }
+    out.push(val as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut val = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*pos];
+        *pos += 1;
+        val |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return val;
+        }
+        shift += 7;
+    }
+}
+
+/// Encodes `older ^ newer` as alternating (unchanged run, changed run) lengths
+/// followed by the changed bytes XORed together.
+fn compress_delta(older: &[u8], newer: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < newer.len() {
+        let start = pos;
+        while pos < newer.len() && older[pos] == newer[pos] {
+            pos += 1;
+        }
+        write_varint(&mut out, pos - start);
+        let start = pos;
+        while pos < newer.len() && older[pos] != newer[pos] {
+            pos += 1;
+        }
+        write_varint(&mut out, pos - start);
+        out.extend((start..pos).map(|i| older[i] ^ newer[i]));
+    }
+    return out;
+}
+
+/// Turns `state` back into the state the delta was made from.
+fn apply_delta(state: &mut [u8], delta: &[u8]) {
+    let mut pos = 0;
+    let mut offset = 0;
+    while pos < delta.len() {
+        offset += read_varint(delta, &mut pos);
+        let changed = read_varint(delta, &mut pos);
+        for byte in &mut state[offset..offset + changed] {
+            *byte ^= delta[pos];
+            pos += 1;
+        }
+        offset += changed;
+    }
+}
+
+#[derive(Debug)]
+pub struct RewindBuffer {
+    newest: Option<Vec<u8>>,
+    // oldest first; each turns the state after it into the one before
+    deltas: VecDeque<Vec<u8>>,
+    delta_bytes: usize,
+    max_bytes: usize,
+    interval: u64,
+    frames_since_capture: u64,
+}
+
+impl RewindBuffer {
+    /// Keeps up to `max_mb` megabytes of history, capturing a state every
+    /// `interval` frames.
+    pub fn init(max_mb: usize, interval: u64) -> Self {
+        return RewindBuffer {
+            newest: None,
+            deltas: VecDeque::new(),
+            delta_bytes: 0,
+            max_bytes: max_mb * BYTES_PER_MB,
+            interval: interval.max(1),
+            frames_since_capture: 0,
+        };
+    }
+
+    pub fn get_interval(&self) -> u64 {
+        return self.interval;
+    }
+
+    /// Number of states that can be stepped back through.
+    pub fn len(&self) -> usize {
+        return self.newest.as_ref().map_or(0, |_| self.deltas.len() + 1);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.newest.is_none();
+    }
+
+    /// Memory held by the captured states, in bytes.
+    pub fn get_used_bytes(&self) -> usize {
+        return self.delta_bytes + self.newest.as_ref().map_or(0, |state| state.len());
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.deltas.clear();
+        self.delta_bytes = 0;
+        self.frames_since_capture = 0;
+    }
+
+    /// Counts a frame and returns true when it's time to capture a state.
+    pub fn tick_frame(&mut self) -> bool {
+        self.frames_since_capture += 1;
+        if self.frames_since_capture < self.interval {
+            return false;
+        }
+        self.frames_since_capture = 0;
+        return true;
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(newest) = self.newest.take() {
+            if newest.len() == state.len() {
+                let delta = compress_delta(&newest, &state);
+                self.delta_bytes += delta.len();
+                self.deltas.push_back(delta);
+            } else {
+                // a different machine, the old history is useless
+                self.clear();
+            }
+        }
+        self.newest = Some(state);
+        while self.get_used_bytes() > self.max_bytes {
+            match self.deltas.pop_front() {
+                Some(delta) => self.delta_bytes -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    /// Removes and returns the newest state; the one before it becomes the
+    /// newest.
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let state = self.newest.take()?;
+        if let Some(delta) = self.deltas.pop_back() {
+            self.delta_bytes -= delta.len();
+            let mut older = state.clone();
+            apply_delta(&mut older, &delta);
+            self.newest = Some(older);
+        }
+        self.frames_since_capture = 0;
+        return Some(state);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn make_state(seed: u8) -> Vec<u8> {
+        let mut state = vec![0u8; 4096];
+        for i in 0..20 {
+            state[i * 200 + seed as usize] = seed.wrapping_mul(31).wrapping_add(i as u8);
+        }
+        state[0] = seed;
+        return state;
+    }
+
+    #[test]
+    fn test_delta_round_trip() {
+        let older = make_state(3);
+        let newer = make_state(4);
+        let delta = compress_delta(&older, &newer);
+        assert!(delta.len() < 200);
+        let mut state = newer.clone();
+        apply_delta(&mut state, &delta);
+        assert_eq!(state, older);
+    }
+
+    #[test]
+    fn test_pop_returns_states_newest_first() {
+        let mut rewind = RewindBuffer::init(1, 1);
+        for seed in 0..10 {
+            rewind.push(make_state(seed));
+        }
+        assert_eq!(rewind.len(), 10);
+        for seed in (0..10).rev() {
+            assert_eq!(rewind.pop(), Some(make_state(seed)));
+        }
+        assert_eq!(rewind.pop(), None);
+        assert_eq!(rewind.get_used_bytes(), 0);
+    }
+
+    #[test]
+    fn test_memory_bound_drops_oldest() {
+        let mut rewind = RewindBuffer::init(1, 1);
+        let mut count = 0;
+        while rewind.len() == count {
+            rewind.push(vec![count as u8; 256 * 1024]);
+            count += 1;
+        }
+        // the newest states survive
+        assert!(rewind.get_used_bytes() <= BYTES_PER_MB);
+        assert_eq!(rewind.pop(), Some(vec![(count - 1) as u8; 256 * 1024]));
+        assert_eq!(rewind.pop(), Some(vec![(count - 2) as u8; 256 * 1024]));
+    }
+
+    #[test]
+    fn test_capture_interval() {
+        let mut rewind = RewindBuffer::init(1, 3);
+        let captures: Vec<bool> = (0..6).map(|_| rewind.tick_frame()).collect();
+        assert_eq!(captures, vec![false, false, true, false, false, true]);
+    }
+}
@@ -0,0 +1,293 @@
+//! Save states: snapshots of the whole machine as a versioned binary blob.
+//!
+//! Every component implements [`SaveState`] by writing its fields in a fixed
+//! order and reading them back in the same order. Values are little endian
+//! with no padding or field names, so any change to what a component saves
+//! has to bump [`STATE_VERSION`]. The header carries a hash of the ROM so a
+//! state can't be loaded into another game.
+
+use std::fmt;
+
+const STATE_MAGIC: [u8; 4] = [b'R', b'S', b'T', 0x1A];
+pub const STATE_VERSION: u32 = 8;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StateError {
+    InvalidHeader,
+    UnsupportedVersion(u32),
+    Truncated,
+    /// The state was saved with another ROM.
+    WrongGame,
+    /// The state doesn't fit this machine, e.g. it was saved with another ROM.
+    Mismatch(&'static str),
+    /// A value that can't be right, from a damaged or edited state.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::InvalidHeader => return write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                return write!(f, "save state version {} is not supported", version)
+            }
+            StateError::Truncated => return write!(f, "save state is truncated"),
+            StateError::WrongGame => return write!(f, "save state is from another game"),
+            StateError::Mismatch(what) => {
+                return write!(f, "save state does not match this game ({})", what)
+            }
+            StateError::Invalid(what) => return write!(f, "save state has an invalid {}", what),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// Something that can be written to and restored from a save state.
+pub trait SaveState {
+    fn save_state(&self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError>;
+}
+
+#[derive(Debug, Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn init() -> Self {
+        return StateWriter::default();
+    }
+
+    pub fn write<T: SaveState + ?Sized>(&mut self, val: &T) {
+        val.save_state(self);
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        return self.data;
+    }
+}
+
+#[derive(Debug)]
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn init(data: &'a [u8]) -> Self {
+        return StateReader { data, pos: 0 };
+    }
+
+    pub fn read<T: SaveState + ?Sized>(&mut self, val: &mut T) -> Result<(), StateError> {
+        return val.load_state(self);
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() - self.pos < len {
+            return Err(StateError::Truncated);
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        return Ok(bytes);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.pos == self.data.len();
+    }
+}
+
+macro_rules! impl_save_state_int {
+    ($($int:ty),*) => {
+        $(
+            impl SaveState for $int {
+                fn save_state(&self, state: &mut StateWriter) {
+                    state.write_bytes(&self.to_le_bytes());
+                }
+
+                fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+                    let bytes = state.read_bytes(std::mem::size_of::<$int>())?;
+                    *self = <$int>::from_le_bytes(bytes.try_into().unwrap());
+                    return Ok(());
+                }
+            }
+        )*
+    };
+}
+
+impl_save_state_int!(u8, u16, u32, u64, f32, f64);
+
+impl SaveState for usize {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&(*self as u64));
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let mut val = 0u64;
+        state.read(&mut val)?;
+        *self = val as usize;
+        return Ok(());
+    }
+}
+
+impl SaveState for bool {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&(*self as u8));
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let mut val = 0u8;
+        state.read(&mut val)?;
+        *self = val != 0;
+        return Ok(());
+    }
+}
+
+impl SaveState for Option<u8> {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.is_some());
+        state.write(&self.unwrap_or(0));
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let mut is_some = false;
+        let mut val = 0u8;
+        state.read(&mut is_some)?;
+        state.read(&mut val)?;
+        *self = if is_some { Some(val) } else { None };
+        return Ok(());
+    }
+}
+
+impl<const N: usize> SaveState for [u8; N] {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(self);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.copy_from_slice(state.read_bytes(N)?);
+        return Ok(());
+    }
+}
+
+/// Memory whose size comes from the cartridge, so it has to match on load.
+impl SaveState for Vec<u8> {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&(self.len() as u32));
+        state.write_bytes(self);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let mut len = 0u32;
+        state.read(&mut len)?;
+        if len as usize != self.len() {
+            return Err(StateError::Mismatch("memory size"));
+        }
+        self.copy_from_slice(state.read_bytes(len as usize)?);
+        return Ok(());
+    }
+}
+
+/// Serializes `component` behind the save state header, tagged with the
+/// hash of the ROM it is running.
+pub fn encode<T: SaveState + ?Sized>(component: &T, rom_hash: u64) -> Vec<u8> {
+    let mut state = StateWriter::init();
+    state.write_bytes(&STATE_MAGIC);
+    state.write(&STATE_VERSION);
+    state.write(&rom_hash);
+    state.write(component);
+    return state.into_bytes();
+}
+
+/// Restores `component` from a blob made by [`encode`] with the same ROM
+/// hash. On error the component may have been partly overwritten.
+pub fn decode<T: SaveState + ?Sized>(
+    component: &mut T,
+    rom_hash: u64,
+    data: &[u8],
+) -> Result<(), StateError> {
+    let mut state = StateReader::init(data);
+    if state.read_bytes(STATE_MAGIC.len()) != Ok(&STATE_MAGIC[..]) {
+        return Err(StateError::InvalidHeader);
+    }
+    let mut version = 0u32;
+    state.read(&mut version)?;
+    if version != STATE_VERSION {
+        return Err(StateError::UnsupportedVersion(version));
+    }
+    let mut hash = 0u64;
+    state.read(&mut hash)?;
+    if hash != rom_hash {
+        return Err(StateError::WrongGame);
+    }
+    state.read(component)?;
+    if !state.is_empty() {
+        return Err(StateError::Mismatch("trailing data"));
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_values_round_trip() {
+        let mut state = StateWriter::init();
+        state.write(&0x1234u16);
+        state.write(&true);
+        state.write(&Some(7u8));
+        state.write(&[1u8, 2, 3]);
+        state.write(&vec![4u8, 5]);
+        state.write(&0.5f32);
+        let data = state.into_bytes();
+
+        let mut state = StateReader::init(&data);
+        let (mut a, mut b, mut c, mut d, mut e, mut f) =
+            (0u16, false, None, [0u8; 3], vec![0u8; 2], 0f32);
+        state.read(&mut a).unwrap();
+        state.read(&mut b).unwrap();
+        state.read(&mut c).unwrap();
+        state.read(&mut d).unwrap();
+        state.read(&mut e).unwrap();
+        state.read(&mut f).unwrap();
+        assert!(state.is_empty());
+        assert_eq!(
+            (a, b, c, d, e, f),
+            (0x1234, true, Some(7), [1, 2, 3], vec![4, 5], 0.5)
+        );
+    }
+
+    #[test]
+    fn test_decode_checks_header_and_size() {
+        let data = encode(&vec![1u8, 2, 3], 1);
+        let mut wrong_size = vec![0u8; 2];
+        assert_eq!(
+            decode(&mut wrong_size, 1, &data),
+            Err(StateError::Mismatch("memory size"))
+        );
+        assert_eq!(
+            decode(&mut vec![0u8; 3], 1, &data[..data.len() - 1]),
+            Err(StateError::Truncated)
+        );
+        assert_eq!(
+            decode(&mut vec![0u8; 3], 1, b"NES\x1a"),
+            Err(StateError::InvalidHeader)
+        );
+
+        let mut newer = data.clone();
+        newer[4] = 99;
+        assert_eq!(
+            decode(&mut vec![0u8; 3], 1, &newer),
+            Err(StateError::UnsupportedVersion(99))
+        );
+        assert_eq!(
+            decode(&mut vec![0u8; 3], 2, &data),
+            Err(StateError::WrongGame)
+        );
+    }
+}
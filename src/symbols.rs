@@ -0,0 +1,388 @@
+//! Labels from assembler and debugger symbol files: ca65 `.dbg` debug info
+//! (from `ld65 --dbgfile`), FCEUX `.nl` name lists and Mesen `.mlb` label
+//! files.
+//!
+//! Labels in PRG ROM are kept by ROM offset when the file says where they
+//! are, so a label only shows up while its bank is mapped in.
+
+use crate::bus::BUS;
+use crate::cartridge::HEADER_SIZE;
+use crate::ram::{START_CARTRIDGE_RAM, START_CARTRIDGE_ROM};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// FCEUX writes one .nl file per 16KB PRG bank
+const NL_BANK_SIZE: usize = 0x4000;
+
+#[derive(Debug)]
+pub enum SymbolError {
+    Io(io::Error),
+    Parse(usize, String),
+    UnknownFormat(String),
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SymbolError::Io(err) => return write!(f, "could not read symbols: {}", err),
+            SymbolError::Parse(line, msg) => return write!(f, "line {}: {}", line, msg),
+            SymbolError::UnknownFormat(name) => {
+                return write!(f, "{} is not a .dbg, .nl or .mlb file", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SymbolError {}
+
+impl From<io::Error> for SymbolError {
+    fn from(err: io::Error) -> Self {
+        return SymbolError::Io(err);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    /// The CPU address, if the file gives one.
+    pub addr: Option<u16>,
+    /// The offset into PRG ROM, for labels in ROM.
+    pub prg_offset: Option<usize>,
+}
+
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+    // indices into symbols; later files win over earlier ones
+    by_addr: BTreeMap<u16, usize>,
+    by_prg_offset: BTreeMap<usize, usize>,
+    by_name: HashMap<String, usize>,
+}
+
+fn parse_hex(text: &str) -> Option<usize> {
+    let digits = text
+        .strip_prefix('$')
+        .or_else(|| text.strip_prefix("0x"))
+        .unwrap_or(text);
+    return usize::from_str_radix(digits, 16).ok();
+}
+
+/// `$12`, `$1234` or `$1234/10` for an array, which only labels its start.
+fn parse_nl_addr(text: &str) -> Option<u16> {
+    let addr = text.split('/').next()?;
+    return parse_hex(addr)
+        .filter(|&addr| addr <= 0xFFFF)
+        .map(|addr| addr as u16);
+}
+
+/// Splits `key=value,key="quoted, value",...` as found in ca65 debug files.
+fn parse_dbg_fields(text: &str) -> HashMap<&str, &str> {
+    let mut fields = HashMap::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let (key, after_key) = match rest.split_once('=') {
+            Some(split) => split,
+            None => break,
+        };
+        let (val, after_val) = match after_key.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"').unwrap_or(quoted.len());
+                let after = quoted.get(end + 1..).unwrap_or("");
+                (&quoted[..end], after.strip_prefix(',').unwrap_or(after))
+            }
+            None => after_key.split_once(',').unwrap_or((after_key, "")),
+        };
+        fields.insert(key.trim(), val);
+        rest = after_val;
+    }
+    return fields;
+}
+
+impl SymbolTable {
+    pub fn init() -> Self {
+        return SymbolTable::default();
+    }
+
+    pub fn len(&self) -> usize {
+        return self.by_name.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.by_name.is_empty();
+    }
+
+    pub fn add(&mut self, symbol: Symbol) {
+        let index = self.symbols.len();
+        if let Some(offset) = symbol.prg_offset {
+            self.by_prg_offset.insert(offset, index);
+        } else if let Some(addr) = symbol.addr {
+            self.by_addr.insert(addr, index);
+        }
+        self.by_name.insert(symbol.name.clone(), index);
+        self.symbols.push(symbol);
+    }
+
+    pub fn get_symbol(&self, name: &str) -> Option<&Symbol> {
+        return self.by_name.get(name).map(|&index| &self.symbols[index]);
+    }
+
+    /// The label for `addr` as the CPU sees it right now.
+    pub fn get_label(&self, bus: &BUS, addr: u16) -> Option<&str> {
+        if addr >= START_CARTRIDGE_ROM && !self.by_prg_offset.is_empty() {
+            let offset = bus
+                .get_mapper()
+                .and_then(|mapper| mapper.get_prg_rom_offset(addr));
+            if let Some(&index) = offset.and_then(|offset| self.by_prg_offset.get(&offset)) {
+                return Some(&self.symbols[index].name);
+            }
+        }
+        return self
+            .by_addr
+            .get(&addr)
+            .map(|&index| self.symbols[index].name.as_str());
+    }
+
+    /// The CPU address of the label `name`. Labels only known by ROM offset
+    /// resolve to wherever that offset is mapped at the moment.
+    pub fn resolve(&self, bus: &BUS, name: &str) -> Option<u16> {
+        let symbol = self.get_symbol(name)?;
+        let offset = match symbol.prg_offset {
+            Some(offset) => offset,
+            None => return symbol.addr,
+        };
+        let mapper = bus.get_mapper()?;
+        if let Some(addr) = symbol.addr {
+            if mapper.get_prg_rom_offset(addr) == Some(offset) {
+                return Some(addr);
+            }
+        }
+        return (START_CARTRIDGE_ROM..=0xFFFF)
+            .find(|&addr| mapper.get_prg_rom_offset(addr) == Some(offset));
+    }
+
+    /// Loads a file, picking the format from its extension. For `.nl` files
+    /// the bank comes from the name, e.g. `game.nes.3.nl`; `game.nes.ram.nl`
+    /// holds RAM labels.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<(), SymbolError> {
+        let path = path.as_ref();
+        let name = path.to_string_lossy().into_owned();
+        let text = fs::read_to_string(path)?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("dbg") => return self.parse_dbg(&text),
+            Some("mlb") => return self.parse_mlb(&text),
+            Some("nl") => {
+                let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+                let bank = stem
+                    .rsplit('.')
+                    .next()
+                    .and_then(|bank| usize::from_str_radix(bank, 16).ok());
+                return self.parse_nl(&text, bank);
+            }
+            _ => return Err(SymbolError::UnknownFormat(name)),
+        }
+    }
+
+    /// FCEUX name lists, `$C000#Reset#comment` per line. `bank` is the 16KB
+    /// PRG bank the file describes, None for the RAM file.
+    pub fn parse_nl(&mut self, text: &str, bank: Option<usize>) -> Result<(), SymbolError> {
+        for (line_number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut fields = line.split('#');
+            let addr = fields.next().and_then(parse_nl_addr);
+            let name = fields.next().map(str::trim).unwrap_or("");
+            let addr = match addr {
+                Some(addr) if !name.is_empty() => addr,
+                _ => {
+                    let msg = format!("expected $addr#label#, got '{}'", line);
+                    return Err(SymbolError::Parse(line_number + 1, msg));
+                }
+            };
+            let prg_offset = match bank {
+                Some(bank) if addr >= START_CARTRIDGE_ROM => {
+                    Some(bank * NL_BANK_SIZE + (addr - START_CARTRIDGE_ROM) as usize % NL_BANK_SIZE)
+                }
+                _ => None,
+            };
+            self.add(Symbol {
+                name: name.to_string(),
+                addr: Some(addr),
+                prg_offset,
+            });
+        }
+        return Ok(());
+    }
+
+    /// Mesen label files, `<type>:<addr>[-<end>]:<label>[:comment]` per line.
+    /// Both Mesen 1 (`P`, `R`, `S`, `W`, `G`) and Mesen 2 (`NesPrgRom`, ...)
+    /// type names are understood; labels in other memory are skipped.
+    pub fn parse_mlb(&mut self, text: &str) -> Result<(), SymbolError> {
+        for (line_number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut fields = line.splitn(4, ':');
+            let kind = fields.next().unwrap_or("");
+            let addr = fields
+                .next()
+                .and_then(|range| parse_hex(range.split('-').next()?));
+            let name = fields.next().unwrap_or("");
+            let addr = match addr {
+                Some(addr) => addr,
+                None => {
+                    let msg = format!("expected type:addr:label, got '{}'", line);
+                    return Err(SymbolError::Parse(line_number + 1, msg));
+                }
+            };
+            // comment-only entries have no label
+            if name.is_empty() {
+                continue;
+            }
+            let (addr, prg_offset) = match kind {
+                "P" | "NesPrgRom" => (None, Some(addr)),
+                "R" | "NesInternalRam" | "G" | "NesMemory" => (Some(addr as u16), None),
+                "S" | "W" | "NesSaveRam" | "NesWorkRam" => {
+                    (Some(START_CARTRIDGE_RAM.wrapping_add(addr as u16)), None)
+                }
+                _ => continue,
+            };
+            self.add(Symbol {
+                name: name.to_string(),
+                addr,
+                prg_offset,
+            });
+        }
+        return Ok(());
+    }
+
+    /// ca65 debug info. Only labels are taken, not equates, and labels in
+    /// segments written to the ROM get their PRG offset from the segment.
+    pub fn parse_dbg(&mut self, text: &str) -> Result<(), SymbolError> {
+        // segment id -> (start address, offset in the output file)
+        let mut segments: HashMap<&str, (usize, Option<usize>)> = HashMap::new();
+        let mut labels = Vec::new();
+        for (line_number, line) in text.lines().enumerate() {
+            let (kind, rest) = line
+                .split_once(|c: char| c.is_whitespace())
+                .unwrap_or((line, ""));
+            let fields = parse_dbg_fields(rest.trim());
+            let error = |msg: &str| SymbolError::Parse(line_number + 1, msg.to_string());
+            match kind {
+                "seg" => {
+                    let id = fields.get("id").ok_or_else(|| error("seg without id"))?;
+                    let start = fields
+                        .get("start")
+                        .and_then(|start| parse_hex(start))
+                        .ok_or_else(|| error("seg without start"))?;
+                    let file_offset = fields.get("ooffs").and_then(|offset| offset.parse().ok());
+                    segments.insert(id, (start, file_offset));
+                }
+                "sym" if fields.get("type") == Some(&"lab") => labels.push(fields),
+                _ => {}
+            }
+        }
+        for fields in labels {
+            let (name, val) = match (fields.get("name"), fields.get("val")) {
+                (Some(name), Some(val)) => (*name, parse_hex(val)),
+                _ => continue,
+            };
+            let val = match val {
+                Some(val) if val <= 0xFFFF => val,
+                _ => continue,
+            };
+            let segment = fields.get("seg").and_then(|seg| segments.get(seg));
+            let prg_offset = match segment {
+                Some(&(start, Some(file_offset))) if val >= START_CARTRIDGE_ROM as usize => {
+                    (file_offset + val - start).checked_sub(HEADER_SIZE)
+                }
+                _ => None,
+            };
+            self.add(Symbol {
+                name: name.to_string(),
+                addr: Some(val as u16),
+                prg_offset,
+            });
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::ines_rom;
+    use crate::cartridge::Cartridge;
+    use crate::mapper;
+
+    fn nrom_bus(prg_banks: u8) -> BUS {
+        let cartridge = Cartridge::from_ines(&ines_rom(0, prg_banks, 1, 0)).unwrap();
+        return BUS::with_mapper(mapper::from_cartridge(&cartridge).unwrap());
+    }
+
+    #[test]
+    fn test_parse_nl() {
+        let mut symbols = SymbolTable::init();
+        symbols
+            .parse_nl("$C000#Reset#entry point\n$C010/04#Table#\n", Some(1))
+            .unwrap();
+        symbols.parse_nl("$0010#counter#\n", None).unwrap();
+        let bus = nrom_bus(2);
+        assert_eq!(symbols.get_label(&bus, 0xC000), Some("Reset"));
+        assert_eq!(symbols.get_label(&bus, 0xC010), Some("Table"));
+        assert_eq!(symbols.get_label(&bus, 0x0010), Some("counter"));
+        assert_eq!(symbols.get_label(&bus, 0x8000), None);
+        assert_eq!(symbols.resolve(&bus, "Reset"), Some(0xC000));
+        assert!(symbols.parse_nl("C000 Reset\n", None).is_err());
+    }
+
+    #[test]
+    fn test_parse_mlb() {
+        let mut symbols = SymbolTable::init();
+        symbols
+            .parse_mlb("P:0010:nmi:vblank handler\nR:0020-0021:ptr\nG:2000:PPUCTRL\nW:0000:save\nP:0030::only a comment\n")
+            .unwrap();
+        let bus = nrom_bus(1);
+        // a 16KB ROM shows up at both $8000 and $C000
+        assert_eq!(symbols.get_label(&bus, 0x8010), Some("nmi"));
+        assert_eq!(symbols.get_label(&bus, 0xC010), Some("nmi"));
+        assert_eq!(symbols.resolve(&bus, "nmi"), Some(0x8010));
+        assert_eq!(symbols.get_label(&bus, 0x0020), Some("ptr"));
+        assert_eq!(symbols.get_label(&bus, 0x2000), Some("PPUCTRL"));
+        assert_eq!(symbols.get_label(&bus, 0x6000), Some("save"));
+        assert_eq!(symbols.len(), 4);
+    }
+
+    #[test]
+    fn test_parse_dbg() {
+        let text = r#"version	major=2,minor=0
+info	csym=0,file=1,lib=0,line=10,mod=1,scope=1,seg=2,span=5,sym=3,type=1
+file	id=0,name="main.s",size=100,mtime=0x5E000000,mod=0
+seg	id=0,name="ZEROPAGE",start=0x000000,size=0x0002,addrsize=zeropage,type=rw
+seg	id=1,name="CODE",start=0x00C000,size=0x0040,addrsize=absolute,type=ro,oname="game.nes",ooffs=16400
+sym	id=0,name="counter",addrsize=zeropage,scope=0,def=1,val=0x1,seg=0,type=lab
+sym	id=1,name="reset",addrsize=absolute,scope=0,def=2,val=0xC004,seg=1,type=lab
+sym	id=2,name="PPUCTRL",addrsize=absolute,scope=0,def=3,val=0x2000,type=equ
+"#;
+        let mut symbols = SymbolTable::init();
+        symbols.parse_dbg(text).unwrap();
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(
+            symbols.get_symbol("reset"),
+            Some(&Symbol {
+                name: String::from("reset"),
+                addr: Some(0xC004),
+                prg_offset: Some(0x4004),
+            })
+        );
+        let bus = nrom_bus(2);
+        assert_eq!(symbols.get_label(&bus, 0xC004), Some("reset"));
+        assert_eq!(symbols.get_label(&bus, 0x0001), Some("counter"));
+        assert_eq!(symbols.resolve(&bus, "PPUCTRL"), None);
+    }
+}
@@ -0,0 +1,460 @@
+//! Execution trace logs, one line per instruction, for diffing against other
+//! emulators.
+//!
+//! Lines are written before the instruction runs, so the registers and cycle
+//! count are the ones it starts with. Memory operands show the value currently
+//! at the effective address, read without side effects.
+
+use crate::bus::BUS;
+use crate::cpu::CPU;
+use crate::disasm::{disassemble_at, Instruction};
+use crate::opcodes::AddressingModes;
+use crate::symbols::SymbolTable;
+use crate::MemoryBus;
+use std::fmt;
+use std::io::{self, Write};
+
+/// Granularity of [`TraceFilter::prg_bank`], the smallest PRG window the
+/// common mappers switch.
+pub const PRG_BANK_SIZE: usize = 0x2000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// The layout of `nestest.log`.
+    Nestest,
+    /// Mesen's default trace logger layout.
+    Mesen,
+    /// FCEUX's trace logger with register logging on.
+    FCEUX,
+}
+
+impl TraceFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "nestest" => return Some(TraceFormat::Nestest),
+            "mesen" => return Some(TraceFormat::Mesen),
+            "fceux" => return Some(TraceFormat::FCEUX),
+            _ => return None,
+        }
+    }
+}
+
+/// Starts logging once this happens and keeps logging from then on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceTrigger {
+    Pc(u16),
+    Frame(u64),
+}
+
+/// Which instructions make it into the log. Everything passes by default.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceFilter {
+    /// Only instructions whose PC is in one of these inclusive ranges.
+    pub ranges: Vec<(u16, u16)>,
+    /// Only instructions running from this PRG ROM bank, counted in
+    /// [`PRG_BANK_SIZE`] units from the start of the ROM.
+    pub prg_bank: Option<usize>,
+    pub trigger: Option<TraceTrigger>,
+}
+
+/// The memory an instruction operand refers to.
+struct MemoryOperand {
+    // the indexed zero page address, or the pointer read for (zp),Y
+    pointer: Option<u16>,
+    addr: u16,
+    val: u8,
+}
+
+/// Reads a little endian pointer the way the CPU does, wrapping within the
+/// page.
+fn read_pointer(bus: &mut BUS, addr: u16) -> u16 {
+    let hi_addr = (addr & 0xFF00) | (addr.wrapping_add(1) & 0x00FF);
+    let lo = bus.peek_memory_byte(addr) as u16;
+    let hi = bus.peek_memory_byte(hi_addr) as u16;
+    return (hi << 8) | lo;
+}
+
+fn get_memory_operand(instruction: &Instruction, cpu: &mut CPU<BUS>) -> Option<MemoryOperand> {
+    let operand = instruction.operand;
+    let x = cpu.get_x() as u16;
+    let y = cpu.get_y() as u16;
+    let bus = cpu.get_bus_mut();
+    let (pointer, addr) = match instruction.mode {
+        AddressingModes::ZeroPage => (None, operand),
+        AddressingModes::ZeroPageX => (None, (operand + x) & 0xFF),
+        AddressingModes::ZeroPageY => (None, (operand + y) & 0xFF),
+        // jumps go to the operand rather than reading it
+        AddressingModes::Absolute if matches!(instruction.opcode, 0x20 | 0x4C) => return None,
+        AddressingModes::Absolute => (None, operand),
+        AddressingModes::AbsoluteX => (None, operand.wrapping_add(x)),
+        AddressingModes::AbsoluteY => (None, operand.wrapping_add(y)),
+        AddressingModes::IndirectX => {
+            let pointer = (operand + x) & 0xFF;
+            (Some(pointer), read_pointer(bus, pointer))
+        }
+        AddressingModes::IndirectY => {
+            let base = read_pointer(bus, operand);
+            (Some(base), base.wrapping_add(y))
+        }
+        _ => return None,
+    };
+    return Some(MemoryOperand {
+        pointer,
+        addr,
+        val: bus.peek_memory_byte(addr),
+    });
+}
+
+/// `NVUBDIZC` with set flags in upper case and clear ones in lower case.
+fn format_flags(p: u8) -> String {
+    return "NVUBDIZC"
+        .chars()
+        .enumerate()
+        .map(|(i, flag)| match p & (0x80 >> i) {
+            0 => flag.to_ascii_lowercase(),
+            _ => flag,
+        })
+        .collect();
+}
+
+fn format_bytes(instruction: &Instruction, prefix: &str) -> String {
+    let bytes: Vec<String> = instruction
+        .get_bytes()
+        .iter()
+        .map(|byte| format!("{}{:02X}", prefix, byte))
+        .collect();
+    return bytes.join(" ");
+}
+
+/// The disassembly with the effective address and value in nestest.log's
+/// notation, e.g. `LDA ($89),Y = 0300 @ 0300 = 89`.
+fn format_nestest_operand(instruction: &Instruction, text: String, cpu: &mut CPU<BUS>) -> String {
+    if instruction.mode == AddressingModes::Indirect {
+        let target = read_pointer(cpu.get_bus_mut(), instruction.operand);
+        return format!("{} = {:04X}", text, target);
+    }
+    let memory = match get_memory_operand(instruction, cpu) {
+        Some(memory) => memory,
+        None => return text,
+    };
+    match instruction.mode {
+        AddressingModes::ZeroPage | AddressingModes::Absolute => {
+            return format!("{} = {:02X}", text, memory.val);
+        }
+        AddressingModes::ZeroPageX | AddressingModes::ZeroPageY => {
+            return format!("{} @ {:02X} = {:02X}", text, memory.addr, memory.val);
+        }
+        AddressingModes::IndirectX => {
+            let pointer = memory.pointer.unwrap();
+            return format!(
+                "{} @ {:02X} = {:04X} = {:02X}",
+                text, pointer, memory.addr, memory.val
+            );
+        }
+        AddressingModes::IndirectY => {
+            let base = memory.pointer.unwrap();
+            return format!(
+                "{} = {:04X} @ {:04X} = {:02X}",
+                text, base, memory.addr, memory.val
+            );
+        }
+        _ => return format!("{} @ {:04X} = {:02X}", text, memory.addr, memory.val),
+    }
+}
+
+/// The disassembly followed by `@ $addr` for indexed operands and the value,
+/// the way FCEUX and Mesen annotate them. `prefix` goes before the value.
+fn format_annotated_operand(
+    instruction: &Instruction,
+    text: String,
+    cpu: &mut CPU<BUS>,
+    prefix: &str,
+) -> String {
+    let memory = match get_memory_operand(instruction, cpu) {
+        Some(memory) => memory,
+        None => return text,
+    };
+    let val = format!("{}{:02X}", prefix, memory.val);
+    match instruction.mode {
+        AddressingModes::ZeroPage | AddressingModes::Absolute => {
+            return format!("{} = {}", text, val);
+        }
+        _ => return format!("{} @ ${:04X} = {}", text, memory.addr, val),
+    }
+}
+
+fn format_line(format: TraceFormat, cpu: &mut CPU<BUS>, symbols: Option<&SymbolTable>) -> String {
+    let pc = cpu.get_pc();
+    let instruction = disassemble_at(cpu.get_bus_mut(), pc);
+    let text = match symbols {
+        Some(symbols) => {
+            let bus = cpu.get_bus();
+            instruction.format_with_labels(|addr| symbols.get_label(bus, addr))
+        }
+        None => instruction.to_string(),
+    };
+    let (a, x, y, p, sp) = (
+        cpu.get_a(),
+        cpu.get_x(),
+        cpu.get_y(),
+        cpu.get_ps(),
+        cpu.get_sp(),
+    );
+    let cycles = cpu.get_cycles();
+    let ppu = cpu.get_bus().get_ppu();
+    let (scanline, dot, frame) = (ppu.get_scanline(), ppu.get_dot(), ppu.get_frame());
+    match format {
+        TraceFormat::Nestest => {
+            return format!(
+                "{:04X}  {:<8}  {:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+                pc,
+                format_bytes(&instruction, ""),
+                format_nestest_operand(&instruction, text, cpu),
+                a,
+                x,
+                y,
+                p,
+                sp,
+                scanline,
+                dot,
+                cycles
+            );
+        }
+        TraceFormat::Mesen => {
+            return format!(
+                "{:04X}  {:<11} {:<32} A:{:02X} X:{:02X} Y:{:02X} S:{:02X} P:{} V:{:<3} H:{:<3} Fr:{} Cy:{}",
+                pc,
+                format_bytes(&instruction, "$"),
+                format_annotated_operand(&instruction, text, cpu, "$"),
+                a,
+                x,
+                y,
+                sp,
+                format_flags(p),
+                scanline,
+                dot,
+                frame,
+                cycles
+            );
+        }
+        TraceFormat::FCEUX => {
+            return format!(
+                "A:{:02X} X:{:02X} Y:{:02X} S:{:02X} P:{}  ${:04X}:{:<8}  {}",
+                a,
+                x,
+                y,
+                sp,
+                format_flags(p),
+                pc,
+                format_bytes(&instruction, ""),
+                format_annotated_operand(&instruction, text, cpu, "#$")
+            );
+        }
+    }
+}
+
+/// Writes a line to `out` for every instruction the filter lets through.
+/// Install one with [`Console::start_trace`](crate::Console::start_trace).
+pub struct TraceLogger {
+    out: Box<dyn Write>,
+    format: TraceFormat,
+    filter: TraceFilter,
+    symbols: Option<SymbolTable>,
+    triggered: bool,
+    lines: u64,
+    error: Option<io::Error>,
+}
+
+impl fmt::Debug for TraceLogger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return f
+            .debug_struct("TraceLogger")
+            .field("format", &self.format)
+            .field("filter", &self.filter)
+            .field("triggered", &self.triggered)
+            .field("lines", &self.lines)
+            .finish();
+    }
+}
+
+impl TraceLogger {
+    /// `out` should be buffered; it gets a write for every line.
+    pub fn init(out: Box<dyn Write>, format: TraceFormat, filter: TraceFilter) -> Self {
+        let triggered = filter.trigger.is_none();
+        return TraceLogger {
+            out,
+            format,
+            filter,
+            symbols: None,
+            triggered,
+            lines: 0,
+            error: None,
+        };
+    }
+
+    /// Shows operand addresses as labels from `symbols`.
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = Some(symbols);
+    }
+
+    pub fn get_lines(&self) -> u64 {
+        return self.lines;
+    }
+
+    fn passes_filter(&mut self, cpu: &CPU<BUS>) -> bool {
+        let pc = cpu.get_pc();
+        if !self.triggered {
+            self.triggered = match self.filter.trigger {
+                Some(TraceTrigger::Pc(addr)) => pc == addr,
+                Some(TraceTrigger::Frame(frame)) => cpu.get_bus().get_ppu().get_frame() >= frame,
+                None => true,
+            };
+            if !self.triggered {
+                return false;
+            }
+        }
+        let ranges = &self.filter.ranges;
+        if !ranges.is_empty() && !ranges.iter().any(|&(start, end)| start <= pc && pc <= end) {
+            return false;
+        }
+        if let Some(bank) = self.filter.prg_bank {
+            let offset = cpu
+                .get_bus()
+                .get_mapper()
+                .and_then(|mapper| mapper.get_prg_rom_offset(pc));
+            if offset.map(|offset| offset / PRG_BANK_SIZE) != Some(bank) {
+                return false;
+            }
+        }
+        return true;
+    }
+
+    /// Logs the instruction at PC, which is about to run. After a write
+    /// error nothing more is logged; [`finish`](Self::finish) reports it.
+    pub fn log(&mut self, cpu: &mut CPU<BUS>) {
+        if self.error.is_some() || !self.passes_filter(cpu) {
+            return;
+        }
+        let line = format_line(self.format, cpu, self.symbols.as_ref());
+        match writeln!(self.out, "{}", line) {
+            Ok(()) => self.lines += 1,
+            Err(err) => self.error = Some(err),
+        }
+    }
+
+    /// Flushes the log and returns the first error writing it.
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        return self.out.flush();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::{nrom_with_program, COUNTER};
+    use crate::cartridge::Cartridge;
+    use crate::console::Console;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            return self.0.borrow_mut().write(buf);
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    fn trace(format: TraceFormat, filter: TraceFilter, steps: usize) -> Vec<String> {
+        return trace_with_symbols(format, filter, None, steps);
+    }
+
+    fn trace_with_symbols(
+        format: TraceFormat,
+        filter: TraceFilter,
+        symbols: Option<SymbolTable>,
+        steps: usize,
+    ) -> Vec<String> {
+        let cartridge = Cartridge::from_ines(&nrom_with_program(COUNTER)).unwrap();
+        let mut console = Console::init(&cartridge).unwrap();
+        let buffer = SharedBuffer::default();
+        let mut logger = TraceLogger::init(Box::new(buffer.clone()), format, filter);
+        if let Some(symbols) = symbols {
+            logger.set_symbols(symbols);
+        }
+        console.start_trace(logger);
+        for _ in 0..steps {
+            console.step();
+        }
+        console.stop_trace().unwrap().finish().unwrap();
+        let text = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        return text.lines().map(String::from).collect();
+    }
+
+    #[test]
+    fn test_formats() {
+        let lines = trace(TraceFormat::Nestest, TraceFilter::default(), 3);
+        assert_eq!(
+            lines[0],
+            "8000  A2 00     LDX #$00                        A:00 X:00 Y:00 P:24 SP:FA PPU:  0, 21 CYC:7"
+        );
+        assert!(lines[2].starts_with("8003  86 10     STX $10 = 00                    A:00 X:01"));
+
+        let lines = trace(TraceFormat::Mesen, TraceFilter::default(), 3);
+        assert!(lines[2].starts_with("8003  $86 $10     STX $10 = $00"));
+        assert!(lines[2].contains(" S:FA P:nvUbdIzc V:0   H:33  Fr:0 Cy:11"));
+
+        let lines = trace(TraceFormat::FCEUX, TraceFilter::default(), 3);
+        assert_eq!(
+            lines[2],
+            "A:00 X:01 Y:00 S:FA P:nvUbdIzc  $8003:86 10     STX $10 = #$00"
+        );
+    }
+
+    #[test]
+    fn test_filters() {
+        let filter = TraceFilter {
+            ranges: vec![(0x8003, 0x8004)],
+            ..TraceFilter::default()
+        };
+        let lines = trace(TraceFormat::FCEUX, filter, 10);
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().all(|line| line.contains("$8003:")));
+
+        let filter = TraceFilter {
+            trigger: Some(TraceTrigger::Pc(0x8005)),
+            ..TraceFilter::default()
+        };
+        let lines = trace(TraceFormat::FCEUX, filter, 5);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("$8005:"));
+
+        let filter = TraceFilter {
+            prg_bank: Some(1),
+            ..TraceFilter::default()
+        };
+        assert!(trace(TraceFormat::FCEUX, filter, 5).is_empty());
+        let filter = TraceFilter {
+            prg_bank: Some(0),
+            ..TraceFilter::default()
+        };
+        assert_eq!(trace(TraceFormat::FCEUX, filter, 5).len(), 5);
+    }
+
+    #[test]
+    fn test_labels() {
+        let mut symbols = SymbolTable::init();
+        symbols.parse_nl("$0010#counter#\n", None).unwrap();
+        let lines =
+            trace_with_symbols(TraceFormat::FCEUX, TraceFilter::default(), Some(symbols), 3);
+        assert!(lines[2].ends_with("STX counter = #$00"));
+    }
+}
@@ -0,0 +1,217 @@
+//! Turning the PPU's 9-bit pixels into RGB, either by looking the colour
+//! up in a palette or by simulating the NTSC composite signal the console
+//! sends to the TV.
+
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use std::f32::consts::PI;
+
+// the PPU puts out 8 signal samples per pixel and the colour subcarrier
+// repeats every 12, so colours shift phase from one pixel to the next
+const SAMPLES_PER_PIXEL: usize = 8;
+const SUBCARRIER_PERIOD: usize = 12;
+// 341 dots of 8 samples leave the next scanline 4 samples further along
+const SCANLINE_PHASE_STEP: usize = 4;
+// and a frame 8 further along, which makes the artifacts crawl
+const FRAME_PHASE_STEP: usize = 8;
+
+// composite voltages for the four luma levels at the low and high points
+// of the colour wave
+const SIGNAL_LOW: [f32; 4] = [0.228, 0.312, 0.552, 0.880];
+const SIGNAL_HIGH: [f32; 4] = [0.616, 0.840, 1.100, 1.100];
+const BLACK: f32 = 0.312;
+const WHITE: f32 = 1.100;
+// how far an emphasis bit pulls the signal down during its third of the wave
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+// lines the decoder's colour reference up with the colour burst
+const HUE_OFFSET: f32 = 3.5;
+const SATURATION: f32 = 2.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VideoFilter {
+    /// Each colour index maps straight to one RGB value.
+    #[default]
+    Palette,
+    /// Each scanline goes through a simulated composite signal and back,
+    /// with the colour fringes and dot crawl that brings.
+    NTSC,
+}
+
+impl VideoFilter {
+    /// Parses `palette` or `ntsc`.
+    pub fn from_name(name: &str) -> Option<VideoFilter> {
+        match name.to_ascii_lowercase().as_str() {
+            "palette" => return Some(VideoFilter::Palette),
+            "ntsc" => return Some(VideoFilter::NTSC),
+            _ => return None,
+        }
+    }
+}
+
+fn in_colour_phase(colour: usize, phase: usize) -> bool {
+    return (colour + phase) % SUBCARRIER_PERIOD < SUBCARRIER_PERIOD / 2;
+}
+
+/// The signal level of `pixel` at subcarrier `phase`, with black at 0 and
+/// white at 1.
+fn get_signal_level(pixel: u16, phase: usize) -> f32 {
+    let colour = (pixel & 0x0F) as usize;
+    let emphasis = pixel >> 6;
+    // $xE and $xF are black whatever the luma bits say
+    let level = if colour > 13 {
+        1
+    } else {
+        ((pixel >> 4) & 0x03) as usize
+    };
+    let mut low = SIGNAL_LOW[level];
+    let mut high = SIGNAL_HIGH[level];
+    // $x0 is a flat grey at the high level, $xD and up at the low one
+    if colour == 0 {
+        low = high;
+    }
+    if colour > 12 {
+        high = low;
+    }
+    let mut signal = if in_colour_phase(colour, phase) {
+        high
+    } else {
+        low
+    };
+    // red, green and blue emphasis darken the phases of colours 0, 4 and 8
+    for (bit, emphasis_colour) in [0, 4, 8].into_iter().enumerate() {
+        if emphasis & (1 << bit) != 0 && in_colour_phase(emphasis_colour, phase) {
+            signal *= EMPHASIS_ATTENUATION;
+        }
+    }
+    return (signal - BLACK) / (WHITE - BLACK);
+}
+
+/// Encodes scanlines as an NTSC signal and decodes them again.
+#[derive(Debug, Clone)]
+pub struct NTSCFilter {
+    // signal level of every 9-bit pixel at every subcarrier phase
+    levels: Vec<[f32; SUBCARRIER_PERIOD]>,
+    // the decoder's I and Q carriers
+    i_carrier: [f32; SUBCARRIER_PERIOD],
+    q_carrier: [f32; SUBCARRIER_PERIOD],
+}
+
+impl NTSCFilter {
+    pub fn init() -> Self {
+        let levels = (0..512u16)
+            .map(|pixel| {
+                let mut levels = [0.0; SUBCARRIER_PERIOD];
+                for (phase, level) in levels.iter_mut().enumerate() {
+                    *level = get_signal_level(pixel, phase);
+                }
+                return levels;
+            })
+            .collect();
+        let mut i_carrier = [0.0; SUBCARRIER_PERIOD];
+        let mut q_carrier = [0.0; SUBCARRIER_PERIOD];
+        for phase in 0..SUBCARRIER_PERIOD {
+            let angle = PI * (phase as f32 + HUE_OFFSET) / 6.0;
+            i_carrier[phase] = angle.cos();
+            q_carrier[phase] = angle.sin();
+        }
+        return NTSCFilter {
+            levels,
+            i_carrier,
+            q_carrier,
+        };
+    }
+
+    /// Converts a 256x240 frame of 9-bit pixels into packed RGB24. `frame`
+    /// is the PPU frame number, which sets where the subcarrier starts.
+    pub fn filter_frame(&self, pixels: &[u16], frame: u64, rgb: &mut [u8]) {
+        let frame_phase = (frame % 3) as usize * FRAME_PHASE_STEP;
+        let mut signal = vec![0.0; SCREEN_WIDTH * SAMPLES_PER_PIXEL];
+        let rows = pixels
+            .chunks_exact(SCREEN_WIDTH)
+            .zip(rgb.chunks_exact_mut(SCREEN_WIDTH * 3))
+            .take(SCREEN_HEIGHT);
+        for (y, (row, rgb_row)) in rows.enumerate() {
+            let line_phase = frame_phase + y * SCANLINE_PHASE_STEP;
+            for (i, sample) in signal.iter_mut().enumerate() {
+                let pixel = row[i / SAMPLES_PER_PIXEL] & 0x1FF;
+                let phase = (line_phase + i) % SUBCARRIER_PERIOD;
+                *sample = self.levels[pixel as usize][phase];
+            }
+            self.decode_scanline(&signal, line_phase, rgb_row);
+        }
+    }
+
+    // Demodulates one subcarrier period of signal around every pixel.
+    fn decode_scanline(&self, signal: &[f32], line_phase: usize, rgb: &mut [u8]) {
+        let last_sample = signal.len() as isize - 1;
+        let scale = 1.0 / SUBCARRIER_PERIOD as f32;
+        for (x, pixel) in rgb.chunks_exact_mut(3).enumerate() {
+            let center = x * SAMPLES_PER_PIXEL + SAMPLES_PER_PIXEL / 2;
+            let start = center as isize - (SUBCARRIER_PERIOD / 2) as isize;
+            let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+            for n in start..start + SUBCARRIER_PERIOD as isize {
+                // at the edges the window runs off the scanline
+                let level = signal[n.clamp(0, last_sample) as usize];
+                let phase = (line_phase as isize + n).rem_euclid(SUBCARRIER_PERIOD as isize);
+                y += level;
+                i += level * self.i_carrier[phase as usize];
+                q += level * self.q_carrier[phase as usize];
+            }
+            let (y, i, q) = (y * scale, i * scale * SATURATION, q * scale * SATURATION);
+            let r = y + 0.946882 * i + 0.623557 * q;
+            let g = y - 0.274788 * i - 0.635691 * q;
+            let b = y - 1.108545 * i + 1.709007 * q;
+            pixel[0] = to_byte(r);
+            pixel[1] = to_byte(g);
+            pixel[2] = to_byte(b);
+        }
+    }
+}
+
+fn to_byte(val: f32) -> u8 {
+    return (val.clamp(0.0, 1.0) * 255.0).round() as u8;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn filter_solid(pixel: u16) -> [u8; 3] {
+        let pixels = vec![pixel; SCREEN_WIDTH * SCREEN_HEIGHT];
+        let mut rgb = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * 3];
+        NTSCFilter::init().filter_frame(&pixels, 0, &mut rgb);
+        // a pixel away from the edges of the middle scanline
+        let offset = (120 * SCREEN_WIDTH + 128) * 3;
+        return [rgb[offset], rgb[offset + 1], rgb[offset + 2]];
+    }
+
+    #[test]
+    fn test_ntsc_filter_colours() {
+        assert_eq!(filter_solid(0x0F), [0, 0, 0]);
+        assert_eq!(filter_solid(0x30), [255, 255, 255]);
+        let [r, g, b] = filter_solid(0x00);
+        assert!(r == g && g == b && r > 64 && r < 160);
+
+        let [r, g, b] = filter_solid(0x16);
+        assert!(r > g && r > b, "red came out as {:?}", [r, g, b]);
+        let [r, g, b] = filter_solid(0x1A);
+        assert!(g > r && g > b, "green came out as {:?}", [r, g, b]);
+        let [r, g, b] = filter_solid(0x12);
+        assert!(b > r && b > g, "blue came out as {:?}", [r, g, b]);
+    }
+
+    #[test]
+    fn test_emphasis_tints() {
+        // each bit dims the other two colours, so grey takes on its colour
+        for channel in 0..3 {
+            let rgb = filter_solid(0x10 | 1 << (6 + channel));
+            let others = (0..3).filter(|c| *c != channel);
+            assert!(
+                others.into_iter().all(|c| rgb[c] < rgb[channel]),
+                "{:?}",
+                rgb
+            );
+        }
+        let [r, g, b] = filter_solid(0x10 | 0b111 << 6);
+        assert!(r == g && g == b && r < filter_solid(0x10)[0]);
+    }
+}
@@ -0,0 +1,135 @@
+//! Runs blargg's test ROMs from the directory in `RUSTES_TEST_ROMS`, laid out
+//! the way the ROMs are distributed (`instr_test-v5/rom_singles/...`).
+//! Suites whose ROMs aren't there are skipped, so these pass trivially when
+//! the variable isn't set. Only ROMs the emulator passes are listed; the
+//! ones left out are noted with each suite. Best run with `--release`.
+
+use rustes::blargg::TestResult;
+use rustes::{Cartridge, Console};
+use std::env;
+use std::path::PathBuf;
+
+// longest any of the ROMs below needs, instr_timing takes about 25 seconds
+const MAX_FRAMES: u64 = 60 * 60;
+
+fn run_suite(suite: &str, roms: &[&str]) {
+    let dir = match env::var_os("RUSTES_TEST_ROMS") {
+        Some(dir) => PathBuf::from(dir).join(suite),
+        None => {
+            eprintln!("skipping {}: RUSTES_TEST_ROMS is not set", suite);
+            return;
+        }
+    };
+    if !dir.is_dir() {
+        eprintln!("skipping {}: {} not found", suite, dir.display());
+        return;
+    }
+
+    let mut failures = Vec::new();
+    for rom in roms {
+        let path = dir.join(rom);
+        let cartridge = match Cartridge::load(&path) {
+            Ok(cartridge) => cartridge,
+            Err(err) => {
+                failures.push(format!("{}: {}", rom, err));
+                continue;
+            }
+        };
+        let mut console = match Console::init(&cartridge) {
+            Ok(console) => console,
+            Err(err) => {
+                failures.push(format!("{}: {}", rom, err));
+                continue;
+            }
+        };
+        match console.run_test(MAX_FRAMES) {
+            TestResult::Finished { status: 0, .. } => {}
+            TestResult::Finished { status, message } => {
+                failures.push(format!("{}: status {}\n{}", rom, status, message))
+            }
+            TestResult::TimedOut => failures.push(format!("{}: timed out", rom)),
+            TestResult::NotDetected => failures.push(format!("{}: no status reported", rom)),
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n\n"));
+}
+
+// 03-09 also cover the unofficial opcodes, which run as NOPs
+#[test]
+fn instr_test_v5() {
+    run_suite(
+        "instr_test-v5",
+        &[
+            "rom_singles/01-basics.nes",
+            "rom_singles/02-implied.nes",
+            "rom_singles/10-branches.nes",
+            "rom_singles/11-stack.nes",
+            "rom_singles/12-jmp_jsr.nes",
+            "rom_singles/13-rts.nes",
+            "rom_singles/14-rti.nes",
+            "rom_singles/15-brk.nes",
+            "rom_singles/16-special.nes",
+        ],
+    );
+}
+
+#[test]
+fn instr_misc() {
+    run_suite(
+        "instr_misc",
+        &[
+            "rom_singles/01-abs_x_wrap.nes",
+            "rom_singles/02-branch_wrap.nes",
+            "rom_singles/03-dummy_reads.nes",
+            "rom_singles/04-dummy_reads_apu.nes",
+        ],
+    );
+}
+
+// 1-instr_timing times unofficial opcodes too; none of cpu_interrupts_v2
+// passes yet
+#[test]
+fn instr_timing() {
+    run_suite("instr_timing", &["rom_singles/2-branch_timing.nes"]);
+}
+
+#[test]
+fn ppu_vbl_nmi() {
+    run_suite(
+        "ppu_vbl_nmi",
+        &[
+            "rom_singles/01-vbl_basics.nes",
+            "rom_singles/02-vbl_set_time.nes",
+            "rom_singles/03-vbl_clear_time.nes",
+            "rom_singles/04-nmi_control.nes",
+            "rom_singles/05-nmi_timing.nes",
+            "rom_singles/06-suppression.nes",
+            "rom_singles/07-nmi_on_timing.nes",
+            "rom_singles/08-nmi_off_timing.nes",
+            "rom_singles/09-even_odd_frames.nes",
+            "rom_singles/10-even_odd_timing.nes",
+        ],
+    );
+}
+
+#[test]
+fn ppu_open_bus() {
+    run_suite("ppu_open_bus", &["ppu_open_bus.nes"]);
+}
+
+// 5-len_timing clocks the first length step too soon
+#[test]
+fn apu_test() {
+    run_suite(
+        "apu_test",
+        &[
+            "rom_singles/1-len_ctr.nes",
+            "rom_singles/2-len_table.nes",
+            "rom_singles/3-irq_flag.nes",
+            "rom_singles/4-jitter.nes",
+            "rom_singles/6-irq_flag_timing.nes",
+            "rom_singles/7-dmc_basics.nes",
+            "rom_singles/8-dmc_rates.nes",
+        ],
+    );
+}